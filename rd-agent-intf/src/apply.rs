@@ -0,0 +1,36 @@
+// Copyright (c) Facebook, Inc. and its affiliates.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use super::side_defs::SideloadSpec;
+
+/// Workload target read by `rd-agent --apply-stdin`. `defs` are passed on
+/// as cmd.json side_defs{} which are added to or override the entries in
+/// sideload-defs.json without changing the file.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApplyTarget {
+    pub sysloads: BTreeMap<String, String>,
+    pub sideloads: BTreeMap<String, String>,
+    pub defs: BTreeMap<String, SideloadSpec>,
+}
+
+/// Result of `rd-agent --apply-stdin`. `applied` is set once the running
+/// agent's report shows `cmd_seq`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApplyResult {
+    pub applied: bool,
+    pub cmd_seq: u64,
+    pub errors: Vec<String>,
+    pub sysloads: BTreeMap<String, String>,
+    pub sideloads: BTreeMap<String, String>,
+}
+
+/// Progress of `rd-agent --apply-stdin`. With --progress, each is printed
+/// on stdout as a line of JSON followed by the ApplyResult on its own line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ApplyProgress {
+    Written { cmd_seq: u64 },
+    Applied { cmd_seq: u64 },
+}
@@ -39,10 +39,39 @@ lazy_static! {
         "-d, --dir=[TOPDIR]     'Top-level dir for operation and scratch files (default: {dfl_dir})'
          -s, --scratch=[DIR]    'Scratch dir for workloads to use (default: $TOPDIR/scratch)'
          -L, --no-iolat         'Disable bpf-based io latency stat monitoring'
+             --no-restart-on-change 'Don't restart running sys/sideloads when their definitions change'
              --dev=[NAME]       'Override storage device autodetection (e.g. sda, nvme0n1)'
              --force            'Ignore startup check results and proceed'
+             --strict           'Refuse to start if any system requirement is missed, overrides --force'
+             --strict-defs      'Refuse to start if any sideload def has an unresolvable binary'
              --prepare          'Prepare the files and directories and exit'
+             --apply-stdin      'Read sys/sideload target json or toml from stdin, apply it through $TOPDIR/cmd.json and exit once the agent applied it'
+             --list-side-bins   'List the embedded side binaries with their sha256 and exit'
+             --progress         'Print the progress of preparation and --apply-stdin as JSON lines on stdout'
              --linux-tar=[FILE] 'Path to linux source tarball to be used by build sideload'
+             --linux-src-shared 'Extract the linux tarball once into a read-only tree and build out-of-tree'
+             --linux-tar-url=[URLS] 'Comma-separated linux source .tar.xz or .tar URLs to try in order instead of the default mirrors'
+             --linux-tar-sha256=[HEX] 'SHA-256 of the decompressed tarball at --linux-tar-url (default: built-in digest of the version if known)'
+             --bin-path=[DIRS]  'Colon-separated dirs to search for sys/sideload binaries before PATH'
+             --max-heavy=[NR]   'Max number of concurrent heavy sys/sideloads (default: unlimited)'
+             --heavy-scr-free=[BYTES] 'Refuse heavy sys/sideloads if scratch free space is lower'
+             --scr-budget=[FRAC] 'Warn when sys/sideloads together use more than FRAC of the scratch device'
+             --scr-budget-hard  'Refuse heavy sys/sideloads instead of warning when over --scr-budget'
+             --settle-timeout=[SECS] 'Max wait for the old unit to go away when re-creating a sys/sideload (default: 5)'
+             --scr-rm-timeout=[SECS] 'Max time to keep retrying removal of a sys/sideload scratch dir (default: 10)'
+             --io-latency=[USECS] 'Protect workload.slice with io.latency target USECS on the scratch device (default: off)'
+             --balloon-refresh=[SECS] 'Check the memory balloon is alive every SECS between size changes (default: off)'
+             --balloon-psi=[PCT] 'Deflate the memory balloon while full memory pressure avg10 is over PCT% (default: off)'
+             --balloon-deflate=[FRAC] 'Fraction of the memory balloon to release under --balloon-psi (default: 0.5)'
+             --report-sock=[PATH] 'Stream reports as newline-delimited json over unix socket PATH'
+             --metrics-port=[PORT] 'Serve sys/sideload metrics in Prometheus text format on TCP PORT (default: off)'
+             --metrics-addr=[ADDR] 'Address to serve --metrics-port on (default: 127.0.0.1)'
+             --prop-audit=[LEVEL] 'Log the properties applied to sys/sideloads, off, summary or full (default: off)'
+             --scr-umount=[MODE] 'Unmount mounts left in workload scratch dirs before removal, off, normal or lazy to fall back to lazy unmount (default: lazy)'
+             --sys-scr-tmpfs    'Back sysload scratch dirs with tmpfs instead of the scratch device'
+             --side-scr-tmpfs   'Back sideload scratch dirs with tmpfs instead of the scratch device'
+             --scr-tmpfs-size=[BYTES] 'Size of each scratch tmpfs (default: tmpfs default, half of memory)'
+             --scr-template=[TMPL] 'Workload scratch dir layout with {{name}}, {{kind}}, {{tag}} and {{date}} (default: {{name}})'
              --reset            'Reset all states except for bench results, linux.tar and testfiles'
              --passive          'Make system configuration changes only when explicitly requested'
          -a, --args=[FILE]      'Load base command line arguments from FILE'
@@ -58,14 +87,50 @@ pub struct Args {
     pub scratch: Option<String>,
     pub dev: Option<String>,
     pub no_iolat: bool,
+    pub no_restart_on_change: bool,
+    pub linux_src_shared: bool,
+    pub bin_path: Option<String>,
+    pub max_heavy: Option<u32>,
+    pub heavy_scr_free: Option<u64>,
+    pub scr_budget: Option<f64>,
+    pub scr_budget_hard: bool,
+    pub settle_timeout: Option<f64>,
+    pub scr_rm_timeout: Option<f64>,
+    pub balloon_refresh: Option<f64>,
+    pub balloon_psi: Option<f64>,
+    pub balloon_deflate: Option<f64>,
+    pub io_latency: Option<u64>,
+    pub report_sock: Option<String>,
+    pub metrics_port: Option<u16>,
+    pub metrics_addr: Option<String>,
+    pub sys_scr_tmpfs: bool,
+    pub side_scr_tmpfs: bool,
+    pub scr_tmpfs_size: Option<u64>,
+    pub scr_template: Option<String>,
+    pub prop_audit: Option<String>,
+    pub scr_umount: Option<String>,
 
     #[serde(skip)]
     pub force: bool,
     #[serde(skip)]
+    pub strict: bool,
+    #[serde(skip)]
+    pub strict_defs: bool,
+    #[serde(skip)]
     pub prepare: bool,
     #[serde(skip)]
+    pub apply_stdin: bool,
+    #[serde(skip)]
+    pub list_side_bins: bool,
+    #[serde(skip)]
+    pub progress: bool,
+    #[serde(skip)]
     pub linux_tar: Option<String>,
     #[serde(skip)]
+    pub linux_tar_url: Option<String>,
+    #[serde(skip)]
+    pub linux_tar_sha256: Option<String>,
+    #[serde(skip)]
     pub reset: bool,
     #[serde(skip)]
     pub passive: bool,
@@ -78,9 +143,38 @@ impl Default for Args {
             scratch: None,
             dev: None,
             no_iolat: false,
+            no_restart_on_change: false,
+            linux_src_shared: false,
+            bin_path: None,
+            max_heavy: None,
+            heavy_scr_free: None,
+            scr_budget: None,
+            scr_budget_hard: false,
+            settle_timeout: None,
+            scr_rm_timeout: None,
+            balloon_refresh: None,
+            balloon_psi: None,
+            balloon_deflate: None,
+            io_latency: None,
+            report_sock: None,
+            metrics_port: None,
+            metrics_addr: None,
+            sys_scr_tmpfs: false,
+            side_scr_tmpfs: false,
+            scr_tmpfs_size: None,
+            scr_template: None,
+            prop_audit: None,
+            scr_umount: None,
             force: false,
+            strict: false,
+            strict_defs: false,
             prepare: false,
+            apply_stdin: false,
+            list_side_bins: false,
+            progress: false,
             linux_tar: None,
+            linux_tar_url: None,
+            linux_tar_sha256: None,
             reset: false,
             passive: false,
         }
@@ -134,11 +228,176 @@ impl JsonArgs for Args {
             updated_base = true;
         }
 
+        if let Some(v) = matches.value_of("bin-path") {
+            self.bin_path = if !v.is_empty() {
+                Some(v.to_string())
+            } else {
+                None
+            };
+            updated_base = true;
+        }
+
+        if let Some(v) = matches.value_of("max-heavy") {
+            self.max_heavy = if !v.is_empty() {
+                Some(v.parse::<u32>().unwrap())
+            } else {
+                None
+            };
+            updated_base = true;
+        }
+
+        if let Some(v) = matches.value_of("heavy-scr-free") {
+            self.heavy_scr_free = if !v.is_empty() {
+                Some(v.parse::<u64>().unwrap())
+            } else {
+                None
+            };
+            updated_base = true;
+        }
+
+        if let Some(v) = matches.value_of("scr-budget") {
+            self.scr_budget = if !v.is_empty() {
+                Some(v.parse::<f64>().unwrap())
+            } else {
+                None
+            };
+            updated_base = true;
+        }
+
+        if let Some(v) = matches.value_of("settle-timeout") {
+            self.settle_timeout = if !v.is_empty() {
+                Some(v.parse::<f64>().unwrap())
+            } else {
+                None
+            };
+            updated_base = true;
+        }
+
+        if let Some(v) = matches.value_of("scr-rm-timeout") {
+            self.scr_rm_timeout = if !v.is_empty() {
+                Some(v.parse::<f64>().unwrap())
+            } else {
+                None
+            };
+            updated_base = true;
+        }
+
+        if let Some(v) = matches.value_of("io-latency") {
+            self.io_latency = if !v.is_empty() {
+                Some(v.parse::<u64>().unwrap())
+            } else {
+                None
+            };
+            updated_base = true;
+        }
+
+        if let Some(v) = matches.value_of("balloon-refresh") {
+            self.balloon_refresh = if !v.is_empty() {
+                Some(v.parse::<f64>().unwrap())
+            } else {
+                None
+            };
+            updated_base = true;
+        }
+
+        if let Some(v) = matches.value_of("balloon-psi") {
+            self.balloon_psi = if !v.is_empty() {
+                Some(v.parse::<f64>().unwrap())
+            } else {
+                None
+            };
+            updated_base = true;
+        }
+
+        if let Some(v) = matches.value_of("balloon-deflate") {
+            self.balloon_deflate = if !v.is_empty() {
+                Some(v.parse::<f64>().unwrap())
+            } else {
+                None
+            };
+            updated_base = true;
+        }
+
+        if let Some(v) = matches.value_of("report-sock") {
+            self.report_sock = if !v.is_empty() {
+                Some(v.to_string())
+            } else {
+                None
+            };
+            updated_base = true;
+        }
+
+        if let Some(v) = matches.value_of("metrics-port") {
+            self.metrics_port = if !v.is_empty() {
+                Some(v.parse::<u16>().unwrap())
+            } else {
+                None
+            };
+            updated_base = true;
+        }
+
+        if let Some(v) = matches.value_of("metrics-addr") {
+            self.metrics_addr = if !v.is_empty() {
+                Some(v.to_string())
+            } else {
+                None
+            };
+            updated_base = true;
+        }
+
+        if let Some(v) = matches.value_of("scr-tmpfs-size") {
+            self.scr_tmpfs_size = if !v.is_empty() {
+                Some(v.parse::<u64>().unwrap())
+            } else {
+                None
+            };
+            updated_base = true;
+        }
+
+        if let Some(v) = matches.value_of("scr-template") {
+            self.scr_template = if !v.is_empty() {
+                Some(v.to_string())
+            } else {
+                None
+            };
+            updated_base = true;
+        }
+
+        if let Some(v) = matches.value_of("prop-audit") {
+            self.prop_audit = if !v.is_empty() {
+                Some(v.to_string())
+            } else {
+                None
+            };
+            updated_base = true;
+        }
+
+        if let Some(v) = matches.value_of("scr-umount") {
+            self.scr_umount = if !v.is_empty() {
+                Some(v.to_string())
+            } else {
+                None
+            };
+            updated_base = true;
+        }
+
         self.no_iolat = matches.is_present("no-iolat");
+        self.no_restart_on_change = matches.is_present("no-restart-on-change");
+        self.linux_src_shared = matches.is_present("linux-src-shared");
+        self.scr_budget_hard = matches.is_present("scr-budget-hard");
+        self.sys_scr_tmpfs = matches.is_present("sys-scr-tmpfs");
+        self.side_scr_tmpfs = matches.is_present("side-scr-tmpfs");
 
         self.force = matches.is_present("force");
+        self.strict = matches.is_present("strict");
+        self.strict_defs = matches.is_present("strict-defs");
         self.prepare = matches.is_present("prepare");
+        self.apply_stdin = matches.is_present("apply-stdin");
+        self.list_side_bins = matches.is_present("list-side-bins");
+        self.progress = matches.is_present("progress");
         self.linux_tar = matches.value_of("linux-tar").map(|x| x.to_string());
+        self.linux_tar_url = matches.value_of("linux-tar-url").map(|x| x.to_string());
+        self.linux_tar_sha256 = matches.value_of("linux-tar-sha256").map(|x| x.to_string());
         self.reset = matches.is_present("reset");
         self.passive = matches.is_present("passive");
 
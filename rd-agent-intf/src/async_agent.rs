@@ -0,0 +1,185 @@
+// Copyright (c) Facebook, Inc. and its affiliates.
+//
+// Async wrappers for embedders with reactive front-ends. rd-agent is run
+// with --progress the same way as from the command line and the progress
+// lines it prints are forwarded through a channel as they come in, so the
+// caller can await the result without blocking its runtime. Send failures
+// are ignored so that the receiver may be dropped if not interested.
+use anyhow::{anyhow, bail, Result};
+use serde::de::DeserializeOwned;
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdout, Command};
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::apply::{ApplyProgress, ApplyResult, ApplyTarget};
+use super::prepare_history::PrepareProgress;
+
+fn spawn_agent(agent_bin: &str, args: &[String], mode: Option<&str>) -> Result<Child> {
+    let mut cmd = Command::new(agent_bin);
+    cmd.args(args).arg("--progress");
+    if let Some(mode) = mode {
+        cmd.arg(mode);
+    }
+    cmd.stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow!("failed to run {:?} ({})", agent_bin, &e))
+}
+
+fn stdout_lines(child: &mut Child) -> Lines<BufReader<ChildStdout>> {
+    BufReader::new(child.stdout.take().unwrap()).lines()
+}
+
+fn parse_line<T: DeserializeOwned>(line: &str) -> Option<T> {
+    serde_json::from_str(line).ok()
+}
+
+/// Run `AGENT_BIN ARGS --progress --prepare` to prepare the side binaries
+/// and the linux tarball, which may have to be downloaded, and send the
+/// stages including the download progress to `progress`.
+pub async fn prepare(
+    agent_bin: &str,
+    args: &[String],
+    progress: UnboundedSender<PrepareProgress>,
+) -> Result<()> {
+    let mut child = spawn_agent(agent_bin, args, Some("--prepare"))?;
+    drop(child.stdin.take());
+
+    let mut lines = stdout_lines(&mut child);
+    while let Some(line) = lines.next_line().await? {
+        if let Some(p) = parse_line(&line) {
+            let _ = progress.send(p);
+        }
+    }
+
+    let status = child.wait().await?;
+    if !status.success() {
+        bail!("{:?} --prepare failed ({})", agent_bin, &status);
+    }
+    Ok(())
+}
+
+/// Pipe `target` into `AGENT_BIN ARGS --progress --apply-stdin` and send
+/// the progress to `progress` until the running agent applied it. The
+/// returned result carries the errors if it couldn't be applied.
+pub async fn apply(
+    agent_bin: &str,
+    args: &[String],
+    target: &ApplyTarget,
+    progress: UnboundedSender<ApplyProgress>,
+) -> Result<ApplyResult> {
+    let body = serde_json::to_vec(target)?;
+    let mut child = spawn_agent(agent_bin, args, Some("--apply-stdin"))?;
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(&body).await?;
+    drop(stdin);
+
+    let mut res = None;
+    let mut lines = stdout_lines(&mut child);
+    while let Some(line) = lines.next_line().await? {
+        if let Some(p) = parse_line(&line) {
+            let _ = progress.send(p);
+        } else if let Some(v) = parse_line(&line) {
+            res = Some(v);
+        }
+    }
+
+    // Exits with 1 if not applied, which is described by the result.
+    child.wait().await?;
+    res.ok_or_else(|| anyhow!("{:?} --apply-stdin didn't print the result", agent_bin))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, prepare};
+    use crate::{ApplyProgress, ApplyTarget, PrepareProgress};
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    // A stand-in for rd-agent which prints `out` and exits with `code`.
+    fn fake_agent(name: &str, out: &str, code: i32) -> String {
+        let dir = std::env::temp_dir().join(format!(
+            "rd-agent-intf-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rd-agent");
+        let script = format!(
+            "#!/bin/sh\ncat > /dev/null\ncat <<'EOF'\n{}\nEOF\nexit {}\n",
+            out, code
+        );
+        fs::write(&path, script).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn remove_fake_agent(bin: &str) {
+        let _ = fs::remove_dir_all(std::path::Path::new(bin).parent().unwrap());
+    }
+
+    fn block_on<F: std::future::Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    #[test]
+    fn test_prepare() {
+        let out = r#""SideBins"
+"LinuxTar"
+"Downloading"
+{"Downloaded":{"done":10,"total":100}}
+not json
+"Done""#;
+        let bin = fake_agent("prepare", out, 0);
+        let (tx, mut rx) = unbounded_channel();
+        block_on(prepare(&bin, &[], tx)).unwrap();
+        let mut got = vec![];
+        while let Ok(p) = rx.try_recv() {
+            got.push(p);
+        }
+        assert_eq!(
+            got,
+            vec![
+                PrepareProgress::SideBins,
+                PrepareProgress::LinuxTar,
+                PrepareProgress::Downloading,
+                PrepareProgress::Downloaded {
+                    done: 10,
+                    total: Some(100)
+                },
+                PrepareProgress::Done,
+            ]
+        );
+        remove_fake_agent(&bin);
+
+        let bin = fake_agent("prepare-fail", "", 1);
+        let (tx, _) = unbounded_channel();
+        assert!(block_on(prepare(&bin, &[], tx)).is_err());
+        remove_fake_agent(&bin);
+    }
+
+    #[test]
+    fn test_apply() {
+        let out = r#"{"Written":{"cmd_seq":3}}
+{"applied":false,"cmd_seq":3,"errors":["timed out"],"sysloads":{},"sideloads":{}}"#;
+        let bin = fake_agent("apply", out, 1);
+        let (tx, mut rx) = unbounded_channel();
+        let res = block_on(apply(&bin, &[], &ApplyTarget::default(), tx)).unwrap();
+        remove_fake_agent(&bin);
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            ApplyProgress::Written { cmd_seq: 3 }
+        );
+        assert!(rx.try_recv().is_err());
+        assert!(!res.applied);
+        assert_eq!(res.cmd_seq, 3);
+        assert_eq!(res.errors, vec!["timed out"]);
+    }
+}
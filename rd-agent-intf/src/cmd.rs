@@ -1,9 +1,10 @@
 // Copyright (c) Facebook, Inc. and its affiliates.
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use util::*;
 
+use super::side_defs::SideloadSpec;
 use rd_hashd_intf;
 
 lazy_static! {
@@ -28,8 +29,8 @@ lazy_static! {
 // control of the sideloader which, among other things, enforces CPU headroom.
 //
 // Each sys/sideload must have a unique name. The actual workload is determined
-// by DEF_ID which points to an entry in sideload-defs.json file. Creating an
-// entry starts the workload. Removing stops it.
+// by DEF_ID which points to an entry in sideload-defs.json file or
+// side_defs{{}}. Creating an entry starts the workload. Removing stops it.
 //
 //  cmd_seq: Written to cmd-ack.json once the commands are accepted
 //  bench_hashd_seq: If > bench::hashd_seq, start benchmark; otherwise, cancel
@@ -49,6 +50,45 @@ lazy_static! {
 //  hashd[].weight: Relative weight between the two hashd instances
 //  sysloads{{}}: \"NAME\": \"DEF_ID\" pairs for active sysloads
 //  sideloads{{}}: \"NAME\": \"DEF_ID\" pairs for active sideloads
+//  side_defs{{}}: \"DEF_ID\": SPEC pairs in the sideload-defs.json format.
+//                Added to the defs for this cmd only, replacing the entries
+//                in sideload-defs.json with the same DEF_ID
+//  swaps{{}}: \"IN_NAME\": \"OUT_NAME\" pairs. When sysloads{{}} or sideloads{{}}
+//            replace OUT_NAME with IN_NAME, IN_NAME is started first and
+//            OUT_NAME is stopped only after IN_NAME stays up for a few
+//            seconds. If IN_NAME fails, it's stopped and OUT_NAME is kept
+//            running until the pair is removed
+//  clones{{}}: \"NEW_NAME\": \"SRC_NAME\" pairs. Run NEW_NAME as another
+//             instance of sys/sideload SRC_NAME with its own scratch dir
+//  kill[]: Sys/sideload NAMEs to SIGKILL immediately when removed instead of
+//          going through the normal stop, for wedged workloads. min_runtime
+//          of the definition is ignored
+//  freeze_sideloads[]: Sideload NAMEs to freeze in place, e.g. to pause a
+//                      build and show latency recovery. Removing a NAME
+//                      thaws it
+//  restart_sysloads{{}}: \"NAME\": SEQ pairs. Increasing SEQ restarts the
+//                       active sysload NAME in place keeping its scratch
+//                       dir without changing sysloads{{}}
+//  dump_units_seq: Increasing writes the equivalent unit files of the active
+//                  sys/sideloads to TOPDIR/units for inspection
+//  dry_run: If true, sysloads{{}}, sideloads{{}}, kill[], freeze_sideloads[],
+//           restart_sysloads{{}}, dump_units_seq and the balloon aren't
+//           applied. Instead, the sys/sideloads which would be started,
+//           stopped, restarted, frozen and thawed, any spec errors, whether
+//           the units would be dumped and the balloon size are written to
+//           TOPDIR/apply-plan.json
+//  canary.lat_threshold: If hashd[0]'s control percentile latency stays above
+//                        this many seconds for canary.breach_dur, Background
+//                        tier sys/sideloads are held back, null to disable
+//  canary.breach_dur: Seconds the latency must stay over the threshold
+//  canary.action: Freeze to freeze held workloads, Stop to stop them
+//  canary.resume_after: Seconds the latency must stay under the threshold
+//                       before the held workloads start to be resumed
+//  canary.resume_interval: Seconds between resuming each held workload
+//  balloon_ratio: Memory balloon size as a ratio of total memory [0.0, 1.0]
+//  balloon_ramp_step: Max balloon size change per step as a ratio of total
+//                     memory, null to resize in one go
+//  balloon_ramp_intv: Seconds between balloon resize steps
 //
 ",
                 dfl_file_ratio = rd_hashd_intf::DFL_PARAMS.file_frac,
@@ -65,6 +105,34 @@ pub struct SideloaderCmd {
     pub cpu_headroom: f64,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CanaryAction {
+    Freeze,
+    Stop,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CanaryCmd {
+    pub lat_threshold: Option<f64>,
+    pub breach_dur: f64,
+    pub action: CanaryAction,
+    pub resume_after: f64,
+    pub resume_interval: f64,
+}
+
+impl Default for CanaryCmd {
+    fn default() -> Self {
+        Self {
+            lat_threshold: None,
+            breach_dur: 5.0,
+            action: CanaryAction::Freeze,
+            resume_after: 30.0,
+            resume_interval: 10.0,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct HashdCmd {
     pub active: bool,
@@ -108,7 +176,18 @@ pub struct Cmd {
     pub hashd: [HashdCmd; 2],
     pub sysloads: BTreeMap<String, String>,
     pub sideloads: BTreeMap<String, String>,
+    pub side_defs: BTreeMap<String, SideloadSpec>,
+    pub swaps: BTreeMap<String, String>,
+    pub clones: BTreeMap<String, String>,
+    pub kill: BTreeSet<String>,
+    pub freeze_sideloads: BTreeSet<String>,
+    pub restart_sysloads: BTreeMap<String, u64>,
+    pub dump_units_seq: u64,
+    pub dry_run: bool,
+    pub canary: CanaryCmd,
     pub balloon_ratio: f64,
+    pub balloon_ramp_step: Option<f64>,
+    pub balloon_ramp_intv: f64,
 }
 
 impl Default for Cmd {
@@ -121,7 +200,18 @@ impl Default for Cmd {
             hashd: Default::default(),
             sysloads: BTreeMap::new(),
             sideloads: BTreeMap::new(),
+            side_defs: BTreeMap::new(),
+            swaps: BTreeMap::new(),
+            clones: BTreeMap::new(),
+            kill: BTreeSet::new(),
+            freeze_sideloads: BTreeSet::new(),
+            restart_sysloads: BTreeMap::new(),
+            dump_units_seq: 0,
+            dry_run: false,
+            canary: Default::default(),
             balloon_ratio: 0.0,
+            balloon_ramp_step: None,
+            balloon_ramp_intv: 1.0,
         }
     }
 }
@@ -21,6 +21,7 @@ const INDEX_DOC: &str = "\
 //  hashd[].params: rd-hashd runtime adjustable parameters
 //  hashd[].report: rd-hashd summary report
 //  sideload_defs: Side and sys workload definitions
+//  prepare_history: Sideload preparation timing history
 //
 ";
 
@@ -46,6 +47,8 @@ pub struct Index {
     pub sideloader_status: String,
     pub hashd: [HashdIndex; 2],
     pub sideload_defs: String,
+    #[serde(default)]
+    pub prepare_history: String,
 }
 
 impl JsonLoad for Index {}
@@ -1,31 +1,42 @@
 // Copyright (c) Facebook, Inc. and its affiliates.
 use serde::{Deserialize, Serialize};
 
+pub mod apply;
 pub mod args;
+#[cfg(feature = "async")]
+pub mod async_agent;
 pub mod bench;
 pub mod cmd;
 pub mod cmd_ack;
 pub mod index;
 pub mod oomd;
+#[cfg(feature = "openmetrics")]
+pub mod openmetrics;
+pub mod prepare_history;
 pub mod report;
 pub mod side_defs;
 pub mod slices;
 pub mod sysreqs;
+pub mod workload_snapshot;
 
+pub use apply::{ApplyProgress, ApplyResult, ApplyTarget};
 pub use args::{Args, DFL_TOP};
 pub use bench::{BenchKnobs, HashdKnobs, IoCostKnobs};
-pub use cmd::{Cmd, HashdCmd, SideloaderCmd};
+pub use cmd::{CanaryAction, CanaryCmd, Cmd, HashdCmd, SideloaderCmd};
 pub use cmd_ack::CmdAck;
 pub use index::Index;
 pub use oomd::{OomdKnobs, OomdSliceMemPressureKnobs, OomdSliceSenpaiKnobs};
+pub use prepare_history::{PrepareHistory, PrepareProgress, PrepareRun, PREPARE_HISTORY_LEN};
 pub use report::{
-    BenchReport, HashdReport, IoCostReport, IoLatReport, OomdReport, Report, ResCtlReport,
-    SideloadReport, SideloaderReport, SvcReport, SvcStateReport, SysloadReport, UsageReport,
-    REPORT_1MIN_RETENTION, REPORT_RETENTION,
+    BalloonReport, BenchReport, CanaryReport, HashdReport, IoCostReport, IoLatProtReport,
+    IoLatReport, OomKillReport, OomdReport, Report, ResCtlReport, ScrBudgetReport, SideloadReport,
+    SideloaderReport, SvcReport, SvcStateReport, SwapReport, SysloadReport, UsageReport,
+    WorkloadUsageReport, REPORT_1MIN_RETENTION, REPORT_RETENTION,
 };
-pub use side_defs::{SideloadDefs, SideloadSpec};
-pub use slices::{DisableSeqKnobs, MemoryKnob, Slice, SliceConfig, SliceKnobs};
-pub use sysreqs::{SysReq, SysReqsReport};
+pub use side_defs::{IoLoad, IoMax, NetLoad, SideloadDefs, SideloadSpec};
+pub use slices::{DisableSeqKnobs, MemoryKnob, Slice, SliceConfig, SliceKnobs, Tier};
+pub use sysreqs::{DepKind, MissingDep, StartupCheck, StartupCheckReport, SysReq, SysReqsReport};
+pub use workload_snapshot::{WorkloadSnapshot, WorkloadSnapshotEntry};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum RunnerState {
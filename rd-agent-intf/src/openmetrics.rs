@@ -0,0 +1,345 @@
+// Copyright (c) Facebook, Inc. and its affiliates.
+//
+// OpenMetrics text exposition of Report. OpenMetrics only allows exemplars
+// on counters and histogram buckets, so trace context supplied by the
+// caller is attached to the per-workload CPU time counters.
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use super::report::{Report, SvcStateReport, WorkloadUsageReport};
+use super::slices::Tier;
+
+/// Max combined length of an exemplar's label names and values.
+pub const EXEMPLAR_MAX_LABEL_CHARS: usize = 128;
+
+#[derive(Debug, Clone, Default)]
+pub struct Exemplar {
+    pub labels: BTreeMap<String, String>,
+    pub value: f64,
+    pub timestamp: Option<f64>,
+}
+
+fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => (),
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn escape_label_value(val: &str) -> String {
+    val.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn labels_str(labels: &[(&str, &str)]) -> String {
+    let kvs: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(v)))
+        .collect();
+    format!("{{{}}}", kvs.join(","))
+}
+
+fn exemplar_str(ex: &Exemplar) -> Result<String> {
+    let mut nr_chars = 0;
+    let mut labels = vec![];
+    for (k, v) in ex.labels.iter() {
+        if !is_label_name(k) {
+            bail!("invalid exemplar label name {:?}", k);
+        }
+        nr_chars += k.chars().count() + v.chars().count();
+        labels.push((k.as_str(), v.as_str()));
+    }
+    if nr_chars > EXEMPLAR_MAX_LABEL_CHARS {
+        bail!(
+            "exemplar labels are {} characters long, max {}",
+            nr_chars,
+            EXEMPLAR_MAX_LABEL_CHARS
+        );
+    }
+
+    let mut buf = format!(" # {} {}", labels_str(&labels), ex.value);
+    if let Some(ts) = ex.timestamp {
+        write!(buf, " {}", ts).unwrap();
+    }
+    Ok(buf)
+}
+
+fn tier_str(tier: Tier) -> &'static str {
+    match tier {
+        Tier::Protected => "protected",
+        Tier::Background => "background",
+    }
+}
+
+struct Family {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    samples: Vec<String>,
+}
+
+impl Family {
+    fn new(name: &'static str, kind: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            kind,
+            help,
+            samples: vec![],
+        }
+    }
+
+    fn sample(&mut self, suffix: &str, labels: &[(&str, &str)], val: f64, exemplar: &str) {
+        self.samples.push(format!(
+            "{}{}{} {}{}",
+            self.name,
+            suffix,
+            labels_str(labels),
+            val,
+            exemplar
+        ));
+    }
+
+    fn write(&self, buf: &mut String) {
+        writeln!(buf, "# TYPE {} {}", self.name, self.kind).unwrap();
+        writeln!(buf, "# HELP {} {}", self.name, self.help).unwrap();
+        for sample in self.samples.iter() {
+            writeln!(buf, "{}", sample).unwrap();
+        }
+    }
+}
+
+/// Format `report` in the OpenMetrics text format. `exemplars` are keyed by
+/// sys/sideload name and attached to the workload's CPU time counter.
+pub fn format_report(report: &Report, exemplars: &BTreeMap<String, Exemplar>) -> Result<String> {
+    let mut hashd_load = Family::new("rd_hashd_load", "gauge", "Current rps / rps_max.");
+    let mut hashd_rps = Family::new("rd_hashd_rps", "gauge", "Current requests per second.");
+    let mut hashd_lat = Family::new(
+        "rd_hashd_latency_seconds",
+        "gauge",
+        "Latency at the control percentile.",
+    );
+    for (i, hashd) in report.hashd.iter().enumerate() {
+        let idx = format!("{}", i);
+        let labels = [("hashd", idx.as_str()), ("tier", tier_str(hashd.tier))];
+        hashd_load.sample("", &labels, hashd.load, "");
+        hashd_rps.sample("", &labels, hashd.rps, "");
+        hashd_lat.sample("", &labels, hashd.lat.ctl, "");
+    }
+
+    let mut pressure = Family::new(
+        "rd_slice_pressure_ratio",
+        "gauge",
+        "Some and full pressure ratios of top-level slices.",
+    );
+    for (slice, usage) in report.usages.iter() {
+        for (res, (some, full)) in [
+            ("cpu", usage.cpu_pressures),
+            ("memory", usage.mem_pressures),
+            ("io", usage.io_pressures),
+        ]
+        .iter()
+        {
+            pressure.sample(
+                "",
+                &[("slice", slice), ("resource", res), ("kind", "some")],
+                *some,
+                "",
+            );
+            pressure.sample(
+                "",
+                &[("slice", slice), ("resource", res), ("kind", "full")],
+                *full,
+                "",
+            );
+        }
+    }
+
+    let mut cpu = Family::new(
+        "rd_workload_cpu_seconds",
+        "counter",
+        "CPU time consumed by the sys/sideload.",
+    );
+    let mut io_read = Family::new(
+        "rd_workload_io_read_bytes",
+        "counter",
+        "Bytes read from the scratch device by the sys/sideload.",
+    );
+    let mut io_write = Family::new(
+        "rd_workload_io_write_bytes",
+        "counter",
+        "Bytes written to the scratch device by the sys/sideload.",
+    );
+    let mut mem = Family::new(
+        "rd_workload_memory_bytes",
+        "gauge",
+        "Current memory usage of the sys/sideload.",
+    );
+    let mut stalls = Family::new(
+        "rd_workload_pressure_seconds",
+        "counter",
+        "Some and full pressure stall time of the sys/sideload.",
+    );
+    let mut running = Family::new(
+        "rd_workload_running",
+        "gauge",
+        "1 if the sys/sideload service is running, 0 otherwise.",
+    );
+    let mut add_workload =
+        |kind: &str, name: &str, tier: Tier, up: bool, usage: &WorkloadUsageReport| -> Result<()> {
+            let labels = [("kind", kind), ("name", name), ("tier", tier_str(tier))];
+            let exemplar = match exemplars.get(name) {
+                Some(ex) => exemplar_str(ex)?,
+                None => String::new(),
+            };
+            cpu.sample(
+                "_total",
+                &labels,
+                usage.cpu_usec as f64 / 1_000_000.0,
+                &exemplar,
+            );
+            io_read.sample("_total", &labels, usage.io_rbytes as f64, "");
+            io_write.sample("_total", &labels, usage.io_wbytes as f64, "");
+            mem.sample("", &labels, usage.mem_bytes as f64, "");
+            for (res, (some, full)) in [
+                ("cpu", usage.cpu_stalls),
+                ("memory", usage.mem_stalls),
+                ("io", usage.io_stalls),
+            ]
+            .iter()
+            {
+                for (which, val) in [("some", some), ("full", full)].iter() {
+                    let stall_labels = [
+                        labels[0],
+                        labels[1],
+                        labels[2],
+                        ("resource", res),
+                        ("stall", which),
+                    ];
+                    stalls.sample("_total", &stall_labels, **val, "");
+                }
+            }
+            running.sample("", &labels, if up { 1.0 } else { 0.0 }, "");
+            Ok(())
+        };
+    for (name, rep) in report.sysloads.iter() {
+        let up = rep.svc.state == SvcStateReport::Running;
+        add_workload("sysload", name, rep.tier, up, &rep.usage)?;
+    }
+    for (name, rep) in report.sideloads.iter() {
+        let up = rep.svc.state == SvcStateReport::Running;
+        add_workload("sideload", name, rep.tier, up, &rep.usage)?;
+    }
+
+    let mut buf = String::new();
+    for family in [
+        &hashd_load,
+        &hashd_rps,
+        &hashd_lat,
+        &pressure,
+        &cpu,
+        &io_read,
+        &io_write,
+        &mem,
+        &stalls,
+        &running,
+    ]
+    .iter()
+    {
+        family.write(&mut buf);
+    }
+    buf += "# EOF\n";
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{exemplar_str, format_report, Exemplar};
+    use crate::report::{Report, SysloadReport};
+    use std::collections::BTreeMap;
+
+    fn exemplar(trace_id: &str) -> Exemplar {
+        let mut labels = BTreeMap::new();
+        labels.insert("trace_id".to_string(), trace_id.to_string());
+        Exemplar {
+            labels,
+            value: 1.5,
+            timestamp: Some(1600000000.25),
+        }
+    }
+
+    #[test]
+    fn test_exemplar_str() {
+        assert_eq!(
+            exemplar_str(&exemplar("abc\"d")).unwrap(),
+            " # {trace_id=\"abc\\\"d\"} 1.5 1600000000.25"
+        );
+        assert!(exemplar_str(&exemplar(&"x".repeat(121))).is_err());
+
+        let mut ex = exemplar("abc");
+        ex.labels.insert("0bad".into(), "".into());
+        assert!(exemplar_str(&ex).is_err());
+    }
+
+    #[test]
+    fn test_format_report() {
+        let mut report: Report = Default::default();
+        report.sysloads.insert(
+            "build".into(),
+            SysloadReport {
+                svc: Default::default(),
+                tier: Default::default(),
+                queued: false,
+                queued_reason: None,
+                pending_removal: false,
+                auto_stopped: None,
+                exit_code: None,
+                oom_killed: false,
+                burst: false,
+                start_limit_hit: false,
+                scr_dev_lost: false,
+                io_max: Default::default(),
+                swap_high: None,
+                oom_group: false,
+                exclusive_cpus: None,
+                cpu_partition: None,
+                swap_current: 0,
+                mem_peak: 0,
+                kconfig: None,
+                status: None,
+                limits_hit: vec![],
+                oom_kills: 0,
+                oom_kill_details: vec![],
+                perf_stat: BTreeMap::new(),
+                usage: Default::default(),
+            },
+        );
+        let mut exemplars = BTreeMap::new();
+        exemplars.insert("build".to_string(), exemplar("abc"));
+
+        let out = format_report(&report, &exemplars).unwrap();
+        assert!(out.ends_with("\n# EOF\n"));
+        assert!(out.contains("# TYPE rd_workload_cpu_seconds counter\n"));
+        assert!(out.contains(
+            "rd_workload_cpu_seconds_total{kind=\"sysload\",name=\"build\",tier=\"background\"} 0 # {trace_id=\"abc\"} 1.5 1600000000.25\n"
+        ));
+        assert!(out.contains(
+            "rd_workload_pressure_seconds_total{kind=\"sysload\",name=\"build\",tier=\"background\",resource=\"io\",stall=\"full\"} 0\n"
+        ));
+        assert!(out.contains(
+            "rd_workload_running{kind=\"sysload\",name=\"build\",tier=\"background\"} 0\n"
+        ));
+        // Every sample line must belong to the family declared above it.
+        let mut family = "";
+        for line in out.lines() {
+            if let Some(rest) = line.strip_prefix("# TYPE ") {
+                family = rest.split(' ').next().unwrap();
+            } else if !line.starts_with('#') {
+                assert!(line.starts_with(family), "{:?} outside {:?}", line, family);
+            }
+        }
+    }
+}
@@ -0,0 +1,68 @@
+// Copyright (c) Facebook, Inc. and its affiliates.
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use util::*;
+
+pub const PREPARE_HISTORY_LEN: usize = 32;
+
+const PREPARE_HISTORY_DOC: &str = "\
+//
+// rd-agent sideload preparation timing history
+//
+// The most recent runs are at the end. Older entries are dropped once
+// the history is longer than the retention count.
+//
+//  runs[].timestamp: When the preparation started
+//  runs[].side_bins: Seconds spent installing the side binaries
+//  runs[].linux_tar: Seconds spent locating or downloading the linux tarball
+//  runs[].linux_tar_source: Where the tarball came from - Download, Local or Cache,
+//                           Skipped if only the side binaries were prepared
+//  runs[].total: Total seconds spent in prepare_sides
+//
+";
+
+/// Stages of `rd-agent --prepare` as they start. With --progress, each is
+/// printed on stdout as a line of JSON. `Downloaded` is repeated as the
+/// linux tarball download makes progress, `total` is null if the server
+/// didn't tell the size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PrepareProgress {
+    SideBins,
+    LinuxTar,
+    Downloading,
+    Downloaded { done: u64, total: Option<u64> },
+    Decompressing,
+    Extracting,
+    Done,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrepareRun {
+    pub timestamp: DateTime<Local>,
+    pub side_bins: f64,
+    pub linux_tar: f64,
+    pub linux_tar_source: String,
+    pub total: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PrepareHistory {
+    pub runs: VecDeque<PrepareRun>,
+}
+
+impl PrepareHistory {
+    pub fn push(&mut self, run: PrepareRun) {
+        self.runs.push_back(run);
+        while self.runs.len() > PREPARE_HISTORY_LEN {
+            self.runs.pop_front();
+        }
+    }
+}
+
+impl JsonLoad for PrepareHistory {}
+impl JsonSave for PrepareHistory {
+    fn preamble() -> Option<String> {
+        Some(PREPARE_HISTORY_DOC.to_string())
+    }
+}
@@ -6,6 +6,9 @@ use std::ops;
 use std::time::UNIX_EPOCH;
 use util::*;
 
+use super::cmd::CanaryAction;
+use super::side_defs::IoMax;
+use super::slices::Tier;
 use super::RunnerState;
 
 const REPORT_DOC: &str = "\
@@ -17,6 +20,11 @@ const REPORT_DOC: &str = "\
 //
 //  timestamp: When this report was generated
 //  seq: Incremented on each execution, used for temporary settings
+//  cmd_seq: cmd_seq of the last cmd.json whose workload changes have been
+//           applied, 0 if none yet
+//  clock_jump: Seconds the wall clock jumped, e.g. on NTP step, since the
+//              previous report, null if it didn't. Timestamps around it are
+//              unreliable but durations use the monotonic clock
 //  state: Idle, Running, BenchHashd or BenchIOCost
 //  oomd.svc.name: OOMD systemd service name
 //  oomd.svc.state: OOMD systemd service state
@@ -37,14 +45,121 @@ const REPORT_DOC: &str = "\
 //  bench.iocost.svc.state: iocost benchmark systemd service state
 //  hashd[].svc.name: rd-hashd systemd service name
 //  hashd[].svc.state: rd-hashd systemd service state
+//  hashd[].tier: Protected
 //  hashd[].load: Current rps / rps_max
 //  hashd[].rps: Current rps
 //  hashd[].lat_pct: Current control percentile
 //  hashd[].lat: Current control percentile latency
 //  sysloads{}.svc.name: Sysload systemd service name
 //  sysloads{}.svc.state: Sysload systemd service state
+//  sysloads{}.tier: Protected or Background, Background unless overridden
+//                   by the definition
+//  sysloads{}.queued: Waiting for a heavy workload slot or, for heavy
+//                    workloads, for enough scratch space
+//  sysloads{}.queued_reason: Why the sysload is queued, null if it isn't
+//  sysloads{}.pending_removal: Removed from the target but kept running
+//                              until min_runtime is reached
+//  sysloads{}.auto_stopped: Why the agent stopped the sysload, e.g. for
+//                           exceeding scratch_quota, null if not. Stays
+//                           until the sysload is removed from the target
+//  sysloads{}.exit_code: Exit code once the sysload finished, null while
+//                        running or if it was killed by a signal
+//  sysloads{}.oom_killed: The sysload finished because it was OOM killed
+//  sysloads{}.burst: Running in burst IO mode
+//  sysloads{}.start_limit_hit: Restarted too often and left failed
+//  sysloads{}.scr_dev_lost: The scratch device disappeared, considered failed
+//  sysloads{}.io_max: Configured absolute IO limits
+//  sysloads{}.swap_high: Configured memory.swap.high
+//  sysloads{}.oom_group: memory.oom.group is set, OOM kills take down the
+//                        whole workload
+//  sysloads{}.exclusive_cpus: CPUs exclusively allocated to the workload
+//  sysloads{}.cpu_partition: cpuset.cpus.partition state of the workload,
+//                            e.g. root or root invalid (...) with why the
+//                            partition didn't take
+//  sysloads{}.swap_current: Current swap usage
+//  sysloads{}.mem_peak: Highest memory usage seen since the sysload was
+//                       last started
+//  sysloads{}.kconfig: Kernel .config file the build is using, null for the
+//                      defconfig target in args
+//  sysloads{}.status: Progress reported by the workload through $STATUS_FILE
+//  sysloads{}.limits_hit[]: Limits hit since the last report - mem_high,
+//                           mem_max, cpu_quota or io_max.{rbps|wbps|riops|wiops}
+//  sysloads{}.oom_kills: Number of processes OOM killed in the workload
+//  sysloads{}.oom_kill_details[]: Killed processes found in the kernel log,
+//                                 the most recent ones. The log is read at
+//                                 most every 10s, so this may lag behind
+//                                 oom_kills. May be missing entries if the
+//                                 log wasn't available
+//  sysloads{}.oom_kill_details[].pid: Process ID
+//  sysloads{}.oom_kill_details[].task: Command name
+//  sysloads{}.oom_kill_details[].{total_vm|anon_rss|file_rss|shmem_rss}:
+//                                 Memory held by the process in bytes
+//  sysloads{}.perf_stat{}: \"EVENT\": COUNT pairs collected by perf_stat once
+//                          the workload is finished
+//  sysloads{}.usage.cpu_usec: Cumulative CPU time in usecs
+//  sysloads{}.usage.cpu_usec_delta: CPU time in usecs since the last report
+//  sysloads{}.usage.io_rbytes: Cumulative bytes read from the scratch device
+//  sysloads{}.usage.io_rbytes_delta: Bytes read since the last report
+//  sysloads{}.usage.io_wbytes: Cumulative bytes written to the scratch device
+//  sysloads{}.usage.io_wbytes_delta: Bytes written since the last report
+//  sysloads{}.usage.mem_bytes: Current memory usage, memory.current,
+//                             only with --metrics-port
+//  sysloads{}.usage.{cpu|mem|io}_stalls: (some, full) seconds stalled on the
+//                                        resource since the instance started,
+//                                        only with --metrics-port
 //  sideloads{}.svc.name: Sideload systemd service name
 //  sideloads{}.svc.state: Sideload systemd service state
+//  sideloads{}.tier: Same as sysloads{}.tier
+//  sideloads{}.queued: Same as sysloads{}.queued, also waiting for the
+//                      sideloads in after[] of the definition to start
+//  sideloads{}.queued_reason: Same as sysloads{}.queued_reason
+//  sideloads{}.warming_up: Running warmup_args, svc is the warmup unit
+//  sideloads{}.warmup_failed: warmup_args failed and the sideload won't be
+//                             started until removed and added back
+//  sideloads{}.pending_removal: Same as sysloads{}.pending_removal
+//  sideloads{}.burst: Running in burst IO mode
+//  sideloads{}.start_limit_hit: Restarted too often and left failed
+//  sideloads{}.scr_dev_lost: The scratch device disappeared, considered failed
+//  sideloads{}.io_max: Configured absolute IO limits
+//  sideloads{}.swap_high: Configured memory.swap.high
+//  sideloads{}.oom_group: Same as sysloads{}.oom_group
+//  sideloads{}.frozen: Frozen in place by cmd freeze_sideloads[] or the canary
+//  sideloads{}.auto_stopped: Same as sysloads{}.auto_stopped, also for
+//                            exceeding max_runtime
+//  sideloads{}.exclusive_cpus: Same as sysloads{}.exclusive_cpus
+//  sideloads{}.cpu_partition: Same as sysloads{}.cpu_partition
+//  sideloads{}.swap_current: Current swap usage
+//  sideloads{}.kconfig: Same as sysloads{}.kconfig
+//  sideloads{}.status: Progress reported by the workload through $STATUS_FILE
+//  sideloads{}.limits_hit[]: Same as sysloads{}.limits_hit[]
+//  sideloads{}.oom_kills: Same as sysloads{}.oom_kills
+//  sideloads{}.oom_kill_details[]: Same as sysloads{}.oom_kill_details[]
+//  sideloads{}.perf_stat{}: Same as sysloads{}.perf_stat{}
+//  sideloads{}.usage: Same as sysloads{}.usage
+//  swaps{}.out: Workload IN_NAME of cmd swaps{} replaced
+//  swaps{}.ok: IN_NAME came up and OUT_NAME was stopped
+//  swaps{}.pending: IN_NAME was started and both are running until it stays up
+//  swaps{}.error: Why IN_NAME failed to come up, OUT_NAME is kept running
+//  killed[]: Sys/sideloads SIGKILL'd through cmd kill[] without running
+//            their cleanup_cmd, listed while they stay in kill[]
+//  scr_budget.usage: Total scratch usage of all sys/sideloads in bytes
+//  scr_budget.budget: --scr-budget of the scratch device size in bytes, 0 if not set
+//  scr_budget.hard: Heavy sys/sideloads are refused rather than warned about when over
+//  scr_budget.over: Usage is over the budget
+//  canary.breach: hashd[0] latency is over cmd canary.lat_threshold
+//  canary.held{}: Freeze or Stop for each Background workload held back
+//  balloon.svc.name: Memory balloon systemd service name
+//  balloon.svc.state: Memory balloon systemd service state
+//  balloon.size: Current balloon size in bytes, 0 if deflated
+//  balloon.running: Whether the balloon service is up and holding the memory
+//  balloon.deflated: Partially deflated due to memory pressure, see
+//                    --balloon-psi
+//  balloon.restarts: Number of times the balloon was restarted after dying
+//  balloon.last_failure: Why the balloon last died or failed to start
+//  io_lat_prot.target: --io-latency target of workload.slice in usecs, null if off
+//  io_lat_prot.engaged: Whether other slices are being throttled, null if
+//                       io.stat doesn't show io.latency stats
+//  io_lat_prot.throttled{}: Queue depth limits of the throttled slices
 //  iolat.{read|write|discard|flush}.p*: IO latency distributions
 //
 //
@@ -53,7 +168,7 @@ const REPORT_DOC: &str = "\
 pub const REPORT_RETENTION: u64 = 60 * 60;
 pub const REPORT_1MIN_RETENTION: u64 = 24 * 60 * 60;
 
-#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SvcStateReport {
     Running,
     Exited,
@@ -67,7 +182,7 @@ impl Default for SvcStateReport {
     }
 }
 
-#[derive(Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct SvcReport {
     pub name: String,
     pub state: SvcStateReport,
@@ -107,6 +222,8 @@ pub struct SideloaderReport {
 #[derive(Clone, Serialize, Deserialize, Default)]
 pub struct HashdReport {
     pub svc: SvcReport,
+    #[serde(default)]
+    pub tier: Tier,
     pub load: f64,
     pub rps: f64,
     pub lat_pct: f64,
@@ -132,14 +249,128 @@ impl<T: Into<f64>> ops::DivAssign<T> for HashdReport {
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct WorkloadUsageReport {
+    pub cpu_usec: u64,
+    pub cpu_usec_delta: u64,
+    pub io_rbytes: u64,
+    pub io_rbytes_delta: u64,
+    pub io_wbytes: u64,
+    pub io_wbytes_delta: u64,
+    pub mem_bytes: u64,
+    pub cpu_stalls: (f64, f64),
+    pub mem_stalls: (f64, f64),
+    pub io_stalls: (f64, f64),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OomKillReport {
+    pub pid: u32,
+    pub task: String,
+    pub total_vm: u64,
+    pub anon_rss: u64,
+    pub file_rss: u64,
+    pub shmem_rss: u64,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SysloadReport {
     pub svc: SvcReport,
+    pub tier: Tier,
+    pub queued: bool,
+    pub queued_reason: Option<String>,
+    pub pending_removal: bool,
+    pub auto_stopped: Option<String>,
+    pub exit_code: Option<i32>,
+    pub oom_killed: bool,
+    pub burst: bool,
+    pub start_limit_hit: bool,
+    pub scr_dev_lost: bool,
+    pub io_max: IoMax,
+    pub swap_high: Option<u64>,
+    pub oom_group: bool,
+    pub exclusive_cpus: Option<String>,
+    pub cpu_partition: Option<String>,
+    pub swap_current: u64,
+    pub mem_peak: u64,
+    pub kconfig: Option<String>,
+    pub status: Option<String>,
+    pub limits_hit: Vec<String>,
+    pub oom_kills: u64,
+    pub oom_kill_details: Vec<OomKillReport>,
+    pub perf_stat: BTreeMap<String, u64>,
+    pub usage: WorkloadUsageReport,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SideloadReport {
     pub svc: SvcReport,
+    pub tier: Tier,
+    pub queued: bool,
+    pub queued_reason: Option<String>,
+    pub warming_up: bool,
+    pub warmup_failed: bool,
+    pub pending_removal: bool,
+    pub burst: bool,
+    pub start_limit_hit: bool,
+    pub scr_dev_lost: bool,
+    pub io_max: IoMax,
+    pub swap_high: Option<u64>,
+    pub oom_group: bool,
+    pub frozen: bool,
+    pub auto_stopped: Option<String>,
+    pub exclusive_cpus: Option<String>,
+    pub cpu_partition: Option<String>,
+    pub swap_current: u64,
+    pub kconfig: Option<String>,
+    pub status: Option<String>,
+    pub limits_hit: Vec<String>,
+    pub oom_kills: u64,
+    pub oom_kill_details: Vec<OomKillReport>,
+    pub perf_stat: BTreeMap<String, u64>,
+    pub usage: WorkloadUsageReport,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct SwapReport {
+    pub out: String,
+    pub ok: bool,
+    #[serde(default)]
+    pub pending: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct ScrBudgetReport {
+    pub usage: u64,
+    pub budget: u64,
+    pub hard: bool,
+    pub over: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct CanaryReport {
+    pub breach: bool,
+    pub held: BTreeMap<String, CanaryAction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct BalloonReport {
+    pub svc: SvcReport,
+    pub size: usize,
+    pub running: bool,
+    pub deflated: bool,
+    pub restarts: u32,
+    pub last_failure: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IoLatProtReport {
+    pub target: Option<u64>,
+    pub engaged: Option<bool>,
+    pub throttled: BTreeMap<String, u32>,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
@@ -253,6 +484,10 @@ impl<T: Into<f64>> ops::DivAssign<T> for IoCostReport {
 pub struct Report {
     pub timestamp: DateTime<Local>,
     pub seq: u64,
+    #[serde(default)]
+    pub cmd_seq: u64,
+    #[serde(default)]
+    pub clock_jump: Option<f64>,
     pub state: RunnerState,
     pub resctl: ResCtlReport,
     pub oomd: OomdReport,
@@ -262,6 +497,18 @@ pub struct Report {
     pub hashd: [HashdReport; 2],
     pub sysloads: BTreeMap<String, SysloadReport>,
     pub sideloads: BTreeMap<String, SideloadReport>,
+    #[serde(default)]
+    pub swaps: BTreeMap<String, SwapReport>,
+    #[serde(default)]
+    pub killed: Vec<String>,
+    #[serde(default)]
+    pub scr_budget: ScrBudgetReport,
+    #[serde(default)]
+    pub canary: CanaryReport,
+    #[serde(default)]
+    pub balloon: BalloonReport,
+    #[serde(default)]
+    pub io_lat_prot: IoLatProtReport,
     pub usages: BTreeMap<String, UsageReport>,
     pub iolat: IoLatReport,
     pub iocost: IoCostReport,
@@ -272,6 +519,8 @@ impl Default for Report {
         Self {
             timestamp: DateTime::from(UNIX_EPOCH),
             seq: 1,
+            cmd_seq: 0,
+            clock_jump: None,
             state: RunnerState::Idle,
             resctl: Default::default(),
             oomd: Default::default(),
@@ -281,6 +530,12 @@ impl Default for Report {
             hashd: Default::default(),
             sysloads: Default::default(),
             sideloads: Default::default(),
+            swaps: Default::default(),
+            killed: vec![],
+            scr_budget: Default::default(),
+            canary: Default::default(),
+            balloon: Default::default(),
+            io_lat_prot: Default::default(),
             usages: Default::default(),
             iolat: Default::default(),
             iocost: Default::default(),
@@ -290,6 +545,25 @@ impl Default for Report {
 
 impl JsonLoad for Report {}
 
+impl Report {
+    /// Sys/sideloads which are hitting their limits, keyed by
+    /// "sysload:NAME" or "sideload:NAME".
+    pub fn limited(&self) -> BTreeMap<String, Vec<String>> {
+        let mut limited = BTreeMap::new();
+        for (name, rep) in self.sysloads.iter() {
+            if !rep.limits_hit.is_empty() {
+                limited.insert(format!("sysload:{}", name), rep.limits_hit.clone());
+            }
+        }
+        for (name, rep) in self.sideloads.iter() {
+            if !rep.limits_hit.is_empty() {
+                limited.insert(format!("sideload:{}", name), rep.limits_hit.clone());
+            }
+        }
+        limited
+    }
+}
+
 impl JsonSave for Report {
     fn preamble() -> Option<String> {
         Some(REPORT_DOC.to_string())
@@ -1,24 +1,264 @@
 // Copyright (c) Facebook, Inc. and its affiliates.
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::BTreeMap;
 use util::*;
 
+use super::slices::Tier;
+
 const SIDE_DEF_DOC: &str = "\
 //
 // rd-agent side/sysload definitions
 //
 //  DEF_ID.args[]: Command arguments
 //  DEF_ID.frozen_exp: Sideloader frozen expiration duration
+//  DEF_ID.heavy: Subject to the agent's concurrent heavy workload limit
+//  DEF_ID.burst: [BURST_SECS, IDLE_SECS] to alternate between IO bursts and
+//                idle periods (read-bomb), null for steady load
+//  DEF_ID.report_interval: Report refresh interval in seconds, null to
+//                          refresh on every report. In between, the last
+//                          report is repeated with the usage deltas and
+//                          limits_hit[] cleared
+//  DEF_ID.tag: Substituted for {tag} in the scratch dir template, null to
+//              use DEF_ID
+//  DEF_ID.scr_external: The scratch dir is provisioned externally and must
+//                       already exist, the agent won't create it
+//  DEF_ID.scr_keep: Don't remove the scratch dir when the workload is removed.
+//                   Never backed by tmpfs, see --sys/side-scr-tmpfs
+//  DEF_ID.scratch_quota: Max bytes the scratch dir may use, null for no
+//                        limit. Checked periodically and the workload is
+//                        stopped once over. It stays stopped until removed
+//                        from the target
+//  DEF_ID.cleanup_cmd[]: Command to run in the scratch dir when the workload
+//                       is removed, empty for none
+//  DEF_ID.warmup_args[]: Command to run to completion in the scratch dir
+//                        before the workload starts, e.g. to untar sources,
+//                        so that it's kept out of the measurement. Output
+//                        goes to warmup.log. If it fails, the workload isn't
+//                        started. It runs as a plain unit in sideload.slice
+//                        outside the sideloader's control, so it isn't
+//                        frozen under pressure. Empty for none (sideloads
+//                        only)
+//  DEF_ID.private_network: Run in a private network namespace with only the
+//                          loopback device, no network access (sysloads only)
+//  DEF_ID.io_max.{rbps|wbps|riops|wiops}: Absolute IO limits on the scratch
+//                                         device, null for no limit
+//  DEF_ID.io_load.read_pct: Percentage of reads for io-load.sh, $IO_RW_MIX
+//  DEF_ID.io_load.block_size: Block size in bytes for io-load.sh, $IO_BS
+//  DEF_ID.io_load.depth: Queue depth for io-load.sh, $IO_DEPTH
+//  DEF_ID.io_load.size: Size of the file io-load.sh works on, $IO_SIZE
+//  DEF_ID.net_load.host: Host to send traffic to for net-load.py, $NET_HOST.
+//                       Something must be discarding connections on
+//                       net_load.port there. null for loopback
+//  DEF_ID.net_load.port: Port to send traffic to, $NET_PORT. 0 picks any
+//                        port for loopback
+//  DEF_ID.net_load.rate: Total bytes per second to send, $NET_RATE, 0 for
+//                        unlimited
+//  DEF_ID.net_load.conns: Number of connections to spread traffic over,
+//                         $NET_CONNS
+//  DEF_ID.extra_envs[]: Additional KEY=VALUE environment variables for the
+//                      workload, e.g. MAKE_JOBS=8. Can't override the ones
+//                      set by the agent
+//  DEF_ID.after[]: Sideload NAMEs which must be started before this one.
+//                  NAMEs not in the sideload target are ignored (sideloads
+//                  only)
+//  DEF_ID.perf_stat: Run under perf stat and report cycles, instructions
+//                    and cache-misses once finished
+//  DEF_ID.clean_env: Run through env -i so that the workload sees only the
+//                    variables set by the agent and a fixed PATH instead
+//                    of the service manager's environment, which differs
+//                    across hosts
+//  DEF_ID.swap_high: memory.swap.high in bytes to throttle swap usage
+//                    gradually, null for no limit
+//  DEF_ID.mem_high: memory.high in bytes, e.g. 1073741824, or as percentage
+//                   of total memory, e.g. 25%, null for no limit (sysloads
+//                   only)
+//  DEF_ID.cpu_weight: CPUWeight [1, 10000] of the workload instead of the
+//                     default, null for the default (sideloads only)
+//  DEF_ID.oom_group: Set memory.oom.group so that the OOM killer kills all
+//                    processes of the workload together
+//  DEF_ID.kconfig: Absolute path to a kernel .config file for build-linux.sh
+//                  to build with instead of the defconfig target in args
+//  DEF_ID.make_jobs: Job count for build-linux.sh, $MAKE_JOBS. A job ratio
+//                    in args, e.g. 2 in build-linux-2x, scales this instead
+//                    of NR_CPUS. Without one, it's used as the -j count as
+//                    is. null to scale NR_CPUS and run make -j without a
+//                    count when there's no ratio. MAKE_JOBS in extra_envs
+//                    takes precedence
+//  DEF_ID.tier: Protected or Background, null to classify by the slice the
+//               workload runs in
+//  DEF_ID.min_runtime: Seconds the workload must have run before it's
+//                      removed, null for none. Removal from the target is
+//                      deferred until then. kill[] in cmd.json bypasses it
+//  DEF_ID.max_runtime: Seconds after which the agent stops the workload,
+//                      null for no limit. It stays stopped until removed
+//                      from the target (sideloads only)
+//  DEF_ID.exclusive_cpus: CPU list, e.g. 2-3,6, to confine the workload to.
+//                        hashd is kept off them and the cgroup is made a
+//                        cpuset partition root so that other workloads
+//                        can't use them either, which only takes if the
+//                        parent slice is a partition too. Running workloads
+//                        can't have overlapping lists. null for no pinning
+//  DEF_ID.kill_signal: Signal to stop the workload with, e.g. SIGINT, null
+//                     for SIGTERM. Sent before the normal stop for sideloads
+//  DEF_ID.restart: Restart on failure (sysloads only)
+//  DEF_ID.start_limit_interval: Restart rate limiting interval in seconds
+//  DEF_ID.start_limit_burst: Max number of starts in start_limit_interval
+//                            before the sysload is left failed
 //
 ";
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IoMax {
+    pub rbps: Option<u64>,
+    pub wbps: Option<u64>,
+    pub riops: Option<u64>,
+    pub wiops: Option<u64>,
+}
+
+impl IoMax {
+    pub fn is_set(&self) -> bool {
+        self.rbps.is_some() || self.wbps.is_some() || self.riops.is_some() || self.wiops.is_some()
+    }
+
+    /// io.max line for the device. Unset limits are reset to max.
+    pub fn line(&self, devnr: (u32, u32)) -> String {
+        let fmt = |v: Option<u64>| match v {
+            Some(v) => format!("{}", v),
+            None => "max".into(),
+        };
+        format!(
+            "{}:{} rbps={} wbps={} riops={} wiops={}",
+            devnr.0,
+            devnr.1,
+            fmt(self.rbps),
+            fmt(self.wbps),
+            fmt(self.riops),
+            fmt(self.wiops)
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IoLoad {
+    pub read_pct: u32,
+    pub block_size: u64,
+    pub depth: u32,
+    pub size: u64,
+}
+
+impl Default for IoLoad {
+    fn default() -> Self {
+        Self {
+            read_pct: 70,
+            block_size: 4096,
+            depth: 32,
+            size: 1 << 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetLoad {
+    pub host: Option<String>,
+    pub port: u16,
+    pub rate: u64,
+    pub conns: u32,
+}
+
+impl Default for NetLoad {
+    fn default() -> Self {
+        Self {
+            host: None,
+            port: 0,
+            rate: 0,
+            conns: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SideloadSpec {
     pub args: Vec<String>,
     pub frozen_exp: u32,
+    pub heavy: bool,
+    pub burst: Option<(u32, u32)>,
+    pub report_interval: Option<u32>,
+    pub tag: Option<String>,
+    pub scr_external: bool,
+    pub scr_keep: bool,
+    pub scratch_quota: Option<u64>,
+    pub cleanup_cmd: Vec<String>,
+    pub warmup_args: Vec<String>,
+    pub private_network: bool,
+    pub io_max: IoMax,
+    pub io_load: Option<IoLoad>,
+    pub net_load: Option<NetLoad>,
+    pub extra_envs: Vec<String>,
+    pub after: Vec<String>,
+    pub swap_high: Option<u64>,
+    pub mem_high: Option<String>,
+    pub cpu_weight: Option<u32>,
+    pub oom_group: bool,
+    pub perf_stat: bool,
+    pub clean_env: bool,
+    pub kconfig: Option<String>,
+    pub make_jobs: Option<u32>,
+    pub tier: Option<Tier>,
+    pub min_runtime: Option<u32>,
+    pub max_runtime: Option<u32>,
+    pub exclusive_cpus: Option<String>,
+    pub kill_signal: Option<String>,
+    pub restart: bool,
+    pub start_limit_interval: u32,
+    pub start_limit_burst: u32,
 }
 
-#[derive(Serialize, Deserialize)]
+impl Default for SideloadSpec {
+    fn default() -> Self {
+        Self {
+            args: vec![],
+            frozen_exp: 30,
+            heavy: false,
+            burst: None,
+            report_interval: None,
+            tag: None,
+            scr_external: false,
+            scr_keep: false,
+            scratch_quota: None,
+            cleanup_cmd: vec![],
+            warmup_args: vec![],
+            private_network: false,
+            io_max: Default::default(),
+            io_load: None,
+            net_load: None,
+            extra_envs: vec![],
+            after: vec![],
+            swap_high: None,
+            mem_high: None,
+            cpu_weight: None,
+            oom_group: false,
+            perf_stat: false,
+            clean_env: false,
+            kconfig: None,
+            make_jobs: None,
+            tier: None,
+            min_runtime: None,
+            max_runtime: None,
+            exclusive_cpus: None,
+            kill_signal: None,
+            restart: false,
+            start_limit_interval: 10,
+            start_limit_burst: 5,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct SideloadDefs {
     #[serde(flatten)]
@@ -39,6 +279,8 @@ impl Default for SideloadDefs {
                             "2".into(),
                         ],
                         frozen_exp: 30,
+                        heavy: true,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -46,6 +288,8 @@ impl Default for SideloadDefs {
                     SideloadSpec {
                         args: vec!["build-linux.sh".into(), "allmodconfig".into(), "1".into()],
                         frozen_exp: 30,
+                        heavy: true,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -53,6 +297,8 @@ impl Default for SideloadDefs {
                     SideloadSpec {
                         args: vec!["build-linux.sh".into(), "allmodconfig".into(), "2".into()],
                         frozen_exp: 30,
+                        heavy: true,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -60,6 +306,8 @@ impl Default for SideloadDefs {
                     SideloadSpec {
                         args: vec!["build-linux.sh".into(), "allmodconfig".into(), "4".into()],
                         frozen_exp: 30,
+                        heavy: true,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -67,6 +315,8 @@ impl Default for SideloadDefs {
                     SideloadSpec {
                         args: vec!["build-linux.sh".into(), "allmodconfig".into(), "8".into()],
                         frozen_exp: 30,
+                        heavy: true,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -74,6 +324,8 @@ impl Default for SideloadDefs {
                     SideloadSpec {
                         args: vec!["build-linux.sh".into(), "allmodconfig".into(), "16".into()],
                         frozen_exp: 30,
+                        heavy: true,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -81,6 +333,8 @@ impl Default for SideloadDefs {
                     SideloadSpec {
                         args: vec!["build-linux.sh".into(), "allmodconfig".into(), "32".into()],
                         frozen_exp: 30,
+                        heavy: true,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -88,6 +342,8 @@ impl Default for SideloadDefs {
                     SideloadSpec {
                         args: vec!["build-linux.sh".into(), "allmodconfig".into()],
                         frozen_exp: 30,
+                        heavy: true,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -95,6 +351,8 @@ impl Default for SideloadDefs {
                     SideloadSpec {
                         args: vec!["build-linux.sh".into(), "allnoconfig".into(), "1".into()],
                         frozen_exp: 30,
+                        heavy: true,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -102,6 +360,8 @@ impl Default for SideloadDefs {
                     SideloadSpec {
                         args: vec!["build-linux.sh".into(), "defconfig".into(), "1".into()],
                         frozen_exp: 30,
+                        heavy: true,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -109,6 +369,7 @@ impl Default for SideloadDefs {
                     SideloadSpec {
                         args: vec!["memory-growth.py".into(), "0%".into(), "10%".into()],
                         frozen_exp: 30,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -116,6 +377,7 @@ impl Default for SideloadDefs {
                     SideloadSpec {
                         args: vec!["memory-growth.py".into(), "0%".into(), "25%".into()],
                         frozen_exp: 30,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -123,6 +385,7 @@ impl Default for SideloadDefs {
                     SideloadSpec {
                         args: vec!["memory-growth.py".into(), "0%".into(), "50%".into()],
                         frozen_exp: 30,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -130,6 +393,7 @@ impl Default for SideloadDefs {
                     SideloadSpec {
                         args: vec!["memory-growth.py".into(), "0%".into(), "100%".into()],
                         frozen_exp: 30,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -137,6 +401,7 @@ impl Default for SideloadDefs {
                     SideloadSpec {
                         args: vec!["memory-growth.py".into(), "0%".into(), "200%".into()],
                         frozen_exp: 30,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -144,6 +409,7 @@ impl Default for SideloadDefs {
                     SideloadSpec {
                         args: vec!["memory-growth.py".into(), "1000%".into(), "100%".into()],
                         frozen_exp: 30,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -151,6 +417,34 @@ impl Default for SideloadDefs {
                     SideloadSpec {
                         args: vec!["read-bomb.py".into(), "1024".into(), "16384".into()],
                         frozen_exp: 30,
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "read-bomb-burst".into(),
+                    SideloadSpec {
+                        args: vec!["read-bomb.py".into(), "1024".into(), "16384".into()],
+                        frozen_exp: 30,
+                        burst: Some((5, 10)),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "io-load".into(),
+                    SideloadSpec {
+                        args: vec!["io-load.sh".into()],
+                        frozen_exp: 30,
+                        io_load: Some(Default::default()),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "net-load".into(),
+                    SideloadSpec {
+                        args: vec!["net-load.py".into()],
+                        frozen_exp: 30,
+                        net_load: Some(Default::default()),
+                        ..Default::default()
                     },
                 ),
                 (
@@ -158,6 +452,7 @@ impl Default for SideloadDefs {
                     SideloadSpec {
                         args: vec!["burn-cpus.sh".into(), "1".into(), "2".into()],
                         frozen_exp: 30,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -165,6 +460,7 @@ impl Default for SideloadDefs {
                     SideloadSpec {
                         args: vec!["burn-cpus.sh".into(), "1".into()],
                         frozen_exp: 30,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -172,6 +468,7 @@ impl Default for SideloadDefs {
                     SideloadSpec {
                         args: vec!["burn-cpus.sh".into(), "2".into()],
                         frozen_exp: 30,
+                        ..Default::default()
                     },
                 ),
             ]
@@ -182,6 +479,21 @@ impl Default for SideloadDefs {
     }
 }
 
+impl SideloadDefs {
+    /// The defs with `overrides` added, replacing the entries with the same
+    /// DEF_ID. Borrowed if there's nothing to override.
+    pub fn overlay(&self, overrides: &BTreeMap<String, SideloadSpec>) -> Cow<'_, SideloadDefs> {
+        if overrides.is_empty() {
+            return Cow::Borrowed(self);
+        }
+        let mut defs = self.clone();
+        for (id, spec) in overrides.iter() {
+            defs.defs.insert(id.clone(), spec.clone());
+        }
+        Cow::Owned(defs)
+    }
+}
+
 impl JsonLoad for SideloadDefs {}
 
 impl JsonSave for SideloadDefs {
@@ -55,6 +55,23 @@ impl Slice {
             Slice::Side => "/sys/fs/cgroup/sideload.slice",
         }
     }
+
+    /// Default tier of the workloads running in the slice.
+    pub fn tier(&self) -> Tier {
+        match self {
+            Slice::Init | Slice::Host | Slice::Work => Tier::Protected,
+            Slice::User | Slice::Sys | Slice::Side => Tier::Background,
+        }
+    }
+}
+
+/// Whether a workload is the protected latency-sensitive one or one of the
+/// background workloads competing with it.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Tier {
+    Protected,
+    #[default]
+    Background,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
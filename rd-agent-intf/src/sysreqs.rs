@@ -1,6 +1,7 @@
 // Copyright (c) Facebook, Inc. and its affiliates.
 use enum_iterator::IntoEnumIterator;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use util::*;
 
 const SYSREQ_DOC: &str = "\
@@ -9,6 +10,15 @@ const SYSREQ_DOC: &str = "\
 //
 // satisfied: List of satifised system requirements
 // missed: List of missed system requirements
+// checks[].req: System requirement
+// checks[].passed: Whether the requirement is satisfied
+// checks[].missing[]: Specific items missing, e.g. binaries for Dependencies
+// checks[].hint: How to fix the requirement if not satisfied
+// missing_deps[].name: Missing binary or devel library
+// missing_deps[].kind: Binary, Library or Outdated if present but older than
+//                      required
+// missing_deps[].hint: Command to install the package providing it, null if
+//                      the distro isn't known
 //
 ";
 
@@ -32,12 +42,93 @@ pub enum SysReq {
     NoSysOomd,
     HostCriticalServices,
     Dependencies,
+    BuildToolVersions,
+}
+
+impl SysReq {
+    /// Remediation hint for the UI and logs.
+    pub fn hint(&self) -> &'static str {
+        match self {
+            Self::Controllers => "Boot with cgroup2 only (systemd.unified_cgroup_hierarchy=1) and enable cpu, memory and io controllers",
+            Self::Freezer => "Use a kernel with the cgroup2 freezer (>= 5.2)",
+            Self::MemCgRecursiveProt => "Mount cgroup2 with memory_recursiveprot (kernel >= 5.7)",
+            Self::IoCost => "Use a kernel with the iocost controller (CONFIG_BLK_CGROUP_IOCOST)",
+            Self::IoCostVer => "Use a kernel with an up-to-date iocost controller",
+            Self::NoOtherIoControllers => "Disable io.latency and io.max configurations on other cgroups",
+            Self::AnonBalance => "Use a kernel with anon/file reclaim balancing (>= 5.8)",
+            Self::Btrfs => "Put the scratch and root directories on btrfs",
+            Self::BtrfsAsyncDiscard => "Mount btrfs with discard=async",
+            Self::NoCompositeStorage => "Specify the physical scratch device with --dev",
+            Self::IoSched => "Use the mq-deadline IO scheduler on the scratch device",
+            Self::NoWbt => "Disable writeback throttling on the scratch device",
+            Self::SwapOnScratch => "Put swap on the scratch device",
+            Self::Swap => "Configure swap of at least 1/3 of memory with swappiness >= 60",
+            Self::Oomd => "Install oomd, see https://github.com/facebookincubator/oomd",
+            Self::NoSysOomd => "Stop the system oomd or earlyoom service",
+            Self::HostCriticalServices => "Run sshd and dbus under hostcritical.slice",
+            Self::Dependencies => "Install the missing build tools and devel libraries",
+            Self::BuildToolVersions => "Upgrade gcc (>= 4.9), make (>= 3.81) and binutils (>= 2.23)",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DepKind {
+    Binary,
+    Library,
+    Outdated,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MissingDep {
+    pub name: String,
+    pub kind: DepKind,
+    pub hint: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StartupCheck {
+    pub req: SysReq,
+    pub passed: bool,
+    pub missing: Vec<String>,
+    pub hint: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StartupCheckReport {
+    pub checks: Vec<StartupCheck>,
+}
+
+impl StartupCheckReport {
+    /// Build a per-requirement report from the failed set and the
+    /// specific items found missing for each requirement.
+    pub fn new(failed: &HashSet<SysReq>, missing: &HashMap<SysReq, Vec<String>>) -> Self {
+        Self {
+            checks: SysReq::into_enum_iter()
+                .map(|req| StartupCheck {
+                    req,
+                    passed: !failed.contains(&req),
+                    missing: missing.get(&req).cloned().unwrap_or_default(),
+                    hint: req.hint().into(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &StartupCheck> {
+        self.checks.iter().filter(|x| !x.passed)
+    }
 }
 
 #[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SysReqsReport {
     pub satisfied: Vec<SysReq>,
     pub missed: Vec<SysReq>,
+    #[serde(default)]
+    pub checks: Vec<StartupCheck>,
+    #[serde(default)]
+    pub missing_deps: Vec<MissingDep>,
 }
 
 impl JsonLoad for SysReqsReport {}
@@ -0,0 +1,45 @@
+// Copyright (c) Facebook, Inc. and its affiliates.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use util::*;
+
+use super::SideloadSpec;
+
+const WORKLOAD_SNAPSHOT_DOC: &str = "\
+//
+// rd-agent active sys/sideloads
+//
+// Rewritten whenever the set of active workloads changes. On startup,
+// rd-agent adopts the sysloads which are still running and in the target
+// and stops and cleans up the leftover units of the rest. Live sys/sideload
+// units missing from here are stopped too but their scratch dirs are kept.
+//
+//  sysloads{}.id: Sideload definition ID
+//  sysloads{}.scr_path: Scratch directory
+//  sysloads{}.envs[]: Environment variables the workload was started with
+//  sysloads{}.spec: Sideload definition as resolved when it was started
+//  sideloads{}: Same as sysloads{} except that envs[] is always empty
+//
+";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorkloadSnapshotEntry {
+    pub id: String,
+    pub scr_path: String,
+    #[serde(default)]
+    pub envs: Vec<String>,
+    pub spec: SideloadSpec,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WorkloadSnapshot {
+    pub sysloads: BTreeMap<String, WorkloadSnapshotEntry>,
+    pub sideloads: BTreeMap<String, WorkloadSnapshotEntry>,
+}
+
+impl JsonLoad for WorkloadSnapshot {}
+impl JsonSave for WorkloadSnapshot {
+    fn preamble() -> Option<String> {
+        Some(WORKLOAD_SNAPSHOT_DOC.to_string())
+    }
+}
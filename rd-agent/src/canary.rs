@@ -0,0 +1,145 @@
+// Copyright (c) Facebook, Inc. and its affiliates.
+//
+// Canary control loop. While the protected workload's latency breaches the
+// configured threshold, Background tier sys/sideloads are frozen or stopped.
+// Once it recovers, they're resumed one by one.
+use log::{info, warn};
+use std::mem;
+use std::time::{Duration, Instant};
+
+use super::side::SideRunner;
+use rd_agent_intf::{CanaryAction, CanaryCmd, CanaryReport};
+
+#[derive(Default)]
+pub struct Canary {
+    breach: bool,
+    breach_since: Option<Instant>,
+    healthy_since: Option<Instant>,
+    last_resume_at: Option<Instant>,
+    // In the order they were held, resumed from the front.
+    held: Vec<(String, CanaryAction)>,
+}
+
+impl Canary {
+    /// Stopped workloads must be left out of the sys/sideload targets.
+    pub fn is_stopped(&self, name: &str) -> bool {
+        self.held
+            .iter()
+            .any(|(held, action)| held == name && *action == CanaryAction::Stop)
+    }
+
+    pub fn is_held(&self, name: &str) -> bool {
+        self.held.iter().any(|(held, _)| held == name)
+    }
+
+    /// Feed the latest protected workload latency, None if not available.
+    /// Returns true if the set of stopped workloads changed and the targets
+    /// need to be re-applied.
+    pub fn update(
+        &mut self,
+        cmd: &CanaryCmd,
+        lat: Option<f64>,
+        side_runner: &mut SideRunner,
+    ) -> bool {
+        let now = Instant::now();
+        let threshold = match cmd.lat_threshold {
+            Some(v) => v,
+            None => return self.release_all(side_runner),
+        };
+        let lat = match lat {
+            Some(v) => v,
+            None => return false,
+        };
+
+        if lat > threshold {
+            self.healthy_since = None;
+            let since = *self.breach_since.get_or_insert(now);
+            if now.duration_since(since) < Duration::from_secs_f64(cmd.breach_dur) {
+                return false;
+            }
+            if !self.breach {
+                warn!(
+                    "canary: Latency {:.1}ms is over {:.1}ms, holding background workloads",
+                    lat * 1000.0,
+                    threshold * 1000.0
+                );
+                self.breach = true;
+            }
+            return self.hold(cmd.action, side_runner);
+        }
+
+        self.breach = false;
+        self.breach_since = None;
+        if self.held.is_empty() {
+            self.healthy_since = None;
+            return false;
+        }
+
+        let since = *self.healthy_since.get_or_insert(now);
+        if now.duration_since(since) < Duration::from_secs_f64(cmd.resume_after) {
+            return false;
+        }
+        if let Some(at) = self.last_resume_at {
+            if now.duration_since(at) < Duration::from_secs_f64(cmd.resume_interval) {
+                return false;
+            }
+        }
+        self.last_resume_at = Some(now);
+        let (name, action) = self.held.remove(0);
+        Self::release(&name, action, side_runner)
+    }
+
+    fn hold(&mut self, action: CanaryAction, side_runner: &mut SideRunner) -> bool {
+        let mut changed = false;
+        for name in side_runner.background_workloads() {
+            if self.held.iter().any(|(held, _)| held == &name) {
+                continue;
+            }
+            match action {
+                CanaryAction::Freeze => {
+                    if let Err(e) = side_runner.set_frozen(&name, true) {
+                        warn!("canary: Failed to freeze {:?} ({:?})", &name, &e);
+                        continue;
+                    }
+                }
+                CanaryAction::Stop => changed = true,
+            }
+            info!("canary: Holding {:?} ({:?})", &name, action);
+            self.held.push((name, action));
+        }
+        changed
+    }
+
+    fn release(name: &str, action: CanaryAction, side_runner: &mut SideRunner) -> bool {
+        info!("canary: Resuming {:?}", name);
+        match action {
+            CanaryAction::Freeze => {
+                // It may have been removed from the target while frozen.
+                if side_runner.contains(name) {
+                    if let Err(e) = side_runner.set_frozen(name, false) {
+                        warn!("canary: Failed to thaw {:?} ({:?})", name, &e);
+                    }
+                }
+                false
+            }
+            CanaryAction::Stop => true,
+        }
+    }
+
+    /// Resume all held workloads right away.
+    pub fn release_all(&mut self, side_runner: &mut SideRunner) -> bool {
+        let mut changed = false;
+        for (name, action) in mem::take(&mut self.held) {
+            changed |= Self::release(&name, action, side_runner);
+        }
+        *self = Default::default();
+        changed
+    }
+
+    pub fn report(&self) -> CanaryReport {
+        CanaryReport {
+            breach: self.breach,
+            held: self.held.iter().cloned().collect(),
+        }
+    }
+}
@@ -1,23 +1,51 @@
 // Copyright (c) Facebook, Inc. and its affiliates.
 use anyhow::Result;
 use log::{debug, error, info, warn};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::{Arc, Mutex};
 use std::thread::sleep;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 use systemd::UnitState as US;
 use util::*;
 
-use rd_agent_intf::{RunnerState, Slice, SliceConfig};
+use rd_agent_intf::{RunnerState, SideloadDefs, Slice, SliceConfig, SvcStateReport};
 
+use super::canary::Canary;
 use super::hashd::HashdSet;
-use super::side::{Balloon, SideRunner, Sideload, Sysload};
+use super::side::{expand_clones, ApplyPlan, Balloon, SideRunner, Sideload, Sysload};
 use super::{bench, report, slices};
 use super::{Config, SysObjs};
 
 const HEALTH_CHECK_INTV: Duration = Duration::from_secs(10);
+const CANARY_INTV: Duration = Duration::from_secs(1);
 
 use RunnerState::*;
 
+fn warn_side_defs(side_runner: &SideRunner, defs: &SideloadDefs) {
+    match side_runner.validate_defs(defs) {
+        Ok(problems) => {
+            for problem in problems.iter() {
+                warn!("cmd: Sideload def {}", problem);
+            }
+        }
+        Err(e) => warn!("cmd: Failed to validate sideload defs ({:?})", &e),
+    }
+}
+
+// What the current cmd would do, written to apply-plan.json in dry run
+// mode.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+struct DryRunPlan {
+    sysloads: ApplyPlan,
+    sideloads: ApplyPlan,
+    restart_sysloads: Vec<String>,
+    freeze_sideloads: Vec<String>,
+    thaw_sideloads: Vec<String>,
+    dump_units: bool,
+    balloon_size: usize,
+}
+
 pub struct RunnerData {
     pub cfg: Arc<Config>,
     pub sobjs: SysObjs,
@@ -31,12 +59,30 @@ pub struct RunnerData {
 
     pub hashd_set: HashdSet,
     pub side_runner: SideRunner,
+    pub canary: Canary,
     pub balloon: Balloon,
+    restart_seqs: BTreeMap<String, u64>,
+    user_frozen: BTreeSet<String>,
+    dump_units_seq: u64,
+    apply_plan: Option<DryRunPlan>,
+    pub applied_cmd_seq: u64,
 }
 
 impl RunnerData {
     fn new(cfg: Config, sobjs: SysObjs) -> Self {
         let cfg = Arc::new(cfg);
+        let mut side_runner = SideRunner::new(cfg.clone());
+        let side_defs = sobjs
+            .side_def_file
+            .data
+            .overlay(&sobjs.cmd_file.data.side_defs);
+        side_runner.adopt_sideloads(&sobjs.cmd_file.data.sideloads, &side_defs);
+        side_runner.restore(&sobjs.cmd_file.data.sysloads);
+        side_runner.sweep_scr_trash();
+        warn_side_defs(&side_runner, &side_defs);
+        let restart_seqs = sobjs.cmd_file.data.restart_sysloads.clone();
+        let dump_units_seq = sobjs.cmd_file.data.dump_units_seq;
+
         Self {
             sobjs,
             state: Idle,
@@ -46,17 +92,77 @@ impl RunnerData {
             bench_hashd: None,
             bench_iocost: None,
             hashd_set: HashdSet::new(&cfg),
-            side_runner: SideRunner::new(cfg.clone()),
+            side_runner,
+            canary: Default::default(),
             balloon: Balloon::new(cfg.clone()),
+            restart_seqs,
+            user_frozen: BTreeSet::new(),
+            dump_units_seq,
+            apply_plan: None,
+            applied_cmd_seq: 0,
             cfg,
         }
     }
 
+    // Write what applying the current cmd would do without touching the
+    // running workloads or the balloon. Swaps are ignored as applying them
+    // would start the incoming workloads.
+    fn write_apply_plan(&mut self) {
+        let cmd = &self.sobjs.cmd_file.data;
+        let side_defs = &*self.sobjs.side_def_file.data.overlay(&cmd.side_defs);
+        let mut sysload_target = cmd.sysloads.clone();
+        let mut sideload_target = cmd.sideloads.clone();
+        for e in expand_clones(&cmd.clones, &mut sysload_target, &mut sideload_target) {
+            warn!("cmd: Failed to clone ({})", &e);
+        }
+        sysload_target.retain(|name, _| !self.canary.is_stopped(name));
+        sideload_target.retain(|name, _| !self.canary.is_stopped(name));
+
+        let plan = DryRunPlan {
+            sysloads: self.side_runner.plan_sysloads(&sysload_target, side_defs),
+            sideloads: self.side_runner.plan_sideloads(&sideload_target, side_defs),
+            restart_sysloads: cmd
+                .restart_sysloads
+                .iter()
+                .filter(|(name, seq)| **seq > self.restart_seqs.get(*name).cloned().unwrap_or(0))
+                .map(|(name, _)| name.clone())
+                .collect(),
+            freeze_sideloads: cmd
+                .freeze_sideloads
+                .iter()
+                .filter(|name| self.side_runner.sideload_frozen(name) == Some(false))
+                .cloned()
+                .collect(),
+            thaw_sideloads: self
+                .user_frozen
+                .difference(&cmd.freeze_sideloads)
+                .filter(|name| {
+                    self.side_runner.sideload_frozen(name) == Some(true)
+                        && !self.canary.is_held(name)
+                })
+                .cloned()
+                .collect(),
+            dump_units: cmd.dump_units_seq > self.dump_units_seq,
+            balloon_size: self.balloon_target(),
+        };
+        if self.apply_plan.as_ref() == Some(&plan) {
+            return;
+        }
+
+        let path = &self.cfg.apply_plan_path;
+        match std::fs::write(path, serde_json::to_string_pretty(&plan).unwrap()) {
+            Ok(()) => info!("cmd: Dry run, wrote apply plan to {:?}", path),
+            Err(e) => warn!("cmd: Failed to write apply plan to {:?} ({:?})", path, &e),
+        }
+        self.apply_plan = Some(plan);
+    }
+
     fn become_idle(&mut self) {
         info!("cmd: Transitioning to Idle state");
         self.bench_hashd = None;
         self.bench_iocost = None;
         self.hashd_set.stop();
+        self.canary.release_all(&mut self.side_runner);
         self.side_runner.stop();
         self.state = Idle;
     }
@@ -81,7 +187,7 @@ impl RunnerData {
 
         // Configs are controlled by benchmarks while they're running, don't
         // reload.
-        let (re_bench, re_slice, _re_side, re_oomd) = match self.state {
+        let (re_bench, re_slice, re_side, re_oomd) = match self.state {
             BenchIOCost | BenchHashd => (false, false, false, false),
             _ => {
                 let force = self.force_apply;
@@ -113,6 +219,15 @@ impl RunnerData {
             }
         }
 
+        if re_side || (re_cmd && !sobjs.cmd_file.data.side_defs.is_empty()) {
+            let side_defs = sobjs
+                .side_def_file
+                .data
+                .overlay(&sobjs.cmd_file.data.side_defs);
+            warn_side_defs(&self.side_runner, &side_defs);
+            self.side_runner.check_active_defs(&side_defs);
+        }
+
         if re_bench || re_oomd {
             if let Err(e) = sobjs.oomd.apply() {
                 error!("cmd: Failed to apply oomd configuration ({:?})", &e);
@@ -157,7 +272,9 @@ impl RunnerData {
             }
         }
 
-        re_bench || re_cmd || re_slice
+        // Changed defs are picked up by the next apply, restarting the
+        // affected workloads unless --no-restart-on-change.
+        re_bench || re_cmd || re_slice || re_side
     }
 
     fn apply_workloads(&mut self) -> Result<()> {
@@ -240,35 +357,61 @@ impl RunnerData {
                         panic!();
                     }
 
-                    let side_defs = &self.sobjs.side_def_file.data;
-                    let sysload_target = &self.sobjs.cmd_file.data.sysloads;
-                    if let Err(e) = self.side_runner.apply_sysloads(
-                        sysload_target,
-                        side_defs,
-                        &self.sobjs.bench_file.data,
-                        Some(removed_sysloads),
-                    ) {
-                        warn!("cmd: Failed to apply sysload changes ({:?})", &e);
-                    }
-                    let sideload_target = &self.sobjs.cmd_file.data.sideloads;
-                    if let Err(e) = self.side_runner.apply_sideloads(
-                        sideload_target,
-                        side_defs,
-                        &self.sobjs.bench_file.data,
-                        Some(removed_sideloads),
-                    ) {
-                        warn!("cmd: Failed to apply sideload changes ({:?})", &e);
-                    }
+                    let cmd = &self.sobjs.cmd_file.data;
+                    if cmd.dry_run {
+                        self.write_apply_plan();
+                    } else {
+                        for name in cmd.kill.iter() {
+                            if !cmd.sysloads.contains_key(name)
+                                && !cmd.sideloads.contains_key(name)
+                                && self.side_runner.contains(name)
+                            {
+                                if let Err(e) = self.side_runner.kill(name) {
+                                    warn!("cmd: Failed to kill {:?} ({:?})", name, &e);
+                                }
+                            }
+                        }
+                        self.side_runner.retain_killed(&cmd.kill);
+
+                        let side_defs = &*self.sobjs.side_def_file.data.overlay(&cmd.side_defs);
+                        let (mut sysload_target, mut sideload_target) =
+                            self.side_runner.apply_swaps(
+                                &cmd.swaps,
+                                &cmd.sysloads,
+                                &cmd.sideloads,
+                                side_defs,
+                                &self.sobjs.bench_file.data,
+                            );
+                        for e in
+                            expand_clones(&cmd.clones, &mut sysload_target, &mut sideload_target)
+                        {
+                            warn!("cmd: Failed to clone ({})", &e);
+                        }
+                        sysload_target.retain(|name, _| !self.canary.is_stopped(name));
+                        sideload_target.retain(|name, _| !self.canary.is_stopped(name));
+                        if let Err(e) = self.side_runner.apply_sysloads(
+                            &sysload_target,
+                            side_defs,
+                            &self.sobjs.bench_file.data,
+                            Some(removed_sysloads),
+                        ) {
+                            warn!("cmd: Failed to apply sysload changes ({:?})", &e);
+                        }
+                        if let Err(e) = self.side_runner.apply_sideloads(
+                            &sideload_target,
+                            side_defs,
+                            &self.sobjs.bench_file.data,
+                            Some(removed_sideloads),
+                        ) {
+                            warn!("cmd: Failed to apply sideload changes ({:?})", &e);
+                        }
+                        self.side_runner.update_work_cpus();
 
-                    let balloon_size =
-                        ((*TOTAL_MEMORY as f64) * &self.sobjs.cmd_file.data.balloon_ratio) as usize;
-                    if let Err(e) = self.balloon.set_size(balloon_size) {
-                        error!(
-                            "cmd: Failed to set balloon size to {:.2}G ({:?})",
-                            to_gb(balloon_size),
-                            &e
-                        );
-                        panic!();
+                        self.apply_restart_sysloads();
+                        self.apply_dump_units();
+                        self.apply_freeze_sideloads();
+                        self.apply_balloon();
+                        self.applied_cmd_seq = self.sobjs.cmd_file.data.cmd_seq;
                     }
                 }
             }
@@ -292,6 +435,115 @@ impl RunnerData {
         Ok(repeat)
     }
 
+    fn apply_restart_sysloads(&mut self) {
+        let cmd = &self.sobjs.cmd_file.data;
+        for (name, seq) in cmd.restart_sysloads.iter() {
+            if *seq > self.restart_seqs.get(name).cloned().unwrap_or(0) {
+                if let Err(e) = self.side_runner.restart_sysload(name) {
+                    warn!("cmd: Failed to restart sysload {:?} ({:?})", name, &e);
+                } else if let Ok(Some(rep)) = self.side_runner.report_sysload(name) {
+                    if rep.svc.state != SvcStateReport::Running {
+                        warn!("cmd: Restarted sysload {:?} isn't running", name);
+                    }
+                }
+            }
+        }
+        self.restart_seqs = cmd.restart_sysloads.clone();
+    }
+
+    fn apply_dump_units(&mut self) {
+        let cmd = &self.sobjs.cmd_file.data;
+        if cmd.dump_units_seq > self.dump_units_seq {
+            match self.side_runner.dump_units(&self.cfg.units_dump_path) {
+                Ok(nr) => info!(
+                    "cmd: Dumped {} unit files to {:?}",
+                    nr, &self.cfg.units_dump_path
+                ),
+                Err(e) => warn!(
+                    "cmd: Failed to dump unit files to {:?} ({:?})",
+                    &self.cfg.units_dump_path, &e
+                ),
+            }
+        }
+        self.dump_units_seq = cmd.dump_units_seq;
+    }
+
+    fn balloon_target(&self) -> usize {
+        ((*TOTAL_MEMORY as f64) * self.sobjs.cmd_file.data.balloon_ratio) as usize
+    }
+
+    fn apply_balloon(&mut self) {
+        let cmd = &self.sobjs.cmd_file.data;
+        let balloon_size = self.balloon_target();
+        let res = match cmd.balloon_ramp_step {
+            Some(step) => self.balloon.set_size_gradual(
+                balloon_size,
+                ((*TOTAL_MEMORY as f64) * step) as usize,
+                Duration::from_secs_f64(cmd.balloon_ramp_intv.max(0.0)),
+            ),
+            None => self.balloon.set_size(balloon_size),
+        };
+        if let Err(e) = res {
+            error!(
+                "cmd: Failed to set balloon size to {:.2}G ({:?})",
+                to_gb(balloon_size),
+                &e
+            );
+            panic!();
+        }
+    }
+
+    // Keep the sideloads in cmd freeze_sideloads[] frozen and thaw the ones
+    // which were removed from it unless the canary is holding them. A
+    // sideload which isn't running has no cgroup to freeze yet and is
+    // picked up once it starts.
+    fn apply_freeze_sideloads(&mut self) {
+        let cmd = &self.sobjs.cmd_file.data;
+        for name in cmd.freeze_sideloads.iter() {
+            let running = match self.side_runner.report_sideload(name) {
+                Ok(Some(rep)) => rep.svc.state == SvcStateReport::Running,
+                _ => false,
+            };
+            if running && self.side_runner.sideload_frozen(name) == Some(false) {
+                info!("cmd: Freezing sideload {:?}", name);
+                if let Err(e) = self.side_runner.freeze_sideload(name) {
+                    warn!("cmd: Failed to freeze sideload {:?} ({:?})", name, &e);
+                }
+            }
+        }
+        for name in self.user_frozen.difference(&cmd.freeze_sideloads) {
+            if self.side_runner.sideload_frozen(name) == Some(true) && !self.canary.is_held(name) {
+                info!("cmd: Thawing sideload {:?}", name);
+                if let Err(e) = self.side_runner.thaw_sideload(name) {
+                    warn!("cmd: Failed to thaw sideload {:?} ({:?})", name, &e);
+                }
+            }
+        }
+        self.user_frozen = cmd.freeze_sideloads.clone();
+    }
+
+    // Control percentile latency of the first hashd instance if running.
+    fn protected_lat(&mut self) -> Option<f64> {
+        let expiration = SystemTime::now() - Duration::from_secs(3);
+        match self.hashd_set.report(expiration) {
+            Ok(rep) if rep[0].svc.state == SvcStateReport::Running => Some(rep[0].lat.ctl),
+            Ok(_) => None,
+            Err(e) => {
+                warn!("cmd: Failed to read hashd report for canary ({:?})", &e);
+                None
+            }
+        }
+    }
+
+    fn update_canary(&mut self) -> bool {
+        let lat = match self.state {
+            Running => self.protected_lat(),
+            _ => None,
+        };
+        self.canary
+            .update(&self.sobjs.cmd_file.data.canary, lat, &mut self.side_runner)
+    }
+
     fn check_completions(&mut self) -> Result<()> {
         match self.state {
             BenchHashd | BenchIOCost => {
@@ -345,6 +597,7 @@ impl Runner {
     pub fn run(&mut self) {
         let mut reporter = None;
         let mut last_health_check_at = Instant::now();
+        let mut last_canary_at = Instant::now();
         let mut cmd_pending = true;
         let mut verify_pending = false;
 
@@ -372,6 +625,13 @@ impl Runner {
             if let Err(e) = data.check_completions() {
                 warn!("cmd: Failed to check completions ({:?})", &e);
             }
+            removed_sideloads.extend(data.side_runner.reap_expired_sideloads());
+            let (over_sys, over_side) = data.side_runner.reap_over_quota();
+            removed_sysloads.extend(over_sys);
+            removed_sideloads.extend(over_side);
+            if let Err(e) = data.side_runner.snapshot() {
+                warn!("cmd: Failed to save active workloads ({:?})", &e);
+            }
 
             // Stopping sys/sideloads and clearing scratch dirs can
             // take a while. Do it unlocked so that it doesn't stall
@@ -417,14 +677,37 @@ impl Runner {
                     );
                 }
 
+                // Queued heavy workloads may be able to start now, warmups
+                // may have finished and deferred removals may have reached
+                // their min_runtime.
+                if data.side_runner.has_queued()
+                    || data.side_runner.has_warmups()
+                    || data.side_runner.has_pending_removal()
+                {
+                    cmd_pending = true;
+                }
+
                 last_health_check_at = now;
                 verify_pending = false;
             }
 
+            if now.duration_since(last_canary_at) >= CANARY_INTV {
+                if data.update_canary() {
+                    cmd_pending = true;
+                }
+                last_canary_at = now;
+            }
+
+            data.balloon.maybe_refresh();
+            data.balloon.maybe_ramp();
+            data.balloon.maybe_deflate();
+
             if data.maybe_reload() {
                 cmd_pending = true;
                 verify_pending = true;
             }
         }
+
+        data.side_runner.flush_cleanup();
     }
 }
@@ -1,11 +1,11 @@
 // Copyright (c) Facebook, Inc. and its affiliates.
 use anyhow::{anyhow, bail, Result};
 use enum_iterator::IntoEnumIterator;
-use glob::glob;
 use log::{debug, error, info, trace, warn};
 use proc_mounts::MountInfo;
 use scan_fmt::scan_fmt;
-use std::collections::HashSet;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io;
 use std::io::prelude::*;
@@ -14,12 +14,13 @@ use std::path::Path;
 use std::process::Command;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use sysinfo::{self, ProcessExt, SystemExt};
 use users;
 use util::*;
 
 mod bench;
+mod canary;
 mod cmd;
 mod hashd;
 mod misc;
@@ -30,11 +31,13 @@ mod sideloader;
 mod slices;
 
 use rd_agent_intf::{
-    Args, BenchKnobs, Cmd, CmdAck, Report, SideloadDefs, SliceKnobs, SvcReport, SvcStateReport,
-    SysReq, SysReqsReport, OOMD_SVC_NAME,
+    ApplyProgress, ApplyResult, ApplyTarget, Args, BenchKnobs, Cmd, CmdAck, DepKind,
+    PrepareProgress, Report, SideloadDefs, SliceKnobs, StartupCheckReport, SvcReport,
+    SvcStateReport, SysReq, SysReqsReport, OOMD_SVC_NAME,
 };
 
 const SWAPPINESS_PATH: &str = "/proc/sys/vm/swappiness";
+const APPLY_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub static INSTANCE_SEQ: AtomicU64 = AtomicU64::new(0);
 
@@ -167,13 +170,42 @@ pub struct Config {
     pub sideloader_daemon_cfg_path: String,
     pub sideloader_daemon_status_path: String,
     pub side_defs_path: String,
+    pub prepare_history_path: String,
+    pub units_dump_path: String,
+    pub apply_plan_path: String,
+    pub workloads_path: String,
     pub side_bin_path: String,
     pub side_scr_path: String,
     pub sys_scr_path: String,
     pub balloon_bin: String,
     pub side_linux_tar_path: Option<String>,
+    pub side_linux_tar_mirrors: Vec<String>,
+    pub side_linux_tar_sha256: Option<String>,
+    pub side_linux_src_shared: bool,
+    pub side_bin_roots: Vec<String>,
+    pub max_heavy: Option<u32>,
+    pub heavy_scr_free: Option<u64>,
+    pub scr_budget: Option<f64>,
+    pub scr_budget_hard: bool,
+    pub sys_scr_tmpfs: bool,
+    pub side_scr_tmpfs: bool,
+    pub scr_tmpfs_size: Option<u64>,
+    pub scr_template: String,
+    pub prop_audit: side::PropAudit,
+    pub scr_umount: side::ScrUmount,
+    pub settle_timeout: Duration,
+    pub scr_rm_timeout: Duration,
+    pub balloon_refresh: Option<Duration>,
+    pub balloon_psi: Option<f64>,
+    pub balloon_deflate: f64,
+    pub io_latency: Option<u64>,
+    pub restart_on_change: bool,
+    pub report_sock_path: Option<String>,
+    pub metrics_port: Option<u16>,
+    pub metrics_addr: String,
 
     pub sr_failed: HashSet<SysReq>,
+    sr_missing: HashMap<SysReq, Vec<String>>,
     sr_wbt: Option<u64>,
     sr_wbt_path: Option<String>,
     sr_swappiness: Option<u32>,
@@ -195,6 +227,13 @@ impl Config {
             .to_string()
     }
 
+    fn verify_abs_dir(what: &str, path: &str) -> Result<()> {
+        if !Path::new(path).is_absolute() {
+            bail!("{} {:?} is not an absolute path", what, path);
+        }
+        Ok(())
+    }
+
     fn sgid_top<P: AsRef<Path>>(top_path: &str, args_path: Option<&P>) -> Result<()> {
         let mut group = None;
         for name in ["wheel", "sudo", "adm"].iter() {
@@ -263,6 +302,20 @@ impl Config {
 
     fn new(args_file: &JsonConfigFile<Args>) -> Self {
         let args = &args_file.data;
+
+        // Relative paths would be resolved against whatever the CWD happens
+        // to be, reject them upfront.
+        let mut roots = vec![("Top-level dir", args.dir.as_str())];
+        if let Some(scr) = args.scratch.as_ref() {
+            roots.push(("Scratch dir", scr.as_str()));
+        }
+        for (what, path) in roots.into_iter() {
+            if let Err(e) = Self::verify_abs_dir(what, path) {
+                error!("cfg: {}", &e);
+                panic!();
+            }
+        }
+
         let top_path = Self::prep_dir(&args.dir);
         if let Err(e) = Self::sgid_top(&top_path, args_file.path.as_ref()) {
             info!(
@@ -314,9 +367,139 @@ impl Config {
         let side_bin_path = top_path.clone() + "/sideload-bin";
         let side_scr_path = scr_path.clone() + "/sideload";
         let sys_scr_path = scr_path.clone() + "/sysload";
-        Self::prep_dir(&side_bin_path);
-        Self::prep_dir(&side_scr_path);
-        Self::prep_dir(&sys_scr_path);
+        for (what, path) in [
+            ("Sideload bin dir", &side_bin_path),
+            ("Sideload scratch dir", &side_scr_path),
+            ("Sysload scratch dir", &sys_scr_path),
+        ]
+        .iter()
+        {
+            if let Err(e) = Self::verify_abs_dir(what, path) {
+                error!("cfg: {}", &e);
+                panic!();
+            }
+            Self::prep_dir(path);
+        }
+
+        let scr_template = match args.scr_template.as_ref() {
+            Some(v) => v.clone(),
+            None => "{name}".into(),
+        };
+        if !scr_template.contains("{name}") {
+            error!(
+                "cfg: Scratch dir template {:?} doesn't contain {{name}}",
+                &scr_template
+            );
+            panic!();
+        }
+
+        if let Some(frac) = args.scr_budget {
+            if frac <= 0.0 || frac > 1.0 {
+                error!("cfg: --scr-budget {} is out of range (0.0, 1.0]", frac);
+                panic!();
+            }
+        }
+
+        let mut side_linux_tar_mirrors: Vec<String> = match args.linux_tar_url.as_ref() {
+            Some(urls) => urls
+                .split(',')
+                .filter(|url| !url.is_empty())
+                .map(|url| url.to_string())
+                .collect(),
+            None => vec![],
+        };
+        for url in side_linux_tar_mirrors.iter() {
+            if !url.ends_with(".tar.xz") && !url.ends_with(".tar") {
+                error!(
+                    "cfg: --linux-tar-url {:?} doesn't end with .tar.xz or .tar",
+                    url
+                );
+                panic!();
+            }
+        }
+        if side_linux_tar_mirrors.is_empty() {
+            side_linux_tar_mirrors.extend(side::LINUX_TAR_XZ_MIRRORS.iter().map(|x| x.to_string()));
+        }
+        if let Some(sha256) = args.linux_tar_sha256.as_ref() {
+            if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                error!(
+                    "cfg: --linux-tar-sha256 {:?} is not a SHA-256 digest",
+                    sha256
+                );
+                panic!();
+            }
+        }
+
+        if let Some(intv) = args.balloon_refresh {
+            if intv <= 0.0 {
+                error!("cfg: --balloon-refresh {} must be positive", intv);
+                panic!();
+            }
+        }
+
+        if let Some(pct) = args.balloon_psi {
+            if pct <= 0.0 || pct > 100.0 {
+                error!("cfg: --balloon-psi {} must be in (0, 100]", pct);
+                panic!();
+            }
+        }
+        let balloon_deflate = args.balloon_deflate.unwrap_or(0.5);
+        if balloon_deflate <= 0.0 || balloon_deflate > 1.0 {
+            error!(
+                "cfg: --balloon-deflate {} must be in (0.0, 1.0]",
+                balloon_deflate
+            );
+            panic!();
+        }
+
+        if let Some(timeout) = args.scr_rm_timeout {
+            if timeout < 0.0 {
+                error!("cfg: --scr-rm-timeout {} can't be negative", timeout);
+                panic!();
+            }
+        }
+
+        if args.metrics_port.is_some() && !cfg!(feature = "metrics") {
+            error!("cfg: --metrics-port requires rd-agent built with the metrics feature");
+            panic!();
+        }
+
+        if args.scr_tmpfs_size == Some(0) {
+            error!("cfg: --scr-tmpfs-size must be positive");
+            panic!();
+        }
+
+        let prop_audit = match args.prop_audit.as_deref() {
+            None | Some("off") => side::PropAudit::Off,
+            Some("summary") => side::PropAudit::Summary,
+            Some("full") => side::PropAudit::Full,
+            Some(v) => {
+                error!("cfg: Invalid --prop-audit level {:?}", v);
+                panic!();
+            }
+        };
+
+        let scr_umount = match args.scr_umount.as_deref() {
+            Some("off") => side::ScrUmount::Off,
+            Some("normal") => side::ScrUmount::Normal,
+            None | Some("lazy") => side::ScrUmount::Lazy,
+            Some(v) => {
+                error!("cfg: Invalid --scr-umount mode {:?}", v);
+                panic!();
+            }
+        };
+
+        // The embedded side bins come first, followed by --bin-path dirs.
+        let mut side_bin_roots = vec![side_bin_path.clone()];
+        if let Some(bin_path) = args.bin_path.as_ref() {
+            for dir in bin_path.split(':').filter(|x| !x.is_empty()) {
+                if let Err(e) = Self::verify_abs_dir("Binary search dir", dir) {
+                    error!("cfg: {}", &e);
+                    panic!();
+                }
+                side_bin_roots.push(dir.into());
+            }
+        }
 
         let report_d_path = top_path.clone() + "/report.d";
         let report_1min_d_path = top_path.clone() + "/report-1min.d";
@@ -327,22 +510,8 @@ impl Config {
         Self::prep_dir(&(top_path.clone() + "/hashd-B"));
         Self::prep_dir(&(top_path.clone() + "/oomd"));
 
-        let sideloader_jobs_d = top_path.clone() + "/sideloader/jobs.d";
-        Self::prep_dir(&sideloader_jobs_d);
-        for path in glob(&format!("{}/*.json", &sideloader_jobs_d))
-            .unwrap()
-            .filter_map(Result::ok)
-        {
-            if let Err(e) = fs::remove_file(&path) {
-                error!(
-                    "cfg: Failed to remove stale sideloader job {:?} ({:?})",
-                    &path, &e
-                );
-                panic!();
-            } else {
-                debug!("cfg: Removed stale sideloader job {:?}", &path);
-            }
-        }
+        // Stale sideloader jobs are adopted or removed by SideRunner.
+        Self::prep_dir(&(top_path.clone() + "/sideloader/jobs.d"));
 
         Self {
             passive: args.passive,
@@ -393,15 +562,47 @@ impl Config {
             sideloader_daemon_jobs_path: top_path.clone() + "/sideloader/jobs.d",
             sideloader_daemon_status_path: top_path.clone() + "/sideloader/status.json",
             side_defs_path: top_path.clone() + "/sideload-defs.json",
+            prepare_history_path: top_path.clone() + "/prepare-history.json",
+            units_dump_path: top_path.clone() + "/units",
+            apply_plan_path: top_path.clone() + "/apply-plan.json",
+            workloads_path: top_path.clone() + "/workloads.json",
             side_bin_path: side_bin_path.clone(),
             side_scr_path,
             sys_scr_path,
             balloon_bin: side_bin_path.clone() + "/memory-balloon.py",
             side_linux_tar_path: args.linux_tar.clone(),
+            side_linux_tar_mirrors,
+            side_linux_tar_sha256: args.linux_tar_sha256.clone(),
+            side_linux_src_shared: args.linux_src_shared,
+            side_bin_roots,
+            max_heavy: args.max_heavy,
+            heavy_scr_free: args.heavy_scr_free,
+            scr_budget: args.scr_budget,
+            scr_budget_hard: args.scr_budget_hard,
+            sys_scr_tmpfs: args.sys_scr_tmpfs,
+            side_scr_tmpfs: args.side_scr_tmpfs,
+            scr_tmpfs_size: args.scr_tmpfs_size,
+            scr_template,
+            prop_audit,
+            scr_umount,
+            restart_on_change: !args.no_restart_on_change,
+            settle_timeout: Duration::from_secs_f64(args.settle_timeout.unwrap_or(5.0)),
+            scr_rm_timeout: Duration::from_secs_f64(args.scr_rm_timeout.unwrap_or(10.0)),
+            balloon_refresh: args.balloon_refresh.map(Duration::from_secs_f64),
+            balloon_psi: args.balloon_psi,
+            balloon_deflate,
+            io_latency: args.io_latency,
+            report_sock_path: args.report_sock.clone(),
+            metrics_port: args.metrics_port,
+            metrics_addr: args
+                .metrics_addr
+                .clone()
+                .unwrap_or_else(|| "127.0.0.1".into()),
             top_path,
             scr_path,
 
             sr_failed: HashSet::new(),
+            sr_missing: HashMap::new(),
             sr_wbt: None,
             sr_wbt_path: None,
             sr_swappiness: None,
@@ -622,7 +823,7 @@ impl Config {
 
         // IO controllers
         self.check_iocost(enforce);
-        slices::check_other_io_controllers(&mut self.sr_failed);
+        slices::check_other_io_controllers(&mut self.sr_failed, self.io_latency);
 
         // anon memory balance
         match report::read_cgroup_flat_keyed_file("/proc/vmstat") {
@@ -851,7 +1052,17 @@ impl Config {
         }
 
         // sideload checks
-        side::startup_checks(&mut self.sr_failed);
+        let missing_deps = side::startup_checks(&mut self.sr_failed);
+        for dep in missing_deps.iter() {
+            let req = match dep.kind {
+                DepKind::Outdated => SysReq::BuildToolVersions,
+                _ => SysReq::Dependencies,
+            };
+            self.sr_missing
+                .entry(req)
+                .or_default()
+                .push(dep.name.clone());
+        }
 
         // Done, report
         let (mut satisfied, mut missed) = (Vec::new(), Vec::new());
@@ -863,7 +1074,13 @@ impl Config {
             }
         }
 
-        SysReqsReport { satisfied, missed }.save(&self.sysreqs_path)?;
+        SysReqsReport {
+            satisfied,
+            missed,
+            checks: self.startup_check_report().checks,
+            missing_deps,
+        }
+        .save(&self.sysreqs_path)?;
 
         if self.sr_failed.is_empty() {
             Ok(())
@@ -872,6 +1089,11 @@ impl Config {
         }
     }
 
+    /// Per-requirement results of the last `startup_checks()`.
+    pub fn startup_check_report(&self) -> StartupCheckReport {
+        StartupCheckReport::new(&self.sr_failed, &self.sr_missing)
+    }
+
     pub fn hashd_paths(&self, sel: HashdSel) -> &HashdPaths {
         &self.hashd_paths[sel as usize]
     }
@@ -905,6 +1127,111 @@ impl Drop for Config {
     }
 }
 
+// Print a line of JSON for --progress.
+fn print_progress<T: Serialize>(progress: &T) {
+    println!("{}", serde_json::to_string(progress).unwrap());
+}
+
+// Read an ApplyTarget from stdin and apply it.
+fn apply_stdin(top_path: &str, progress: &mut dyn FnMut(ApplyProgress)) -> ApplyResult {
+    let mut res: ApplyResult = Default::default();
+
+    let mut body = String::new();
+    if let Err(e) = io::stdin().read_to_string(&mut body) {
+        res.errors.push(format!("failed to read stdin ({})", &e));
+        return res;
+    }
+    match side::parse_apply_target(&body) {
+        Ok(target) => apply_target(top_path, target, progress),
+        Err(e) => {
+            res.errors.push(format!("failed to parse target ({})", &e));
+            res
+        }
+    }
+}
+
+// Wait for report.json to show that the running agent applied `cmd_seq`.
+fn wait_cmd_applied(report_path: &str, cmd_seq: u64, timeout: Duration) -> Result<()> {
+    let started_at = Instant::now();
+    loop {
+        if let Ok(rep) = Report::load(report_path) {
+            if rep.cmd_seq >= cmd_seq {
+                return Ok(());
+            }
+        }
+        if started_at.elapsed() >= timeout {
+            bail!(
+                "agent didn't apply cmd_seq {} in {}s, is it running and done with benchmarks?",
+                cmd_seq,
+                timeout.as_secs()
+            );
+        }
+        sleep(Duration::from_millis(250));
+    }
+}
+
+// Verify the ApplyTarget against the defs, write it to cmd.json and wait for
+// the running agent to apply it. The inline defs go into cmd.json too so
+// that sideload-defs.json is left alone.
+fn apply_target(
+    top_path: &str,
+    target: ApplyTarget,
+    progress: &mut dyn FnMut(ApplyProgress),
+) -> ApplyResult {
+    let mut res: ApplyResult = Default::default();
+    let defs_path = top_path.to_string() + "/sideload-defs.json";
+    let cmd_path = top_path.to_string() + "/cmd.json";
+    let report_path = top_path.to_string() + "/report.json";
+
+    let defs = match SideloadDefs::load(&defs_path) {
+        Ok(v) => v,
+        Err(e) => {
+            res.errors
+                .push(format!("failed to load {:?} ({})", &defs_path, &e));
+            return res;
+        }
+    };
+    res.errors = side::verify_target(&target, &defs.overlay(&target.defs));
+    if !res.errors.is_empty() {
+        return res;
+    }
+
+    let mut cmd = match Cmd::load(&cmd_path) {
+        Ok(v) => v,
+        Err(e) => {
+            res.errors
+                .push(format!("failed to load {:?} ({})", &cmd_path, &e));
+            return res;
+        }
+    };
+    cmd.cmd_seq += 1;
+    cmd.sysloads = target.sysloads;
+    cmd.sideloads = target.sideloads;
+    cmd.side_defs = target.defs;
+    if let Err(e) = cmd.save(&cmd_path) {
+        res.errors
+            .push(format!("failed to update {:?} ({})", &cmd_path, &e));
+        return res;
+    }
+
+    res.cmd_seq = cmd.cmd_seq;
+    res.sysloads = cmd.sysloads;
+    res.sideloads = cmd.sideloads;
+    progress(ApplyProgress::Written {
+        cmd_seq: res.cmd_seq,
+    });
+    match wait_cmd_applied(&report_path, cmd.cmd_seq, APPLY_WAIT_TIMEOUT) {
+        Ok(()) => {
+            res.applied = true;
+            progress(ApplyProgress::Applied {
+                cmd_seq: res.cmd_seq,
+            });
+        }
+        Err(e) => res.errors.push(format!("{}", &e)),
+    }
+    res
+}
+
 fn reset_agent_states(cfg: &Config) {
     for path in vec![
         &cfg.index_path,
@@ -926,6 +1253,9 @@ fn reset_agent_states(cfg: &Config) {
         &cfg.sideloader_daemon_jobs_path,
         &cfg.sideloader_daemon_status_path,
         &cfg.side_defs_path,
+        &cfg.prepare_history_path,
+        &cfg.apply_plan_path,
+        &cfg.workloads_path,
         &cfg.side_bin_path,
         &cfg.side_scr_path,
         &cfg.sys_scr_path,
@@ -1055,6 +1385,7 @@ fn update_index(cfg: &Config) -> Result<()> {
             },
         ],
         sideload_defs: cfg.side_defs_path.clone(),
+        prepare_history: cfg.prepare_history_path.clone(),
     };
 
     index.save(&cfg.index_path)
@@ -1071,6 +1402,28 @@ fn main() {
         panic!();
     });
 
+    if args_file.data.list_side_bins {
+        println!("rd-agent {} side binaries:", env!("CARGO_PKG_VERSION"));
+        for (name, sha256) in side::side_bins_info() {
+            println!("  {:<20} {}", name, sha256);
+        }
+        return;
+    }
+
+    if args_file.data.apply_stdin {
+        let res = apply_stdin(&args_file.data.dir, &mut |p| {
+            if args_file.data.progress {
+                print_progress(&p);
+            }
+        });
+        if args_file.data.progress {
+            print_progress(&res);
+        } else {
+            println!("{}", serde_json::to_string_pretty(&res).unwrap());
+        }
+        std::process::exit(if res.applied { 0 } else { 1 });
+    }
+
     let mut cfg = Config::new(&args_file);
 
     if args_file.data.reset {
@@ -1087,13 +1440,49 @@ fn main() {
         panic!();
     }
 
-    if let Err(e) = side::prepare_sides(&cfg) {
-        error!("cfg: Failed to prepare sideloads ({:?})", &e);
-        panic!();
+    let mut progress = |p: PrepareProgress| {
+        if args_file.data.progress {
+            print_progress(&p);
+        }
+    };
+    match side::prepare_sides(&cfg, &mut progress) {
+        Ok(tar) => {
+            info!(
+                "cfg: Using linux {} tarball {:?} ({:?}, {:.2}G, sha256 {})",
+                tar.version.as_deref().unwrap_or("UNKNOWN"),
+                &tar.path,
+                tar.source,
+                to_gb(tar.size),
+                &tar.sha256
+            );
+            if !tar.tool_versions.is_empty() {
+                let tools: Vec<String> = tar
+                    .tool_versions
+                    .iter()
+                    .map(|(k, v)| format!("{}-{}", k, v))
+                    .collect();
+                info!("cfg: Linux tarball fetched with {}", tools.join(" "));
+            }
+        }
+        Err(e) => {
+            error!("cfg: Failed to prepare sideloads ({:?})", &e);
+            panic!();
+        }
     }
 
     if let Err(e) = cfg.startup_checks() {
-        if args_file.data.force {
+        if args_file.data.strict {
+            let missed: Vec<String> = cfg
+                .startup_check_report()
+                .failed()
+                .map(|check| format!("{:?}", check.req))
+                .collect();
+            error!(
+                "cfg: Refusing to start with unmet system requirements as per --strict: {}",
+                missed.join(", ")
+            );
+            panic!();
+        } else if args_file.data.force {
             warn!("cfg: Ignoring startup check failures as per --force");
         } else {
             error!("cfg: {:?}", e);
@@ -1108,6 +1497,28 @@ fn main() {
     let mut sobjs = SysObjs::new(&cfg);
     trace!("{:#?}", &cfg);
 
+    if let Err(e) = side::check_jobs_path(&cfg.sideloader_daemon_jobs_path) {
+        error!("cfg: {}", &e);
+        panic!();
+    }
+
+    // The defs are validated and warned about once the side runner is up.
+    // Only --strict-defs is enforced here.
+    let missing = side::check_side_defs(&cfg, &sobjs.side_def_file.data);
+    if !missing.is_empty() && args_file.data.strict_defs {
+        for (id, bin) in missing.iter() {
+            error!(
+                "cfg: Sideload def {:?} has unresolvable binary {:?}",
+                id, bin
+            );
+        }
+        error!(
+            "cfg: Refusing to start with {} unresolvable sideload def binaries as per --strict-defs",
+            missing.len()
+        );
+        panic!();
+    }
+
     if let Err(e) = bench::apply_iocost(&sobjs.bench_file.data, &cfg) {
         error!(
             "cfg: Failed to configure iocost controller on {:?} ({:?})",
@@ -1150,3 +1561,18 @@ fn main() {
 
     cmd::Runner::new(cfg, sobjs).run();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+
+    #[test]
+    fn test_verify_abs_dir() {
+        assert!(Config::verify_abs_dir("test", "/var/lib/resctl-demo").is_ok());
+        assert!(Config::verify_abs_dir("test", "/").is_ok());
+        assert!(Config::verify_abs_dir("test", "resctl-demo").is_err());
+        assert!(Config::verify_abs_dir("test", "./scratch").is_err());
+        assert!(Config::verify_abs_dir("test", "../scratch").is_err());
+        assert!(Config::verify_abs_dir("test", "").is_err());
+    }
+}
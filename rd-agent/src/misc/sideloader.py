@@ -247,6 +247,7 @@ class JobFile:
         self.ino = ino
         self.path = path
         self.fh = fh
+        self.mtime = os.fstat(fh.fileno()).st_mtime
 
     def __repr__(self):
         return f'{self.ino}:{self.path}'
@@ -269,12 +270,27 @@ class Job:
         self.envs = cfg['envs']
         self.frozen_exp = frozen_exp
         self.frozen_at = None
+        self.held = bool(cfg.get('frozen', False))
         self.done = False
         self.kill_why = None
         self.killed = False
         self.svc_name = f'{args.svc_prefix}{jobid}{SVC_SUFFIX}'
         self.svc_status = None
         self.working_dir = cfg['working_dir'] if 'working_dir' in cfg else None;
+        self.cpu_weight = cfg.get('cpu_weight')
+        self.stdout_path = cfg.get('stdout_path')
+        self.stderr_path = cfg.get('stderr_path')
+
+    # Only the frozen state can be changed by rewriting the job file in place.
+    def update_cfg(self, cfg):
+        if 'frozen_expiration' in cfg:
+            self.frozen_exp = float(cfg['frozen_expiration'])
+        held = bool(cfg.get('frozen', False))
+        if held != self.held:
+            log(f'JOB: {"Holding" if held else "Releasing"} {self.jobid} frozen')
+            self.held = held
+            # Don't count the hold against frozen_expiration
+            self.frozen_at = None
 
     def update_frozen(self, freeze, now):
         changed = False
@@ -906,6 +922,23 @@ def process_job_dir(jobfiles, jobs, now):
     for jf in gone_jobfiles:
         del jobfiles[jf.ino]
 
+    # Job files rewritten in place update their jobs. The mtime is recorded
+    # only after a successful parse so that a file caught mid-write is
+    # retried on the next pass.
+    for ino, jf in input_jobfiles.items():
+        if ino not in jobfiles or jf.mtime == jobfiles[ino].mtime:
+            continue
+        try:
+            parsed = json.load(jf.fh)
+            for ent in parsed['sideloader_jobs']:
+                for job in (jobs.get(ent['id']), jobs_pending.get(ent['id'])):
+                    if job is not None and job.jobfile.ino == ino:
+                        job.update_cfg(ent)
+        except Exception as e:
+            warn(f'Failed to reload {jf.path} ({e})')
+        else:
+            jobfiles[ino].mtime = jf.mtime
+
     # Collect active jobids and determine jobs to kill.
     jobids = set()
     jobs_to_kill = {}
@@ -1063,6 +1096,12 @@ while True:
                    '--slice', config.side_slice, '--unit', job.svc_name]
             if job.working_dir is not None:
                 cmd += ['--working-directory', job.working_dir]
+            if job.cpu_weight is not None:
+                cmd += ['-p', f'CPUWeight={int(job.cpu_weight)}']
+            if job.stdout_path is not None:
+                cmd += ['-p', f'StandardOutput=file:{job.stdout_path}']
+            if job.stderr_path is not None:
+                cmd += ['-p', f'StandardError=file:{job.stderr_path}']
             for env in job.envs:
                 cmd += ['-E', env]
             cmd += job.args
@@ -1138,12 +1177,12 @@ while True:
         for jobid, job in jobs.items():
             job.update_frozen(True, now)
             ddbg(f'{jobid} frozen for {int(now - job.frozen_at)}s exp={job.frozen_exp}')
-            if now - job.frozen_at >= job.frozen_exp:
+            if not job.held and now - job.frozen_at >= job.frozen_exp:
                 job.kill('frozen for too long')
     else:
         overload_hold = max(overload_hold - config.ov_hold_decay, 0)
         for jobid, job in jobs.items():
-            job.update_frozen(False, now)
+            job.update_frozen(job.held, now)
 
     # Process frozen timeouts
     for jobid, job in jobs.items():
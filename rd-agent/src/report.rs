@@ -11,21 +11,33 @@ use scan_fmt::scan_fmt;
 use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
 use std::fs;
+use std::io;
 use std::io::prelude::*;
 use std::io::BufReader;
+#[cfg(feature = "metrics")]
+use std::net::{TcpListener, TcpStream};
 use std::os::unix::fs::symlink;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::panic;
 use std::process::{Command, Stdio};
+#[cfg(feature = "metrics")]
+use std::sync::{Arc, Mutex};
 use std::thread::{spawn, JoinHandle};
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use util::*;
 
 use super::cmd::Runner;
+use super::slices;
+#[cfg(feature = "metrics")]
+use rd_agent_intf::openmetrics;
 use rd_agent_intf::{
     BenchReport, HashdReport, IoCostReport, IoLatReport, Report, ResCtlReport, Slice, UsageReport,
     HASHD_A_SVC_NAME, HASHD_B_SVC_NAME, REPORT_1MIN_RETENTION, REPORT_RETENTION,
 };
 
+// Wall clock steps larger than this between two checks are flagged.
+const CLOCK_JUMP_THRESHOLD: f64 = 1.0;
+
 #[derive(Debug, Default)]
 struct Usage {
     cpu_busy: f64,
@@ -39,7 +51,7 @@ struct Usage {
     io_stalls: (f64, f64),
 }
 
-fn read_stalls(path: &str) -> Result<(f64, f64)> {
+pub fn read_stalls(path: &str) -> Result<(f64, f64)> {
     let f = fs::OpenOptions::new().read(true).open(path)?;
     let r = BufReader::new(f);
     let (mut some, mut full) = (None, None);
@@ -316,6 +328,39 @@ impl UsageTracker {
     }
 }
 
+// Detects wall clock jumps by comparing how much the wall and monotonic
+// clocks advanced since the last check.
+struct ClockJumpDetector {
+    last_at: (Instant, SystemTime),
+}
+
+impl ClockJumpDetector {
+    fn new() -> Self {
+        Self {
+            last_at: (Instant::now(), SystemTime::now()),
+        }
+    }
+
+    /// Returns how many seconds the wall clock jumped since the last check,
+    /// negative if it went backward.
+    fn check(&mut self) -> Option<f64> {
+        let now = (Instant::now(), SystemTime::now());
+        let mono = now.0.duration_since(self.last_at.0).as_secs_f64();
+        let wall = match now.1.duration_since(self.last_at.1) {
+            Ok(v) => v.as_secs_f64(),
+            Err(e) => -e.duration().as_secs_f64(),
+        };
+        self.last_at = now;
+
+        let jump = wall - mono;
+        if jump.abs() > CLOCK_JUMP_THRESHOLD {
+            Some(jump)
+        } else {
+            None
+        }
+    }
+}
+
 struct ReportFile {
     intv: u64,
     retention: u64,
@@ -326,6 +371,7 @@ struct ReportFile {
     hashd_acc: [HashdReport; 2],
     iolat_acc: IoLatReport,
     iocost_acc: IoCostReport,
+    clock_jump_acc: Option<f64>,
     nr_samples: u32,
 }
 
@@ -371,6 +417,7 @@ impl ReportFile {
             hashd_acc: Default::default(),
             iolat_acc: Default::default(),
             iocost_acc: Default::default(),
+            clock_jump_acc: None,
             nr_samples: 0,
         };
 
@@ -380,16 +427,22 @@ impl ReportFile {
         rf
     }
 
-    fn tick(&mut self, base_report: &Report, now: u64) {
+    // Returns the filled report if a new summary was written.
+    fn tick(&mut self, base_report: &Report, now: u64) -> Option<Report> {
         for i in 0..2 {
             self.hashd_acc[i] += &base_report.hashd[i];
         }
         self.iolat_acc.accumulate(&base_report.iolat);
         self.iocost_acc += &base_report.iocost;
+        if let Some(jump) = base_report.clock_jump {
+            *self.clock_jump_acc.get_or_insert(0.0) += jump;
+            // Don't wait for the old schedule if the clock went backward.
+            self.next_at = self.next_at.min((now / self.intv + 1) * self.intv);
+        }
         self.nr_samples += 1;
 
         if now < self.next_at {
-            return;
+            return None;
         }
 
         trace!("report: Reporting {}s summary at {}", self.intv, now);
@@ -406,6 +459,7 @@ impl ReportFile {
             self.hashd_acc[i] /= self.nr_samples;
             report.hashd[i] = HashdReport {
                 svc: report.hashd[i].svc.clone(),
+                tier: report.hashd[i].tier,
                 ..self.hashd_acc[i].clone()
             };
         }
@@ -418,13 +472,16 @@ impl ReportFile {
         report.iocost = self.iocost_acc.clone();
         self.iocost_acc = Default::default();
 
+        let clock_jump = self.clock_jump_acc.take();
+        report.clock_jump = clock_jump;
+
         self.nr_samples = 0;
 
         report.usages = match self.usage_tracker.update() {
             Ok(v) => v,
             Err(e) => {
                 warn!("report: Failed to update {}s usages ({:?})", self.intv, &e);
-                return;
+                return None;
             }
         };
 
@@ -449,12 +506,166 @@ impl ReportFile {
             );
         }
 
-        // delete expired ones
+        // delete expired ones, the range is meaningless across clock jumps
+        if clock_jump.is_some() {
+            if let Err(e) = self.clear_old_files(now) {
+                warn!("report: Failed to clear stale report files ({:?})", &e);
+            }
+            return Some(report_file.data);
+        }
         for i in was_at..now {
             let path = format!("{}/{}.json", &self.d_path, i - self.retention);
             trace!("report: Removing expired {:?}", &path);
             let _ = fs::remove_file(&path);
         }
+        Some(report_file.data)
+    }
+}
+
+// Streams each base report as a line of json to the clients connected to a
+// unix domain socket. Everything is non-blocking. A client which can't keep
+// up or went away is disconnected rather than stalling the report worker.
+struct ReportSock {
+    path: String,
+    listener: UnixListener,
+    clients: Vec<UnixStream>,
+}
+
+impl ReportSock {
+    fn new(path: &str) -> Result<Self> {
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        info!("report: Streaming reports to {:?}", path);
+        Ok(Self {
+            path: path.into(),
+            listener,
+            clients: vec![],
+        })
+    }
+
+    fn accept(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => match stream.set_nonblocking(true) {
+                    Ok(()) => {
+                        debug!("report: New client on {:?}", &self.path);
+                        self.clients.push(stream);
+                    }
+                    Err(e) => warn!("report: Failed to set up client ({:?})", &e),
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("report: Failed to accept on {:?} ({:?})", &self.path, &e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, report: &Report) {
+        self.accept();
+        if self.clients.is_empty() {
+            return;
+        }
+
+        let mut line = match serde_json::to_string(report) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("report: Failed to serialize report ({:?})", &e);
+                return;
+            }
+        };
+        line += "\n";
+
+        let path = &self.path;
+        self.clients
+            .retain(|mut client| match client.write_all(line.as_bytes()) {
+                Ok(()) => true,
+                Err(e) => {
+                    debug!("report: Dropping client on {:?} ({:?})", path, &e);
+                    false
+                }
+            });
+    }
+}
+
+impl Drop for ReportSock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Serves the sys/sideload metrics of the latest 1s report in the
+// OpenMetrics text format, which Prometheus scrapes natively. The report
+// worker renders each new report and each client is served from its own
+// thread with the last rendered text, so a scrape never waits for the
+// report lock or another client.
+#[cfg(feature = "metrics")]
+struct MetricsServer {
+    text: Arc<Mutex<String>>,
+}
+
+#[cfg(feature = "metrics")]
+impl MetricsServer {
+    const CONTENT_TYPE: &'static str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+    fn new(addr: &str, port: u16) -> Result<Self> {
+        let listener = TcpListener::bind((addr, port))?;
+        info!("report: Serving metrics on {}:{}", addr, port);
+        let text = Arc::new(Mutex::new("# EOF\n".to_string()));
+        let text_copy = text.clone();
+        spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let text = text_copy.clone();
+                        spawn(move || {
+                            if let Err(e) = Self::serve(stream, &text) {
+                                debug!("report: Failed to serve metrics ({:?})", &e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("report: Failed to accept metrics client ({:?})", &e),
+                }
+            }
+        });
+        Ok(Self { text })
+    }
+
+    fn serve(mut stream: TcpStream, text: &Mutex<String>) -> Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+        // Consume the headers too, closing with unread input resets the
+        // connection before the client sees the response.
+        let mut reader = BufReader::new(&stream);
+        let mut req = String::new();
+        reader.read_line(&mut req)?;
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 2 {
+            line.clear();
+        }
+        let (status, ctype, body) = match req.split_whitespace().collect::<Vec<_>>()[..] {
+            ["GET", "/metrics", _] => ("200 OK", Self::CONTENT_TYPE, text.lock().unwrap().clone()),
+            _ => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            ctype,
+            body.len(),
+            body
+        )?;
+        Ok(())
+    }
+
+    fn update(&self, report: &Report) {
+        match openmetrics::format_report(report, &BTreeMap::new()) {
+            Ok(v) => *self.text.lock().unwrap() = v,
+            Err(e) => warn!("report: Failed to format metrics ({:?})", &e),
+        }
     }
 }
 
@@ -463,6 +674,9 @@ struct ReportWorker {
     term_rx: Receiver<()>,
     report_file: ReportFile,
     report_file_1min: ReportFile,
+    report_sock: Option<ReportSock>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<MetricsServer>,
     iolat: IoLatReport,
     iocost_devnr: (u32, u32),
 }
@@ -488,6 +702,15 @@ impl ReportWorker {
                 &cfg.report_1min_d_path,
                 cfg.scr_devnr,
             ),
+            report_sock: match cfg.report_sock_path.as_ref() {
+                Some(path) => Some(ReportSock::new(path)?),
+                None => None,
+            },
+            #[cfg(feature = "metrics")]
+            metrics: match cfg.metrics_port {
+                Some(port) => Some(MetricsServer::new(&cfg.metrics_addr, port)?),
+                None => None,
+            },
 
             iolat: Default::default(),
             iocost_devnr: cfg.scr_devnr,
@@ -521,6 +744,7 @@ impl ReportWorker {
         let iocost = self.read_iocost()?;
 
         let mut runner = self.runner.data.lock().unwrap();
+        runner.side_runner.refresh_scratch_usage();
 
         let bench_hashd = match runner.bench_hashd.as_mut() {
             Some(svc) => super::svc_refresh_and_report(&mut svc.unit)?,
@@ -542,6 +766,8 @@ impl ReportWorker {
         Ok(Report {
             timestamp: DateTime::from(now),
             seq: super::instance_seq(),
+            cmd_seq: runner.applied_cmd_seq,
+            clock_jump: None,
             state: runner.state,
             resctl,
             oomd: runner.sobjs.oomd.report()?,
@@ -551,6 +777,12 @@ impl ReportWorker {
             hashd: runner.hashd_set.report(expiration)?,
             sysloads: runner.side_runner.report_sysloads()?,
             sideloads: runner.side_runner.report_sideloads()?,
+            swaps: runner.side_runner.report_swaps(),
+            killed: runner.side_runner.report_killed(),
+            scr_budget: runner.side_runner.report_scr_budget(),
+            canary: runner.canary.report(),
+            balloon: runner.balloon.report()?,
+            io_lat_prot: slices::io_lat_prot_report(&runner.cfg),
             usages: BTreeMap::new(),
             iolat: self.iolat.clone(),
             iocost,
@@ -611,6 +843,8 @@ impl ReportWorker {
 
         drop(runner);
         let mut sleep_dur = Duration::from_secs(0);
+        let mut clock = ClockJumpDetector::new();
+        let mut clock_jump: Option<f64> = None;
 
         'outer: loop {
             select! {
@@ -637,6 +871,14 @@ impl ReportWorker {
                 recv(channel::after(sleep_dur)) -> _ => (),
             }
 
+            // The schedule is in wall clock time. If it jumped, report right
+            // away instead of sleeping until the old schedule catches up.
+            if let Some(jump) = clock.check() {
+                warn!("report: Wall clock jumped by {:.3}s", jump);
+                *clock_jump.get_or_insert(0.0) += jump;
+                next_at = unix_now();
+            }
+
             let sleep_till = UNIX_EPOCH + Duration::from_secs(next_at) + Duration::from_millis(500);
             match sleep_till.duration_since(SystemTime::now()) {
                 Ok(v) => {
@@ -652,15 +894,28 @@ impl ReportWorker {
 
             // generate base
             let base_report = match self.base_report() {
-                Ok(v) => v,
+                Ok(v) => Report {
+                    clock_jump: clock_jump.take(),
+                    ..v
+                },
                 Err(e) => {
                     error!("report: Failed to generate base report ({:?})", &e);
                     continue;
                 }
             };
 
+            #[cfg(feature = "metrics")]
+            if let Some(report) = self.report_file.tick(&base_report, now) {
+                if let Some(metrics) = self.metrics.as_ref() {
+                    metrics.update(&report);
+                }
+            }
+            #[cfg(not(feature = "metrics"))]
             self.report_file.tick(&base_report, now);
             self.report_file_1min.tick(&base_report, now);
+            if let Some(sock) = self.report_sock.as_mut() {
+                sock.send(&base_report);
+            }
         }
 
         drop(iolat_rx);
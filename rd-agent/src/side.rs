@@ -1,21 +1,35 @@
 // Copyright (c) Facebook, Inc. and its affiliates.
 use super::{prepare_bin_file, Config};
 use anyhow::{anyhow, bail, Result};
+use chrono::prelude::*;
+use glob::glob;
 use lazy_static::lazy_static;
 use libc;
 use log::{debug, error, info, warn};
+use proc_mounts::MountList;
 use regex;
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashSet};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::ffi::CString;
+use std::fmt;
 use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path};
 use std::process::Command;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::spawn;
 use std::time::{Duration, Instant};
 use util::*;
 
+use super::report::{read_cgroup_flat_keyed_file, read_cgroup_nested_keyed_file, read_stalls};
 use rd_agent_intf::{
-    BenchKnobs, SideloadDefs, SideloadReport, SideloadSpec, Slice, SysReq, SysloadReport,
-    SIDELOAD_SVC_PREFIX, SYSLOAD_SVC_PREFIX,
+    ApplyTarget, BalloonReport, BenchKnobs, DepKind, IoMax, MissingDep, OomKillReport,
+    PrepareHistory, PrepareProgress, PrepareRun, ScrBudgetReport, SideloadDefs, SideloadReport,
+    SideloadSpec, Slice, SvcStateReport, SwapReport, SysReq, SysloadReport, Tier, WorkloadSnapshot,
+    WorkloadSnapshotEntry, WorkloadUsageReport, SIDELOAD_SVC_PREFIX, SYSLOAD_SVC_PREFIX,
 };
 
 fn sysload_svc_name(name: &str) -> String {
@@ -26,13 +40,135 @@ fn sideload_svc_name(name: &str) -> String {
     format!("{}{}.service", SIDELOAD_SVC_PREFIX, name)
 }
 
+// systemd rejects unit names longer than UNIT_NAME_MAX (255) characters.
+const UNIT_NAME_MAX: usize = 255;
+
+/// Verify that the unit names for `name` fit in UNIT_NAME_MAX. Both the
+/// sysload and sideload unit names are checked so that a valid name works
+/// as either.
+fn verify_unit_name_len(name: &str) -> Result<()> {
+    for unit in [sysload_svc_name(name), sideload_svc_name(name)].iter() {
+        if unit.len() > UNIT_NAME_MAX {
+            bail!(
+                "unit name {:?} is {} characters long, max {}",
+                unit,
+                unit.len(),
+                UNIT_NAME_MAX
+            );
+        }
+    }
+    Ok(())
+}
+
+fn sysload_cgrp(name: &str) -> String {
+    format!("{}/{}", Slice::Sys.cgrp(), sysload_svc_name(name))
+}
+
+fn sideload_cgrp(name: &str) -> String {
+    format!("{}/{}", Slice::Side.cgrp(), sideload_svc_name(name))
+}
+
+fn write_cgroup_freeze(cgrp: &str, frozen: bool) -> Result<()> {
+    write_one_line(
+        format!("{}/cgroup.freeze", cgrp),
+        if frozen { "1" } else { "0" },
+    )
+}
+
+/// Source of the current time for sys/sideload management. Tests can
+/// substitute a manually advanced clock to exercise timeouts and runtimes.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn local_now(&self) -> DateTime<Local>;
+}
+
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn local_now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
 lazy_static! {
     static ref SIDE_NAME_RE: regex::Regex = regex::Regex::new("^[a-zA-Z0-9_-]+$").unwrap();
 }
 
-const LINUX_TAR_XZ_URL: &str = "https://cdn.kernel.org/pub/linux/kernel/v5.x/linux-5.8.11.tar.xz";
+/// Default mirrors of the linux tarball, tried in order.
+pub const LINUX_TAR_XZ_MIRRORS: &[&str] = &[
+    "https://cdn.kernel.org/pub/linux/kernel/v5.x/linux-5.8.11.tar.xz",
+    "https://mirrors.edge.kernel.org/pub/linux/kernel/v5.x/linux-5.8.11.tar.xz",
+    "https://mirrors.kernel.org/pub/linux/kernel/v5.x/linux-5.8.11.tar.xz",
+];
+// SHA-256's of the decompressed tarballs keyed by their names without the
+// .xz suffix, so that any mirror or --linux-tar-url serving a listed version
+// is checked. kernel.org only publishes digests of the compressed archives,
+// so entries have to be taken from verified downloads. Tarballs which aren't
+// listed need --linux-tar-sha256 or are used unverified with a warning.
+const LINUX_TAR_SHA256S: &[(&str, &str)] = &[];
+const TAR_BLOCK_SIZE: usize = 512;
+// Compressed and uncompressed tarballs are ~110M and ~900M respectively.
+// Anything below these can't be a kernel source tarball.
+const LINUX_TAR_XZ_MIN_SIZE: u64 = 1 << 20;
+const LINUX_TAR_MIN_SIZE: u64 = 16 << 20;
+// Download attempts, the wait before each retry doubles from the backoff.
+const LINUX_TAR_DL_ATTEMPTS: u32 = 3;
+const LINUX_TAR_DL_BACKOFF: Duration = Duration::from_secs(5);
+const DL_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const DL_READ_TIMEOUT: Duration = Duration::from_secs(60);
+// Download progress is logged every this many percent, or bytes if the
+// size isn't known.
+const DL_PROGRESS_PCT: u64 = 10;
+const DL_PROGRESS_BYTES: u64 = 64 << 20;
+const CLEANUP_TIMEOUT: Duration = Duration::from_secs(10);
+const PERF_STAT_EVENTS: &str = "cycles,instructions,cache-misses";
+const PERF_STAT_FILE: &str = "perf-stat.txt";
+const STDOUT_LOG_FILE: &str = "stdout.log";
+const STDERR_LOG_FILE: &str = "stderr.log";
+const TAIL_LOG_MAX_BYTES: u64 = 1 << 20;
+const FAILURE_LOG_LINES: usize = 10;
+const CLEAN_ENV_BIN: &str = "/usr/bin/env";
+const CLEAN_ENV_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+const IO_MAX_HIT_RATIO: f64 = 0.95;
+const SCR_FREE_CACHE_DUR: Duration = Duration::from_secs(1);
+const KILL_SIGNAL_GRACE: Duration = Duration::from_secs(10);
+const SCR_USAGE_CACHE_DUR: Duration = Duration::from_secs(10);
+
+// Set by SideRunner::envs(), can't be overridden through extra_envs.
+const BUILTIN_ENV_KEYS: &[&str] = &[
+    "NR_CPUS",
+    "TOTAL_MEMORY",
+    "TOTAL_SWAP",
+    "ROTATIONAL_SWAP",
+    "IO_DEV",
+    "IO_DEVNR",
+    "IO_RBPS",
+    "IO_WBPS",
+    "STATUS_FILE",
+    "CGROUP_PATH",
+    "LINUX_TAR",
+    "LINUX_SRC",
+    "KCONFIG",
+    "READ_BOMB_BURST",
+    "IO_RW_MIX",
+    "IO_BS",
+    "IO_DEPTH",
+    "IO_SIZE",
+    "NET_HOST",
+    "NET_PORT",
+    "NET_RATE",
+    "NET_CONNS",
+];
+const SWAP_HEALTHY_DUR: Duration = Duration::from_secs(3);
+const SWAP_START_TIMEOUT: Duration = Duration::from_secs(30);
+const OOM_KILL_HISTORY: usize = 8;
+const OOM_LOG_INTV_SECS: i64 = 10;
 
-const SIDE_BINS: [(&str, &[u8]); 5] = [
+const SIDE_BINS: [(&str, &[u8]); 7] = [
     ("build-linux.sh", include_bytes!("side/build-linux.sh")),
     ("memory-growth.py", include_bytes!("side/memory-growth.py")),
     (
@@ -41,429 +177,6900 @@ const SIDE_BINS: [(&str, &[u8]); 5] = [
     ),
     ("read-bomb.py", include_bytes!("side/read-bomb.py")),
     ("burn-cpus.sh", include_bytes!("side/burn-cpus.sh")),
+    ("io-load.sh", include_bytes!("side/io-load.sh")),
+    ("net-load.py", include_bytes!("side/net-load.py")),
 ];
 
-fn prepare_side_bins(cfg: &Config) -> Result<()> {
+/// Names and sha256 digests of the side binaries embedded in this build.
+pub fn side_bins_info() -> Vec<(&'static str, String)> {
+    SIDE_BINS
+        .iter()
+        .map(|(name, body)| (*name, format!("{:x}", Sha256::digest(body))))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinUpdate {
+    Unchanged,
+    Installed,
+    Replaced,
+}
+
+// Write `body` to `path` unless the file already has the same content.
+fn update_bin_file(path: &str, body: &[u8]) -> Result<BinUpdate> {
+    let res = match fs::read(path) {
+        Ok(cur) if cur == body => return Ok(BinUpdate::Unchanged),
+        Ok(_) => {
+            fs::remove_file(path)?;
+            BinUpdate::Replaced
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => BinUpdate::Installed,
+        Err(e) => return Err(e.into()),
+    };
+    prepare_bin_file(path, body)?;
+    Ok(res)
+}
+
+/// Install the embedded side binaries into the side bin dir. The ones which
+/// are already up to date are left alone. Files which differ, including
+/// locally modified ones, are replaced with the embedded copy.
+pub fn prepare_side_bins(cfg: &Config) -> Result<()> {
     for (name, body) in &SIDE_BINS {
-        prepare_bin_file(&format!("{}/{}", &cfg.side_bin_path, name), body)?;
+        let path = format!("{}/{}", &cfg.side_bin_path, name);
+        match update_bin_file(&path, body)? {
+            BinUpdate::Unchanged => (),
+            BinUpdate::Installed => debug!("side: Installed {:?}", &path),
+            BinUpdate::Replaced => info!(
+                "side: Replaced {:?} which differed from the embedded copy",
+                &path
+            ),
+        }
     }
     Ok(())
 }
 
-fn verify_linux_tar(path: &str) -> bool {
-    match fs::metadata(path) {
-        Ok(md) => md.len() > 0,
-        Err(_) => false,
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LinuxTarSource {
+    Download,
+    Local,
+    Cache,
 }
 
-fn prepare_linux_tar(cfg: &Config) -> Result<()> {
-    let tar_path = cfg.scr_path.clone() + "/linux.tar";
+/// Which steps `prepare_selected` runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrepareSteps {
+    pub side_bins: bool,
+    pub linux_tar: bool,
+}
 
-    if let Some(path) = cfg.side_linux_tar_path.as_ref() {
-        if !verify_linux_tar(path) {
-            bail!("{:?} is not a valid tarball", path);
-        }
-        info!("side: Copying ${:?} to ${:?}", path, &tar_path);
-        fs::copy(path, &tar_path)?;
-        return Ok(());
-    }
+impl PrepareSteps {
+    pub const ALL: Self = Self {
+        side_bins: true,
+        linux_tar: true,
+    };
+}
 
-    if verify_linux_tar(&tar_path) {
-        debug!("using existing {:?}", &tar_path);
-        return Ok(());
-    }
+/// How much of the properties applied to sys/sideloads to log. Summary
+/// logs one line per workload, full one line per property.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PropAudit {
+    Off,
+    Summary,
+    Full,
+}
 
-    info!("side: Downloading linux tarball, you can specify local file with --linux-tar");
-    let tmp_path = cfg.scr_path.clone() + "/linux.tar.tmp";
-    let xz_path = cfg.scr_path.clone() + "/linux.tar.tmp.xz";
-    if !Command::new("wget")
-        .arg("--progress=dot:mega")
-        .arg(LINUX_TAR_XZ_URL)
-        .arg("-O")
-        .arg(&xz_path)
-        .status()
-        .map_err(|e| anyhow!("failed to execute wget ({})", &e))?
-        .success()
-    {
-        bail!("failed to download linux tarball");
+fn prop_str(prop: &systemd::Prop) -> String {
+    match prop {
+        systemd::Prop::I32(v) => format!("{}", v),
+        systemd::Prop::U32(v) => format!("{}", v),
+        systemd::Prop::U64(v) => format!("{}", v),
+        systemd::Prop::Bool(v) => format!("{}", v),
+        systemd::Prop::String(v) => format!("{:?}", v),
+        systemd::Prop::Bytes(v) => format!("{:?}", v),
     }
+}
 
-    info!("side: Decompressing linux tarball");
-    if !Command::new("xz")
-        .arg("--decompress")
-        .arg(&xz_path)
-        .status()?
-        .success()
-    {
-        bail!("failed to decompress linux tarball");
+fn audit_props(level: PropAudit, name: &str, what: &str, props: &[(String, String)]) {
+    if props.is_empty() {
+        return;
     }
+    match level {
+        PropAudit::Off => (),
+        PropAudit::Summary => {
+            let kvs: Vec<String> = props.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            info!("audit: {:?} {} {}", name, what, kvs.join(" "));
+        }
+        PropAudit::Full => {
+            for (k, v) in props.iter() {
+                info!("audit: {:?} {} {}={}", name, what, k, v);
+            }
+        }
+    }
+}
 
-    fs::rename(&tmp_path, &tar_path)?;
+fn audit_svc_props(level: PropAudit, name: &str, svc: &TransientService) {
+    let props: BTreeMap<String, String> = svc
+        .extra_props
+        .iter()
+        .map(|(k, v)| (k.clone(), prop_str(v)))
+        .collect();
+    let props: Vec<(String, String)> = props.into_iter().collect();
+    audit_props(level, name, "systemd", &props);
+}
 
-    Ok(())
+fn audit_job_props(level: PropAudit, name: &str, job: &SideloaderJob) {
+    let mut props = vec![(
+        "frozen_expiration".to_string(),
+        format!("{}", job.frozen_expiration),
+    )];
+    if let Some(weight) = job.cpu_weight {
+        props.push(("cpu_weight".to_string(), format!("{}", weight)));
+    }
+    audit_props(level, name, "sideloader", &props);
 }
 
-pub fn prepare_sides(cfg: &Config) -> Result<()> {
-    prepare_side_bins(cfg)?;
-    prepare_linux_tar(cfg)
+// Property value in unit file syntax. Bytes are only used for CPU masks.
+fn unit_prop_str(prop: &systemd::Prop) -> String {
+    match prop {
+        systemd::Prop::String(v) => v.clone(),
+        systemd::Prop::Bytes(v) => {
+            let cpus: BTreeSet<u32> = (0..v.len() as u32 * 8)
+                .filter(|cpu| v[*cpu as usize / 8] & (1 << (cpu % 8)) != 0)
+                .collect();
+            cpu_list_str(&cpus)
+        }
+        v => prop_str(v),
+    }
 }
 
-pub fn startup_checks(sr_failed: &mut HashSet<SysReq>) {
-    for bin in &["gcc", "ld", "make", "bison", "flex", "pkg-config", "stress"] {
-        if find_bin(bin, Option::<&str>::None).is_none() {
-            warn!("side: binary dependency {:?} is missing", bin);
-            sr_failed.insert(SysReq::Dependencies);
+fn resctl_unit_props(resctl: &systemd::UnitResCtl) -> BTreeMap<String, String> {
+    let mut props = BTreeMap::new();
+    for (key, val) in [
+        ("CPUWeight", resctl.cpu_weight),
+        ("IOWeight", resctl.io_weight),
+        ("MemoryMin", resctl.mem_min),
+        ("MemoryLow", resctl.mem_low),
+        ("MemoryHigh", resctl.mem_high),
+        ("MemoryMax", resctl.mem_max),
+    ]
+    .iter()
+    {
+        if let Some(v) = val {
+            props.insert(key.to_string(), format!("{}", v));
         }
     }
+    props
+}
 
-    for lib in &["libssl", "libelf"] {
-        let st = match Command::new("pkg-config").arg("--exists").arg(lib).status() {
-            Ok(v) => v,
-            Err(e) => {
-                warn!("side: pkg-config failed ({:?})", &e);
-                sr_failed.insert(SysReq::Dependencies);
-                continue;
+fn unit_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && !arg
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '\\' || c == '$')
+    {
+        return arg.into();
+    }
+    let mut buf = String::from("\"");
+    for c in arg.chars() {
+        match c {
+            '"' | '\\' => {
+                buf.push('\\');
+                buf.push(c);
             }
-        };
-
-        if !st.success() {
-            warn!("side: devel library dependency {:?} is missing", lib);
-            sr_failed.insert(SysReq::Dependencies);
+            '$' => buf.push_str("$$"),
+            c => buf.push(c),
         }
     }
+    buf.push('"');
+    buf
 }
 
-fn really_remove_dir_all(path: &str) {
-    let started_at = Instant::now();
+// Static unit file equivalent of a transient workload. Cgroup knobs which
+// systemd doesn't know about are listed as comments.
+fn format_unit_file(
+    desc: &str,
+    args: &[String],
+    envs: &[String],
+    props: &BTreeMap<String, String>,
+    knobs: &[(&'static str, String)],
+) -> String {
+    let mut buf = String::from("# Generated by rd-agent for inspection, not meant to be loaded\n");
+    for (file, val) in knobs.iter() {
+        buf += &format!("# cgroup {}: {}\n", file, val);
+    }
+    buf += &format!("\n[Unit]\nDescription={}\n\n[Service]\n", desc);
+    let args: Vec<String> = args.iter().map(|x| unit_quote(x)).collect();
+    buf += &format!("ExecStart={}\n", args.join(" "));
+    for env in envs.iter() {
+        buf += &format!("Environment={}\n", unit_quote(env));
+    }
+    for (key, val) in props.iter() {
+        buf += &format!("{}={}\n", key, val);
+    }
+    buf
+}
 
-    loop {
-        let e = match fs::remove_dir_all(path) {
-            Ok(()) => break,
-            Err(e) => e,
-        };
+/// What the build-linux sideload is going to build.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinuxTarInfo {
+    pub path: String,
+    pub source: LinuxTarSource,
+    pub version: Option<String>,
+    pub size: u64,
+    pub sha256: String,
+    /// Versions of the external tools which were run to fetch the tarball.
+    pub tool_versions: BTreeMap<String, String>,
+}
 
-        match e.raw_os_error() {
-            Some(libc::ENOENT) => {
-                break;
-            }
-            Some(libc::ENOTEMPTY) => (),
-            _ => {
-                error!("side: Failed to remove {:?} ({:?})", path, &e);
-                break;
-            }
-        }
+impl LinuxTarInfo {
+    fn new(path: &str, source: LinuxTarSource) -> io::Result<Self> {
+        Ok(Self {
+            path: path.into(),
+            source,
+            version: match Self::read_version(path) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("side: Failed to read version from {:?} ({:?})", path, &e);
+                    None
+                }
+            },
+            size: fs::metadata(path)?.len(),
+            sha256: Self::sha256(path)?,
+            tool_versions: BTreeMap::new(),
+        })
+    }
 
-        if Instant::now().duration_since(started_at) > Duration::from_secs(10) {
-            error!("side: Failed to remove {:?} after trying for 10s", path);
-            break;
+    // Linux source tarballs contain a single top-level "linux-VERSION/"
+    // directory. Skip pax headers and extract the version from the name of
+    // the first real entry.
+    fn read_version(path: &str) -> Result<Option<String>> {
+        let mut f = fs::File::open(path)?;
+        let mut hdr = [0u8; TAR_BLOCK_SIZE];
+
+        loop {
+            f.read_exact(&mut hdr)?;
+
+            let size_str = String::from_utf8_lossy(&hdr[124..136]);
+            let size_str = size_str.trim_matches(|c: char| c == '\0' || c == ' ');
+            let size = u64::from_str_radix(size_str, 8).unwrap_or(0);
+
+            match hdr[156] {
+                b'g' | b'x' => {
+                    let skip = (size as usize).div_ceil(TAR_BLOCK_SIZE);
+                    for _ in 0..skip {
+                        f.read_exact(&mut hdr)?;
+                    }
+                }
+                _ => {
+                    let name_len = hdr[..100].iter().position(|c| *c == 0).unwrap_or(100);
+                    let name = String::from_utf8_lossy(&hdr[..name_len]);
+                    let top = name.split('/').next().unwrap_or("");
+                    return Ok(top.strip_prefix("linux-").map(|v| v.to_string()));
+                }
+            }
         }
+    }
 
-        debug!("side: {:?} not empty, trying to remove again", path);
+    fn sha256(path: &str) -> io::Result<String> {
+        let mut f = fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        io::copy(&mut f, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
     }
 }
 
-pub struct Sysload {
-    scr_path: String,
-    svc: TransientService,
-}
+// Check the ustar magic and the header checksum so that e.g. an HTML error
+// page saved as linux.tar doesn't get accepted.
+fn check_tar_header(hdr: &[u8]) -> Result<()> {
+    if hdr.len() < TAR_BLOCK_SIZE {
+        bail!("too short for a tar header");
+    }
+    if &hdr[257..262] != b"ustar" {
+        bail!("ustar magic missing");
+    }
 
-impl Drop for Sysload {
-    fn drop(&mut self) {
-        really_remove_dir_all(&self.scr_path);
+    let cksum_str = String::from_utf8_lossy(&hdr[148..156]);
+    let cksum_str = cksum_str.trim_matches(|c: char| c == '\0' || c == ' ');
+    let cksum = match u64::from_str_radix(cksum_str, 8) {
+        Ok(v) => v,
+        Err(_) => bail!("invalid header checksum {:?}", cksum_str),
+    };
+
+    // The checksum is calculated with the checksum field filled with spaces.
+    let sum: u64 = hdr[..TAR_BLOCK_SIZE]
+        .iter()
+        .enumerate()
+        .map(|(i, c)| if (148..156).contains(&i) { b' ' } else { *c } as u64)
+        .sum();
+    if sum != cksum {
+        bail!("header checksum mismatch ({} != {})", sum, cksum);
     }
+    Ok(())
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct SideloaderJob {
-    id: String,
-    args: Vec<String>,
-    envs: Vec<String>,
-    frozen_expiration: u32,
-    working_dir: String,
+fn check_output_size(path: &str, min_size: u64) -> Result<u64> {
+    let size = match fs::metadata(path) {
+        Ok(v) => v.len(),
+        Err(e) => bail!("{:?} is missing ({})", path, &e),
+    };
+    if size == 0 {
+        bail!("{:?} is empty", path);
+    }
+    if size < min_size {
+        bail!(
+            "{:?} is implausibly small ({} bytes, expected at least {})",
+            path,
+            size,
+            min_size
+        );
+    }
+    Ok(size)
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
-struct SideloaderJobs {
-    sideloader_jobs: Vec<SideloaderJob>,
+// Extract the version from the first line of "BIN --version" output, e.g.
+// "GNU Wget 1.20.3 built on linux-gnu." or "xz (XZ Utils) 5.2.4".
+fn parse_tool_version(output: &str) -> Option<String> {
+    output
+        .lines()
+        .next()?
+        .split_whitespace()
+        .find(|tok| tok.starts_with(|c: char| c.is_ascii_digit()))
+        .map(|tok| tok.trim_end_matches(|c: char| !c.is_ascii_alphanumeric()))
+        .map(|tok| tok.to_string())
 }
 
-impl JsonSave for SideloaderJobs {}
+fn tool_version(bin: &str) -> String {
+    let output = match Command::new(bin).arg("--version").output() {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("side: Failed to run {:?} --version ({})", bin, &e);
+            return "UNKNOWN".into();
+        }
+    };
+    parse_tool_version(&String::from_utf8_lossy(&output.stdout)).unwrap_or_else(|| "UNKNOWN".into())
+}
 
-pub struct Sideload {
-    name: String,
-    scr_path: String,
-    job_path: String,
-    unit: systemd::Unit,
+/// Why a download failed. Name resolution, TLS and connection failures
+/// are told apart from the server refusing the request, e.g. with a 404
+/// from a rotted mirror.
+#[derive(Debug)]
+pub enum DownloadError {
+    Dns(String),
+    Tls(String),
+    Connect(String),
+    Http(u16, String),
+    Network(String),
+    Unusable(String),
+    Io(io::Error),
 }
 
-impl Drop for Sideload {
-    fn drop(&mut self) {
-        match fs::remove_file(&self.job_path) {
-            Ok(()) => (),
-            Err(e) => error!("side: Failed to remove {:?} ({:?})", &self.job_path, &e),
+impl DownloadError {
+    // Whether trying the same URL again may help.
+    fn retryable(&self) -> bool {
+        match self {
+            Self::Dns(_) | Self::Connect(_) | Self::Network(_) => true,
+            Self::Http(status, _) => *status >= 500 || *status == 408 || *status == 429,
+            _ => false,
         }
-        if let Err(e) = self.unit.stop_and_reset() {
-            error!("side: Failed to stop {:?} ({:?})", self.name, &e);
+    }
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Dns(msg) => write!(f, "DNS lookup failed ({})", msg),
+            Self::Tls(msg) => write!(f, "TLS handshake failed ({})", msg),
+            Self::Connect(msg) => write!(f, "connection failed ({})", msg),
+            Self::Http(status, reason) => write!(f, "HTTP {} {}", status, reason),
+            Self::Network(msg) => write!(f, "transfer failed ({})", msg),
+            Self::Unusable(msg) => write!(f, "download is unusable ({})", msg),
+            Self::Io(e) => write!(f, "I/O error ({})", e),
         }
-        really_remove_dir_all(&self.scr_path);
     }
 }
 
-pub struct SideRunner {
-    cfg: Arc<Config>,
-    sysloads: BTreeMap<String, Sysload>,
-    sideloads: BTreeMap<String, Sideload>,
+impl std::error::Error for DownloadError {}
+
+impl From<io::Error> for DownloadError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
 }
 
-impl SideRunner {
-    pub fn new(cfg: Arc<Config>) -> Self {
-        Self {
-            cfg,
-            sysloads: BTreeMap::new(),
-            sideloads: BTreeMap::new(),
+impl From<ureq::Error> for DownloadError {
+    fn from(e: ureq::Error) -> Self {
+        match e {
+            ureq::Error::Status(status, resp) => Self::Http(status, resp.status_text().into()),
+            ureq::Error::Transport(t) => {
+                let msg = format!("{}", &t);
+                match t.kind() {
+                    ureq::ErrorKind::Dns => Self::Dns(msg),
+                    // ureq reports TLS handshake failures as connection
+                    // failures and only tells them apart in the message.
+                    ureq::ErrorKind::ConnectionFailed
+                        if t.message().is_some_and(|m| m.starts_with("tls")) =>
+                    {
+                        Self::Tls(msg)
+                    }
+                    ureq::ErrorKind::ConnectionFailed => Self::Connect(msg),
+                    _ => Self::Network(msg),
+                }
+            }
         }
     }
+}
 
-    pub fn stop(&mut self) {
-        self.sysloads.clear();
+// Percentage of `done` out of `total` if it crossed a DL_PROGRESS_PCT step
+// since `prev`.
+fn dl_progress_step(prev: u64, done: u64, total: u64) -> Option<u64> {
+    let pct = |bytes: u64| bytes.min(total) * 100 / total.max(1);
+    if pct(done) / DL_PROGRESS_PCT > pct(prev) / DL_PROGRESS_PCT {
+        Some(pct(done))
+    } else {
+        None
     }
+}
 
-    fn verify_and_lookup_svc(
-        &self,
-        name: &str,
-        id: &String,
-        defs: &SideloadDefs,
-    ) -> Result<SideloadSpec> {
-        if !SIDE_NAME_RE.is_match(name) {
-            bail!(
-                "Invalid sideload name {:?}, should only contain alnums, - and _",
-                name
-            );
+// Fetch `url` appending to `dest`. If `dest` already has a partial
+// download, only the rest is requested. A server which doesn't do ranges
+// makes it start over.
+fn download_once(
+    agent: &ureq::Agent,
+    url: &str,
+    dest: &str,
+    progress: &mut dyn FnMut(PrepareProgress),
+) -> Result<(), DownloadError> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dest)?;
+    let mut done = file.metadata()?.len();
+
+    let mut req = agent.get(url);
+    if done > 0 {
+        req = req.set("Range", &format!("bytes={}-", done));
+    }
+    let resp = match req.call() {
+        Ok(v) => v,
+        // The partial is already complete.
+        Err(ureq::Error::Status(416, _)) if done > 0 => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if done > 0 {
+        if resp.status() == 206 {
+            info!("side: Resuming download at {}", format_size(done));
+        } else {
+            info!("side: Server doesn't support resuming, starting over");
+            file.set_len(0)?;
+            done = 0;
         }
+    }
+    let total = resp
+        .header("Content-Length")
+        .and_then(|len| len.parse::<u64>().ok())
+        .map(|len| len + done);
 
-        let mut spec = match defs.defs.get(id) {
-            Some(v) => v.clone(),
-            None => bail!("unknown sideload ID {:?}", id),
+    let mut reader = resp.into_reader();
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(v) => v,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(DownloadError::Network(format!("{}", &e))),
         };
-
-        if spec.args.len() < 1 {
-            bail!("{:?} has no command", id);
+        file.write_all(&buf[..len])?;
+        let prev = done;
+        done += len as u64;
+        match total {
+            Some(total) => {
+                if let Some(pct) = dl_progress_step(prev, done, total) {
+                    info!(
+                        "side: Downloaded {}% ({}/{})",
+                        pct,
+                        format_size(done),
+                        format_size(total)
+                    );
+                    progress(PrepareProgress::Downloaded {
+                        done,
+                        total: Some(total),
+                    });
+                }
+            }
+            None if done / DL_PROGRESS_BYTES > prev / DL_PROGRESS_BYTES => {
+                info!("side: Downloaded {}", format_size(done));
+                progress(PrepareProgress::Downloaded { done, total: None });
+            }
+            None => (),
         }
+    }
 
-        spec.args[0] = match find_bin(&spec.args[0], Some(&self.cfg.side_bin_path)) {
-            Some(v) => v.to_str().unwrap().to_string(),
-            None => bail!("failed to resolve binary {:?}", spec.args[0]),
+    match total {
+        Some(total) if done < total => Err(DownloadError::Network(format!(
+            "connection closed after {} of {} bytes",
+            done, total
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Download `url` to `dest`, retrying transient failures with backoff.
+/// Each attempt continues from the partial `dest` left by an earlier
+/// attempt or run, and the partial is kept on failure so that the next
+/// run can resume. `progress` gets `PrepareProgress::Downloaded` as the
+/// download is logged.
+pub fn download_file(
+    url: &str,
+    dest: &str,
+    progress: &mut dyn FnMut(PrepareProgress),
+) -> Result<(), DownloadError> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(DL_CONNECT_TIMEOUT)
+        .timeout_read(DL_READ_TIMEOUT)
+        .build();
+    let mut backoff = LINUX_TAR_DL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let e = match download_once(&agent, url, dest, progress) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
         };
+        if !e.retryable() {
+            return Err(e);
+        }
+        if attempt == LINUX_TAR_DL_ATTEMPTS {
+            error!(
+                "side: Downloading {:?} failed after {} attempts",
+                url, LINUX_TAR_DL_ATTEMPTS
+            );
+            return Err(e);
+        }
+        attempt += 1;
+        warn!(
+            "side: Download failed ({}), retrying in {}s (attempt {}/{})",
+            &e,
+            backoff.as_secs(),
+            attempt,
+            LINUX_TAR_DL_ATTEMPTS
+        );
+        std::thread::sleep(backoff);
+        backoff *= 2;
+    }
+}
 
-        Ok(spec)
+/// Why `prepare_linux_tar` failed, so that e.g. a network failure which
+/// may be retried can be told apart from a corrupt archive.
+#[derive(Debug)]
+pub enum TarPrepError {
+    Download(DownloadError),
+    Decompress(String),
+    Checksum { expected: String, actual: String },
+    InvalidArchive(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for TarPrepError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Download(msg) => write!(f, "download failed ({})", msg),
+            Self::Decompress(msg) => write!(f, "decompression failed ({})", msg),
+            Self::Checksum { expected, actual } => write!(
+                f,
+                "SHA-256 mismatch (expected {}, got {})",
+                expected, actual
+            ),
+            Self::InvalidArchive(msg) => write!(f, "invalid tarball ({})", msg),
+            Self::Io(e) => write!(f, "I/O error ({})", e),
+        }
     }
+}
 
-    fn prep_scr_dir(dir: &str, name: &str) -> Result<String> {
-        let scr_path = format!("{}/{}", dir, name);
-        match fs::create_dir_all(&scr_path) {
-            Ok(()) => Ok(scr_path),
-            Err(e) => bail!("failed to create scratch dir for {:?} ({:?})", name, &e),
+impl std::error::Error for TarPrepError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
         }
     }
+}
 
-    fn envs(&self, bench: &BenchKnobs) -> Vec<String> {
-        let cfg = &self.cfg;
+impl From<io::Error> for TarPrepError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
 
-        vec![
-            format!("NR_CPUS={}", *NR_CPUS),
-            format!("TOTAL_MEMORY={}", *TOTAL_MEMORY),
-            format!("TOTAL_SWAP={}", *TOTAL_SWAP),
-            format!("ROTATIONAL_SWAP={}", if *ROTATIONAL_SWAP { 1 } else { 0 }),
-            format!("IO_DEV={}", &cfg.scr_dev),
-            format!("IO_DEVNR={}:{}", cfg.scr_devnr.0, cfg.scr_devnr.1),
-            format!("IO_RBPS={}", bench.iocost.model.rbps),
-            format!("IO_WBPS={}", bench.iocost.model.wbps),
-        ]
+fn verify_linux_tar(path: &str, expected_sha256: Option<&str>) -> Result<(), TarPrepError> {
+    let mut f = fs::File::open(path)?;
+    if f.metadata()?.len() == 0 {
+        return Err(TarPrepError::InvalidArchive("empty file".into()));
+    }
+    let mut hdr = [0u8; TAR_BLOCK_SIZE];
+    if let Err(e) = f.read_exact(&mut hdr) {
+        return Err(TarPrepError::InvalidArchive(format!(
+            "failed to read tar header ({})",
+            &e
+        )));
+    }
+    if let Err(e) = check_tar_header(&hdr) {
+        return Err(TarPrepError::InvalidArchive(format!("{}", &e)));
     }
 
-    pub fn apply_sysloads(
-        &mut self,
-        target: &BTreeMap<String, String>,
-        defs: &SideloadDefs,
-        bench: &BenchKnobs,
-        mut removed: Option<&mut Vec<Sysload>>,
-    ) -> Result<()> {
-        let sysloads = &mut self.sysloads;
+    if let Some(expected) = expected_sha256 {
+        let sha256 = LinuxTarInfo::sha256(path)?;
+        if !sha256.eq_ignore_ascii_case(expected) {
+            return Err(TarPrepError::Checksum {
+                expected: expected.into(),
+                actual: sha256,
+            });
+        }
+    }
+    Ok(())
+}
 
-        let target_keys: HashSet<String> = target.keys().cloned().collect();
-        let active_keys: HashSet<String> = sysloads.keys().cloned().collect();
+// Download the tarball from `url` and decompress it if necessary into
+// `tmp_path`, which is verified on success.
+fn fetch_linux_tar(
+    url: &str,
+    expected_sha256: Option<&str>,
+    tmp_path: &str,
+    tool_versions: &mut BTreeMap<String, String>,
+    progress: &mut dyn FnMut(PrepareProgress),
+) -> Result<(), TarPrepError> {
+    let compressed = url.ends_with(".tar.xz");
+    let xz_path = tmp_path.to_string() + ".xz";
+    let (dl_path, dl_min_size) = if compressed {
+        (xz_path.as_str(), LINUX_TAR_XZ_MIN_SIZE)
+    } else {
+        (tmp_path, LINUX_TAR_MIN_SIZE)
+    };
+    download_file(url, dl_path, progress).map_err(TarPrepError::Download)?;
+    if let Err(e) = check_output_size(dl_path, dl_min_size) {
+        let _ = fs::remove_file(dl_path);
+        return Err(TarPrepError::Download(DownloadError::Unusable(format!(
+            "{}",
+            &e
+        ))));
+    }
 
-        for goner in active_keys.difference(&target_keys) {
-            if let Some(sl) = sysloads.remove(goner) {
-                if let Some(rm) = removed.as_mut() {
-                    rm.push(sl);
-                }
+    if compressed {
+        info!("side: Decompressing linux tarball");
+        progress(PrepareProgress::Decompressing);
+        tool_versions.insert("xz".to_string(), tool_version("xz"));
+        match Command::new("xz")
+            .arg("--decompress")
+            .arg(&xz_path)
+            .status()
+        {
+            Ok(status) if status.success() => (),
+            Ok(status) => return Err(TarPrepError::Decompress(format!("xz {}", &status))),
+            Err(e) => {
+                return Err(TarPrepError::Decompress(format!(
+                    "failed to execute xz ({})",
+                    &e
+                )))
             }
         }
-
+        if let Err(e) = check_output_size(tmp_path, LINUX_TAR_MIN_SIZE) {
+            let _ = fs::remove_file(tmp_path);
+            return Err(TarPrepError::Decompress(format!(
+                "xz succeeded but the decompressed tarball is unusable ({})",
+                &e
+            )));
+        }
+    }
+
+    verify_fetched_tar(tmp_path, expected_sha256)
+}
+
+// "linux-5.8.11.tar" for ".../linux-5.8.11.tar.xz".
+fn linux_tar_name(url: &str) -> &str {
+    let name = url.rsplit('/').next().unwrap_or(url);
+    name.strip_suffix(".xz").unwrap_or(name)
+}
+
+// The digest given with --linux-tar-sha256, or the built-in one for the
+// tarball version at `url`.
+fn expected_linux_tar_sha256<'a>(url: &str, cfg_sha256: Option<&'a str>) -> Option<&'a str> {
+    let name = linux_tar_name(url);
+    cfg_sha256.or_else(|| {
+        LINUX_TAR_SHA256S
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, sha256)| *sha256)
+    })
+}
+
+// Verify a freshly fetched tarball, deleting it if it's bad so that the
+// next attempt downloads it again instead of reusing it.
+fn verify_fetched_tar(path: &str, expected_sha256: Option<&str>) -> Result<(), TarPrepError> {
+    if let Err(e) = verify_linux_tar(path, expected_sha256) {
+        let _ = fs::remove_file(path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn partial_url_path(tmp_path: &str) -> String {
+    tmp_path.to_string() + ".url"
+}
+
+// A partial download can only be resumed from the URL it came from. Discard
+// the partials left by a different URL and remember `url` as the source of
+// the ones to come.
+fn discard_foreign_partial(tmp_path: &str, url: &str) {
+    let url_path = partial_url_path(tmp_path);
+    if read_one_line(&url_path).ok().as_deref() == Some(url) {
+        return;
+    }
+    let _ = fs::remove_file(tmp_path);
+    let _ = fs::remove_file(tmp_path.to_string() + ".xz");
+    if let Err(e) = fs::write(&url_path, url.to_string() + "\n") {
+        warn!("side: Failed to write {:?} ({})", &url_path, &e);
+    }
+}
+
+/// Copy, reuse or download the linux tarball for build-linux.sh.
+pub fn prepare_linux_tar(
+    cfg: &Config,
+    progress: &mut dyn FnMut(PrepareProgress),
+) -> Result<LinuxTarInfo, TarPrepError> {
+    let tar_path = cfg.scr_path.clone() + "/linux.tar";
+
+    if let Some(path) = cfg.side_linux_tar_path.as_ref() {
+        match verify_linux_tar(path, None) {
+            Ok(()) => (),
+            Err(TarPrepError::Io(e)) => return Err(TarPrepError::Io(e)),
+            Err(e) => {
+                return Err(TarPrepError::InvalidArchive(format!(
+                    "{:?} is not a valid tarball ({})",
+                    path, &e
+                )))
+            }
+        }
+        warn!(
+            "side: Integrity of {:?} is not verified, make sure it's a pristine linux tarball",
+            path
+        );
+        info!("side: Copying ${:?} to ${:?}", path, &tar_path);
+        fs::copy(path, &tar_path)?;
+        return Ok(LinuxTarInfo::new(&tar_path, LinuxTarSource::Local)?);
+    }
+
+    match verify_linux_tar(&tar_path, None) {
+        Ok(()) => {
+            debug!("using existing {:?}", &tar_path);
+            return Ok(LinuxTarInfo::new(&tar_path, LinuxTarSource::Cache)?);
+        }
+        Err(e) if Path::new(&tar_path).exists() => {
+            warn!("side: Discarding invalid {:?} ({})", &tar_path, &e);
+        }
+        Err(_) => (),
+    }
+
+    info!("side: Downloading linux tarball, you can specify local file with --linux-tar");
+    progress(PrepareProgress::Downloading);
+    let mut tool_versions = BTreeMap::new();
+    let tmp_path = cfg.scr_path.clone() + "/linux.tar.tmp";
+
+    let mirrors = &cfg.side_linux_tar_mirrors;
+    for (idx, url) in mirrors.iter().enumerate() {
+        info!(
+            "side: Trying mirror {:?} ({}/{})",
+            url,
+            idx + 1,
+            mirrors.len()
+        );
+        discard_foreign_partial(&tmp_path, url);
+        let expected_sha256 = expected_linux_tar_sha256(url, cfg.side_linux_tar_sha256.as_deref());
+        if expected_sha256.is_none() {
+            warn!(
+                "side: No known SHA-256 for {:?}, its integrity won't be verified, see --linux-tar-sha256",
+                linux_tar_name(url)
+            );
+        }
+        match fetch_linux_tar(
+            url,
+            expected_sha256,
+            &tmp_path,
+            &mut tool_versions,
+            progress,
+        ) {
+            Ok(()) => break,
+            Err(e) if idx + 1 == mirrors.len() => {
+                error!("side: All {} mirrors failed", mirrors.len());
+                return Err(e);
+            }
+            Err(e) => warn!("side: Mirror {:?} failed ({})", url, &e),
+        }
+    }
+    fs::rename(&tmp_path, &tar_path)?;
+    let _ = fs::remove_file(partial_url_path(&tmp_path));
+
+    let mut info = LinuxTarInfo::new(&tar_path, LinuxTarSource::Download)?;
+    info.tool_versions = tool_versions;
+    Ok(info)
+}
+
+fn chmod_tree(path: &str, mode: &str) -> Result<()> {
+    if !Command::new("chmod")
+        .args(["-R", mode, path])
+        .status()
+        .map_err(|e| anyhow!("failed to execute chmod ({})", &e))?
+        .success()
+    {
+        bail!("failed to chmod {} {:?}", mode, path);
+    }
+    Ok(())
+}
+
+fn remove_src_tree(path: &str) -> Result<()> {
+    if Path::new(path).exists() {
+        chmod_tree(path, "u+w")?;
+        fs::remove_dir_all(path)?;
+    }
+    Ok(())
+}
+
+// The shared tree is usable if the stamp matches the tarball and the
+// top-level Makefile is there. The stamp is written only after extraction
+// completes, so an interrupted extraction is never reused.
+fn verify_linux_src(src_path: &str, stamp_path: &str, tar: &LinuxTarInfo) -> Result<()> {
+    let stamp = match fs::read_to_string(stamp_path) {
+        Ok(v) => v,
+        Err(e) => bail!("failed to read {:?} ({})", stamp_path, &e),
+    };
+    if stamp.trim() != tar.sha256 {
+        bail!("extracted from a different tarball");
+    }
+    let version = tar.version.as_deref().unwrap_or("*");
+    let pattern = format!("{}/linux-{}/Makefile", src_path, version);
+    if glob(&pattern)?.filter_map(|x| x.ok()).next().is_none() {
+        bail!("{:?} is missing", &pattern);
+    }
+    Ok(())
+}
+
+/// Extract the linux tarball into the read-only source tree shared by
+/// build-linux.sh runs, which then build out-of-tree. An existing tree is
+/// reused if it was extracted from the same tarball.
+pub fn prepare_linux_src(
+    cfg: &Config,
+    tar: &LinuxTarInfo,
+    progress: &mut dyn FnMut(PrepareProgress),
+) -> Result<()> {
+    let src_path = cfg.scr_path.clone() + "/linux-src";
+    let stamp_path = cfg.scr_path.clone() + "/linux-src.sha256";
+
+    match verify_linux_src(&src_path, &stamp_path, tar) {
+        Ok(()) => {
+            debug!("using existing {:?}", &src_path);
+            return Ok(());
+        }
+        Err(e) if Path::new(&src_path).exists() => {
+            warn!("side: Discarding invalid {:?} ({})", &src_path, &e);
+        }
+        Err(_) => (),
+    }
+
+    info!("side: Extracting linux tarball into {:?}", &src_path);
+    progress(PrepareProgress::Extracting);
+    let _ = fs::remove_file(&stamp_path);
+    let tmp_path = src_path.clone() + ".tmp";
+    remove_src_tree(&src_path)?;
+    remove_src_tree(&tmp_path)?;
+    fs::create_dir_all(&tmp_path)?;
+
+    if !Command::new("tar")
+        .args(["-xf", &tar.path, "-C", &tmp_path])
+        .status()
+        .map_err(|e| anyhow!("failed to execute tar ({})", &e))?
+        .success()
+    {
+        let _ = remove_src_tree(&tmp_path);
+        bail!("failed to extract {:?}", &tar.path);
+    }
+
+    chmod_tree(&tmp_path, "a-w")?;
+    fs::rename(&tmp_path, &src_path)?;
+    fs::write(&stamp_path, format!("{}\n", &tar.sha256))?;
+    Ok(())
+}
+
+/// Prepare the side binaries and the linux tarball. `progress` is called as
+/// each stage starts.
+pub fn prepare_sides(
+    cfg: &Config,
+    progress: &mut dyn FnMut(PrepareProgress),
+) -> Result<LinuxTarInfo> {
+    prepare_selected(cfg, PrepareSteps::ALL, progress).map(Option::unwrap)
+}
+
+/// Run only the selected preparation steps, e.g. to re-download the
+/// tarball without reinstalling the side binaries. The tarball info is
+/// returned if `steps.linux_tar`.
+pub fn prepare_selected(
+    cfg: &Config,
+    steps: PrepareSteps,
+    progress: &mut dyn FnMut(PrepareProgress),
+) -> Result<Option<LinuxTarInfo>> {
+    let timestamp = Local::now();
+    let started_at = Instant::now();
+
+    // The side binaries are written on a separate thread while the tarball,
+    // which usually dominates, is prepared. They don't share any paths.
+    let (bins_res, tar_res) = crossbeam::scope(|s| {
+        let bins = if steps.side_bins {
+            progress(PrepareProgress::SideBins);
+            Some(s.spawn(|_| {
+                let at = Instant::now();
+                prepare_side_bins(cfg).map(|_| at.elapsed())
+            }))
+        } else {
+            None
+        };
+
+        let tar = if steps.linux_tar {
+            progress(PrepareProgress::LinuxTar);
+            let at = Instant::now();
+            let res = prepare_linux_tar(cfg, progress)
+                .map_err(Into::into)
+                .and_then(|tar| {
+                    if cfg.side_linux_src_shared {
+                        prepare_linux_src(cfg, &tar, progress)?;
+                    }
+                    Ok(tar)
+                });
+            Some(res.map(|tar| (tar, at.elapsed())))
+        } else {
+            None
+        };
+
+        let bins = bins.map(|jh| {
+            jh.join()
+                .unwrap_or_else(|_| Err(anyhow!("side binary preparation panicked")))
+        });
+        (bins, tar)
+    })
+    .unwrap();
+    let total = started_at.elapsed();
+
+    let mut errors = vec![];
+    let side_bins = match bins_res {
+        Some(Ok(dur)) => dur,
+        Some(Err(e)) => {
+            errors.push(e);
+            Duration::from_secs(0)
+        }
+        None => Duration::from_secs(0),
+    };
+    let (tar, linux_tar) = match tar_res {
+        Some(Ok((tar, dur))) => (Some(tar), dur),
+        Some(Err(e)) => {
+            errors.push(e);
+            (None, Duration::from_secs(0))
+        }
+        None => (None, Duration::from_secs(0)),
+    };
+    match errors.len() {
+        0 => (),
+        1 => return Err(errors.pop().unwrap()),
+        _ => bail!(
+            "{}",
+            errors
+                .iter()
+                .map(|e| format!("{:#}", e))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+    }
+
+    record_prepare_run(
+        cfg,
+        PrepareRun {
+            timestamp,
+            side_bins: side_bins.as_secs_f64(),
+            linux_tar: linux_tar.as_secs_f64(),
+            linux_tar_source: match tar.as_ref() {
+                Some(tar) => format!("{:?}", tar.source),
+                None => "Skipped".into(),
+            },
+            total: total.as_secs_f64(),
+        },
+    );
+    progress(PrepareProgress::Done);
+    Ok(tar)
+}
+
+/// Append `run` to the bounded timing history. The history is purely
+/// informational, so failures are only warned about.
+fn record_prepare_run(cfg: &Config, run: PrepareRun) {
+    let path = &cfg.prepare_history_path;
+    let mut hist = match PrepareHistory::load(path) {
+        Ok(v) => v,
+        Err(e) => {
+            if Path::new(path).exists() {
+                warn!("side: Discarding unreadable {:?} ({:?})", path, &e);
+            }
+            PrepareHistory::default()
+        }
+    };
+
+    hist.push(run);
+    if let Err(e) = hist.save(path) {
+        warn!("side: Failed to update {:?} ({:?})", path, &e);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PkgMgr {
+    Apt,
+    Dnf,
+}
+
+// Determine the package manager family from the contents of /etc/os-release.
+fn os_release_pkg_mgr(os_release: &str) -> Option<PkgMgr> {
+    let mut ids = vec![];
+    for line in os_release.lines() {
+        if let Some((key, val)) = line.split_once('=') {
+            if key == "ID" || key == "ID_LIKE" {
+                ids.extend(
+                    val.trim_matches('"')
+                        .split_whitespace()
+                        .map(|x| x.to_string()),
+                );
+            }
+        }
+    }
+    for id in ids.iter() {
+        match id.as_str() {
+            "debian" | "ubuntu" => return Some(PkgMgr::Apt),
+            "fedora" | "rhel" | "centos" => return Some(PkgMgr::Dnf),
+            _ => (),
+        }
+    }
+    None
+}
+
+fn dep_pkg_name(dep: &str, mgr: PkgMgr) -> &str {
+    match (dep, mgr) {
+        ("ld", _) => "binutils",
+        ("pkg-config", PkgMgr::Dnf) => "pkgconf-pkg-config",
+        ("libssl", PkgMgr::Apt) => "libssl-dev",
+        ("libssl", PkgMgr::Dnf) => "openssl-devel",
+        ("libelf", PkgMgr::Apt) => "libelf-dev",
+        ("libelf", PkgMgr::Dnf) => "elfutils-libelf-devel",
+        (dep, _) => dep,
+    }
+}
+
+// The command to install the packages providing `missing`.
+fn install_hint(missing: &[&str], mgr: PkgMgr) -> String {
+    let pkgs: BTreeSet<&str> = missing.iter().map(|x| dep_pkg_name(x, mgr)).collect();
+    let cmd = match mgr {
+        PkgMgr::Apt => "apt install",
+        PkgMgr::Dnf => "dnf install",
+    };
+    format!("{} {}", cmd, pkgs.into_iter().collect::<Vec<_>>().join(" "))
+}
+
+// The minimum versions needed to build the bundled kernel.
+const MIN_BUILD_TOOL_VERS: [(&str, (u32, u32)); 3] =
+    [("gcc", (4, 9)), ("make", (3, 81)), ("ld", (2, 23))];
+
+// MAJOR.MINOR of a version from tool_version(), e.g. "2.23.52.0.1-55.el7".
+fn parse_major_minor(ver: &str) -> Option<(u32, u32)> {
+    let mut nums = ver
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|x| x.parse::<u32>());
+    match (nums.next(), nums.next()) {
+        (Some(Ok(major)), Some(Ok(minor))) => Some((major, minor)),
+        _ => None,
+    }
+}
+
+/// Check the sideload build dependencies. Failures are added to
+/// `sr_failed` and the missing binaries and libraries and the outdated
+/// build tools are returned along with how to install them.
+pub fn startup_checks(sr_failed: &mut HashSet<SysReq>) -> Vec<MissingDep> {
+    let mut missing = vec![];
+
+    for bin in &["gcc", "ld", "make", "bison", "flex", "pkg-config", "stress"] {
+        if find_bin(bin, Option::<&str>::None).is_none() {
+            warn!("side: binary dependency {:?} is missing", bin);
+            sr_failed.insert(SysReq::Dependencies);
+            missing.push((*bin, DepKind::Binary));
+        }
+    }
+
+    for (bin, min) in MIN_BUILD_TOOL_VERS.iter() {
+        if missing.iter().any(|(name, _)| name == bin) {
+            continue;
+        }
+        match parse_major_minor(&tool_version(bin)) {
+            Some(ver) if ver < *min => {
+                warn!(
+                    "side: {:?} {}.{} is older than the required {}.{}",
+                    bin, ver.0, ver.1, min.0, min.1
+                );
+                sr_failed.insert(SysReq::BuildToolVersions);
+                missing.push((*bin, DepKind::Outdated));
+            }
+            Some(_) => (),
+            None => warn!("side: Failed to determine the version of {:?}", bin),
+        }
+    }
+
+    if find_bin("perf", Option::<&str>::None).is_none() {
+        warn!("side: \"perf\" is missing, perf_stat will be ignored");
+    }
+    if find_bin("fio", Option::<&str>::None).is_none() {
+        warn!("side: \"fio\" is missing, io_load workloads will be refused");
+    }
+
+    for lib in &["libssl", "libelf"] {
+        let st = match Command::new("pkg-config").arg("--exists").arg(lib).status() {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("side: pkg-config failed ({:?})", &e);
+                sr_failed.insert(SysReq::Dependencies);
+                missing.push((*lib, DepKind::Library));
+                continue;
+            }
+        };
+
+        if !st.success() {
+            warn!("side: devel library dependency {:?} is missing", lib);
+            sr_failed.insert(SysReq::Dependencies);
+            missing.push((*lib, DepKind::Library));
+        }
+    }
+
+    if missing.is_empty() {
+        return vec![];
+    }
+
+    let names: Vec<&str> = missing.iter().map(|(name, _)| *name).collect();
+    let mgr = fs::read_to_string("/etc/os-release")
+        .ok()
+        .and_then(|x| os_release_pkg_mgr(&x));
+    match mgr {
+        Some(mgr) => warn!(
+            "side: Install the missing or outdated dependencies with \"{}\"",
+            install_hint(&names, mgr)
+        ),
+        None => warn!("side: Install the packages providing {}", names.join(", ")),
+    }
+
+    missing
+        .into_iter()
+        .map(|(name, kind)| MissingDep {
+            name: name.into(),
+            kind,
+            hint: mgr.map(|mgr| install_hint(&[name], mgr)),
+        })
+        .collect()
+}
+
+/// How to deal with mounts which workloads leave behind in their scratch
+/// dirs. They make removal fail until they're unmounted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScrUmount {
+    Off,
+    Normal,
+    Lazy,
+}
+
+// Unmount everything mounted under `path`, deepest first. Returns whether
+// anything was unmounted.
+fn umount_sub_mounts(path: &str, mode: ScrUmount) -> bool {
+    if mode == ScrUmount::Off {
+        return false;
+    }
+    let mounts = match MountList::new() {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("side: Failed to read mount list ({:?})", &e);
+            return false;
+        }
+    };
+
+    let mut dests: Vec<&Path> = mounts
+        .0
+        .iter()
+        .map(|mi| mi.dest.as_path())
+        .filter(|dest| dest.starts_with(path))
+        .collect();
+    dests.sort_by_key(|dest| std::cmp::Reverse(dest.components().count()));
+    dests.dedup();
+
+    let mut unmounted = false;
+    for dest in dests {
+        let cpath = CString::new(dest.to_string_lossy().as_bytes()).unwrap();
+        if unsafe { libc::umount2(cpath.as_ptr(), 0) } == 0 {
+            info!("side: Unmounted {:?} left in {:?}", dest, path);
+            unmounted = true;
+            continue;
+        }
+        let e = io::Error::last_os_error();
+        if mode == ScrUmount::Lazy
+            && unsafe { libc::umount2(cpath.as_ptr(), libc::MNT_DETACH) } == 0
+        {
+            info!(
+                "side: Lazily unmounted {:?} left in {:?} ({:?})",
+                dest, path, &e
+            );
+            unmounted = true;
+        } else {
+            warn!("side: Failed to unmount {:?} ({:?})", dest, &e);
+        }
+    }
+    unmounted
+}
+
+fn tmpfs_mount_opts(size: Option<u64>) -> String {
+    match size {
+        Some(size) => format!("mode=0755,size={}", size),
+        None => "mode=0755".into(),
+    }
+}
+
+fn is_mount_point(path: &str) -> bool {
+    match MountList::new() {
+        Ok(mounts) => mounts.0.iter().any(|mi| mi.dest == Path::new(path)),
+        Err(_) => false,
+    }
+}
+
+// Back the scratch dir at `path` with tmpfs. A tmpfs which is already there,
+// e.g. from a previous instance on a shared dir, is reused.
+fn mount_scr_tmpfs(path: &str, size: Option<u64>) -> Result<()> {
+    if is_mount_point(path) {
+        return Ok(());
+    }
+    let src = CString::new("tmpfs").unwrap();
+    let dst = CString::new(path).unwrap();
+    let data = CString::new(tmpfs_mount_opts(size)).unwrap();
+    let ret = unsafe {
+        libc::mount(
+            src.as_ptr(),
+            dst.as_ptr(),
+            src.as_ptr(),
+            0,
+            data.as_ptr() as *const libc::c_void,
+        )
+    };
+    if ret != 0 {
+        bail!(
+            "failed to mount tmpfs on {:?} ({:?})",
+            path,
+            io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+// Unmount the tmpfs mounted by mount_scr_tmpfs(). Falls back to lazy
+// unmount as anything still holding it would fail the removal anyway.
+fn umount_scr_tmpfs(path: &str) {
+    let cpath = CString::new(path).unwrap();
+    if unsafe { libc::umount2(cpath.as_ptr(), 0) } == 0 {
+        return;
+    }
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() == Some(libc::EINVAL) {
+        // Not mounted.
+        return;
+    }
+    if unsafe { libc::umount2(cpath.as_ptr(), libc::MNT_DETACH) } != 0 {
+        warn!(
+            "side: Failed to unmount scratch tmpfs {:?} ({:?})",
+            path,
+            io::Error::last_os_error()
+        );
+    } else {
+        info!("side: Lazily unmounted scratch tmpfs {:?} ({:?})", path, &e);
+    }
+}
+
+/// Scratch tmpfs mounts by path. Workloads sharing a scratch dir share its
+/// tmpfs, which is unmounted when the last ScrMount referencing it is
+/// dropped.
+#[derive(Default)]
+pub struct ScrMounts {
+    refs: Mutex<BTreeMap<String, usize>>,
+}
+
+impl ScrMounts {
+    // Mount tmpfs on `path` unless already held and take a reference.
+    fn get(self: &Arc<Self>, path: &str, size: Option<u64>) -> Result<ScrMount> {
+        let mut refs = self.refs.lock().unwrap();
+        if !refs.contains_key(path) {
+            mount_scr_tmpfs(path, size)?;
+        }
+        *refs.entry(path.into()).or_insert(0) += 1;
+        Ok(ScrMount {
+            path: path.into(),
+            mounts: self.clone(),
+            released: false,
+        })
+    }
+
+    // Take a reference on the tmpfs a previous instance left mounted on
+    // `path` without mounting anything.
+    fn adopt(self: &Arc<Self>, path: &str) -> ScrMount {
+        *self.refs.lock().unwrap().entry(path.into()).or_insert(0) += 1;
+        ScrMount {
+            path: path.into(),
+            mounts: self.clone(),
+            released: false,
+        }
+    }
+
+    fn is_held(&self, path: &str) -> bool {
+        self.refs.lock().unwrap().contains_key(path)
+    }
+
+    // Drop a reference, returns whether it was the last one.
+    fn put(&self, path: &str) -> bool {
+        let mut refs = self.refs.lock().unwrap();
+        let nr = refs.get_mut(path).unwrap();
+        *nr -= 1;
+        if *nr > 0 {
+            return false;
+        }
+        refs.remove(path);
+        umount_scr_tmpfs(path);
+        true
+    }
+}
+
+struct ScrMount {
+    path: String,
+    mounts: Arc<ScrMounts>,
+    released: bool,
+}
+
+impl ScrMount {
+    // Drop the reference in `mount` if any. Returns whether the scratch dir
+    // is no longer in use by another workload and can be removed.
+    fn release(mount: &mut Option<ScrMount>) -> bool {
+        match mount.take() {
+            Some(mut mount) => {
+                mount.released = true;
+                mount.mounts.put(&mount.path)
+            }
+            None => true,
+        }
+    }
+}
+
+impl Drop for ScrMount {
+    fn drop(&mut self) {
+        if !self.released {
+            self.mounts.put(&self.path);
+        }
+    }
+}
+
+// Number of entries under `path`, not following symlinks.
+fn count_dir_entries(path: &Path) -> usize {
+    let entries = match fs::read_dir(path) {
+        Ok(v) => v,
+        Err(_) => return 0,
+    };
+    entries
+        .filter_map(|x| x.ok())
+        .map(|entry| match entry.file_type() {
+            Ok(ft) if ft.is_dir() => 1 + count_dir_entries(&entry.path()),
+            _ => 1,
+        })
+        .sum()
+}
+
+fn really_remove_dir_all(path: &str, umount: ScrUmount, timeout: Duration, clock: &dyn Clock) {
+    let started_at = clock.now();
+
+    // Removing with mounts in place would delete the mounted contents and
+    // then fail on the mount points anyway.
+    umount_sub_mounts(path, umount);
+
+    loop {
+        let e = match fs::remove_dir_all(path) {
+            Ok(()) => break,
+            Err(e) => e,
+        };
+
+        match e.raw_os_error() {
+            Some(libc::ENOENT) => {
+                break;
+            }
+            // The scratch device is gone, nothing more we can do.
+            Some(libc::EIO) | Some(libc::ENODEV) | Some(libc::ENXIO) => {
+                warn!(
+                    "side: Skipping removal of {:?}, scratch device lost? ({:?})",
+                    path, &e
+                );
+                break;
+            }
+            Some(libc::ENOTEMPTY) | Some(libc::EBUSY) => {
+                // Something may have been mounted since the last attempt.
+                umount_sub_mounts(path, umount);
+            }
+            _ => {
+                error!("side: Failed to remove {:?} ({:?})", path, &e);
+                break;
+            }
+        }
+
+        if clock.now().duration_since(started_at) > timeout {
+            error!(
+                "side: Failed to remove {:?} after trying for {:.1}s",
+                path,
+                timeout.as_secs_f64()
+            );
+            break;
+        }
+
+        debug!(
+            "side: {:?} not empty, {} entries left, trying to remove again",
+            path,
+            count_dir_entries(Path::new(path))
+        );
+    }
+}
+
+struct CleanupJob {
+    path: String,
+    umount: ScrUmount,
+    timeout: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+// Whether `name` is a dir renamed by CleanupQueue::push(), i.e.
+// "NAME.rm-PID-SEQ".
+fn is_trash_name(name: &str) -> bool {
+    match name.rsplit_once(".rm-") {
+        Some((base, suffix)) if !base.is_empty() => match suffix.split_once('-') {
+            Some((pid, seq)) => pid.parse::<u32>().is_ok() && seq.parse::<u64>().is_ok(),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+fn find_trash(dir: &Path, depth: usize, trash: &mut Vec<String>) {
+    if depth == 0 {
+        return;
+    }
+    let rd = match fs::read_dir(dir) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+    for ent in rd.filter_map(|x| x.ok()) {
+        if !ent.file_type().map(|ft| ft.is_dir()).unwrap_or(false) {
+            continue;
+        }
+        let path = ent.path();
+        match ent.file_name().to_str() {
+            Some(name) if is_trash_name(name) => {
+                if let Some(path) = path.to_str() {
+                    trash.push(path.to_string());
+                }
+            }
+            _ => find_trash(&path, depth - 1, trash),
+        }
+    }
+}
+
+/// Removes the scratch dirs of dropped sys/sideloads on a worker thread so
+/// that tearing down workloads with large scratch dirs doesn't stall the
+/// agent loop.
+pub struct CleanupQueue {
+    tx: crossbeam::channel::Sender<CleanupJob>,
+    pending: Arc<(Mutex<usize>, Condvar)>,
+    seq: AtomicU64,
+}
+
+impl CleanupQueue {
+    pub fn new() -> Self {
+        let (tx, rx) = crossbeam::channel::unbounded::<CleanupJob>();
+        let pending = Arc::new((Mutex::new(0), Condvar::new()));
+        let worker_pending = pending.clone();
+        spawn(move || {
+            while let Ok(job) = rx.recv() {
+                really_remove_dir_all(&job.path, job.umount, job.timeout, &*job.clock);
+                let (nr, cv) = &*worker_pending;
+                *nr.lock().unwrap() -= 1;
+                cv.notify_all();
+            }
+        });
+        Self {
+            tx,
+            pending,
+            seq: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue `path` for removal. It's renamed out of the way first so that
+    /// a new instance can reuse the path right away. If that fails, it's
+    /// removed synchronously.
+    pub fn push(&self, path: &str, umount: ScrUmount, timeout: Duration, clock: Arc<dyn Clock>) {
+        let trash = format!(
+            "{}.rm-{}-{}",
+            path,
+            std::process::id(),
+            self.seq.fetch_add(1, Ordering::Relaxed)
+        );
+        if let Err(e) = fs::rename(path, &trash) {
+            if e.raw_os_error() != Some(libc::ENOENT) {
+                debug!(
+                    "side: Failed to move {:?} for removal, removing in place ({:?})",
+                    path, &e
+                );
+                really_remove_dir_all(path, umount, timeout, &*clock);
+            }
+            return;
+        }
+
+        self.queue(CleanupJob {
+            path: trash,
+            umount,
+            timeout,
+            clock,
+        });
+    }
+
+    /// Queue the dirs under `root` which an earlier instance renamed for
+    /// removal but didn't get to remove before exiting or crashing. Scratch
+    /// dirs are `depth` levels below `root`. Returns the number queued.
+    pub fn sweep(
+        &self,
+        root: &str,
+        depth: usize,
+        umount: ScrUmount,
+        timeout: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> usize {
+        let mut trash = vec![];
+        find_trash(Path::new(root), depth, &mut trash);
+        let nr = trash.len();
+        for path in trash.into_iter() {
+            self.queue(CleanupJob {
+                path,
+                umount,
+                timeout,
+                clock: clock.clone(),
+            });
+        }
+        nr
+    }
+
+    fn queue(&self, job: CleanupJob) {
+        *self.pending.0.lock().unwrap() += 1;
+        if let Err(e) = self.tx.send(job) {
+            *self.pending.0.lock().unwrap() -= 1;
+            let job = e.into_inner();
+            really_remove_dir_all(&job.path, job.umount, job.timeout, &*job.clock);
+        }
+    }
+
+    /// Wait for all queued removals to finish. Should be called before
+    /// exiting so that scratch dirs aren't left behind.
+    pub fn flush(&self) {
+        let (nr, cv) = &*self.pending;
+        let mut nr = nr.lock().unwrap();
+        if *nr > 0 {
+            info!("side: Waiting for {} scratch dir removals to finish", *nr);
+        }
+        while *nr > 0 {
+            nr = cv.wait(nr).unwrap();
+        }
+    }
+}
+
+// Run the workload's cleanup command. Give up and kill it if it doesn't
+// finish in CLEANUP_TIMEOUT so that teardown can't get stuck.
+fn run_cleanup_cmd(name: &str, args: &[String], dir: &str, clock: &dyn Clock) {
+    if args.is_empty() {
+        return;
+    }
+
+    let mut child = match Command::new(&args[0])
+        .args(&args[1..])
+        .current_dir(dir)
+        .spawn()
+    {
+        Ok(v) => v,
+        Err(e) => {
+            error!("side: Failed to run cleanup for {:?} ({:?})", name, &e);
+            return;
+        }
+    };
+
+    let started_at = clock.now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(st)) => {
+                if !st.success() {
+                    error!("side: Cleanup for {:?} failed ({:?})", name, &st);
+                }
+                return;
+            }
+            Ok(None) => (),
+            Err(e) => {
+                error!("side: Failed to wait for cleanup of {:?} ({:?})", name, &e);
+                return;
+            }
+        }
+
+        if clock.now().duration_since(started_at) > CLEANUP_TIMEOUT {
+            error!(
+                "side: Cleanup for {:?} didn't finish in {:?}, killing",
+                name, CLEANUP_TIMEOUT
+            );
+            let _ = child.kill();
+            let _ = child.wait();
+            return;
+        }
+
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+// Tracks cgroup CPU and IO counters of a sys/sideload across reports.
+// Counters restart from zero when the service is restarted. Treat a
+// decreasing counter as a reset and count the whole new value as delta so
+// that the cumulative totals keep increasing. memory.current and the
+// pressure stall totals are reported as-is.
+#[derive(Default)]
+struct WorkloadUsage {
+    last: (u64, u64, u64),
+    rep: WorkloadUsageReport,
+}
+
+impl WorkloadUsage {
+    fn read_counters(cgrp: &str, devnr: (u32, u32)) -> (u64, u64, u64) {
+        let mut cpu_usec = 0;
+        let (mut rbytes, mut wbytes) = (0, 0);
+
+        if let Ok(cs) = read_cgroup_flat_keyed_file(&(cgrp.to_string() + "/cpu.stat")) {
+            cpu_usec = *cs.get("usage_usec").unwrap_or(&0);
+        }
+
+        if let Ok(is) = read_cgroup_nested_keyed_file(&(cgrp.to_string() + "/io.stat")) {
+            if let Some(stat) = is.get(&format!("{}:{}", devnr.0, devnr.1)) {
+                if let Some(val) = stat.get("rbytes") {
+                    rbytes = val.parse::<u64>().unwrap_or(0);
+                }
+                if let Some(val) = stat.get("wbytes") {
+                    wbytes = val.parse::<u64>().unwrap_or(0);
+                }
+            }
+        }
+
+        (cpu_usec, rbytes, wbytes)
+    }
+
+    fn delta(cur: u64, last: u64) -> u64 {
+        if cur >= last {
+            cur - last
+        } else {
+            cur
+        }
+    }
+
+    // Memory usage and pressure are only consumed by the metrics exporter.
+    // Skip reading them unless `metrics` is set.
+    fn update(&mut self, cgrp: &str, devnr: (u32, u32), metrics: bool) -> WorkloadUsageReport {
+        let cur = Self::read_counters(cgrp, devnr);
+        let rep = &mut self.rep;
+
+        rep.cpu_usec_delta = Self::delta(cur.0, self.last.0);
+        rep.io_rbytes_delta = Self::delta(cur.1, self.last.1);
+        rep.io_wbytes_delta = Self::delta(cur.2, self.last.2);
+        rep.cpu_usec += rep.cpu_usec_delta;
+        rep.io_rbytes += rep.io_rbytes_delta;
+        rep.io_wbytes += rep.io_wbytes_delta;
+
+        if metrics {
+            rep.mem_bytes = read_one_line(&(cgrp.to_string() + "/memory.current"))
+                .ok()
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .unwrap_or(0);
+            rep.cpu_stalls = read_stalls(&(cgrp.to_string() + "/cpu.pressure")).unwrap_or_default();
+            rep.mem_stalls =
+                read_stalls(&(cgrp.to_string() + "/memory.pressure")).unwrap_or_default();
+            rep.io_stalls = read_stalls(&(cgrp.to_string() + "/io.pressure")).unwrap_or_default();
+        }
+
+        self.last = cur;
+        rep.clone()
+    }
+}
+
+// A reused report covers no time since the last one. Clear the fields which
+// are relative to the last report so that consumers don't count them twice.
+fn clear_since_last(usage: &mut WorkloadUsageReport, limits_hit: &mut Vec<String>) {
+    usage.cpu_usec_delta = 0;
+    usage.io_rbytes_delta = 0;
+    usage.io_wbytes_delta = 0;
+    limits_hit.clear();
+}
+
+// Tracks whether a sys/sideload is running into its limits. memory.high,
+// memory.max and CPU quota are detected from their event counters going up
+// since the last report. io.max doesn't have one. Consider it hit if the IO
+// rate is within IO_MAX_HIT_RATIO of the limit.
+#[derive(Default)]
+struct WorkloadLimits {
+    last: (u64, u64, u64, u64, u64),
+    last_at: Option<Instant>,
+}
+
+impl WorkloadLimits {
+    fn read_counters(cgrp: &str, devnr: (u32, u32)) -> (u64, u64, u64, u64, u64) {
+        let (mut high, mut max, mut throttled) = (0, 0, 0);
+        let (mut rios, mut wios) = (0, 0);
+
+        if let Ok(ev) = read_cgroup_flat_keyed_file(&(cgrp.to_string() + "/memory.events")) {
+            high = *ev.get("high").unwrap_or(&0);
+            max = *ev.get("max").unwrap_or(&0);
+        }
+
+        if let Ok(cs) = read_cgroup_flat_keyed_file(&(cgrp.to_string() + "/cpu.stat")) {
+            throttled = *cs.get("nr_throttled").unwrap_or(&0);
+        }
+
+        if let Ok(is) = read_cgroup_nested_keyed_file(&(cgrp.to_string() + "/io.stat")) {
+            if let Some(stat) = is.get(&format!("{}:{}", devnr.0, devnr.1)) {
+                if let Some(val) = stat.get("rios") {
+                    rios = val.parse::<u64>().unwrap_or(0);
+                }
+                if let Some(val) = stat.get("wios") {
+                    wios = val.parse::<u64>().unwrap_or(0);
+                }
+            }
+        }
+
+        (high, max, throttled, rios, wios)
+    }
+
+    fn update(
+        &mut self,
+        cgrp: &str,
+        devnr: (u32, u32),
+        io_max: &IoMax,
+        usage: &WorkloadUsageReport,
+        now: Instant,
+    ) -> Vec<String> {
+        let cur = Self::read_counters(cgrp, devnr);
+        let delta = WorkloadUsage::delta;
+        let mut hit = vec![];
+
+        if delta(cur.0, self.last.0) > 0 {
+            hit.push("mem_high".to_string());
+        }
+        if delta(cur.1, self.last.1) > 0 {
+            hit.push("mem_max".to_string());
+        }
+        if delta(cur.2, self.last.2) > 0 {
+            hit.push("cpu_quota".to_string());
+        }
+
+        if let Some(last_at) = self.last_at {
+            let dur = now.duration_since(last_at).as_secs_f64();
+            if dur > 0.0 {
+                for (key, limit, amount) in [
+                    ("rbps", io_max.rbps, usage.io_rbytes_delta),
+                    ("wbps", io_max.wbps, usage.io_wbytes_delta),
+                    ("riops", io_max.riops, delta(cur.3, self.last.3)),
+                    ("wiops", io_max.wiops, delta(cur.4, self.last.4)),
+                ]
+                .iter()
+                {
+                    if let Some(limit) = limit {
+                        if *amount as f64 / dur >= *limit as f64 * IO_MAX_HIT_RATIO {
+                            hit.push(format!("io_max.{}", key));
+                        }
+                    }
+                }
+            }
+        }
+
+        self.last = cur;
+        self.last_at = Some(now);
+        hit
+    }
+}
+
+// Cgroup knobs which systemd doesn't know about are written directly and
+// lost whenever the cgroup is recreated, e.g. on restart. Keep writing them
+// while the workload is running until they stick.
+#[derive(Default)]
+struct CgroupKnobs {
+    knobs: Vec<(&'static str, String)>,
+    applied: bool,
+}
+
+impl CgroupKnobs {
+    fn new(spec: &SideloadSpec, devnr: (u32, u32)) -> Self {
+        let mut knobs = vec![];
+        if spec.io_max.is_set() {
+            knobs.push(("io.max", spec.io_max.line(devnr)));
+        }
+        if let Some(v) = spec.swap_high {
+            knobs.push(("memory.swap.high", format!("{}", v)));
+        }
+        if spec.oom_group {
+            knobs.push(("memory.oom.group", "1".into()));
+        }
+        Self {
+            knobs,
+            applied: false,
+        }
+    }
+
+    fn update(&mut self, name: &str, cgrp: &str, running: bool, audit: PropAudit) {
+        if !running {
+            self.applied = false;
+            return;
+        }
+        if self.applied {
+            return;
+        }
+
+        self.applied = true;
+        let mut audited = vec![];
+        for (file, val) in self.knobs.iter() {
+            let path = format!("{}/{}", cgrp, file);
+            match write_one_line(&path, val) {
+                Ok(()) => {
+                    debug!("side: Applied {} {:?} to {:?}", file, val, name);
+                    audited.push((file.to_string(), format!("{:?}", val)));
+                }
+                Err(e) => {
+                    warn!("side: Failed to write {:?} ({:?})", &path, &e);
+                    self.applied = false;
+                }
+            }
+        }
+        if !audited.is_empty() {
+            audit_props(audit, name, "cgroup", &audited);
+        }
+    }
+}
+
+// Exclusive CPUs are set through systemd's AllowedCPUs so that it enables
+// the cpuset controller up the tree. The cgroup is then made a partition
+// root, which needs the parent slice to be one too and thus usually ends
+// up "root invalid". That's reported as an error and not retried. The
+// workload is still confined to its CPUs and SideRunner::update_work_cpus()
+// keeps hashd off them either way.
+#[derive(Default)]
+struct CpuPartition {
+    mask: Option<Vec<u8>>,
+    applied: bool,
+}
+
+impl CpuPartition {
+    fn new(spec: &SideloadSpec) -> Self {
+        Self {
+            mask: verify_exclusive_cpus(spec)
+                .ok()
+                .flatten()
+                .map(|cpus| cpu_mask(&cpus)),
+            applied: false,
+        }
+    }
+
+    fn update(&mut self, name: &str, unit: &mut systemd::Unit, cgrp: &str, running: bool) {
+        let mask = match self.mask.as_ref() {
+            Some(v) => v,
+            None => return,
+        };
+        if !running {
+            self.applied = false;
+            return;
+        }
+        if self.applied {
+            return;
+        }
+
+        if let Err(e) = unit.set_prop("AllowedCPUs", systemd::Prop::Bytes(mask.clone())) {
+            warn!("side: Failed to set AllowedCPUs on {:?} ({:?})", name, &e);
+            return;
+        }
+        self.applied = true;
+        if let Err(e) = write_one_line(format!("{}/cpuset.cpus.partition", cgrp), "root") {
+            error!(
+                "side: Failed to make {:?} a cpuset partition ({:?})",
+                name, &e
+            );
+            return;
+        }
+        if let Some(state) = self.state(cgrp).filter(|state| state.contains("invalid")) {
+            error!(
+                "side: cpuset partition of {:?} is {:?}, other sys/sideloads may share its CPUs",
+                name, &state
+            );
+        }
+    }
+
+    fn state(&self, cgrp: &str) -> Option<String> {
+        self.mask.as_ref()?;
+        read_one_line(format!("{}/cpuset.cpus.partition", cgrp))
+            .ok()
+            .map(|x| x.trim().to_string())
+    }
+}
+
+// Match the kernel's OOM kill messages against `memcg`, the cgroup path
+// relative to the cgroup2 root. The oom-kill line names the victim's cgroup
+// and the following "Killed process" line how much memory it held.
+fn parse_oom_kills(log: &str, memcg: &str) -> Vec<OomKillReport> {
+    let mut pids = vec![];
+    let mut kills = vec![];
+
+    for line in log.lines() {
+        if let Some(pos) = line.find("oom-kill:") {
+            let fields: BTreeMap<&str, &str> = line[pos + 9..]
+                .split(',')
+                .filter_map(|kv| kv.split_once('='))
+                .collect();
+            if fields.get("task_memcg") == Some(&memcg) {
+                if let Some(pid) = fields.get("pid").and_then(|v| v.parse::<u32>().ok()) {
+                    pids.push(pid);
+                }
+            }
+            continue;
+        }
+
+        let rest = match line.find("Killed process ") {
+            Some(pos) => &line[pos + 15..],
+            None => continue,
+        };
+        let pid = match rest.split(' ').next().and_then(|v| v.parse::<u32>().ok()) {
+            Some(v) if pids.contains(&v) => v,
+            _ => continue,
+        };
+        let task = match (rest.find('('), rest.find(") ")) {
+            (Some(open), Some(close)) if open < close => rest[open + 1..close].to_string(),
+            _ => String::new(),
+        };
+        let kb = |key: &str| -> u64 {
+            rest.find(&format!(" {}:", key))
+                .map(|pos| &rest[pos + key.len() + 2..])
+                .and_then(|v| v.split("kB").next())
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(0)
+                * 1024
+        };
+        kills.push(OomKillReport {
+            pid,
+            task,
+            total_vm: kb("total-vm"),
+            anon_rss: kb("anon-rss"),
+            file_rss: kb("file-rss"),
+            shmem_rss: kb("shmem-rss"),
+        });
+    }
+    kills
+}
+
+fn read_kernel_log(since: Option<i64>) -> Option<String> {
+    let mut cmd = Command::new("journalctl");
+    cmd.args(["-k", "-q", "-o", "cat"]);
+    match since {
+        Some(at) => cmd.arg(format!("--since=@{}", at)),
+        None => cmd.arg("-b"),
+    };
+    if let Ok(out) = cmd.output() {
+        if out.status.success() {
+            return Some(String::from_utf8_lossy(&out.stdout).to_string());
+        }
+    }
+    match Command::new("dmesg").output() {
+        Ok(out) if out.status.success() => Some(String::from_utf8_lossy(&out.stdout).to_string()),
+        _ => None,
+    }
+}
+
+// Tracks memory.events::oom_kill and looks up the victims in the kernel log
+// when it goes up. Reading the log is slow, so it's done at most once every
+// OOM_LOG_INTV_SECS and the kills in between are looked up together.
+#[derive(Default)]
+struct WorkloadOoms {
+    count: u64,
+    looked_up: u64,
+    checked_at: Option<i64>,
+    kills: Vec<OomKillReport>,
+}
+
+impl WorkloadOoms {
+    fn update(&mut self, name: &str, cgrp: &str) -> (u64, Vec<OomKillReport>) {
+        let count = read_cgroup_flat_keyed_file(&(cgrp.to_string() + "/memory.events"))
+            .ok()
+            .and_then(|ev| ev.get("oom_kill").cloned())
+            .unwrap_or(0);
+
+        let now = Local::now().timestamp();
+        let due = match self.checked_at {
+            Some(at) => now - at >= OOM_LOG_INTV_SECS,
+            None => true,
+        };
+        if count > self.looked_up && due {
+            // Look back a bit as the kernel log may lag behind the counter.
+            match read_kernel_log(self.checked_at.map(|at| at - 5)) {
+                Some(log) => {
+                    let memcg = cgrp.trim_start_matches("/sys/fs/cgroup");
+                    for kill in parse_oom_kills(&log, memcg) {
+                        if !self.kills.iter().any(|k| k.pid == kill.pid) {
+                            info!(
+                                "side: {:?} OOM killed {} ({}), anon-rss {}",
+                                name,
+                                kill.pid,
+                                &kill.task,
+                                format_size(kill.anon_rss)
+                            );
+                            self.kills.push(kill);
+                        }
+                    }
+                    let nr = self.kills.len();
+                    if nr > OOM_KILL_HISTORY {
+                        self.kills.drain(..nr - OOM_KILL_HISTORY);
+                    }
+                }
+                None => debug!(
+                    "side: {:?} had OOM kills but the kernel log isn't available",
+                    name
+                ),
+            }
+            self.checked_at = Some(now);
+            self.looked_up = count;
+        }
+
+        // The counter restarts when the service is restarted.
+        if count < self.count {
+            self.looked_up = count;
+        }
+        self.count = count;
+        (count, self.kills.clone())
+    }
+}
+
+// Workloads can report progress by writing a single line to $STATUS_FILE
+// which is included in their reports.
+fn status_file_path(scr_path: &str) -> String {
+    scr_path.to_string() + "/status"
+}
+
+fn read_status_file(scr_path: &str) -> Option<String> {
+    match read_one_line(status_file_path(scr_path)) {
+        Ok(line) if !line.trim().is_empty() => Some(line.trim().to_string()),
+        _ => None,
+    }
+}
+
+// Workload stdout and stderr are captured in the scratch dir so that there's
+// something to look at when a workload dies right away.
+fn stdout_log_path(scr_path: &str) -> String {
+    format!("{}/{}", scr_path, STDOUT_LOG_FILE)
+}
+
+fn stderr_log_path(scr_path: &str) -> String {
+    format!("{}/{}", scr_path, STDERR_LOG_FILE)
+}
+
+// Last `n` lines of the file at `path`. Only the tail end of large files is
+// read.
+fn tail_file(path: &str, n: usize) -> Result<Vec<String>> {
+    let mut f = fs::File::open(path)?;
+    let len = f.metadata()?.len();
+    let skip = len.saturating_sub(TAIL_LOG_MAX_BYTES);
+    f.seek(SeekFrom::Start(skip))?;
+    let mut buf = vec![];
+    f.read_to_end(&mut buf)?;
+
+    let body = String::from_utf8_lossy(&buf);
+    let mut lines: Vec<&str> = body.lines().collect();
+    // The first line may be partial if the head was skipped.
+    if skip > 0 && !lines.is_empty() {
+        lines.remove(0);
+    }
+    let start = lines.len().saturating_sub(n);
+    Ok(lines[start..].iter().map(|x| x.to_string()).collect())
+}
+
+// Parse the counters from "perf stat -x," output.
+fn read_perf_stat(scr_path: &str) -> BTreeMap<String, u64> {
+    let mut stat = BTreeMap::new();
+    let body = match fs::read_to_string(format!("{}/{}", scr_path, PERF_STAT_FILE)) {
+        Ok(v) => v,
+        Err(_) => return stat,
+    };
+    for line in body.lines().filter(|x| !x.starts_with('#')) {
+        let fields: Vec<&str> = line.split(',').collect();
+        if fields.len() < 3 {
+            continue;
+        }
+        if let Ok(cnt) = fields[0].parse::<u64>() {
+            stat.insert(fields[2].to_string(), cnt);
+        }
+    }
+    stat
+}
+
+fn unit_is_active(unit: &systemd::Unit) -> bool {
+    !matches!(
+        unit.state,
+        systemd::UnitState::Exited | systemd::UnitState::Failed(_)
+    )
+}
+
+fn unit_start_limit_hit(unit: &systemd::Unit) -> bool {
+    match unit.state {
+        systemd::UnitState::Failed(_) => {
+            unit.props.string("Result").as_deref() == Some("start-limit-hit")
+        }
+        _ => false,
+    }
+}
+
+// The exit code and whether it was OOM killed once the unit's main process
+// is gone, None while it's still running. The exit code is None if the
+// process was killed by a signal.
+fn unit_exit_status(unit: &systemd::Unit) -> Option<(Option<i32>, bool)> {
+    match unit.state {
+        systemd::UnitState::Exited | systemd::UnitState::Failed(_) => (),
+        _ => return None,
+    }
+    let exit_code = match unit.props.i32("ExecMainCode") {
+        Some(libc::CLD_EXITED) => unit.props.i32("ExecMainStatus"),
+        _ => None,
+    };
+    let oom_killed = unit.props.string("Result").as_deref() == Some("oom-kill");
+    Some((exit_code, oom_killed))
+}
+
+pub struct Sysload {
+    name: String,
+    id: String,
+    started_at: Instant,
+    spec: SideloadSpec,
+    scr_path: String,
+    scr_keep: bool,
+    scr_umount: ScrUmount,
+    scr_rm_timeout: Duration,
+    scr_mount: Option<ScrMount>,
+    cleanup_cmd: Vec<String>,
+    heavy: bool,
+    burst: bool,
+    svc: TransientService,
+    usage: WorkloadUsage,
+    limits: WorkloadLimits,
+    ooms: WorkloadOoms,
+    cg_knobs: CgroupKnobs,
+    cpu_part: CpuPartition,
+    frozen: bool,
+    killed: bool,
+    report_intv: Duration,
+    last_report: Option<(Instant, SysloadReport)>,
+    exit: Option<(Option<i32>, bool)>,
+    mem_peak: u64,
+    clock: Arc<dyn Clock>,
+    cleanup: Arc<CleanupQueue>,
+}
+
+impl Drop for Sysload {
+    fn drop(&mut self) {
+        // Frozen tasks can't act on SIGTERM, thaw so that stop is quick.
+        if self.frozen {
+            let _ = write_cgroup_freeze(&sysload_cgrp(&self.name), false);
+        }
+        if let Err(e) = self.svc.unit.stop_and_reset() {
+            error!("side: Failed to stop {:?} ({:?})", self.name, &e);
+        }
+        // A killed workload is wedged, don't wait on its cleanup_cmd.
+        if !self.killed {
+            run_cleanup_cmd(&self.name, &self.cleanup_cmd, &self.scr_path, &*self.clock);
+        }
+        // A tmpfs still shared with another workload must be left alone.
+        if ScrMount::release(&mut self.scr_mount) && !self.scr_keep {
+            self.cleanup.push(
+                &self.scr_path,
+                self.scr_umount,
+                self.scr_rm_timeout,
+                self.clock.clone(),
+            );
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SideloaderJob {
+    id: String,
+    args: Vec<String>,
+    envs: Vec<String>,
+    frozen_expiration: u32,
+    // Held frozen by the daemon regardless of overload, see freeze_sideload().
+    #[serde(default)]
+    frozen: bool,
+    working_dir: String,
+    cpu_weight: Option<u32>,
+    stdout_path: Option<String>,
+    stderr_path: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SideloaderJobs {
+    sideloader_jobs: Vec<SideloaderJob>,
+}
+
+impl JsonLoad for SideloaderJobs {}
+impl JsonSave for SideloaderJobs {}
+
+pub struct Sideload {
+    name: String,
+    id: String,
+    started_at: Instant,
+    spec: SideloadSpec,
+    scr_path: String,
+    scr_keep: bool,
+    scr_umount: ScrUmount,
+    scr_rm_timeout: Duration,
+    scr_mount: Option<ScrMount>,
+    cleanup_cmd: Vec<String>,
+    heavy: bool,
+    burst: bool,
+    job_path: String,
+    unit: systemd::Unit,
+    usage: WorkloadUsage,
+    limits: WorkloadLimits,
+    ooms: WorkloadOoms,
+    cg_knobs: CgroupKnobs,
+    cpu_part: CpuPartition,
+    frozen: bool,
+    killed: bool,
+    report_intv: Duration,
+    last_report: Option<(Instant, SideloadReport)>,
+    clock: Arc<dyn Clock>,
+    cleanup: Arc<CleanupQueue>,
+}
+
+// The sideloader creates the sideload units, so KillSignal can't be set on
+// them. Deliver the signal directly and give the workload a chance to exit
+// before the normal stop.
+fn signal_and_wait(unit: &mut systemd::Unit, name: &str, sig: i32, clock: &dyn Clock) {
+    if let Err(e) = unit.kill(sig) {
+        warn!("side: Failed to signal {:?} ({:?})", name, &e);
+        return;
+    }
+    let started_at = clock.now();
+    while clock.now().duration_since(started_at) < KILL_SIGNAL_GRACE {
+        match unit.refresh() {
+            Ok(()) if unit.state == systemd::UnitState::Running => (),
+            _ => return,
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+impl Drop for Sideload {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.job_path) {
+            Ok(()) => (),
+            Err(e) => error!("side: Failed to remove {:?} ({:?})", &self.job_path, &e),
+        }
+        if self.frozen {
+            let _ = write_cgroup_freeze(&sideload_cgrp(&self.name), false);
+        }
+        if let Some(sig) = self.spec.kill_signal.as_deref().filter(|_| !self.killed) {
+            if let Ok(sig) = parse_signal(sig) {
+                signal_and_wait(&mut self.unit, &self.name, sig, &*self.clock);
+            }
+        }
+        if let Err(e) = self.unit.stop_and_reset() {
+            error!("side: Failed to stop {:?} ({:?})", self.name, &e);
+        }
+        if !self.killed {
+            run_cleanup_cmd(&self.name, &self.cleanup_cmd, &self.scr_path, &*self.clock);
+        }
+        // A tmpfs still shared with another workload must be left alone.
+        if ScrMount::release(&mut self.scr_mount) && !self.scr_keep {
+            self.cleanup.push(
+                &self.scr_path,
+                self.scr_umount,
+                self.scr_rm_timeout,
+                self.clock.clone(),
+            );
+        }
+    }
+}
+
+fn warmup_svc_name(name: &str) -> String {
+    format!("{}{}-warmup.service", SIDELOAD_SVC_PREFIX, name)
+}
+
+fn warmup_log_path(scr_path: &str) -> String {
+    format!("{}/warmup.log", scr_path)
+}
+
+// A sideload's warmup_args running to completion in its scratch dir before
+// the sideloader job is written. Once the main job takes over, the scratch
+// dir is handed over to it. Otherwise, dropping cleans it up like a
+// sideload would.
+struct Warmup {
+    svc: TransientService,
+    scr_path: String,
+    scr_keep: bool,
+    scr_umount: ScrUmount,
+    scr_rm_timeout: Duration,
+    scr_mount: Option<ScrMount>,
+    failed: bool,
+    handed_over: bool,
+    clock: Arc<dyn Clock>,
+    cleanup: Arc<CleanupQueue>,
+}
+
+impl Drop for Warmup {
+    fn drop(&mut self) {
+        if let Err(e) = self.svc.unit.stop_and_reset() {
+            error!("side: Failed to stop {:?} ({:?})", &self.svc.unit.name, &e);
+        }
+        if self.handed_over || self.scr_keep {
+            return;
+        }
+        if !ScrMount::release(&mut self.scr_mount) {
+            return;
+        }
+        self.cleanup.push(
+            &self.scr_path,
+            self.scr_umount,
+            self.scr_rm_timeout,
+            self.clock.clone(),
+        );
+    }
+}
+
+// Look for the binary in the configured roots in order and then PATH.
+// Returns the resolved path and where it was found.
+fn resolve_side_bin(cfg: &Config, name: &str) -> Option<(String, String)> {
+    for root in cfg.side_bin_roots.iter() {
+        if let Ok(path) = Path::new(root).join(name).canonicalize() {
+            if is_executable(&path) {
+                return Some((path.to_str().unwrap().to_string(), format!("{:?}", root)));
+            }
+        }
+    }
+
+    find_bin(name, Option::<&str>::None)
+        .map(|path| (path.to_str().unwrap().to_string(), "PATH".to_string()))
+}
+
+// The error for binary `name` which resolve_side_bin() couldn't resolve.
+// Point out a file which is there but not executable rather than leaving
+// the user to wonder why it wasn't found.
+fn unresolved_bin_err(roots: &[String], what: &str, name: &str) -> anyhow::Error {
+    let path_dirs = std::env::var_os("PATH").unwrap_or_default();
+    let dirs = roots
+        .iter()
+        .map(|root| Path::new(root).to_path_buf())
+        .chain(std::env::split_paths(&path_dirs));
+    for dir in dirs {
+        let path = dir.join(name);
+        if path.is_file() {
+            return anyhow!("{} {:?} is not executable", what, &path);
+        }
+    }
+    anyhow!("failed to resolve {} {:?}", what, name)
+}
+
+// The interpreter on the #! line of script `path`, None if it isn't one.
+fn script_interpreter(path: &str) -> Option<String> {
+    let mut buf = [0u8; 256];
+    let len = fs::File::open(path).ok()?.read(&mut buf).ok()?;
+    let line = buf[..len].split(|c| *c == b'\n').next()?;
+    let line = std::str::from_utf8(line.strip_prefix(b"#!")?).ok()?;
+    line.split_whitespace().next().map(|x| x.to_string())
+}
+
+/// Parse an ApplyTarget from JSON or, if it doesn't look like JSON, TOML.
+pub fn parse_apply_target(body: &str) -> Result<ApplyTarget> {
+    if body.trim_start().starts_with('{') {
+        Ok(serde_json::from_str(body)?)
+    } else {
+        Ok(toml::from_str(body)?)
+    }
+}
+
+/// What applying a sys/sideload target would do, see
+/// `SideRunner::plan_sysloads()`. Heavy workloads which would have to wait
+/// for a slot are listed in `queued` instead of `to_start`.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct ApplyPlan {
+    pub to_start: Vec<String>,
+    pub to_stop: Vec<String>,
+    pub to_restart: Vec<String>,
+    pub deferred: Vec<String>,
+    pub queued: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+fn verify_kconfig(spec: &SideloadSpec) -> Result<()> {
+    if let Some(path) = spec.kconfig.as_ref() {
+        if !Path::new(path).is_absolute() {
+            bail!("kconfig {:?} is not an absolute path", path);
+        }
+        if !Path::new(path).is_file() {
+            bail!("kconfig {:?} doesn't exist", path);
+        }
+    }
+    Ok(())
+}
+
+/// Signal number for `name` with or without the "SIG" prefix.
+fn parse_signal(name: &str) -> Result<i32> {
+    let sig = match name.strip_prefix("SIG").unwrap_or(name) {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "TERM" => libc::SIGTERM,
+        "CONT" => libc::SIGCONT,
+        "WINCH" => libc::SIGWINCH,
+        _ => bail!("unknown kill_signal {:?}", name),
+    };
+    Ok(sig)
+}
+
+/// memory.high bytes for `val` which is either absolute bytes or a
+/// percentage of `total`, e.g. "25%".
+fn parse_mem_high(val: &str, total: u64) -> Result<u64> {
+    let val = val.trim();
+    let bytes = match val.strip_suffix('%') {
+        Some(pct) => {
+            let pct = pct
+                .trim()
+                .parse::<f64>()
+                .map_err(|_| anyhow!("invalid mem_high {:?}", val))?;
+            if !(pct > 0.0 && pct <= 100.0) {
+                bail!("mem_high {:?} is not in (0%, 100%]", val);
+            }
+            (total as f64 * pct / 100.0) as u64
+        }
+        None => val
+            .parse::<u64>()
+            .map_err(|_| anyhow!("invalid mem_high {:?}", val))?,
+    };
+    if bytes == 0 {
+        bail!("mem_high {:?} is zero", val);
+    }
+    if bytes > total {
+        bail!(
+            "mem_high {} is larger than total memory {}",
+            format_size(bytes),
+            format_size(total)
+        );
+    }
+    Ok(bytes)
+}
+
+fn verify_mem_high(spec: &SideloadSpec) -> Result<Option<u64>> {
+    match spec.mem_high.as_ref() {
+        Some(val) => Ok(Some(parse_mem_high(val, *TOTAL_MEMORY as u64)?)),
+        None => Ok(None),
+    }
+}
+
+fn verify_kill_signal(spec: &SideloadSpec) -> Result<Option<i32>> {
+    match spec.kill_signal.as_ref() {
+        Some(name) => Ok(Some(parse_signal(name)?)),
+        None => Ok(None),
+    }
+}
+
+// Parse a cpuset style CPU list, e.g. "0-3,6".
+fn parse_cpu_list(list: &str) -> Result<BTreeSet<u32>> {
+    let mut cpus = BTreeSet::new();
+    for part in list.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (first.parse::<u32>()?, last.parse::<u32>()?),
+            None => {
+                let cpu = part.parse::<u32>()?;
+                (cpu, cpu)
+            }
+        };
+        if first > last {
+            bail!("invalid range {:?}", part);
+        }
+        cpus.extend(first..=last);
+    }
+    if cpus.is_empty() {
+        bail!("no CPU");
+    }
+    Ok(cpus)
+}
+
+fn cpu_list_str(cpus: &BTreeSet<u32>) -> String {
+    let mut ranges: Vec<(u32, u32)> = vec![];
+    for &cpu in cpus.iter() {
+        match ranges.last_mut() {
+            Some(last) if last.1 + 1 == cpu => last.1 = cpu,
+            _ => ranges.push((cpu, cpu)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|(first, last)| {
+            if first == last {
+                format!("{}", first)
+            } else {
+                format!("{}-{}", first, last)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+// The bitmask systemd expects for AllowedCPUs.
+fn cpu_mask(cpus: &BTreeSet<u32>) -> Vec<u8> {
+    let mut mask = vec![0u8; *NR_CPUS / 8 + 1];
+    for &cpu in cpus.iter() {
+        mask[cpu as usize / 8] |= 1 << (cpu % 8);
+    }
+    mask
+}
+
+fn verify_exclusive_cpus(spec: &SideloadSpec) -> Result<Option<BTreeSet<u32>>> {
+    let list = match spec.exclusive_cpus.as_ref() {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let cpus = match parse_cpu_list(list) {
+        Ok(v) => v,
+        Err(e) => bail!("invalid exclusive_cpus {:?} ({})", list, &e),
+    };
+    if let Some(cpu) = cpus.iter().find(|&&cpu| cpu as usize >= *NR_CPUS) {
+        bail!(
+            "exclusive_cpus {:?} contains CPU {} but there are only {}",
+            list,
+            cpu,
+            *NR_CPUS
+        );
+    }
+    if cpus.len() >= *NR_CPUS {
+        bail!("exclusive_cpus {:?} can't take all CPUs", list);
+    }
+    Ok(Some(cpus))
+}
+
+/// Verify that the sys/sideload names are valid and the DEF_IDs exist.
+pub fn verify_target(target: &ApplyTarget, defs: &SideloadDefs) -> Vec<String> {
+    let mut errors = vec![];
+    let mut exclusive: Vec<(&String, BTreeSet<u32>)> = vec![];
+    for (kind, loads) in [
+        ("sysload", &target.sysloads),
+        ("sideload", &target.sideloads),
+    ]
+    .iter()
+    {
+        for (name, id) in loads.iter() {
+            if !SIDE_NAME_RE.is_match(name) {
+                errors.push(format!("invalid {} name {:?}", kind, name));
+            } else if let Err(e) = verify_unit_name_len(name) {
+                errors.push(format!("{} {:?}: {}", kind, name, &e));
+            }
+            match defs.defs.get(id) {
+                Some(spec) if spec.args.is_empty() => {
+                    errors.push(format!("{} {:?}: {:?} has no command", kind, name, id))
+                }
+                Some(spec) => {
+                    if let Err(e) = verify_kconfig(spec) {
+                        errors.push(format!("{} {:?}: {:?} {}", kind, name, id, &e));
+                    }
+                    if let Err(e) = verify_kill_signal(spec) {
+                        errors.push(format!("{} {:?}: {:?} {}", kind, name, id, &e));
+                    }
+                    match verify_exclusive_cpus(spec) {
+                        Ok(Some(cpus)) => exclusive.push((name, cpus)),
+                        Ok(None) => (),
+                        Err(e) => errors.push(format!("{} {:?}: {:?} {}", kind, name, id, &e)),
+                    }
+                }
+                None => errors.push(format!("{} {:?}: unknown ID {:?}", kind, name, id)),
+            }
+        }
+    }
+    for name in target.sysloads.keys() {
+        if target.sideloads.contains_key(name) {
+            errors.push(format!("{:?} is both a sysload and a sideload", name));
+        }
+    }
+    for (i, (name, cpus)) in exclusive.iter().enumerate() {
+        for (other, other_cpus) in exclusive[i + 1..].iter() {
+            if !cpus.is_disjoint(other_cpus) {
+                errors.push(format!(
+                    "{:?} and {:?} have overlapping exclusive_cpus",
+                    name, other
+                ));
+            }
+        }
+    }
+    errors
+}
+
+/// Add the `clones` (NEW_NAME -> SRC_NAME) to the target which contains
+/// SRC_NAME with the same DEF_ID. Returns the clones which couldn't be
+/// added.
+pub fn expand_clones(
+    clones: &BTreeMap<String, String>,
+    sysloads: &mut BTreeMap<String, String>,
+    sideloads: &mut BTreeMap<String, String>,
+) -> Vec<String> {
+    let mut errors = vec![];
+    for (new_name, src_name) in clones.iter() {
+        if !SIDE_NAME_RE.is_match(new_name) {
+            errors.push(format!("invalid clone name {:?}", new_name));
+            continue;
+        }
+        if sysloads.contains_key(new_name) || sideloads.contains_key(new_name) {
+            errors.push(format!(
+                "clone {:?} collides with an existing workload",
+                new_name
+            ));
+            continue;
+        }
+        if let Some(id) = sysloads.get(src_name).cloned() {
+            sysloads.insert(new_name.clone(), id);
+        } else if let Some(id) = sideloads.get(src_name).cloned() {
+            sideloads.insert(new_name.clone(), id);
+        } else {
+            errors.push(format!(
+                "clone {:?}: source {:?} isn't a sys/sideload",
+                new_name, src_name
+            ));
+        }
+    }
+    errors
+}
+
+fn verify_extra_envs(spec: &SideloadSpec) -> Result<()> {
+    let mut keys = HashSet::new();
+    for env in spec.extra_envs.iter() {
+        let key = match env.split_once('=') {
+            Some((key, _)) => key,
+            None => bail!("extra_envs entry {:?} isn't KEY=VALUE", env),
+        };
+        let mut chars = key.chars();
+        let valid = match chars.next() {
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+            }
+            _ => false,
+        };
+        if !valid {
+            bail!("extra_envs entry {:?} has invalid key {:?}", env, key);
+        }
+        if BUILTIN_ENV_KEYS.contains(&key) {
+            bail!("extra_envs can't override built-in {:?}", key);
+        }
+        if !keys.insert(key) {
+            bail!("extra_envs has duplicate {:?}", key);
+        }
+    }
+    Ok(())
+}
+
+// Record that workload `name` is queued because of `why`. Logged only when
+// it's newly queued or the reason changed.
+fn queue_load(queued: &mut BTreeMap<String, String>, what: &str, name: &str, why: String) {
+    if queued.get(name) != Some(&why) {
+        info!("side: Queueing {} {:?}, {}", what, name, &why);
+    }
+    queued.insert(name.into(), why);
+}
+
+/// Order the sideloads in `target` so that each comes after the ones in
+/// its `after` list. Names in `after` which aren't in `target` are ignored.
+/// Ties are broken by name.
+fn sideload_start_order(
+    target: &BTreeMap<String, String>,
+    defs: &SideloadDefs,
+) -> Result<Vec<String>> {
+    let deps = |name: &String| -> Vec<&String> {
+        match target.get(name).and_then(|id| defs.defs.get(id)) {
+            Some(spec) => spec
+                .after
+                .iter()
+                .filter(|dep| target.contains_key(*dep))
+                .collect(),
+            None => vec![],
+        }
+    };
+
+    let mut order = vec![];
+    let mut done = BTreeSet::new();
+    while done.len() < target.len() {
+        let ready: Vec<&String> = target
+            .keys()
+            .filter(|name| {
+                !done.contains(*name) && deps(name).iter().all(|dep| done.contains(*dep))
+            })
+            .collect();
+        if ready.is_empty() {
+            let left: Vec<&String> = target.keys().filter(|name| !done.contains(*name)).collect();
+            bail!("sideloads {:?} have a dependency cycle in after", left);
+        }
+        for name in ready {
+            done.insert(name);
+            order.push(name.clone());
+        }
+    }
+    Ok(order)
+}
+
+// The checks which only depend on the spec itself. Binaries and conflicts
+// with the running workloads are checked separately.
+// fio is optional and only io-load.sh needs it.
+fn needs_fio(spec: &SideloadSpec) -> bool {
+    spec.io_load.is_some()
+        || spec
+            .args
+            .first()
+            .is_some_and(|arg| Path::new(arg).file_name() == Some("io-load.sh".as_ref()))
+}
+
+fn verify_spec(spec: &SideloadSpec) -> Result<()> {
+    verify_kconfig(spec)?;
+    verify_kill_signal(spec)?;
+    verify_mem_high(spec)?;
+    verify_exclusive_cpus(spec)?;
+    verify_extra_envs(spec)?;
+    for dep in spec.after.iter() {
+        if !SIDE_NAME_RE.is_match(dep) {
+            bail!("invalid sideload name {:?} in after", dep);
+        }
+    }
+    if let Some(max_runtime) = spec.max_runtime {
+        if max_runtime == 0 {
+            bail!("has zero max_runtime");
+        }
+        if max_runtime < spec.min_runtime.unwrap_or(0) {
+            bail!(
+                "max_runtime {}s is shorter than min_runtime {}s",
+                max_runtime,
+                spec.min_runtime.unwrap()
+            );
+        }
+    }
+    if spec.make_jobs == Some(0) {
+        bail!("has zero make_jobs");
+    }
+    if let Some(io_load) = spec.io_load.as_ref() {
+        if io_load.read_pct > 100 {
+            bail!("io_load.read_pct {} is over 100", io_load.read_pct);
+        }
+        // fio runs with direct IO.
+        if io_load.block_size == 0 || io_load.block_size % 512 != 0 {
+            bail!(
+                "io_load.block_size {} isn't a positive multiple of 512",
+                io_load.block_size
+            );
+        }
+        if io_load.depth == 0 {
+            bail!("has zero io_load.depth");
+        }
+        if io_load.size < io_load.block_size {
+            bail!(
+                "io_load.size {} is smaller than io_load.block_size {}",
+                io_load.size,
+                io_load.block_size
+            );
+        }
+    }
+    if let Some(net_load) = spec.net_load.as_ref() {
+        if net_load.conns == 0 {
+            bail!("has zero net_load.conns");
+        }
+        if net_load.host.is_some() && net_load.port == 0 {
+            bail!("net_load.host requires net_load.port");
+        }
+    }
+    if spec.scratch_quota == Some(0) {
+        bail!("has zero scratch_quota");
+    }
+    if let Some(weight) = spec.cpu_weight {
+        if !(1..=10000).contains(&weight) {
+            bail!(
+                "cpu_weight {} is out of systemd's CPUWeight range [1, 10000]",
+                weight
+            );
+        }
+    }
+    if let Some(swap_high) = spec.swap_high {
+        if swap_high > *TOTAL_SWAP as u64 {
+            bail!(
+                "swap_high {} is larger than total swap {}",
+                format_size(swap_high),
+                format_size(*TOTAL_SWAP)
+            );
+        }
+    }
+    let io_max = &spec.io_max;
+    for v in [io_max.rbps, io_max.wbps, io_max.riops, io_max.wiops].iter() {
+        if *v == Some(0) {
+            bail!("has zero io_max limit");
+        }
+    }
+    Ok(())
+}
+
+/// Resolve the binaries of all defs and return the (DEF_ID, BINARY) pairs
+/// which couldn't be resolved.
+pub fn check_side_defs(cfg: &Config, defs: &SideloadDefs) -> Vec<(String, String)> {
+    let mut missing = vec![];
+    for (id, spec) in defs.defs.iter() {
+        for cmd in [&spec.args, &spec.cleanup_cmd, &spec.warmup_args].iter() {
+            if let Some(bin) = cmd.first() {
+                if resolve_side_bin(cfg, bin).is_none() {
+                    missing.push((id.clone(), bin.clone()));
+                }
+            }
+        }
+        if spec.args.is_empty() {
+            missing.push((id.clone(), "".into()));
+        }
+    }
+    missing
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ScratchKind {
+    Sys,
+    Side,
+}
+
+impl ScratchKind {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Sys => "sys",
+            Self::Side => "side",
+        }
+    }
+
+    fn root<'a>(&self, cfg: &'a Config) -> &'a str {
+        match self {
+            Self::Sys => &cfg.sys_scr_path,
+            Self::Side => &cfg.side_scr_path,
+        }
+    }
+
+    // scr_keep dirs outlive their workloads and would pin the tmpfs
+    // forever, so they always stay on the scratch device.
+    fn tmpfs(&self, cfg: &Config, spec: &SideloadSpec) -> bool {
+        let enabled = match self {
+            Self::Sys => cfg.sys_scr_tmpfs,
+            Self::Side => cfg.side_scr_tmpfs,
+        };
+        enabled && !spec.scr_external && !spec.scr_keep
+    }
+}
+
+// Expand the scratch dir template into a path relative to the kind's
+// scratch root. The result may not escape the root.
+fn expand_scr_template(
+    tmpl: &str,
+    name: &str,
+    kind: ScratchKind,
+    tag: &str,
+    date: &str,
+) -> Result<String> {
+    let rel = tmpl
+        .replace("{name}", name)
+        .replace("{kind}", kind.name())
+        .replace("{tag}", tag)
+        .replace("{date}", date);
+
+    if rel.is_empty()
+        || !Path::new(&rel)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+    {
+        bail!(
+            "scratch dir template {:?} expands to invalid path {:?}",
+            tmpl,
+            &rel
+        );
+    }
+    Ok(rel)
+}
+
+fn statvfs(path: &str) -> Result<libc::statvfs> {
+    let cpath = CString::new(path)?;
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(cpath.as_ptr(), &mut st) } < 0 {
+        bail!(
+            "statvfs on {:?} failed ({:?})",
+            path,
+            io::Error::last_os_error()
+        );
+    }
+    Ok(st)
+}
+
+// Turn a failure to write into the sideloader jobs dir into an actionable
+// error. A read-only filesystem is a setup problem distinct from the dir
+// missing.
+fn jobs_path_err(jobs_path: &str, e: &io::Error) -> anyhow::Error {
+    match e.raw_os_error() {
+        Some(libc::EROFS) => anyhow!(
+            "sideloader jobs path {:?} is on a read-only filesystem",
+            jobs_path
+        ),
+        Some(libc::ENOENT) => anyhow!("sideloader jobs path {:?} doesn't exist", jobs_path),
+        _ => anyhow!(
+            "failed to write to sideloader jobs path {:?} ({})",
+            jobs_path,
+            e
+        ),
+    }
+}
+
+/// Verify that sideloader job files can be created in `jobs_path` so that
+/// setup problems are caught before any sideload is applied.
+pub fn check_jobs_path(jobs_path: &str) -> Result<()> {
+    let cpath = CString::new(jobs_path)?;
+    if unsafe { libc::access(cpath.as_ptr(), libc::W_OK) } < 0 {
+        return Err(jobs_path_err(jobs_path, &io::Error::last_os_error()));
+    }
+    if !Path::new(jobs_path).is_dir() {
+        bail!("sideloader jobs path {:?} is not a directory", jobs_path);
+    }
+    Ok(())
+}
+
+fn statvfs_avail(path: &str) -> Result<u64> {
+    let st = statvfs(path)?;
+    Ok(st.f_bavail as u64 * st.f_frsize as u64)
+}
+
+fn statvfs_size(path: &str) -> Result<u64> {
+    let st = statvfs(path)?;
+    Ok(st.f_blocks as u64 * st.f_frsize as u64)
+}
+
+// Bytes allocated under `path` without crossing into other filesystems.
+// Entries which disappear while walking are skipped.
+fn dir_usage(path: &Path, dev: u64) -> u64 {
+    let md = match fs::symlink_metadata(path) {
+        Ok(v) if v.dev() == dev => v,
+        _ => return 0,
+    };
+    let mut usage = md.blocks() * 512;
+    if md.is_dir() {
+        if let Ok(entries) = fs::read_dir(path) {
+            for entry in entries.filter_map(|x| x.ok()) {
+                usage += dir_usage(&entry.path(), dev);
+            }
+        }
+    }
+    usage
+}
+
+/// Check whether `svc_name`, started at `started_at`, has been running for
+/// SWAP_HEALTHY_DUR. Returns Ok(false) while it's still coming up.
+fn check_healthy(
+    svc_name: &str,
+    started_at: Instant,
+    running_since: &mut Option<Instant>,
+    now: Instant,
+) -> Result<bool> {
+    match systemd::Unit::new_sys(svc_name.into())?.state {
+        systemd::UnitState::Running => {
+            let since = *running_since.get_or_insert(now);
+            Ok(now.duration_since(since) >= SWAP_HEALTHY_DUR)
+        }
+        systemd::UnitState::Exited => bail!("exited prematurely"),
+        systemd::UnitState::Failed(why) => bail!("failed ({})", &why),
+        _ if running_since.is_some() => bail!("stopped running"),
+        _ if now.duration_since(started_at) >= SWAP_START_TIMEOUT => {
+            bail!("not running after {:.1}s", SWAP_START_TIMEOUT.as_secs_f64())
+        }
+        _ => Ok(false),
+    }
+}
+
+// Wrap `args` with env -i so that only `envs` and a fixed PATH are visible
+// to the workload. The envs have to be on the command line as env -i drops
+// the ones systemd sets too.
+fn clean_env_args(args: Vec<String>, envs: &[String]) -> Vec<String> {
+    let mut wrapped = vec![
+        CLEAN_ENV_BIN.to_string(),
+        "-i".into(),
+        format!("PATH={}", CLEAN_ENV_PATH),
+    ];
+    wrapped.extend(envs.iter().cloned());
+    wrapped.extend(args);
+    wrapped
+}
+
+/// How much longer the workload has to run to satisfy its min_runtime.
+fn min_runtime_left(spec: &SideloadSpec, started_at: Instant, now: Instant) -> Option<Duration> {
+    let min_runtime = Duration::from_secs(spec.min_runtime? as u64);
+    let ran = now.duration_since(started_at);
+    if ran < min_runtime {
+        Some(min_runtime - ran)
+    } else {
+        None
+    }
+}
+
+/// Split the active workloads missing from `target` into the ones which can
+/// be stopped now and the ones held back by min_runtime.
+fn plan_removals(
+    target: &BTreeMap<String, String>,
+    active: &[(&String, &SideloadSpec, Instant)],
+    now: Instant,
+) -> (Vec<String>, Vec<String>) {
+    let mut to_stop = vec![];
+    let mut deferred = vec![];
+    for (name, spec, started_at) in active.iter() {
+        if target.contains_key(*name) {
+            continue;
+        }
+        match min_runtime_left(spec, *started_at, now) {
+            Some(_) => deferred.push(name.to_string()),
+            None => to_stop.push(name.to_string()),
+        }
+    }
+    (to_stop, deferred)
+}
+
+/// Whether the workload has run past its max_runtime.
+fn max_runtime_exceeded(spec: &SideloadSpec, started_at: Instant, now: Instant) -> bool {
+    match spec.max_runtime {
+        Some(max_runtime) => {
+            now.duration_since(started_at) >= Duration::from_secs(max_runtime as u64)
+        }
+        None => false,
+    }
+}
+
+/// Why the workload should be stopped for its scratch dir using `usage`
+/// bytes, None if it's within scratch_quota.
+fn scratch_quota_exceeded(spec: &SideloadSpec, usage: u64) -> Option<String> {
+    match spec.scratch_quota {
+        Some(quota) if usage > quota => Some(format!(
+            "scratch_quota {} exceeded ({})",
+            format_size(quota),
+            format_size(usage)
+        )),
+        _ => None,
+    }
+}
+
+// What SideRunner::restore() does with a sysload in the snapshot.
+#[derive(Debug, PartialEq)]
+enum RestoreAction {
+    // Stopped and cleaned up by the previous instance.
+    Gone,
+    Adopt,
+    Clean,
+}
+
+fn restore_action(
+    state: &systemd::UnitState,
+    scr_exists: bool,
+    id: &str,
+    target_id: Option<&String>,
+) -> RestoreAction {
+    match state {
+        systemd::UnitState::NotFound if !scr_exists => RestoreAction::Gone,
+        systemd::UnitState::Running if target_id.map(|v| v.as_str()) == Some(id) => {
+            RestoreAction::Adopt
+        }
+        _ => RestoreAction::Clean,
+    }
+}
+
+// Names of the `prefix`ed service units with a cgroup under `cgrp` which
+// aren't in `known`.
+fn unknown_units(cgrp: &str, prefix: &str, known: &BTreeSet<String>) -> Vec<String> {
+    let entries = match fs::read_dir(cgrp) {
+        Ok(v) => v,
+        Err(_) => return vec![],
+    };
+    let mut units: Vec<String> = entries
+        .filter_map(|ent| ent.ok()?.file_name().into_string().ok())
+        .filter(|name| {
+            name.starts_with(prefix) && name.ends_with(".service") && !known.contains(name)
+        })
+        .collect();
+    units.sort();
+    units
+}
+
+// Forget all sys or sideloads. The active ones are dropped, which stops
+// their units.
+fn clear_loads<T>(
+    active: &mut BTreeMap<String, T>,
+    queued: &mut BTreeMap<String, String>,
+    pending_rm: &mut BTreeSet<String>,
+) {
+    active.clear();
+    queued.clear();
+    pending_rm.clear();
+}
+
+// A make-before-break swap to IN_NAME. While pending, poll_swap() checks on
+// IN_NAME from each apply_swaps() instead of blocking the cmd loop.
+struct Swap {
+    out_id: String,
+    started_at: Instant,
+    running_since: Option<Instant>,
+    rep: SwapReport,
+}
+
+pub struct SideRunner {
+    cfg: Arc<Config>,
+    sysloads: BTreeMap<String, Sysload>,
+    sideloads: BTreeMap<String, Sideload>,
+    // Queued workloads and why they're waiting.
+    queued_sysloads: BTreeMap<String, String>,
+    queued_sideloads: BTreeMap<String, String>,
+    pending_rm_sysloads: BTreeSet<String>,
+    pending_rm_sideloads: BTreeSet<String>,
+    auto_stopped_sysloads: BTreeMap<String, String>,
+    auto_stopped_sideloads: BTreeMap<String, String>,
+    // Tiers of the target workloads for the report placeholders of the
+    // queued and auto-stopped ones.
+    sysload_tiers: BTreeMap<String, Tier>,
+    sideload_tiers: BTreeMap<String, Tier>,
+    warmups: BTreeMap<String, Warmup>,
+    scr_free_cache: BTreeMap<ScratchKind, (Instant, u64)>,
+    scr_usage_cache: Option<(Instant, BTreeMap<String, u64>)>,
+    scr_dev_lost: bool,
+    swaps: BTreeMap<String, Swap>,
+    killed: BTreeSet<String>,
+    work_excl_cpus: BTreeSet<u32>,
+    last_snapshot: Option<WorkloadSnapshot>,
+    clock: Arc<dyn Clock>,
+    cleanup: Arc<CleanupQueue>,
+    scr_mounts: Arc<ScrMounts>,
+}
+
+impl SideRunner {
+    pub fn new(cfg: Arc<Config>) -> Self {
+        Self::with_clock(cfg, Arc::new(RealClock))
+    }
+
+    pub fn with_clock(cfg: Arc<Config>, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            cfg,
+            sysloads: BTreeMap::new(),
+            sideloads: BTreeMap::new(),
+            queued_sysloads: BTreeMap::new(),
+            queued_sideloads: BTreeMap::new(),
+            pending_rm_sysloads: BTreeSet::new(),
+            pending_rm_sideloads: BTreeSet::new(),
+            auto_stopped_sysloads: BTreeMap::new(),
+            auto_stopped_sideloads: BTreeMap::new(),
+            sysload_tiers: BTreeMap::new(),
+            sideload_tiers: BTreeMap::new(),
+            warmups: BTreeMap::new(),
+            scr_free_cache: BTreeMap::new(),
+            scr_usage_cache: None,
+            scr_dev_lost: false,
+            swaps: BTreeMap::new(),
+            killed: BTreeSet::new(),
+            work_excl_cpus: BTreeSet::new(),
+            last_snapshot: None,
+            clock,
+            cleanup: Arc::new(CleanupQueue::new()),
+            scr_mounts: Default::default(),
+        }
+    }
+
+    /// Stop all sys/sideloads. Dropping a sideload removes its job file
+    /// and stops its unit.
+    pub fn stop(&mut self) {
+        clear_loads(
+            &mut self.sysloads,
+            &mut self.queued_sysloads,
+            &mut self.pending_rm_sysloads,
+        );
+        clear_loads(
+            &mut self.sideloads,
+            &mut self.queued_sideloads,
+            &mut self.pending_rm_sideloads,
+        );
+        self.warmups.clear();
+        self.update_work_cpus();
+    }
+
+    /// Keep hashd and the rest of workload.slice off the exclusive_cpus of
+    /// the active sys/sideloads. Their cpuset partitions would do that
+    /// only if valid, see CpuPartition.
+    pub fn update_work_cpus(&mut self) {
+        let mut excl = BTreeSet::new();
+        for spec in self
+            .sysloads
+            .values()
+            .map(|sl| &sl.spec)
+            .chain(self.sideloads.values().map(|sl| &sl.spec))
+        {
+            if let Ok(Some(cpus)) = verify_exclusive_cpus(spec) {
+                excl.extend(cpus);
+            }
+        }
+        if excl == self.work_excl_cpus {
+            return;
+        }
+
+        let allowed: BTreeSet<u32> = (0..*NR_CPUS as u32)
+            .filter(|cpu| !excl.contains(cpu))
+            .collect();
+        if allowed.is_empty() {
+            warn!("side: exclusive_cpus cover all CPUs, not restricting hashd");
+            return;
+        }
+        let name = Slice::Work.name();
+        match systemd::Unit::new_sys(name.into()).and_then(|mut unit| {
+            unit.set_prop("AllowedCPUs", systemd::Prop::Bytes(cpu_mask(&allowed)))
+        }) {
+            Ok(()) => {
+                info!(
+                    "side: Restricted {:?} to CPUs {}",
+                    name,
+                    cpu_list_str(&allowed)
+                );
+                self.work_excl_cpus = excl;
+            }
+            Err(e) => warn!("side: Failed to set AllowedCPUs on {:?} ({:?})", name, &e),
+        }
+    }
+
+    /// Wait for the scratch dirs of the dropped sys/sideloads to be
+    /// removed. Called on exit.
+    pub fn flush_cleanup(&self) {
+        self.cleanup.flush();
+    }
+
+    /// Remove the scratch dirs which a previous instance queued for removal
+    /// but didn't finish. Called on startup.
+    pub fn sweep_scr_trash(&self) {
+        let depth = self.cfg.scr_template.split('/').count();
+        let roots: BTreeSet<&str> = [ScratchKind::Sys, ScratchKind::Side]
+            .iter()
+            .map(|kind| kind.root(&self.cfg))
+            .collect();
+        for root in roots.into_iter() {
+            let nr = self.cleanup.sweep(
+                root,
+                depth,
+                self.cfg.scr_umount,
+                self.cfg.scr_rm_timeout,
+                self.clock.clone(),
+            );
+            if nr > 0 {
+                info!(
+                    "side: Removing {} leftover scratch dirs under {:?}",
+                    nr, root
+                );
+            }
+        }
+    }
+
+    /// SIGKILL all processes of sys/sideload `name` right away without
+    /// going through the stop timeout and cleanup_cmd and drop the entry.
+    /// The kill is reported until `name` is dropped from retain_killed().
+    /// If `name` is still in the target, the next apply starts it again.
+    pub fn kill(&mut self, name: &str) -> Result<()> {
+        if let Some(mut sysload) = self.sysloads.remove(name) {
+            warn!("side: Force-killing sysload {:?}", name);
+            sysload.killed = true;
+            sysload.svc.unit.kill(libc::SIGKILL)?;
+        } else if let Some(mut sideload) = self.sideloads.remove(name) {
+            warn!("side: Force-killing sideload {:?}", name);
+            sideload.killed = true;
+            sideload.unit.kill(libc::SIGKILL)?;
+        } else {
+            bail!("no sys/sideload named {:?}", name);
+        }
+        self.killed.insert(name.to_string());
+        Ok(())
+    }
+
+    /// Forget the kills of the sys/sideloads which aren't in `kill` anymore.
+    pub fn retain_killed(&mut self, kill: &BTreeSet<String>) {
+        self.killed.retain(|name| kill.contains(name));
+    }
+
+    pub fn report_killed(&self) -> Vec<String> {
+        self.killed.iter().cloned().collect()
+    }
+
+    /// Start `in_name` to replace running sys/sideload `out_name` without a
+    /// window where neither is running. `out_name` is left alone until
+    /// poll_swap() sees `in_name` stay up for SWAP_HEALTHY_DUR.
+    fn start_swap(
+        &mut self,
+        out_name: &str,
+        in_name: &str,
+        in_id: &str,
+        defs: &SideloadDefs,
+        bench: &BenchKnobs,
+    ) -> Result<()> {
+        let is_sys = if self.sysloads.contains_key(out_name) {
+            true
+        } else if self.sideloads.contains_key(out_name) {
+            false
+        } else {
+            bail!("{:?} isn't running", out_name);
+        };
+        if self.contains(in_name) {
+            bail!("{:?} is already running", in_name);
+        }
+
+        let spec = self.verify_and_lookup_svc(in_name, &in_id.to_string(), defs)?;
+        info!("side: Swapping {:?} for {:?}", out_name, in_name);
+
+        if is_sys {
+            self.start_sysload(in_name, in_id, spec, bench)
+        } else {
+            self.start_sideload(in_name, in_id, spec, bench)
+        }
+    }
+
+    /// Check on the pending swap to `in_name`. Once it has stayed up for
+    /// SWAP_HEALTHY_DUR, the workload it replaces is stopped. If it fails
+    /// to, it's stopped and the one it replaces is left alone.
+    fn poll_swap(&mut self, in_name: &str, swap: &mut Swap) {
+        let now = self.clock.now();
+        let res = if self.sysloads.contains_key(in_name) {
+            check_healthy(
+                &sysload_svc_name(in_name),
+                swap.started_at,
+                &mut swap.running_since,
+                now,
+            )
+        } else if self.sideloads.contains_key(in_name) {
+            check_healthy(
+                &sideload_svc_name(in_name),
+                swap.started_at,
+                &mut swap.running_since,
+                now,
+            )
+        } else {
+            Err(anyhow!("stopped"))
+        };
+
+        match res {
+            Ok(false) => return,
+            Ok(true) => {
+                let out_name = &swap.rep.out;
+                self.sysloads.remove(out_name);
+                self.sideloads.remove(out_name);
+                info!("side: Swapped {:?} for {:?}", out_name, in_name);
+                swap.rep.ok = true;
+            }
+            Err(e) => {
+                warn!(
+                    "side: {:?} didn't come up, rolling back to {:?} ({})",
+                    in_name, &swap.rep.out, &e
+                );
+                self.sysloads.remove(in_name);
+                self.sideloads.remove(in_name);
+                swap.rep.error = Some(format!("{:?} didn't come up ({})", in_name, &e));
+            }
+        }
+        swap.rep.pending = false;
+    }
+
+    /// Perform the swaps in `swaps` (IN_NAME -> OUT_NAME) which the targets
+    /// call for and return the sysload and sideload targets to apply. While
+    /// a swap is pending, both are kept in the returned targets. Failed
+    /// swaps keep OUT_NAME in and IN_NAME out of the returned targets for as
+    /// long as the pair stays in `swaps`.
+    pub fn apply_swaps(
+        &mut self,
+        swaps: &BTreeMap<String, String>,
+        sysloads: &BTreeMap<String, String>,
+        sideloads: &BTreeMap<String, String>,
+        defs: &SideloadDefs,
+        bench: &BenchKnobs,
+    ) -> (BTreeMap<String, String>, BTreeMap<String, String>) {
+        let mut sys_target = sysloads.clone();
+        let mut side_target = sideloads.clone();
+
+        self.swaps
+            .retain(|in_name, swap| swaps.get(in_name) == Some(&swap.rep.out));
+
+        for (in_name, out_name) in swaps.iter() {
+            let target = if sys_target.contains_key(in_name) {
+                &mut sys_target
+            } else if side_target.contains_key(in_name) {
+                &mut side_target
+            } else {
+                continue;
+            };
+
+            if let Some(mut swap) = self.swaps.remove(in_name) {
+                if swap.rep.pending {
+                    self.poll_swap(in_name, &mut swap);
+                }
+                if swap.rep.pending {
+                    target.insert(out_name.clone(), swap.out_id.clone());
+                } else if !swap.rep.ok {
+                    target.remove(in_name);
+                    target.insert(out_name.clone(), swap.out_id.clone());
+                }
+                self.swaps.insert(in_name.clone(), swap);
+                continue;
+            }
+
+            if target.contains_key(out_name) || self.contains(in_name) {
+                continue;
+            }
+            let out_id = match (self.sysloads.get(out_name), self.sideloads.get(out_name)) {
+                (Some(sl), _) => sl.id.clone(),
+                (None, Some(sl)) => sl.id.clone(),
+                (None, None) => continue,
+            };
+
+            let in_id = target.get(in_name).unwrap().clone();
+            let mut rep = SwapReport {
+                out: out_name.clone(),
+                ok: false,
+                pending: true,
+                error: None,
+            };
+            match self.start_swap(out_name, in_name, &in_id, defs, bench) {
+                Ok(()) => {
+                    target.insert(out_name.clone(), out_id.clone());
+                }
+                Err(e) => {
+                    target.remove(in_name);
+                    target.insert(out_name.clone(), out_id.clone());
+                    rep.pending = false;
+                    rep.error = Some(format!("{:#}", &e));
+                }
+            }
+            self.swaps.insert(
+                in_name.clone(),
+                Swap {
+                    out_id,
+                    started_at: self.clock.now(),
+                    running_since: None,
+                    rep,
+                },
+            );
+        }
+
+        (sys_target, side_target)
+    }
+
+    pub fn report_swaps(&self) -> BTreeMap<String, SwapReport> {
+        self.swaps
+            .iter()
+            .map(|(in_name, swap)| (in_name.clone(), swap.rep.clone()))
+            .collect()
+    }
+
+    /// Freeze or thaw running sys/sideload `name`. Sysloads are frozen
+    /// with the cgroup freezer directly, sideloads through the sideloader.
+    pub fn set_frozen(&mut self, name: &str, frozen: bool) -> Result<()> {
+        if let Some(sl) = self.sysloads.get_mut(name) {
+            write_cgroup_freeze(&sysload_cgrp(name), frozen)?;
+            sl.frozen = frozen;
+            Ok(())
+        } else if self.sideloads.contains_key(name) {
+            self.set_sideload_frozen(name, frozen)
+        } else {
+            bail!("{:?} is not running", name);
+        }
+    }
+
+    // The sideloader owns the freezer of its jobs and thaws whatever it
+    // didn't freeze itself once it isn't overloaded. Rewrite the job file in
+    // place instead, which the daemon picks up on its next pass and holds
+    // the job frozen without counting it against frozen_expiration.
+    fn set_sideload_frozen(&mut self, name: &str, frozen: bool) -> Result<()> {
+        let sl = match self.sideloads.get_mut(name) {
+            Some(v) => v,
+            None => bail!("sideload {:?} is not running", name),
+        };
+        let mut jobs = SideloaderJobs::load(&sl.job_path)?;
+        for job in jobs.sideloader_jobs.iter_mut() {
+            job.frozen = frozen;
+        }
+        jobs.save(&sl.job_path)?;
+        sl.frozen = frozen;
+        sl.last_report = None;
+        Ok(())
+    }
+
+    /// Freeze sideload `name` in place through the sideloader, e.g. to
+    /// pause a compile job without losing its progress.
+    pub fn freeze_sideload(&mut self, name: &str) -> Result<()> {
+        self.set_sideload_frozen(name, true)
+    }
+
+    pub fn thaw_sideload(&mut self, name: &str) -> Result<()> {
+        self.set_sideload_frozen(name, false)
+    }
+
+    pub fn sideload_frozen(&self, name: &str) -> Option<bool> {
+        self.sideloads.get(name).map(|sl| sl.frozen)
+    }
+
+    /// Check every def in `defs` whether it's referenced or not and return
+    /// the problems found, e.g. unresolvable binaries or invalid limits, so
+    /// that broken defs are noticed before they're used.
+    pub fn validate_defs(&self, defs: &SideloadDefs) -> Result<Vec<String>> {
+        let mut problems = vec![];
+        for (id, bin) in check_side_defs(&self.cfg, defs) {
+            if bin.is_empty() {
+                problems.push(format!("{:?} has no command", id));
+            } else {
+                problems.push(format!("{:?} has unresolvable binary {:?}", id, bin));
+            }
+        }
+        for (id, spec) in defs.defs.iter() {
+            if let Err(e) = verify_spec(spec) {
+                problems.push(format!("{:?} {}", id, &e));
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Re-validate the active sys/sideloads against reloaded `defs`. The ones
+    /// whose definitions are gone or no longer valid are kept running as-is
+    /// and only warned about.
+    pub fn check_active_defs(&self, defs: &SideloadDefs) {
+        let sys = self.sysloads.iter().map(|(k, v)| ("sysload", k, &v.id));
+        let side = self.sideloads.iter().map(|(k, v)| ("sideload", k, &v.id));
+        for (kind, name, id) in sys.chain(side) {
+            if !defs.defs.contains_key(id) {
+                warn!(
+                    "side: Definition {:?} of active {} {:?} is gone, leaving it running",
+                    id, kind, name
+                );
+            } else if let Err(e) = self.verify_and_lookup_svc(name, id, defs) {
+                warn!(
+                    "side: Definition {:?} of active {} {:?} is invalid, leaving it running ({})",
+                    id, kind, name, &e
+                );
+            }
+        }
+    }
+
+    /// The last `n` lines of the stdout and stderr of sys/sideload `name`
+    /// which are captured in its scratch dir.
+    pub fn tail_log(&self, name: &str, n: usize) -> Result<(Vec<String>, Vec<String>)> {
+        let scr_path = match (self.sysloads.get(name), self.sideloads.get(name)) {
+            (Some(sysload), _) => &sysload.scr_path,
+            (None, Some(sideload)) => &sideload.scr_path,
+            (None, None) => bail!("{:?} is not running", name),
+        };
+        Ok((
+            tail_file(&stdout_log_path(scr_path), n)?,
+            tail_file(&stderr_log_path(scr_path), n)?,
+        ))
+    }
+
+    fn log_failure(&self, name: &str) {
+        match self.tail_log(name, FAILURE_LOG_LINES) {
+            Ok((_, stderr)) if !stderr.is_empty() => warn!(
+                "side: {:?} failed, last lines of stderr:\n{}",
+                name,
+                stderr.join("\n")
+            ),
+            Ok(_) => warn!("side: {:?} failed with empty stderr", name),
+            Err(e) => warn!("side: {:?} failed, no stderr log ({:?})", name, &e),
+        }
+    }
+
+    /// Write the equivalent static unit file of each active sys/sideload
+    /// into `dir` with all the properties the runner applied. Stale unit
+    /// files are removed so that `dir` reflects the current configuration.
+    /// Returns the number of unit files written.
+    pub fn dump_units(&self, dir: &str) -> Result<usize> {
+        fs::create_dir_all(dir)?;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map(|x| x == "service").unwrap_or(false) {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let mut nr = 0;
+        for (name, sysload) in self.sysloads.iter() {
+            let svc = &sysload.svc;
+            let mut props = resctl_unit_props(&svc.unit.resctl);
+            for (k, v) in svc.extra_props.iter() {
+                props.insert(k.clone(), unit_prop_str(v));
+            }
+            let text = format_unit_file(
+                &format!("rd-agent sysload {} ({})", name, &sysload.id),
+                &svc.args,
+                &svc.envs,
+                &props,
+                &sysload.cg_knobs.knobs,
+            );
+            fs::write(format!("{}/{}", dir, &svc.unit.name), text)?;
+            nr += 1;
+        }
+
+        for (name, sideload) in self.sideloads.iter() {
+            let (args, envs) = match SideloaderJobs::load(&sideload.job_path) {
+                Ok(jobs) if !jobs.sideloader_jobs.is_empty() => {
+                    let job = &jobs.sideloader_jobs[0];
+                    (job.args.clone(), job.envs.clone())
+                }
+                Ok(_) => bail!("no job in {:?}", &sideload.job_path),
+                Err(e) => bail!("failed to load {:?} ({})", &sideload.job_path, &e),
+            };
+            let mut props = resctl_unit_props(&sideload.unit.resctl);
+            props.insert("Slice".into(), Slice::Side.name().into());
+            props.insert("WorkingDirectory".into(), sideload.scr_path.clone());
+            if let Some(weight) = sideload.spec.cpu_weight {
+                props.insert("CPUWeight".into(), format!("{}", weight));
+            }
+            let text = format_unit_file(
+                &format!("rd-agent sideload {} ({})", name, &sideload.id),
+                &args,
+                &envs,
+                &props,
+                &sideload.cg_knobs.knobs,
+            );
+            fs::write(format!("{}/{}", dir, &sideload.unit.name), text)?;
+            nr += 1;
+        }
+        Ok(nr)
+    }
+
+    /// Names of the running sys/sideloads in the Background tier.
+    pub fn background_workloads(&self) -> Vec<String> {
+        let sys = self.sysloads.iter().filter(|(_, sl)| {
+            sl.spec.tier.unwrap_or_else(|| Slice::Sys.tier()) == Tier::Background
+        });
+        let side = self.sideloads.iter().filter(|(_, sl)| {
+            sl.spec.tier.unwrap_or_else(|| Slice::Side.tier()) == Tier::Background
+        });
+        sys.map(|(name, _)| name.clone())
+            .chain(side.map(|(name, _)| name.clone()))
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.sysloads.contains_key(name) || self.sideloads.contains_key(name)
+    }
+
+    pub fn has_queued(&self) -> bool {
+        !self.queued_sysloads.is_empty() || !self.queued_sideloads.is_empty()
+    }
+
+    pub fn has_warmups(&self) -> bool {
+        !self.warmups.is_empty()
+    }
+
+    pub fn has_pending_removal(&self) -> bool {
+        !self.pending_rm_sysloads.is_empty() || !self.pending_rm_sideloads.is_empty()
+    }
+
+    /// Free bytes available to unprivileged users on the scratch device
+    /// of `kind`. Cached for a second to avoid hammering statvfs.
+    pub fn scratch_free(&mut self, kind: ScratchKind) -> Result<u64> {
+        let now = self.clock.now();
+        if let Some((at, free)) = self.scr_free_cache.get(&kind) {
+            if now.duration_since(*at) < SCR_FREE_CACHE_DUR {
+                return Ok(*free);
+            }
+        }
+
+        let free = statvfs_avail(kind.root(&self.cfg))?;
+        self.scr_free_cache.insert(kind, (now, free));
+        Ok(free)
+    }
+
+    // Why a heavy workload can't start for lack of scratch free space,
+    // None if it can.
+    fn heavy_scr_free_short(&mut self, kind: ScratchKind) -> Option<String> {
+        let min = self.cfg.heavy_scr_free?;
+        match self.scratch_free(kind) {
+            Ok(free) if free < min => Some(format!(
+                "scratch free space {} is lower than {}",
+                format_size(free),
+                format_size(min)
+            )),
+            Ok(_) => None,
+            Err(e) => {
+                warn!("side: Failed to check scratch free space ({:?})", &e);
+                None
+            }
+        }
+    }
+
+    /// Total bytes used by the scratch dirs of all running sys/sideloads.
+    /// Walking the dirs is expensive, so the result is cached for
+    /// SCR_USAGE_CACHE_DUR.
+    pub fn scratch_usage(&mut self) -> u64 {
+        self.scratch_usages().values().sum()
+    }
+
+    // Bytes used by each scratch dir of the running sys/sideloads, cached
+    // for SCR_USAGE_CACHE_DUR.
+    fn scratch_usages(&mut self) -> &BTreeMap<String, u64> {
+        let now = self.clock.now();
+        let fresh = match self.scr_usage_cache.as_ref() {
+            Some((at, _)) => now.duration_since(*at) < SCR_USAGE_CACHE_DUR,
+            None => false,
+        };
+        if !fresh {
+            // Workloads may share scratch dirs depending on the template.
+            let paths: BTreeSet<&str> = self
+                .sysloads
+                .values()
+                .map(|x| x.scr_path.as_str())
+                .chain(self.sideloads.values().map(|x| x.scr_path.as_str()))
+                .collect();
+            let usages = paths
+                .into_iter()
+                .filter_map(|path| {
+                    let dev = fs::metadata(path).ok()?.dev();
+                    Some((path.to_string(), dir_usage(Path::new(path), dev)))
+                })
+                .collect();
+            self.scr_usage_cache = Some((now, usages));
+        }
+        &self.scr_usage_cache.as_ref().unwrap().1
+    }
+
+    /// Refresh the cached scratch usage if the scratch budget or
+    /// reap_over_quota() needs it. Called from the report thread so that
+    /// the cmd loop doesn't have to walk the scratch dirs.
+    pub fn refresh_scratch_usage(&mut self) {
+        let quota = self
+            .sysloads
+            .values()
+            .map(|x| &x.spec)
+            .chain(self.sideloads.values().map(|x| &x.spec))
+            .any(|spec| spec.scratch_quota.is_some());
+        if quota || self.cfg.scr_budget.is_some() {
+            self.scratch_usages();
+        }
+    }
+
+    fn scratch_budget(&self) -> Result<Option<u64>> {
+        match self.cfg.scr_budget {
+            Some(frac) => Ok(Some(
+                (statvfs_size(&self.cfg.scr_path)? as f64 * frac) as u64,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    // Returns false if the heavy workload shouldn't be started because the
+    // scratch usage is over the hard budget.
+    // Why a heavy workload can't start because the scratch usage is over
+    // the hard budget, None if it can.
+    fn scr_budget_exceeded(&mut self, name: &str) -> Option<String> {
+        let budget = match self.scratch_budget() {
+            Ok(Some(v)) => v,
+            Ok(None) => return None,
+            Err(e) => {
+                warn!("side: Failed to determine scratch budget ({:?})", &e);
+                return None;
+            }
+        };
+        let usage = self.scratch_usage();
+        if usage <= budget {
+            return None;
+        }
+
+        let why = format!(
+            "scratch usage {} is over budget {}",
+            format_size(usage),
+            format_size(budget)
+        );
+        if self.cfg.scr_budget_hard {
+            return Some(why);
+        }
+        warn!("side: Starting heavy workload {:?}, {}", name, &why);
+        None
+    }
+
+    // Why heavy workload `name` can't start because of its scratch space,
+    // None if it can.
+    fn heavy_scr_blocked(&mut self, kind: ScratchKind, name: &str) -> Option<String> {
+        self.heavy_scr_free_short(kind)
+            .or_else(|| self.scr_budget_exceeded(name))
+    }
+
+    pub fn report_scr_budget(&mut self) -> ScrBudgetReport {
+        let budget = match self.scratch_budget() {
+            Ok(Some(v)) => v,
+            Ok(None) => return Default::default(),
+            Err(e) => {
+                warn!("side: Failed to determine scratch budget ({:?})", &e);
+                return Default::default();
+            }
+        };
+        let usage = self.scratch_usage();
+        ScrBudgetReport {
+            usage,
+            budget,
+            hard: self.cfg.scr_budget_hard,
+            over: usage > budget,
+        }
+    }
+
+    // The scratch device can be yanked from under us (USB, iSCSI). Detect
+    // it by checking both the block device and the scratch root.
+    fn check_scr_dev(&mut self) -> bool {
+        let cfg = &self.cfg;
+        let devnr_path = format!("/sys/dev/block/{}:{}", cfg.scr_devnr.0, cfg.scr_devnr.1);
+        let lost = !Path::new(&devnr_path).exists() || fs::metadata(&cfg.scr_path).is_err();
+
+        if lost && !self.scr_dev_lost {
+            error!(
+                "side: Scratch device {:?} for {:?} is lost, all sys/sideloads are failed",
+                &cfg.scr_dev, &cfg.scr_path
+            );
+        } else if !lost && self.scr_dev_lost {
+            info!("side: Scratch device {:?} is back", &cfg.scr_dev);
+        }
+        self.scr_dev_lost = lost;
+        lost
+    }
+
+    fn heavy_slot_available(&self) -> bool {
+        let max = match self.cfg.max_heavy {
+            Some(v) => v as usize,
+            None => return true,
+        };
+        let nr_sys = self
+            .sysloads
+            .values()
+            .filter(|sl| sl.heavy && unit_is_active(&sl.svc.unit))
+            .count();
+        let nr_side = self
+            .sideloads
+            .values()
+            .filter(|sl| sl.heavy && unit_is_active(&sl.unit))
+            .count();
+        nr_sys + nr_side < max
+    }
+
+    fn adopt_one_sideload(
+        &self,
+        name: &str,
+        job_path: &str,
+        id: &String,
+        defs: &SideloadDefs,
+    ) -> Result<Sideload> {
+        let jobs = SideloaderJobs::load(job_path)?;
+        let job = match jobs.sideloader_jobs.iter().find(|job| job.id == name) {
+            Some(v) => v,
+            None => bail!("no job with matching ID"),
+        };
+
+        // The def may have changed or gone away since the job was created.
+        // Use whatever is there and fall back to the defaults. If it doesn't
+        // match, the sideload gets restarted on the next apply.
+        let spec = self
+            .verify_and_lookup_svc(name, id, defs)
+            .unwrap_or_default();
+
+        Ok(Sideload {
+            name: name.into(),
+            id: id.into(),
+            started_at: self.clock.now(),
+            spec: spec.clone(),
+            scr_path: job.working_dir.clone(),
+            scr_keep: spec.scr_keep,
+            scr_umount: self.cfg.scr_umount,
+            scr_rm_timeout: self.cfg.scr_rm_timeout,
+            scr_mount: self.adopt_scr_mount(ScratchKind::Side, &job.working_dir, &spec),
+            cleanup_cmd: spec.cleanup_cmd.clone(),
+            heavy: spec.heavy,
+            burst: job
+                .envs
+                .iter()
+                .any(|env| env.starts_with("READ_BOMB_BURST=")),
+            job_path: job_path.into(),
+            unit: systemd::Unit::new_sys(sideload_svc_name(name))?,
+            usage: Default::default(),
+            limits: Default::default(),
+            ooms: Default::default(),
+            frozen: false,
+            killed: false,
+            cg_knobs: CgroupKnobs::new(&spec, self.cfg.scr_devnr),
+            cpu_part: CpuPartition::new(&spec),
+            report_intv: Duration::from_secs(spec.report_interval.unwrap_or(0) as u64),
+            last_report: None,
+            clock: self.clock.clone(),
+            cleanup: self.cleanup.clone(),
+        })
+    }
+
+    /// Scan the sideloader jobs dir for jobs left behind by a previous
+    /// instance. Jobs whose names are in `target` are adopted so that they
+    /// can be cleaned up later. All others are removed.
+    pub fn adopt_sideloads(&mut self, target: &BTreeMap<String, String>, defs: &SideloadDefs) {
+        let jobs_path = &self.cfg.sideloader_daemon_jobs_path;
+
+        for path in glob(&format!("{}/*.json", jobs_path))
+            .unwrap()
+            .filter_map(Result::ok)
+        {
+            let job_path = path.to_str().unwrap_or_default().to_string();
+            let name = path
+                .file_stem()
+                .unwrap_or_default()
+                .to_str()
+                .unwrap_or_default()
+                .to_string();
+
+            if let (true, Some(id)) = (SIDE_NAME_RE.is_match(&name), target.get(&name)) {
+                match self.adopt_one_sideload(&name, &job_path, id, defs) {
+                    Ok(sideload) => {
+                        info!("side: Adopted existing sideload {:?}", &name);
+                        self.sideloads.insert(name, sideload);
+                        continue;
+                    }
+                    Err(e) => warn!("side: Failed to adopt {:?} ({:?})", &job_path, &e),
+                }
+            }
+
+            match fs::remove_file(&path) {
+                Ok(()) => debug!("side: Removed stale sideloader job {:?}", &path),
+                Err(e) => error!(
+                    "side: Failed to remove stale sideloader job {:?} ({:?})",
+                    &path, &e
+                ),
+            }
+        }
+    }
+
+    // Rebuild sysload `name` started by a previous instance from its
+    // snapshot entry. The unit is left as-is until the Sysload is dropped.
+    fn restore_one_sysload(&self, name: &str, ent: &WorkloadSnapshotEntry) -> Result<Sysload> {
+        let spec = &ent.spec;
+        let svc = TransientService::new_sys(
+            sysload_svc_name(name),
+            self.exec_args(name, spec, &ent.envs),
+            ent.envs.clone(),
+            Some(0o002),
+        )?;
+
+        Ok(Sysload {
+            name: name.into(),
+            id: ent.id.clone(),
+            started_at: self.clock.now(),
+            spec: spec.clone(),
+            scr_path: ent.scr_path.clone(),
+            scr_keep: spec.scr_keep,
+            scr_umount: self.cfg.scr_umount,
+            scr_rm_timeout: self.cfg.scr_rm_timeout,
+            scr_mount: self.adopt_scr_mount(ScratchKind::Sys, &ent.scr_path, spec),
+            cleanup_cmd: spec.cleanup_cmd.clone(),
+            heavy: spec.heavy,
+            burst: spec.burst.is_some(),
+            svc,
+            usage: Default::default(),
+            limits: Default::default(),
+            ooms: Default::default(),
+            frozen: false,
+            killed: false,
+            cg_knobs: CgroupKnobs::new(spec, self.cfg.scr_devnr),
+            cpu_part: CpuPartition::new(spec),
+            report_intv: Duration::from_secs(spec.report_interval.unwrap_or(0) as u64),
+            last_report: None,
+            exit: None,
+            mem_peak: 0,
+            clock: self.clock.clone(),
+            cleanup: self.cleanup.clone(),
+        })
+    }
+
+    /// Reconcile the sys/sideloads recorded by snapshot() of a previous
+    /// instance. Sysloads which are still running and in `target` with the
+    /// same ID are adopted. The leftover units of all others which weren't
+    /// adopted, including sideloads adopt_sideloads() passed on, are stopped
+    /// and their scratch dirs cleaned up. Live units which aren't in the
+    /// snapshot, e.g. started after it was last written, are stopped too.
+    pub fn restore(&mut self, target: &BTreeMap<String, String>) {
+        let path = &self.cfg.workloads_path;
+        let snap = match WorkloadSnapshot::load(path) {
+            Ok(v) => v,
+            Err(e) => {
+                if Path::new(path).exists() {
+                    warn!("side: Failed to load {:?} ({:?})", path, &e);
+                }
+                Default::default()
+            }
+        };
+
+        for (name, ent) in snap.sysloads.iter() {
+            let state = match systemd::Unit::new_sys(sysload_svc_name(name)) {
+                Ok(unit) => unit.state,
+                Err(e) => {
+                    warn!("side: Failed to look up sysload {:?} ({:?})", name, &e);
+                    continue;
+                }
+            };
+            let scr_exists = Path::new(&ent.scr_path).exists();
+            let action = restore_action(&state, scr_exists, &ent.id, target.get(name));
+            if action == RestoreAction::Gone {
+                continue;
+            }
+            let sysload = match self.restore_one_sysload(name, ent) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("side: Failed to restore sysload {:?} ({:?})", name, &e);
+                    continue;
+                }
+            };
+            if action == RestoreAction::Adopt {
+                info!("side: Adopted existing sysload {:?}", name);
+                self.sysloads.insert(name.clone(), sysload);
+            } else {
+                // Dropping stops the unit and cleans up the scratch dir.
+                info!("side: Cleaning up leftover sysload {:?}", name);
+            }
+        }
+
+        for (name, ent) in snap.sideloads.iter() {
+            if self.sideloads.contains_key(name) {
+                continue;
+            }
+            for svc_name in [sideload_svc_name(name), warmup_svc_name(name)].iter() {
+                match systemd::Unit::new_sys(svc_name.clone()) {
+                    Ok(unit) if unit.state == systemd::UnitState::NotFound => (),
+                    Ok(mut unit) => {
+                        info!("side: Stopping leftover unit {:?}", svc_name);
+                        if let Err(e) = unit.stop_and_reset() {
+                            warn!("side: Failed to stop {:?} ({:?})", svc_name, &e);
+                        }
+                    }
+                    Err(e) => warn!("side: Failed to look up {:?} ({:?})", svc_name, &e),
+                }
+            }
+            if !ent.spec.scr_keep && Path::new(&ent.scr_path).exists() {
+                info!("side: Cleaning up leftover sideload {:?}", name);
+                if ScratchKind::Side.tmpfs(&self.cfg, &ent.spec)
+                    && !self.scr_mounts.is_held(&ent.scr_path)
+                {
+                    umount_scr_tmpfs(&ent.scr_path);
+                }
+                self.cleanup.push(
+                    &ent.scr_path,
+                    self.cfg.scr_umount,
+                    self.cfg.scr_rm_timeout,
+                    self.clock.clone(),
+                );
+            }
+        }
+
+        self.stop_unknown_units();
+    }
+
+    // Stop the live sys/sideload units which weren't adopted. Those missing
+    // from the snapshot have unknown specs, so their scratch dirs are left
+    // alone.
+    fn stop_unknown_units(&self) {
+        let known: BTreeSet<String> = self
+            .sysloads
+            .keys()
+            .map(|name| sysload_svc_name(name))
+            .chain(self.sideloads.keys().map(|name| sideload_svc_name(name)))
+            .collect();
+        let units = unknown_units(Slice::Sys.cgrp(), SYSLOAD_SVC_PREFIX, &known)
+            .into_iter()
+            .chain(unknown_units(
+                Slice::Side.cgrp(),
+                SIDELOAD_SVC_PREFIX,
+                &known,
+            ));
+        for svc_name in units {
+            match systemd::Unit::new_sys(svc_name.clone()) {
+                Ok(unit) if unit.state == systemd::UnitState::NotFound => (),
+                Ok(mut unit) => {
+                    info!("side: Stopping unknown leftover unit {:?}", &svc_name);
+                    if let Err(e) = unit.stop_and_reset() {
+                        warn!("side: Failed to stop {:?} ({:?})", &svc_name, &e);
+                    }
+                }
+                Err(e) => warn!("side: Failed to look up {:?} ({:?})", &svc_name, &e),
+            }
+        }
+    }
+
+    /// Record the active sys/sideloads in the workloads file so that the
+    /// next instance can restore() them. The file is only rewritten when
+    /// they changed.
+    pub fn snapshot(&mut self) -> Result<()> {
+        let entry = |id: &str, scr_path: &str, envs: &[String], spec: &SideloadSpec| {
+            WorkloadSnapshotEntry {
+                id: id.into(),
+                scr_path: scr_path.into(),
+                envs: envs.to_vec(),
+                spec: spec.clone(),
+            }
+        };
+        let snap = WorkloadSnapshot {
+            sysloads: self
+                .sysloads
+                .iter()
+                .map(|(name, sl)| {
+                    let ent = entry(&sl.id, &sl.scr_path, &sl.svc.envs, &sl.spec);
+                    (name.clone(), ent)
+                })
+                .collect(),
+            sideloads: self
+                .sideloads
+                .iter()
+                .map(|(name, sl)| (name.clone(), entry(&sl.id, &sl.scr_path, &[], &sl.spec)))
+                .collect(),
+        };
+        if self.last_snapshot.as_ref() == Some(&snap) {
+            return Ok(());
+        }
+        // Remember it even if saving fails so that the error isn't
+        // repeated until the workloads change again.
+        self.last_snapshot = Some(snap.clone());
+        snap.save(&self.cfg.workloads_path)
+    }
+
+    fn resolve_bin(&self, name: &str) -> Option<String> {
+        resolve_side_bin(&self.cfg, name).map(|(path, root)| {
+            info!("side: Resolved {:?} to {:?} in {}", name, &path, root);
+            path
+        })
+    }
+
+    fn verify_and_lookup_svc(
+        &self,
+        name: &str,
+        id: &String,
+        defs: &SideloadDefs,
+    ) -> Result<SideloadSpec> {
+        if !SIDE_NAME_RE.is_match(name) {
+            bail!(
+                "Invalid sideload name {:?}, should only contain alnums, - and _",
+                name
+            );
+        }
+        verify_unit_name_len(name)?;
+
+        let mut spec = match defs.defs.get(id) {
+            Some(v) => v.clone(),
+            None => bail!("unknown sideload ID {:?}", id),
+        };
+
+        if spec.args.len() < 1 {
+            bail!("{:?} has no command", id);
+        }
+
+        spec.args[0] = match self.resolve_bin(&spec.args[0]) {
+            Some(v) => v,
+            None => {
+                let roots = &self.cfg.side_bin_roots;
+                return Err(unresolved_bin_err(roots, "binary", &spec.args[0]));
+            }
+        };
+        if let Some(interp) = script_interpreter(&spec.args[0]) {
+            if !is_executable(&interp) {
+                bail!(
+                    "interpreter {:?} of {:?} is missing or not executable",
+                    &interp,
+                    &spec.args[0]
+                );
+            }
+        }
+
+        if needs_fio(&spec) && find_bin("fio", Option::<&str>::None).is_none() {
+            bail!("{:?} runs io-load.sh but \"fio\" is missing", id);
+        }
+        if let Err(e) = verify_spec(&spec) {
+            bail!("{:?} {}", id, &e);
+        }
+        if let Some(cpus) = verify_exclusive_cpus(&spec).map_err(|e| anyhow!("{:?} {}", id, &e))? {
+            for (other, other_spec) in self
+                .sysloads
+                .iter()
+                .map(|(k, v)| (k, &v.spec))
+                .chain(self.sideloads.iter().map(|(k, v)| (k, &v.spec)))
+            {
+                if other == name {
+                    continue;
+                }
+                if let Ok(Some(other_cpus)) = verify_exclusive_cpus(other_spec) {
+                    if !cpus.is_disjoint(&other_cpus) {
+                        bail!("{:?} exclusive_cpus overlap with running {:?}", id, other);
+                    }
+                }
+            }
+        }
+
+        if spec.io_max.is_set() {
+            let devnr = self.cfg.scr_devnr;
+            if !Path::new(&format!("/sys/dev/block/{}:{}", devnr.0, devnr.1)).exists() {
+                bail!(
+                    "scratch device {}:{} for io_max not found",
+                    devnr.0,
+                    devnr.1
+                );
+            }
+        }
+
+        if !spec.cleanup_cmd.is_empty() {
+            spec.cleanup_cmd[0] = match self.resolve_bin(&spec.cleanup_cmd[0]) {
+                Some(v) => v,
+                None => {
+                    let roots = &self.cfg.side_bin_roots;
+                    let bin = &spec.cleanup_cmd[0];
+                    return Err(unresolved_bin_err(roots, "cleanup binary", bin));
+                }
+            };
+        }
+        if !spec.warmup_args.is_empty() {
+            spec.warmup_args[0] = match self.resolve_bin(&spec.warmup_args[0]) {
+                Some(v) => v,
+                None => {
+                    let roots = &self.cfg.side_bin_roots;
+                    let bin = &spec.warmup_args[0];
+                    return Err(unresolved_bin_err(roots, "warmup binary", bin));
+                }
+            };
+        }
+
+        Ok(spec)
+    }
+
+    // A unit which was just removed may still be on its way out. Wait for
+    // it to disappear before re-creating one with the same name.
+    fn wait_settle(&self, svc_name: &str) {
+        let started_at = self.clock.now();
+        let mut waited = false;
+
+        loop {
+            match systemd::Unit::new_sys(svc_name.into()) {
+                Ok(unit) if unit.state != systemd::UnitState::NotFound => (),
+                _ => break,
+            }
+
+            if self.clock.now().duration_since(started_at) >= self.cfg.settle_timeout {
+                warn!(
+                    "side: {:?} still exists after {:.1}s, proceeding anyway",
+                    svc_name,
+                    self.cfg.settle_timeout.as_secs_f64()
+                );
+                return;
+            }
+
+            waited = true;
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        if waited {
+            debug!(
+                "side: Waited {:.1}s for {:?} to settle",
+                self.clock.now().duration_since(started_at).as_secs_f64(),
+                svc_name
+            );
+        }
+    }
+
+    // Reference the scratch tmpfs of a workload adopted from a previous
+    // instance.
+    fn adopt_scr_mount(
+        &self,
+        kind: ScratchKind,
+        scr_path: &str,
+        spec: &SideloadSpec,
+    ) -> Option<ScrMount> {
+        if kind.tmpfs(&self.cfg, spec) && is_mount_point(scr_path) {
+            Some(self.scr_mounts.adopt(scr_path))
+        } else {
+            None
+        }
+    }
+
+    fn prep_scr_dir(
+        &self,
+        kind: ScratchKind,
+        name: &str,
+        tag: &str,
+        spec: &SideloadSpec,
+    ) -> Result<(String, Option<ScrMount>)> {
+        let date = self.clock.local_now().format("%Y-%m-%d").to_string();
+        let rel = expand_scr_template(&self.cfg.scr_template, name, kind, tag, &date)?;
+        let scr_path = format!("{}/{}", kind.root(&self.cfg), rel);
+        if spec.scr_external {
+            if !Path::new(&scr_path).is_dir() {
+                bail!(
+                    "externally managed scratch dir {:?} for {:?} doesn't exist",
+                    &scr_path,
+                    name
+                );
+            }
+            return Ok((scr_path, None));
+        }
+        if let Err(e) = fs::create_dir_all(&scr_path) {
+            bail!("failed to create scratch dir for {:?} ({:?})", name, &e);
+        }
+        let mut scr_mount = None;
+        if kind.tmpfs(&self.cfg, spec) {
+            scr_mount = Some(
+                self.scr_mounts
+                    .get(&scr_path, self.cfg.scr_tmpfs_size)
+                    .map_err(|e| anyhow!("{:?} {}", name, &e))?,
+            );
+        }
+        Ok((scr_path, scr_mount))
+    }
+
+    // The command line to run, wrapped with "perf stat" if requested.
+    fn exec_args(&self, name: &str, spec: &SideloadSpec, envs: &[String]) -> Vec<String> {
+        let args = self.perf_stat_args(name, spec);
+        if spec.clean_env {
+            clean_env_args(args, envs)
+        } else {
+            args
+        }
+    }
+
+    fn perf_stat_args(&self, name: &str, spec: &SideloadSpec) -> Vec<String> {
+        if !spec.perf_stat {
+            return spec.args.clone();
+        }
+        let perf = match find_bin("perf", Option::<&str>::None) {
+            Some(v) => v.to_str().unwrap().to_string(),
+            None => {
+                warn!(
+                    "side: \"perf\" not found, running {:?} without perf_stat",
+                    name
+                );
+                return spec.args.clone();
+            }
+        };
+        let mut args: Vec<String> = vec![
+            perf,
+            "stat".into(),
+            "-x,".into(),
+            "-e".into(),
+            PERF_STAT_EVENTS.into(),
+            "-o".into(),
+            PERF_STAT_FILE.into(),
+            "--".into(),
+        ];
+        args.extend(spec.args.iter().cloned());
+        args
+    }
+
+    // `cgrp` is where the workload's unit runs, which differs between
+    // sysloads and sideloads.
+    fn envs(
+        &self,
+        spec: &SideloadSpec,
+        bench: &BenchKnobs,
+        scr_path: &str,
+        cgrp: &str,
+    ) -> Vec<String> {
+        let cfg = &self.cfg;
+
+        let mut envs = vec![
+            format!("NR_CPUS={}", *NR_CPUS),
+            format!("TOTAL_MEMORY={}", *TOTAL_MEMORY),
+            format!("TOTAL_SWAP={}", *TOTAL_SWAP),
+            format!("ROTATIONAL_SWAP={}", if *ROTATIONAL_SWAP { 1 } else { 0 }),
+            format!("IO_DEV={}", &cfg.scr_dev),
+            format!("IO_DEVNR={}:{}", cfg.scr_devnr.0, cfg.scr_devnr.1),
+            format!("IO_RBPS={}", bench.iocost.model.rbps),
+            format!("IO_WBPS={}", bench.iocost.model.wbps),
+            format!("STATUS_FILE={}", status_file_path(scr_path)),
+            format!("CGROUP_PATH={}", cgrp),
+            format!("LINUX_TAR={}/linux.tar", &cfg.scr_path),
+        ];
+        if cfg.side_linux_src_shared {
+            envs.push(format!("LINUX_SRC={}/linux-src", &cfg.scr_path));
+        }
+        if let Some(kconfig) = spec.kconfig.as_ref() {
+            envs.push(format!("KCONFIG={}", kconfig));
+        }
+        if let Some(make_jobs) = spec.make_jobs {
+            envs.push(format!("MAKE_JOBS={}", make_jobs));
+        }
+        if let Some((burst, idle)) = spec.burst {
+            envs.push(format!("READ_BOMB_BURST={}:{}", burst, idle));
+        }
+        if let Some(io_load) = spec.io_load.as_ref() {
+            envs.push(format!("IO_RW_MIX={}", io_load.read_pct));
+            envs.push(format!("IO_BS={}", io_load.block_size));
+            envs.push(format!("IO_DEPTH={}", io_load.depth));
+            envs.push(format!("IO_SIZE={}", io_load.size));
+        }
+        if let Some(net_load) = spec.net_load.as_ref() {
+            if let Some(host) = net_load.host.as_ref() {
+                envs.push(format!("NET_HOST={}", host));
+            }
+            envs.push(format!("NET_PORT={}", net_load.port));
+            envs.push(format!("NET_RATE={}", net_load.rate));
+            envs.push(format!("NET_CONNS={}", net_load.conns));
+        }
+        envs.extend(spec.extra_envs.iter().cloned());
+        envs
+    }
+
+    fn start_sysload(
+        &mut self,
+        name: &str,
+        id: &str,
+        spec: SideloadSpec,
+        bench: &BenchKnobs,
+    ) -> Result<()> {
+        self.wait_settle(&sysload_svc_name(name));
+        let tag = spec.tag.as_deref().unwrap_or(id);
+        let (scr_path, scr_mount) = self.prep_scr_dir(ScratchKind::Sys, name, tag, &spec)?;
+        let envs = self.envs(&spec, bench, &scr_path, &sysload_cgrp(name));
+        self.launch_sysload(name, id, spec, scr_path, scr_mount, envs)
+    }
+
+    fn launch_sysload(
+        &mut self,
+        name: &str,
+        id: &str,
+        spec: SideloadSpec,
+        scr_path: String,
+        scr_mount: Option<ScrMount>,
+        envs: Vec<String>,
+    ) -> Result<()> {
+        let mut svc = TransientService::new_sys(
+            sysload_svc_name(name),
+            self.exec_args(name, &spec, &envs),
+            envs,
+            Some(0o002),
+        )?;
+        svc.set_slice(Slice::Sys.name())
+            .set_working_dir(&scr_path)
+            .add_prop(
+                "StandardOutputFile".into(),
+                systemd::Prop::String(stdout_log_path(&scr_path)),
+            )
+            .add_prop(
+                "StandardErrorFile".into(),
+                systemd::Prop::String(stderr_log_path(&scr_path)),
+            );
+        // A plain network namespace for now. Finer-grained setups, e.g.
+        // veth pairs with shaping, can hook in here later.
+        if spec.private_network {
+            svc.add_prop("PrivateNetwork".into(), systemd::Prop::Bool(true));
+        }
+        if let Some(sig) = verify_kill_signal(&spec)? {
+            svc.add_prop("KillSignal".into(), systemd::Prop::I32(sig));
+        }
+        if let Some(cpus) = verify_exclusive_cpus(&spec)? {
+            svc.add_prop("AllowedCPUs".into(), systemd::Prop::Bytes(cpu_mask(&cpus)));
+        }
+        if let Some(bytes) = verify_mem_high(&spec)? {
+            svc.add_prop("MemoryHigh".into(), systemd::Prop::U64(bytes));
+        }
+        if spec.restart {
+            svc.add_prop("Restart".into(), systemd::Prop::String("on-failure".into()))
+                .add_prop(
+                    "StartLimitIntervalUSec".into(),
+                    systemd::Prop::U64(spec.start_limit_interval as u64 * 1_000_000),
+                )
+                .add_prop(
+                    "StartLimitBurst".into(),
+                    systemd::Prop::U32(spec.start_limit_burst),
+                );
+        }
+
+        let mut sysload = Sysload {
+            name: name.into(),
+            id: id.into(),
+            started_at: self.clock.now(),
+            spec: spec.clone(),
+            scr_path,
+            scr_keep: spec.scr_keep,
+            scr_umount: self.cfg.scr_umount,
+            scr_rm_timeout: self.cfg.scr_rm_timeout,
+            scr_mount,
+            cleanup_cmd: spec.cleanup_cmd.clone(),
+            heavy: spec.heavy,
+            burst: spec.burst.is_some(),
+            svc,
+            usage: Default::default(),
+            limits: Default::default(),
+            ooms: Default::default(),
+            frozen: false,
+            killed: false,
+            cg_knobs: CgroupKnobs::new(&spec, self.cfg.scr_devnr),
+            cpu_part: CpuPartition::new(&spec),
+            report_intv: Duration::from_secs(spec.report_interval.unwrap_or(0) as u64),
+            last_report: None,
+            exit: None,
+            mem_peak: 0,
+            clock: self.clock.clone(),
+            cleanup: self.cleanup.clone(),
+        };
+        audit_svc_props(self.cfg.prop_audit, name, &sysload.svc);
+        if let Err(e) = sysload.svc.start() {
+            error!(
+                "side: Failed to start sysload {:?} running {:?} ({:?})",
+                name, &sysload.spec.args[0], &e
+            );
+        }
+
+        self.sysloads.insert(name.into(), sysload);
+        Ok(())
+    }
+
+    /// Restart the active sysload `name` in place. The unit is stopped and
+    /// started again with the same spec and envs in the same scratch dir,
+    /// whose contents and tmpfs are kept. cleanup_cmd isn't run as the
+    /// workload isn't going away.
+    pub fn restart_sysload(&mut self, name: &str) -> Result<()> {
+        let now = self.clock.now();
+        let sysload = match self.sysloads.get_mut(name) {
+            Some(v) => v,
+            None => bail!("sysload {:?} is not active", name),
+        };
+
+        info!("side: Restarting sysload {:?}", name);
+        // Frozen tasks can't act on SIGTERM, thaw so that stop is quick.
+        if sysload.frozen {
+            let _ = write_cgroup_freeze(&sysload_cgrp(name), false);
+            sysload.frozen = false;
+        }
+        if let Err(e) = fs::create_dir_all(&sysload.scr_path) {
+            bail!("failed to recreate scratch dir for {:?} ({:?})", name, &e);
+        }
+        sysload.svc.start()?;
+
+        // The cgroup is new, start tracking it from scratch.
+        sysload.started_at = now;
+        sysload.usage = Default::default();
+        sysload.limits = Default::default();
+        sysload.ooms = Default::default();
+        sysload.cg_knobs.applied = false;
+        sysload.cpu_part.applied = false;
+        sysload.exit = None;
+        sysload.mem_peak = 0;
+        Ok(())
+    }
+
+    fn plan_loads(
+        &self,
+        target: &BTreeMap<String, String>,
+        defs: &SideloadDefs,
+        active: &[(&String, &SideloadSpec, Instant)],
+        auto_stopped: &BTreeMap<String, String>,
+    ) -> ApplyPlan {
+        let mut plan: ApplyPlan = Default::default();
+        let (to_stop, deferred) = plan_removals(target, active, self.clock.now());
+        plan.to_stop = to_stop;
+        plan.deferred = deferred;
+
+        for (name, id) in target.iter() {
+            if auto_stopped.contains_key(name) {
+                continue;
+            }
+            let spec = match self.verify_and_lookup_svc(name, id, defs) {
+                Ok(v) => v,
+                Err(e) => {
+                    plan.errors.push(format!("{:?}: {}", name, &e));
+                    continue;
+                }
+            };
+            match active.iter().find(|(n, _, _)| *n == name) {
+                Some((_, cur, _)) => {
+                    if self.cfg.restart_on_change && **cur != spec {
+                        plan.to_restart.push(name.clone());
+                    }
+                }
+                None if spec.heavy && !self.heavy_slot_available() => {
+                    plan.queued.push(name.clone())
+                }
+                None => plan.to_start.push(name.clone()),
+            }
+        }
+        plan
+    }
+
+    /// Compute what `apply_sysloads()` would do for `target` without
+    /// starting or stopping anything. Spec errors are collected in the
+    /// plan instead of aborting.
+    pub fn plan_sysloads(
+        &self,
+        target: &BTreeMap<String, String>,
+        defs: &SideloadDefs,
+    ) -> ApplyPlan {
+        let active: Vec<_> = self
+            .sysloads
+            .iter()
+            .map(|(name, sl)| (name, &sl.spec, sl.started_at))
+            .collect();
+        self.plan_loads(target, defs, &active, &self.auto_stopped_sysloads)
+    }
+
+    /// Sideload counterpart of `plan_sysloads()`.
+    pub fn plan_sideloads(
+        &self,
+        target: &BTreeMap<String, String>,
+        defs: &SideloadDefs,
+    ) -> ApplyPlan {
+        let active: Vec<_> = self
+            .sideloads
+            .iter()
+            .map(|(name, sl)| (name, &sl.spec, sl.started_at))
+            .collect();
+        self.plan_loads(target, defs, &active, &self.auto_stopped_sideloads)
+    }
+
+    pub fn apply_sysloads(
+        &mut self,
+        target: &BTreeMap<String, String>,
+        defs: &SideloadDefs,
+        bench: &BenchKnobs,
+        mut removed: Option<&mut Vec<Sysload>>,
+    ) -> Result<()> {
+        let sysloads = &mut self.sysloads;
+
+        let target_keys: HashSet<String> = target.keys().cloned().collect();
+        let active_keys: HashSet<String> = sysloads.keys().cloned().collect();
+
+        let mut pending_rm = BTreeSet::new();
+        for goner in active_keys.difference(&target_keys) {
+            let sl = sysloads.get(goner).unwrap();
+            if let Some(left) = min_runtime_left(&sl.spec, sl.started_at, self.clock.now()) {
+                if !self.pending_rm_sysloads.contains(goner) {
+                    info!(
+                        "side: Deferring removal of sysload {:?} by {:.1}s for min_runtime",
+                        goner,
+                        left.as_secs_f64()
+                    );
+                }
+                pending_rm.insert(goner.clone());
+                continue;
+            }
+            if let Some(sl) = sysloads.remove(goner) {
+                if let Some(rm) = removed.as_mut() {
+                    rm.push(sl);
+                }
+            }
+        }
+        self.pending_rm_sysloads = pending_rm;
+        self.queued_sysloads.retain(|n, _| target.contains_key(n));
+        self.auto_stopped_sysloads
+            .retain(|name, _| target.contains_key(name));
+        self.sysload_tiers
+            .retain(|name, _| target.contains_key(name));
+
+        // Restart the ones whose specs changed. The old instances are
+        // dropped right away so that the new ones can reuse the names.
+        if self.cfg.restart_on_change {
+            for name in active_keys.intersection(&target_keys) {
+                let spec = match self.verify_and_lookup_svc(name, target.get(name).unwrap(), defs) {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+                if self.sysloads.get(name).map(|sl| sl.spec != spec) == Some(true) {
+                    info!("side: sysload {:?} changed, restarting", name);
+                    self.sysloads.remove(name);
+                }
+            }
+        }
+        let active_keys: HashSet<String> = self.sysloads.keys().cloned().collect();
+
         for name in target_keys.difference(&active_keys) {
+            if self.auto_stopped_sysloads.contains_key(name) {
+                continue;
+            }
+            let spec = self.verify_and_lookup_svc(name, target.get(name).unwrap(), defs)?;
+            self.sysload_tiers
+                .insert(name.clone(), spec.tier.unwrap_or_else(|| Slice::Sys.tier()));
+
+            let why = if !spec.heavy {
+                None
+            } else if !self.heavy_slot_available() {
+                Some("no heavy slot available".into())
+            } else {
+                self.heavy_scr_blocked(ScratchKind::Sys, name)
+            };
+            if let Some(why) = why {
+                queue_load(&mut self.queued_sysloads, "sysload", name, why);
+                continue;
+            }
+            self.queued_sysloads.remove(name);
+
+            let id = target.get(name).unwrap();
+            self.start_sysload(name, id, spec, bench)?;
+        }
+
+        Ok(())
+    }
+
+    // The warmup runs as a transient unit in sideload.slice rather than as a
+    // sideloader job, so the sideloader doesn't freeze it under pressure.
+    fn start_warmup(
+        &mut self,
+        name: &str,
+        id: &str,
+        spec: &SideloadSpec,
+        bench: &BenchKnobs,
+    ) -> Result<()> {
+        let svc_name = warmup_svc_name(name);
+        self.wait_settle(&svc_name);
+        let tag = spec.tag.as_deref().unwrap_or(id);
+        let (scr_path, scr_mount) = self.prep_scr_dir(ScratchKind::Side, name, tag, spec)?;
+        let envs = self.envs(spec, bench, &scr_path, &sideload_cgrp(name));
+
+        let mut svc =
+            TransientService::new_sys(svc_name, spec.warmup_args.clone(), envs, Some(0o002))?;
+        svc.set_slice(Slice::Side.name())
+            .set_working_dir(&scr_path)
+            .add_prop(
+                "StandardOutputFile".into(),
+                systemd::Prop::String(warmup_log_path(&scr_path)),
+            )
+            .add_prop(
+                "StandardErrorFile".into(),
+                systemd::Prop::String(warmup_log_path(&scr_path)),
+            );
+        svc.start()?;
+
+        info!("side: {:?} warming up", name);
+        self.warmups.insert(
+            name.into(),
+            Warmup {
+                svc,
+                scr_path,
+                scr_keep: spec.scr_keep,
+                scr_umount: self.cfg.scr_umount,
+                scr_rm_timeout: self.cfg.scr_rm_timeout,
+                scr_mount,
+                failed: false,
+                handed_over: false,
+                clock: self.clock.clone(),
+                cleanup: self.cleanup.clone(),
+            },
+        );
+        Ok(())
+    }
+
+    // Run the warmup_args of sideload `name` if it has them. Returns whether
+    // the main job can be started.
+    fn warmed_up(
+        &mut self,
+        name: &str,
+        id: &str,
+        spec: &SideloadSpec,
+        bench: &BenchKnobs,
+    ) -> Result<bool> {
+        if spec.warmup_args.is_empty() {
+            return Ok(true);
+        }
+        let warmup = match self.warmups.get_mut(name) {
+            Some(v) => v,
+            None => {
+                self.start_warmup(name, id, spec, bench)?;
+                return Ok(false);
+            }
+        };
+        if warmup.failed {
+            return Ok(false);
+        }
+        warmup.svc.unit.refresh()?;
+        match &warmup.svc.unit.state {
+            systemd::UnitState::Exited => {
+                info!("side: {:?} warmed up", name);
+                Ok(true)
+            }
+            systemd::UnitState::Failed(why) => {
+                warn!(
+                    "side: Warmup of {:?} failed ({}), see {:?}",
+                    name,
+                    why,
+                    warmup_log_path(&warmup.scr_path)
+                );
+                warmup.failed = true;
+                Ok(false)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn start_sideload(
+        &mut self,
+        name: &str,
+        id: &str,
+        spec: SideloadSpec,
+        bench: &BenchKnobs,
+    ) -> Result<()> {
+        self.wait_settle(&sideload_svc_name(name));
+        let job_path = format!("{}/{}.json", &self.cfg.sideloader_daemon_jobs_path, name);
+        let tag = spec.tag.as_deref().unwrap_or(id);
+        let (scr_path, scr_mount) = self.prep_scr_dir(ScratchKind::Side, name, tag, &spec)?;
+        let envs = self.envs(&spec, bench, &scr_path, &sideload_cgrp(name));
+        let unit = systemd::Unit::new_sys(sideload_svc_name(name))?;
+
+        let jobs = SideloaderJobs {
+            sideloader_jobs: vec![SideloaderJob {
+                id: name.into(),
+                args: self.exec_args(name, &spec, &envs),
+                envs,
+                frozen_expiration: spec.frozen_exp,
+                frozen: false,
+                working_dir: scr_path.clone(),
+                cpu_weight: spec.cpu_weight,
+                stdout_path: Some(stdout_log_path(&scr_path)),
+                stderr_path: Some(stderr_log_path(&scr_path)),
+            }],
+        };
+
+        audit_job_props(self.cfg.prop_audit, name, &jobs.sideloader_jobs[0]);
+        if let Err(e) = jobs.save(&job_path) {
+            return Err(match e.downcast_ref::<io::Error>() {
+                Some(ie) => jobs_path_err(&self.cfg.sideloader_daemon_jobs_path, ie),
+                None => e,
+            });
+        }
+
+        self.sideloads.insert(
+            name.into(),
+            Sideload {
+                name: name.into(),
+                id: id.into(),
+                started_at: self.clock.now(),
+                spec: spec.clone(),
+                scr_path: scr_path,
+                scr_keep: spec.scr_keep,
+                scr_umount: self.cfg.scr_umount,
+                scr_rm_timeout: self.cfg.scr_rm_timeout,
+                scr_mount,
+                cleanup_cmd: spec.cleanup_cmd.clone(),
+                heavy: spec.heavy,
+                burst: spec.burst.is_some(),
+                job_path: job_path,
+                unit,
+                usage: Default::default(),
+                limits: Default::default(),
+                ooms: Default::default(),
+                frozen: false,
+                killed: false,
+                cg_knobs: CgroupKnobs::new(&spec, self.cfg.scr_devnr),
+                cpu_part: CpuPartition::new(&spec),
+                report_intv: Duration::from_secs(spec.report_interval.unwrap_or(0) as u64),
+                last_report: None,
+                clock: self.clock.clone(),
+                cleanup: self.cleanup.clone(),
+            },
+        );
+
+        info!("side: {:?} started", name);
+        Ok(())
+    }
+
+    pub fn apply_sideloads(
+        &mut self,
+        target: &BTreeMap<String, String>,
+        defs: &SideloadDefs,
+        bench: &BenchKnobs,
+        mut removed: Option<&mut Vec<Sideload>>,
+    ) -> Result<()> {
+        let sideloads = &mut self.sideloads;
+
+        let target_keys: HashSet<String> = target.keys().cloned().collect();
+        let active_keys: HashSet<String> = sideloads.keys().cloned().collect();
+
+        let mut pending_rm = BTreeSet::new();
+        for goner in active_keys.difference(&target_keys) {
+            let sl = sideloads.get(goner).unwrap();
+            if let Some(left) = min_runtime_left(&sl.spec, sl.started_at, self.clock.now()) {
+                if !self.pending_rm_sideloads.contains(goner) {
+                    info!(
+                        "side: Deferring removal of sideload {:?} by {:.1}s for min_runtime",
+                        goner,
+                        left.as_secs_f64()
+                    );
+                }
+                pending_rm.insert(goner.clone());
+                continue;
+            }
+            if let Some(sl) = sideloads.remove(goner) {
+                if let Some(rm) = removed.as_mut() {
+                    rm.push(sl);
+                }
+            }
+        }
+        self.pending_rm_sideloads = pending_rm;
+        self.queued_sideloads.retain(|n, _| target.contains_key(n));
+        self.auto_stopped_sideloads
+            .retain(|name, _| target.contains_key(name));
+        self.sideload_tiers
+            .retain(|name, _| target.contains_key(name));
+        self.warmups.retain(|name, _| target.contains_key(name));
+
+        // Restart the ones whose specs changed. The old instances are
+        // dropped right away so that the new ones can reuse the names.
+        if self.cfg.restart_on_change {
+            for name in active_keys.intersection(&target_keys) {
+                let spec = match self.verify_and_lookup_svc(name, target.get(name).unwrap(), defs) {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+                if self.sideloads.get(name).map(|sl| sl.spec != spec) == Some(true) {
+                    info!("side: sideload {:?} changed, restarting", name);
+                    self.sideloads.remove(name);
+                }
+            }
+        }
+        let active_keys: HashSet<String> = self.sideloads.keys().cloned().collect();
+
+        let order = sideload_start_order(target, defs)?;
+        for name in order.iter().filter(|name| !active_keys.contains(*name)) {
+            if self.auto_stopped_sideloads.contains_key(name) {
+                continue;
+            }
             let spec = self.verify_and_lookup_svc(name, target.get(name).unwrap(), defs)?;
+            self.sideload_tiers.insert(
+                name.clone(),
+                spec.tier.unwrap_or_else(|| Slice::Side.tier()),
+            );
+
+            if let Some(dep) = spec
+                .after
+                .iter()
+                .find(|dep| target.contains_key(*dep) && !self.sideloads.contains_key(*dep))
+            {
+                let why = format!("waiting for {:?} to start", dep);
+                queue_load(&mut self.queued_sideloads, "sideload", name, why);
+                continue;
+            }
+
+            let why = if !spec.heavy {
+                None
+            } else if !self.heavy_slot_available() {
+                Some("no heavy slot available".into())
+            } else {
+                self.heavy_scr_blocked(ScratchKind::Side, name)
+            };
+            if let Some(why) = why {
+                queue_load(&mut self.queued_sideloads, "sideload", name, why);
+                continue;
+            }
+            self.queued_sideloads.remove(name);
+            if spec.private_network {
+                warn!(
+                    "side: private_network is not supported for sideloads, ignoring for {:?}",
+                    name
+                );
+            }
+
+            let id = target.get(name).unwrap();
+            if !self.warmed_up(name, id, &spec, bench)? {
+                continue;
+            }
+            self.start_sideload(name, id, spec, bench)?;
+            if let Some(mut warmup) = self.warmups.remove(name.as_str()) {
+                warmup.handed_over = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stop the sideloads which have run longer than their max_runtime. They
+    /// stay stopped until removed from the target. The stopped instances are
+    /// returned so that the caller can drop them without holding locks.
+    pub fn reap_expired_sideloads(&mut self) -> Vec<Sideload> {
+        let now = self.clock.now();
+        let expired: Vec<String> = self
+            .sideloads
+            .iter()
+            .filter(|(_, sl)| max_runtime_exceeded(&sl.spec, sl.started_at, now))
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        let mut reaped = vec![];
+        for name in expired.into_iter() {
+            let sl = self.sideloads.remove(&name).unwrap();
+            let reason = format!("max_runtime {}s exceeded", sl.spec.max_runtime.unwrap());
+            info!("side: Stopping sideload {:?}, {}", &name, &reason);
+            self.pending_rm_sideloads.remove(&name);
+            self.sideload_tiers.insert(
+                name.clone(),
+                sl.spec.tier.unwrap_or_else(|| Slice::Side.tier()),
+            );
+            self.auto_stopped_sideloads.insert(name, reason);
+            reaped.push(sl);
+        }
+        reaped
+    }
+
+    /// Stop the sys/sideloads whose scratch dirs grew over their
+    /// scratch_quota. Walking the dirs is expensive, so this only looks at
+    /// the usage cached by refresh_scratch_usage() on the report thread.
+    /// Like max_runtime, the stopped workloads stay stopped until removed
+    /// from the target.
+    pub fn reap_over_quota(&mut self) -> (Vec<Sysload>, Vec<Sideload>) {
+        let (at, usages) = match self.scr_usage_cache.as_ref() {
+            Some(v) => v,
+            None => return (vec![], vec![]),
+        };
+        // Usage measured before the instance started may be of the
+        // previous instance's scratch dir.
+        let check = |spec: &SideloadSpec, scr_path: &str, started_at: Instant| {
+            spec.scratch_quota?;
+            if *at < started_at {
+                return None;
+            }
+            scratch_quota_exceeded(spec, *usages.get(scr_path)?)
+        };
+        let over_sys: Vec<(String, String)> = self
+            .sysloads
+            .iter()
+            .filter_map(|(name, sl)| {
+                Some((name.clone(), check(&sl.spec, &sl.scr_path, sl.started_at)?))
+            })
+            .collect();
+        let over_side: Vec<(String, String)> = self
+            .sideloads
+            .iter()
+            .filter_map(|(name, sl)| {
+                Some((name.clone(), check(&sl.spec, &sl.scr_path, sl.started_at)?))
+            })
+            .collect();
+
+        let mut reaped_sys = vec![];
+        for (name, reason) in over_sys.into_iter() {
+            warn!("side: Stopping sysload {:?}, {}", &name, &reason);
+            let sl = self.sysloads.remove(&name).unwrap();
+            self.pending_rm_sysloads.remove(&name);
+            self.sysload_tiers.insert(
+                name.clone(),
+                sl.spec.tier.unwrap_or_else(|| Slice::Sys.tier()),
+            );
+            reaped_sys.push(sl);
+            self.auto_stopped_sysloads.insert(name, reason);
+        }
+        let mut reaped_side = vec![];
+        for (name, reason) in over_side.into_iter() {
+            warn!("side: Stopping sideload {:?}, {}", &name, &reason);
+            let sl = self.sideloads.remove(&name).unwrap();
+            self.pending_rm_sideloads.remove(&name);
+            self.sideload_tiers.insert(
+                name.clone(),
+                sl.spec.tier.unwrap_or_else(|| Slice::Side.tier()),
+            );
+            reaped_side.push(sl);
+            self.auto_stopped_sideloads.insert(name, reason);
+        }
+        (reaped_sys, reaped_side)
+    }
+
+    pub fn report_sysloads(&mut self) -> Result<BTreeMap<String, SysloadReport>> {
+        let scr_dev_lost = self.check_scr_dev();
+        let now = self.clock.now();
+        let names: BTreeSet<String> = self
+            .sysloads
+            .keys()
+            .chain(self.queued_sysloads.keys())
+            .chain(self.auto_stopped_sysloads.keys())
+            .cloned()
+            .collect();
+        let mut rep = BTreeMap::new();
+        for name in names.into_iter() {
+            if let Some(srep) = self.sysload_report(&name, scr_dev_lost, now)? {
+                rep.insert(name, srep);
+            }
+        }
+        Ok(rep)
+    }
+
+    /// Report of sysload `name` alone, None if it's neither active, queued
+    /// nor auto-stopped. Unlike report_sysloads(), it doesn't disturb the
+    /// periodic reports. An active sysload gets its last report with the
+    /// unit state refreshed and the deltas cleared, and None until it has
+    /// been reported since it was (re)started.
+    pub fn report_sysload(&mut self, name: &str) -> Result<Option<SysloadReport>> {
+        let scr_dev_lost = self.scr_dev_lost;
+        if let Some(rep) = self.sysload_placeholder(name, scr_dev_lost) {
+            return Ok(Some(rep));
+        }
+        let sysload = match self.sysloads.get_mut(name) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let mut rep = match sysload.last_report.as_ref() {
+            Some((_, rep)) => rep.clone(),
+            None => return Ok(None),
+        };
+        rep.svc = super::svc_refresh_and_report(&mut sysload.svc.unit)?;
+        if scr_dev_lost {
+            rep.svc.state = SvcStateReport::Failed;
+        }
+        clear_since_last(&mut rep.usage, &mut rep.limits_hit);
+        Ok(Some(rep))
+    }
+
+    // Report placeholder of sysload `name` if it's queued or auto-stopped.
+    fn sysload_placeholder(&self, name: &str, scr_dev_lost: bool) -> Option<SysloadReport> {
+        let auto_stopped = self.auto_stopped_sysloads.get(name).cloned();
+        if auto_stopped.is_none() && !self.queued_sysloads.contains_key(name) {
+            return None;
+        }
+        Some(SysloadReport {
+            svc: Default::default(),
+            tier: self
+                .sysload_tiers
+                .get(name)
+                .cloned()
+                .unwrap_or_else(|| Slice::Sys.tier()),
+            queued: auto_stopped.is_none(),
+            queued_reason: self.queued_sysloads.get(name).cloned(),
+            pending_removal: false,
+            auto_stopped,
+            exit_code: None,
+            oom_killed: false,
+            burst: false,
+            start_limit_hit: false,
+            scr_dev_lost,
+            io_max: Default::default(),
+            swap_high: None,
+            oom_group: false,
+            exclusive_cpus: None,
+            cpu_partition: None,
+            swap_current: 0,
+            mem_peak: 0,
+            kconfig: None,
+            status: None,
+            limits_hit: vec![],
+            oom_kills: 0,
+            oom_kill_details: vec![],
+            perf_stat: BTreeMap::new(),
+            usage: Default::default(),
+        })
+    }
+
+    fn sysload_report(
+        &mut self,
+        name: &str,
+        scr_dev_lost: bool,
+        now: Instant,
+    ) -> Result<Option<SysloadReport>> {
+        if let Some(rep) = self.sysload_placeholder(name, scr_dev_lost) {
+            return Ok(Some(rep));
+        }
+
+        let sysload = match self.sysloads.get_mut(name) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        // Reuse the last report until the workload's interval expires.
+        if let Some((at, last)) = sysload.last_report.as_ref() {
+            if now.duration_since(*at) < sysload.report_intv {
+                let mut rep = last.clone();
+                clear_since_last(&mut rep.usage, &mut rep.limits_hit);
+                return Ok(Some(rep));
+            }
+        }
+
+        let cgrp = sysload_cgrp(name);
+        let mut svc = super::svc_refresh_and_report(&mut sysload.svc.unit)?;
+        if scr_dev_lost {
+            svc.state = SvcStateReport::Failed;
+        }
+        // Remember how it finished in case the unit gets reset and
+        // goes away before the sysload is removed from the target.
+        match unit_exit_status(&sysload.svc.unit) {
+            Some(exit) => sysload.exit = Some(exit),
+            None if svc.state == SvcStateReport::Running => sysload.exit = None,
+            None => (),
+        }
+        sysload.cg_knobs.update(
+            name,
+            &cgrp,
+            svc.state == SvcStateReport::Running,
+            self.cfg.prop_audit,
+        );
+        sysload.cpu_part.update(
+            name,
+            &mut sysload.svc.unit,
+            &cgrp,
+            svc.state == SvcStateReport::Running,
+        );
+        let usage =
+            sysload
+                .usage
+                .update(&cgrp, self.cfg.scr_devnr, self.cfg.metrics_port.is_some());
+        // memory.peak catches spikes between reports if available.
+        // Otherwise, sample memory.current.
+        let peak = ["memory.peak", "memory.current"]
+            .iter()
+            .find_map(|knob| {
+                read_one_line(format!("{}/{}", &cgrp, knob))
+                    .ok()
+                    .and_then(|x| x.trim().parse::<u64>().ok())
+            })
+            .unwrap_or(0);
+        sysload.mem_peak = sysload.mem_peak.max(peak);
+        let limits_hit =
+            sysload
+                .limits
+                .update(&cgrp, self.cfg.scr_devnr, &sysload.spec.io_max, &usage, now);
+        let (oom_kills, oom_kill_details) = sysload.ooms.update(name, &cgrp);
+        // perf stat writes out the counters on exit.
+        let perf_stat = match svc.state {
+            SvcStateReport::Exited | SvcStateReport::Failed if sysload.spec.perf_stat => {
+                read_perf_stat(&sysload.scr_path)
+            }
+            _ => BTreeMap::new(),
+        };
+        let srep = SysloadReport {
+            svc,
+            tier: sysload.spec.tier.unwrap_or_else(|| Slice::Sys.tier()),
+            queued: false,
+            queued_reason: None,
+            pending_removal: self.pending_rm_sysloads.contains(name),
+            auto_stopped: None,
+            exit_code: sysload.exit.and_then(|(code, _)| code),
+            oom_killed: sysload.exit.map(|(_, oom)| oom).unwrap_or(false),
+            burst: sysload.burst,
+            start_limit_hit: unit_start_limit_hit(&sysload.svc.unit),
+            scr_dev_lost,
+            io_max: sysload.spec.io_max.clone(),
+            swap_high: sysload.spec.swap_high,
+            oom_group: sysload.spec.oom_group,
+            exclusive_cpus: verify_exclusive_cpus(&sysload.spec)
+                .ok()
+                .flatten()
+                .map(|cpus| cpu_list_str(&cpus)),
+            cpu_partition: sysload.cpu_part.state(&cgrp),
+            swap_current: read_one_line(format!("{}/memory.swap.current", &cgrp))
+                .ok()
+                .and_then(|x| x.trim().parse::<u64>().ok())
+                .unwrap_or(0),
+            mem_peak: sysload.mem_peak,
+            kconfig: sysload.spec.kconfig.clone(),
+            status: read_status_file(&sysload.scr_path),
+            limits_hit,
+            oom_kills,
+            oom_kill_details,
+            perf_stat,
+            usage,
+        };
+        let was_failed = sysload.last_report.as_ref().map(|(_, last)| last.svc.state)
+            == Some(SvcStateReport::Failed);
+        sysload.last_report = Some((now, srep.clone()));
+        if srep.svc.state == SvcStateReport::Failed && !was_failed {
+            self.log_failure(name);
+        }
+        Ok(Some(srep))
+    }
+
+    pub fn report_sideloads(&mut self) -> Result<BTreeMap<String, SideloadReport>> {
+        let scr_dev_lost = self.check_scr_dev();
+        let now = self.clock.now();
+        let names: BTreeSet<String> = self
+            .sideloads
+            .keys()
+            .chain(self.queued_sideloads.keys())
+            .chain(self.auto_stopped_sideloads.keys())
+            .chain(self.warmups.keys())
+            .cloned()
+            .collect();
+        let mut rep = BTreeMap::new();
+        for name in names.into_iter() {
+            if let Some(srep) = self.sideload_report(&name, scr_dev_lost, now)? {
+                rep.insert(name, srep);
+            }
+        }
+        Ok(rep)
+    }
+
+    // Report placeholder of sideload `name` if it's warming up, queued or
+    // auto-stopped.
+    /// Report of sideload `name` alone, None if it's neither active, queued,
+    /// warming up nor auto-stopped. Same as report_sysload() otherwise.
+    pub fn report_sideload(&mut self, name: &str) -> Result<Option<SideloadReport>> {
+        let scr_dev_lost = self.scr_dev_lost;
+        if let Some(rep) = self.sideload_placeholder(name, scr_dev_lost)? {
+            return Ok(Some(rep));
+        }
+        let sideload = match self.sideloads.get_mut(name) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let mut rep = match sideload.last_report.as_ref() {
+            Some((_, rep)) => rep.clone(),
+            None => return Ok(None),
+        };
+        rep.svc = super::svc_refresh_and_report(&mut sideload.unit)?;
+        if scr_dev_lost {
+            rep.svc.state = SvcStateReport::Failed;
+        }
+        rep.frozen = sideload.frozen;
+        clear_since_last(&mut rep.usage, &mut rep.limits_hit);
+        Ok(Some(rep))
+    }
+
+    fn sideload_placeholder(
+        &mut self,
+        name: &str,
+        scr_dev_lost: bool,
+    ) -> Result<Option<SideloadReport>> {
+        let (svc, warming_up, warmup_failed, auto_stopped) = match self.warmups.get_mut(name) {
+            Some(warmup) => (
+                super::svc_refresh_and_report(&mut warmup.svc.unit)?,
+                !warmup.failed,
+                warmup.failed,
+                None,
+            ),
+            None => (
+                Default::default(),
+                false,
+                false,
+                self.auto_stopped_sideloads.get(name).cloned(),
+            ),
+        };
+        if !self.warmups.contains_key(name)
+            && auto_stopped.is_none()
+            && !self.queued_sideloads.contains_key(name)
+        {
+            return Ok(None);
+        }
+        Ok(Some(SideloadReport {
+            svc,
+            tier: self
+                .sideload_tiers
+                .get(name)
+                .cloned()
+                .unwrap_or_else(|| Slice::Side.tier()),
+            queued: self.queued_sideloads.contains_key(name),
+            queued_reason: self.queued_sideloads.get(name).cloned(),
+            warming_up,
+            warmup_failed,
+            pending_removal: false,
+            burst: false,
+            start_limit_hit: false,
+            scr_dev_lost,
+            io_max: Default::default(),
+            swap_high: None,
+            oom_group: false,
+            frozen: false,
+            auto_stopped,
+            exclusive_cpus: None,
+            cpu_partition: None,
+            swap_current: 0,
+            kconfig: None,
+            status: None,
+            limits_hit: vec![],
+            oom_kills: 0,
+            oom_kill_details: vec![],
+            perf_stat: BTreeMap::new(),
+            usage: Default::default(),
+        }))
+    }
+
+    fn sideload_report(
+        &mut self,
+        name: &str,
+        scr_dev_lost: bool,
+        now: Instant,
+    ) -> Result<Option<SideloadReport>> {
+        if let Some(rep) = self.sideload_placeholder(name, scr_dev_lost)? {
+            return Ok(Some(rep));
+        }
+
+        let sideload = match self.sideloads.get_mut(name) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        if let Some((at, last)) = sideload.last_report.as_ref() {
+            if now.duration_since(*at) < sideload.report_intv {
+                let mut rep = last.clone();
+                clear_since_last(&mut rep.usage, &mut rep.limits_hit);
+                return Ok(Some(rep));
+            }
+        }
+
+        let cgrp = sideload_cgrp(name);
+        let mut svc = super::svc_refresh_and_report(&mut sideload.unit)?;
+        if scr_dev_lost {
+            svc.state = SvcStateReport::Failed;
+        }
+        sideload.cg_knobs.update(
+            name,
+            &cgrp,
+            svc.state == SvcStateReport::Running,
+            self.cfg.prop_audit,
+        );
+        sideload.cpu_part.update(
+            name,
+            &mut sideload.unit,
+            &cgrp,
+            svc.state == SvcStateReport::Running,
+        );
+        let usage =
+            sideload
+                .usage
+                .update(&cgrp, self.cfg.scr_devnr, self.cfg.metrics_port.is_some());
+        let limits_hit = sideload.limits.update(
+            &cgrp,
+            self.cfg.scr_devnr,
+            &sideload.spec.io_max,
+            &usage,
+            now,
+        );
+        let (oom_kills, oom_kill_details) = sideload.ooms.update(name, &cgrp);
+        // perf stat writes out the counters on exit.
+        let perf_stat = match svc.state {
+            SvcStateReport::Exited | SvcStateReport::Failed if sideload.spec.perf_stat => {
+                read_perf_stat(&sideload.scr_path)
+            }
+            _ => BTreeMap::new(),
+        };
+        let srep = SideloadReport {
+            svc,
+            tier: sideload.spec.tier.unwrap_or_else(|| Slice::Side.tier()),
+            queued: false,
+            queued_reason: None,
+            warming_up: false,
+            warmup_failed: false,
+            pending_removal: self.pending_rm_sideloads.contains(name),
+            burst: sideload.burst,
+            start_limit_hit: unit_start_limit_hit(&sideload.unit),
+            scr_dev_lost,
+            io_max: sideload.spec.io_max.clone(),
+            swap_high: sideload.spec.swap_high,
+            oom_group: sideload.spec.oom_group,
+            frozen: sideload.frozen,
+            auto_stopped: None,
+            exclusive_cpus: verify_exclusive_cpus(&sideload.spec)
+                .ok()
+                .flatten()
+                .map(|cpus| cpu_list_str(&cpus)),
+            cpu_partition: sideload.cpu_part.state(&cgrp),
+            swap_current: read_one_line(format!("{}/memory.swap.current", &cgrp))
+                .ok()
+                .and_then(|x| x.trim().parse::<u64>().ok())
+                .unwrap_or(0),
+            kconfig: sideload.spec.kconfig.clone(),
+            status: read_status_file(&sideload.scr_path),
+            limits_hit,
+            oom_kills,
+            oom_kill_details,
+            perf_stat,
+            usage,
+        };
+        let was_failed = sideload
+            .last_report
+            .as_ref()
+            .map(|(_, last)| last.svc.state)
+            == Some(SvcStateReport::Failed);
+        sideload.last_report = Some((now, srep.clone()));
+        if srep.svc.state == SvcStateReport::Failed && !was_failed {
+            self.log_failure(name);
+        }
+        Ok(Some(srep))
+    }
+}
+
+const BALLOON_PSI_INTV: Duration = Duration::from_secs(1);
+// Re-inflate once the pressure falls below this ratio of the threshold.
+const BALLOON_PSI_RESUME_RATIO: f64 = 0.5;
+
+// Whether the balloon should be deflated given the full memory pressure
+// `psi` in percents. The hysteresis keeps it from flapping around `thr`.
+fn balloon_should_deflate(deflated: bool, psi: f64, thr: f64) -> bool {
+    if deflated {
+        psi >= thr * BALLOON_PSI_RESUME_RATIO
+    } else {
+        psi >= thr
+    }
+}
+
+fn read_mem_full_avg10() -> Result<f64> {
+    let pressure = read_cgroup_nested_keyed_file("/proc/pressure/memory")?;
+    match pressure.get("full").and_then(|x| x.get("avg10")) {
+        Some(v) => Ok(v.parse::<f64>()?),
+        None => bail!("full avg10 missing in /proc/pressure/memory"),
+    }
+}
+
+// In-progress gradual resize of the balloon.
+struct BalloonRamp {
+    target: usize,
+    step: usize,
+    intv: Duration,
+    last_step_at: Instant,
+}
+
+// The next balloon size when moving from `cur` towards `target` by `step`.
+fn next_ramp_size(cur: usize, target: usize, step: usize) -> usize {
+    if cur < target {
+        target.min(cur.saturating_add(step))
+    } else {
+        target.max(cur.saturating_sub(step))
+    }
+}
+
+pub struct Balloon {
+    cfg: Arc<Config>,
+    size: usize,
+    svc: Option<TransientService>,
+    restarts: u32,
+    last_failure: Option<String>,
+    last_refresh_at: Instant,
+    ramp: Option<BalloonRamp>,
+    actual_size: usize,
+    deflated: bool,
+    last_psi_at: Instant,
+    clock: Arc<dyn Clock>,
+}
+
+impl Balloon {
+    const UNIT_NAME: &'static str = "rd-balloon.service";
+    const MAX_RESTARTS: u32 = 5;
+
+    pub fn new(cfg: Arc<Config>) -> Self {
+        Self::with_clock(cfg, Arc::new(RealClock))
+    }
+
+    pub fn with_clock(cfg: Arc<Config>, clock: Arc<dyn Clock>) -> Self {
+        match systemd::Unit::new_sys(Self::UNIT_NAME.into()) {
+            Ok(mut unit) => {
+                if let Err(e) = unit.stop_and_reset() {
+                    warn!("balloon: Failed to stop {:?} ({:?})", Self::UNIT_NAME, &e);
+                }
+            }
+            Err(e) => warn!(
+                "balloon: Failed to create unit for {:?} ({:?})",
+                Self::UNIT_NAME,
+                &e
+            ),
+        }
+        Self {
+            cfg,
+            svc: None,
+            size: 0,
+            restarts: 0,
+            last_failure: None,
+            last_refresh_at: clock.now(),
+            ramp: None,
+            actual_size: 0,
+            deflated: false,
+            last_psi_at: clock.now(),
+            clock,
+        }
+    }
+
+    fn start(&mut self, size: usize) -> Result<()> {
+        let mut svc = TransientService::new_sys(
+            Self::UNIT_NAME.into(),
+            vec![self.cfg.balloon_bin.clone(), format!("{}", size)],
+            vec![],
+            Some(0o002),
+        )?;
+
+        svc.set_slice(Slice::Sys.name())
+            .add_prop("MemorySwapMax".into(), systemd::Prop::U64(0));
+        audit_svc_props(self.cfg.prop_audit, Self::UNIT_NAME, &svc);
+        svc.start()?;
+
+        self.svc = Some(svc);
+        Ok(())
+    }
+
+    /// Resize the balloon to `size` right away, canceling any gradual
+    /// resize in progress.
+    pub fn set_size(&mut self, size: usize) -> Result<()> {
+        self.ramp = None;
+        self.resize(size)
+    }
+
+    /// Resize the balloon towards `target` by at most `step` bytes every
+    /// `interval` so that memory pressure builds up smoothly. The first step
+    /// is taken right away and the rest by `maybe_ramp()`. A zero `step`
+    /// behaves the same as `set_size()`.
+    pub fn set_size_gradual(
+        &mut self,
+        target: usize,
+        step: usize,
+        interval: Duration,
+    ) -> Result<()> {
+        if step == 0 || self.size == target {
+            return self.set_size(target);
+        }
+        if let Some(ramp) = self.ramp.as_mut() {
+            if ramp.target == target {
+                ramp.step = step;
+                ramp.intv = interval;
+                return Ok(());
+            }
+        }
+        info!(
+            "balloon: Ramping from {:.2}G to {:.2}G by {:.2}G every {:.1}s",
+            to_gb(self.size),
+            to_gb(target),
+            to_gb(step),
+            interval.as_secs_f64()
+        );
+        self.ramp = Some(BalloonRamp {
+            target,
+            step,
+            intv: interval,
+            last_step_at: self.clock.now(),
+        });
+        self.ramp_step()
+    }
+
+    fn ramp_step(&mut self) -> Result<()> {
+        let ramp = match self.ramp.as_mut() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let size = next_ramp_size(self.size, ramp.target, ramp.step);
+        ramp.last_step_at = self.clock.now();
+        if size == ramp.target {
+            self.ramp = None;
+        }
+        self.resize(size)
+    }
+
+    /// Take the next step of the gradual resize in progress if its interval
+    /// has passed.
+    pub fn maybe_ramp(&mut self) {
+        match self.ramp.as_ref() {
+            Some(ramp) if self.clock.now().duration_since(ramp.last_step_at) >= ramp.intv => (),
+            _ => return,
+        }
+        if let Err(e) = self.ramp_step() {
+            error!("balloon: Failed to resize to the next step ({:?})", &e);
+            self.ramp = None;
+        }
+    }
+
+    // Deflate under --balloon-psi. `size` stays the requested size so that
+    // the balloon can re-inflate to it once the pressure subsides.
+    fn pressure_adjusted(&self, size: usize) -> usize {
+        if self.deflated {
+            (size as f64 * (1.0 - self.cfg.balloon_deflate)) as usize
+        } else {
+            size
+        }
+    }
+
+    /// Deflate the balloon by `--balloon-deflate` while full memory pressure
+    /// is over `--balloon-psi` and re-inflate once it subsides. Does nothing
+    /// if not configured.
+    pub fn maybe_deflate(&mut self) {
+        let thr = match self.cfg.balloon_psi {
+            Some(v) => v,
+            None => return,
+        };
+        let now = self.clock.now();
+        if now.duration_since(self.last_psi_at) < BALLOON_PSI_INTV {
+            return;
+        }
+        self.last_psi_at = now;
+
+        let psi = match read_mem_full_avg10() {
+            Ok(v) => v,
+            Err(e) => {
+                debug!("balloon: Failed to read memory pressure ({:?})", &e);
+                return;
+            }
+        };
+        let deflated = balloon_should_deflate(self.deflated, psi, thr);
+        if deflated == self.deflated {
+            return;
+        }
+
+        self.deflated = deflated;
+        if self.size == 0 {
+            return;
+        }
+        if deflated {
+            warn!(
+                "balloon: Full memory pressure {:.1}% over {:.1}%, deflating",
+                psi, thr
+            );
+        } else {
+            info!(
+                "balloon: Full memory pressure {:.1}% subsided, re-inflating",
+                psi
+            );
+        }
+        if let Err(e) = self.resize(self.size) {
+            error!("balloon: Failed to resize for memory pressure ({:?})", &e);
+        }
+    }
+
+    fn resize(&mut self, size: usize) -> Result<()> {
+        let actual_size = self.pressure_adjusted(size);
+        if self.size == size && self.actual_size == actual_size {
+            if let Some(svc) = self.svc.as_mut() {
+                if let Ok(()) = svc.unit.refresh() {
+                    if svc.unit.state == systemd::UnitState::Running {
+                        return Ok(());
+                    }
+                }
+            }
+        } else {
+            self.restarts = 0;
+        }
 
-            let mut svc = TransientService::new_sys(
-                sysload_svc_name(name),
-                spec.args.clone(),
-                self.envs(bench),
-                Some(0o002),
-            )?;
-            let scr_path = Self::prep_scr_dir(&self.cfg.sys_scr_path, name)?;
-            svc.set_slice(Slice::Sys.name()).set_working_dir(&scr_path);
+        self.svc.take();
+
+        if actual_size == 0 {
+            self.size = size;
+            self.actual_size = 0;
+            return Ok(());
+        }
+
+        if let Err(e) = self.start(actual_size) {
+            self.last_failure = Some(format!("failed to start ({:?})", &e));
+            return Err(e);
+        }
+        self.size = size;
+        self.actual_size = actual_size;
+        Ok(())
+    }
 
-            let mut sysload = Sysload { scr_path, svc };
-            if let Err(e) = sysload.svc.start() {
-                warn!("side: Failed to start sysload {:?} ({:?})", name, &e);
+    // Re-inflate the balloon if it died while it's supposed to be up.
+    fn watchdog(&mut self) {
+        let state = match self.svc.as_mut() {
+            Some(svc) if self.actual_size > 0 => {
+                let _ = svc.unit.refresh();
+                if svc.unit.state == systemd::UnitState::Running {
+                    return;
+                }
+                format!("{:?}", &svc.unit.state)
             }
+            _ => return,
+        };
 
-            self.sysloads.insert(name.clone(), sysload);
+        if self.restarts >= Self::MAX_RESTARTS {
+            return;
         }
 
-        Ok(())
+        self.restarts += 1;
+        warn!(
+            "balloon: Died unexpectedly ({}), restarting ({}/{})",
+            &state,
+            self.restarts,
+            Self::MAX_RESTARTS
+        );
+        self.last_failure = Some(state);
+
+        self.svc.take();
+        if let Err(e) = self.start(self.actual_size) {
+            error!("balloon: Failed to restart ({:?})", &e);
+            self.last_failure = Some(format!("{:?}", &e));
+        } else if self.restarts == Self::MAX_RESTARTS {
+            warn!("balloon: Reached max restarts, won't restart again");
+        }
     }
 
-    pub fn apply_sideloads(
-        &mut self,
-        target: &BTreeMap<String, String>,
-        defs: &SideloadDefs,
-        bench: &BenchKnobs,
-        mut removed: Option<&mut Vec<Sideload>>,
-    ) -> Result<()> {
-        let sideloads = &mut self.sideloads;
+    /// Refresh the unit state and restart the balloon if it died, at most
+    /// once every `--balloon-refresh`. Does nothing if not configured.
+    pub fn maybe_refresh(&mut self) {
+        let intv = match self.cfg.balloon_refresh {
+            Some(v) => v,
+            None => return,
+        };
+        if self.clock.now().duration_since(self.last_refresh_at) >= intv {
+            self.watchdog();
+            self.last_refresh_at = self.clock.now();
+        }
+    }
 
-        let target_keys: HashSet<String> = target.keys().cloned().collect();
-        let active_keys: HashSet<String> = sideloads.keys().cloned().collect();
+    pub fn report(&mut self) -> Result<BalloonReport> {
+        self.watchdog();
 
-        for goner in active_keys.difference(&target_keys) {
-            if let Some(sl) = sideloads.remove(goner) {
-                if let Some(rm) = removed.as_mut() {
-                    rm.push(sl);
-                }
+        let svc = match self.svc.as_mut() {
+            Some(svc) => super::svc_refresh_and_report(&mut svc.unit)?,
+            None => Default::default(),
+        };
+        Ok(BalloonReport {
+            running: svc.state == SvcStateReport::Running,
+            svc,
+            size: self.actual_size,
+            deflated: self.deflated && self.size > 0,
+            restarts: self.restarts,
+            last_failure: self.last_failure.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        balloon_should_deflate, check_jobs_path, check_output_size, check_tar_header,
+        clean_env_args, clear_loads, count_dir_entries, cpu_list_str, discard_foreign_partial,
+        dl_progress_step, download_file, download_once, expand_clones, expand_scr_template,
+        expected_linux_tar_sha256, format_unit_file, install_hint, is_trash_name, jobs_path_err,
+        linux_tar_name, max_runtime_exceeded, min_runtime_left, needs_fio, next_ramp_size,
+        os_release_pkg_mgr, parse_apply_target, parse_cpu_list, parse_major_minor, parse_mem_high,
+        parse_oom_kills, parse_signal, parse_tool_version, partial_url_path, plan_removals,
+        really_remove_dir_all, restore_action, scratch_quota_exceeded, script_interpreter,
+        sideload_start_order, tail_file, tmpfs_mount_opts, unit_prop_str, unknown_units,
+        unresolved_bin_err, update_bin_file, verify_extra_envs, verify_fetched_tar,
+        verify_linux_tar, verify_spec, verify_target, verify_unit_name_len, BinUpdate,
+        CleanupQueue, Clock, DownloadError, PkgMgr, RestoreAction, ScrMount, ScrMounts, ScrUmount,
+        ScratchKind, TarPrepError, LINUX_TAR_SHA256S, LINUX_TAR_XZ_MIRRORS, TAR_BLOCK_SIZE,
+    };
+    use chrono::prelude::*;
+    use rd_agent_intf::{
+        IoLoad, NetLoad, PrepareProgress, SideloadDefs, SideloadSpec, WorkloadSnapshot,
+        WorkloadSnapshotEntry,
+    };
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use util::systemd::UnitState;
+    use util::{JsonLoad, JsonSave};
+
+    // Only moves when advanced.
+    struct MockClock {
+        base: Instant,
+        base_local: DateTime<Local>,
+        offset: Mutex<Duration>,
+    }
+
+    impl MockClock {
+        fn new() -> Self {
+            Self {
+                base: Instant::now(),
+                base_local: Local.ymd(2020, 10, 1).and_hms(12, 0, 0),
+                offset: Mutex::new(Duration::from_secs(0)),
             }
         }
 
-        for name in target_keys.difference(&active_keys) {
-            let spec = self.verify_and_lookup_svc(name, target.get(name).unwrap(), defs)?;
-            let job_path = format!("{}/{}.json", &self.cfg.sideloader_daemon_jobs_path, name);
-            let scr_path = Self::prep_scr_dir(&self.cfg.side_scr_path, name)?;
-
-            let jobs = SideloaderJobs {
-                sideloader_jobs: vec![SideloaderJob {
-                    id: name.into(),
-                    args: spec.args.clone(),
-                    envs: self.envs(bench),
-                    frozen_expiration: spec.frozen_exp,
-                    working_dir: scr_path.clone(),
-                }],
-            };
+        fn advance(&self, dur: Duration) {
+            *self.offset.lock().unwrap() += dur;
+        }
+    }
 
-            jobs.save(&job_path)?;
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.base + *self.offset.lock().unwrap()
+        }
 
-            self.sideloads.insert(
-                name.clone(),
-                Sideload {
-                    name: name.clone(),
-                    scr_path: scr_path,
-                    job_path: job_path,
-                    unit: systemd::Unit::new_sys(sideload_svc_name(&name))?,
-                },
-            );
+        fn local_now(&self) -> DateTime<Local> {
+            self.base_local + chrono::Duration::from_std(*self.offset.lock().unwrap()).unwrap()
+        }
+    }
+
+    // Per-test temporary directory, removed when dropped even if the test
+    // panics.
+    struct TestDir {
+        root: String,
+    }
 
-            info!("side: {:?} started", &name);
+    impl TestDir {
+        fn new(what: &str) -> Self {
+            let root = std::env::temp_dir()
+                .join(format!("rd-agent-test-{}-{}", what, std::process::id()))
+                .to_str()
+                .unwrap()
+                .to_string();
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+            Self { root }
         }
 
-        Ok(())
+        fn path(&self, rel: &str) -> String {
+            format!("{}/{}", &self.root, rel)
+        }
     }
 
-    pub fn report_sysloads(&mut self) -> Result<BTreeMap<String, SysloadReport>> {
-        let mut rep = BTreeMap::new();
-        for (name, sysload) in self.sysloads.iter_mut() {
-            rep.insert(
-                name.into(),
-                SysloadReport {
-                    svc: super::svc_refresh_and_report(&mut sysload.svc.unit)?,
-                },
-            );
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
         }
-        Ok(rep)
     }
 
-    pub fn report_sideloads(&mut self) -> Result<BTreeMap<String, SideloadReport>> {
-        let mut rep = BTreeMap::new();
-        for (name, sideload) in self.sideloads.iter_mut() {
-            rep.insert(
-                name.into(),
-                SideloadReport {
-                    svc: super::svc_refresh_and_report(&mut sideload.unit)?,
+    #[test]
+    fn test_clear_loads() {
+        struct Load(Arc<Mutex<Vec<String>>>, String);
+        impl Drop for Load {
+            fn drop(&mut self) {
+                self.0.lock().unwrap().push(self.1.clone());
+            }
+        }
+
+        let stopped = Arc::new(Mutex::new(vec![]));
+        let mut sys = BTreeMap::new();
+        let mut side = BTreeMap::new();
+        for name in ["sys-a", "sys-b"].iter() {
+            sys.insert(name.to_string(), Load(stopped.clone(), name.to_string()));
+        }
+        side.insert("side-a".to_string(), Load(stopped.clone(), "side-a".into()));
+        let mut sys_queued: BTreeMap<String, String> =
+            [("sys-c".to_string(), "no heavy slot available".to_string())]
+                .iter()
+                .cloned()
+                .collect();
+        let mut side_queued = BTreeMap::new();
+        let mut sys_pending_rm: BTreeSet<String> = ["sys-a".to_string()].iter().cloned().collect();
+        let mut side_pending_rm = BTreeSet::new();
+
+        clear_loads(&mut sys, &mut sys_queued, &mut sys_pending_rm);
+        clear_loads(&mut side, &mut side_queued, &mut side_pending_rm);
+
+        assert!(sys.is_empty() && sys_queued.is_empty() && sys_pending_rm.is_empty());
+        assert!(side.is_empty() && side_queued.is_empty() && side_pending_rm.is_empty());
+        assert_eq!(*stopped.lock().unwrap(), vec!["sys-a", "sys-b", "side-a"]);
+    }
+
+    #[test]
+    fn test_scr_mounts_refcount() {
+        let dir = TestDir::new("scr-mounts");
+        let path = dir.path("scr");
+        let mounts = Arc::new(ScrMounts::default());
+
+        let mut first = Some(mounts.adopt(&path));
+        let second = mounts.adopt(&path);
+        assert!(!ScrMount::release(&mut first));
+        assert!(first.is_none() && mounts.is_held(&path));
+        drop(second);
+        assert!(!mounts.is_held(&path));
+
+        let mut third = Some(mounts.adopt(&path));
+        assert!(ScrMount::release(&mut third));
+        assert!(ScrMount::release(&mut None));
+        assert!(!mounts.is_held(&path));
+    }
+
+    #[test]
+    fn test_workload_snapshot_round_trip() {
+        let dir = TestDir::new("workload-snapshot");
+        let path = dir.path("workloads.json");
+        let mut snap = WorkloadSnapshot::default();
+        snap.sysloads.insert(
+            "sys-a".into(),
+            WorkloadSnapshotEntry {
+                id: "compile-job".into(),
+                scr_path: "/var/lib/rd-agent/sys-scr/sys-a".into(),
+                envs: vec!["STATUS_FILE=/var/lib/rd-agent/sys-scr/sys-a/status".into()],
+                spec: SideloadSpec {
+                    args: vec!["make".into()],
+                    scr_keep: true,
+                    ..Default::default()
                 },
+            },
+        );
+        snap.sideloads.insert(
+            "side-a".into(),
+            WorkloadSnapshotEntry {
+                id: "build-linux".into(),
+                scr_path: "/var/lib/rd-agent/side-scr/side-a".into(),
+                envs: vec![],
+                spec: Default::default(),
+            },
+        );
+
+        snap.save(&path).unwrap();
+        assert_eq!(WorkloadSnapshot::load(&path).unwrap(), snap);
+    }
+
+    #[test]
+    fn test_restore_action() {
+        let id = "compile-job".to_string();
+        let other = "build-linux".to_string();
+        let cases = [
+            (UnitState::NotFound, false, Some(&id), RestoreAction::Gone),
+            (UnitState::NotFound, true, Some(&id), RestoreAction::Clean),
+            (UnitState::Running, true, Some(&id), RestoreAction::Adopt),
+            (UnitState::Running, false, Some(&id), RestoreAction::Adopt),
+            (UnitState::Running, true, Some(&other), RestoreAction::Clean),
+            (UnitState::Running, true, None, RestoreAction::Clean),
+            (
+                UnitState::Failed("exit-code".into()),
+                true,
+                Some(&id),
+                RestoreAction::Clean,
+            ),
+            (UnitState::Exited, false, Some(&id), RestoreAction::Clean),
+        ];
+        for (state, scr_exists, target_id, action) in cases.iter() {
+            assert_eq!(
+                restore_action(state, *scr_exists, &id, *target_id),
+                *action,
+                "{:?} scr_exists={} target_id={:?}",
+                state,
+                scr_exists,
+                target_id
             );
         }
-        Ok(rep)
     }
-}
 
-pub struct Balloon {
-    cfg: Arc<Config>,
-    size: usize,
-    svc: Option<TransientService>,
-}
+    #[test]
+    fn test_unknown_units() {
+        let dir = TestDir::new("unknown-units");
+        for name in [
+            "rd-sysload-a.service",
+            "rd-sysload-b.service",
+            "rd-sysload-c.scope",
+            "rd-hashd-A.service",
+            "cgroup.procs",
+        ]
+        .iter()
+        {
+            fs::create_dir_all(dir.path(name)).unwrap();
+        }
+        let known: BTreeSet<String> = ["rd-sysload-a.service".to_string()]
+            .iter()
+            .cloned()
+            .collect();
 
-impl Balloon {
-    const UNIT_NAME: &'static str = "rd-balloon.service";
+        assert_eq!(
+            unknown_units(&dir.path(""), "rd-sysload-", &known),
+            vec!["rd-sysload-b.service"]
+        );
+        assert!(unknown_units(&dir.path("nonexistent"), "rd-sysload-", &known).is_empty());
+    }
 
-    pub fn new(cfg: Arc<Config>) -> Self {
-        match systemd::Unit::new_sys(Self::UNIT_NAME.into()) {
-            Ok(mut unit) => {
-                if let Err(e) = unit.stop_and_reset() {
-                    warn!("balloon: Failed to stop {:?} ({:?})", Self::UNIT_NAME, &e);
-                }
-            }
-            Err(e) => warn!(
-                "balloon: Failed to create unit for {:?} ({:?})",
-                Self::UNIT_NAME,
-                &e
-            ),
+    #[test]
+    fn test_min_runtime_left() {
+        let clock = MockClock::new();
+        let spec = SideloadSpec {
+            min_runtime: Some(10),
+            ..Default::default()
+        };
+        let started_at = clock.now();
+
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(
+            min_runtime_left(&spec, started_at, clock.now()),
+            Some(Duration::from_secs(6))
+        );
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(min_runtime_left(&spec, started_at, clock.now()), None);
+        assert_eq!(
+            min_runtime_left(&Default::default(), started_at, clock.now()),
+            None
+        );
+        assert_eq!(
+            clock.local_now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            "2020-10-01 12:00:10"
+        );
+    }
+
+    #[test]
+    fn test_plan_removals() {
+        let clock = MockClock::new();
+        let held = SideloadSpec {
+            min_runtime: Some(10),
+            ..Default::default()
+        };
+        let plain: SideloadSpec = Default::default();
+        let (a, b, c) = ("a".to_string(), "b".to_string(), "c".to_string());
+        let active = [
+            (&a, &plain, clock.now()),
+            (&b, &held, clock.now()),
+            (&c, &plain, clock.now()),
+        ];
+        let mut target = BTreeMap::new();
+        target.insert("c".to_string(), "id".to_string());
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(
+            plan_removals(&target, &active, clock.now()),
+            (vec!["a".to_string()], vec!["b".to_string()])
+        );
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(
+            plan_removals(&target, &active, clock.now()),
+            (vec!["a".to_string(), "b".to_string()], vec![])
+        );
+    }
+
+    #[test]
+    fn test_max_runtime_exceeded() {
+        let clock = MockClock::new();
+        let spec = SideloadSpec {
+            max_runtime: Some(10),
+            ..Default::default()
+        };
+        let started_at = clock.now();
+
+        clock.advance(Duration::from_secs(9));
+        assert!(!max_runtime_exceeded(&spec, started_at, clock.now()));
+        clock.advance(Duration::from_secs(1));
+        assert!(max_runtime_exceeded(&spec, started_at, clock.now()));
+        assert!(!max_runtime_exceeded(
+            &Default::default(),
+            started_at,
+            clock.now()
+        ));
+    }
+
+    #[test]
+    fn test_really_remove_dir_all() {
+        let dir = TestDir::new("remove");
+        let path = dir.path("scr");
+        fs::create_dir_all(path.clone() + "/a/b").unwrap();
+        fs::write(path.clone() + "/a/b/c", b"c").unwrap();
+        assert_eq!(count_dir_entries(std::path::Path::new(&path)), 3);
+
+        let timeout = Duration::from_secs(10);
+        really_remove_dir_all(&path, ScrUmount::Off, timeout, &MockClock::new());
+        assert!(!std::path::Path::new(&path).exists());
+        assert_eq!(count_dir_entries(std::path::Path::new(&path)), 0);
+        really_remove_dir_all(&path, ScrUmount::Off, timeout, &MockClock::new());
+    }
+
+    #[test]
+    fn test_update_bin_file() {
+        let dir = TestDir::new("bin");
+        let path = dir.path("bin");
+
+        assert_eq!(update_bin_file(&path, b"v1").unwrap(), BinUpdate::Installed);
+        assert_eq!(update_bin_file(&path, b"v1").unwrap(), BinUpdate::Unchanged);
+        assert_eq!(update_bin_file(&path, b"v2").unwrap(), BinUpdate::Replaced);
+        assert_eq!(fs::read(&path).unwrap(), b"v2");
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o111,
+            0o111
+        );
+    }
+
+    #[test]
+    fn test_unresolved_bin_err() {
+        let dir = TestDir::new("noexec");
+        let script = dir.path("noexec.sh");
+        fs::write(&script, b"#!/nonexistent/bash -e\necho hi\n").unwrap();
+        let roots = vec![dir.root.clone()];
+
+        let e = unresolved_bin_err(&roots, "binary", "noexec.sh");
+        assert!(format!("{}", e).ends_with("noexec.sh\" is not executable"));
+        let e = unresolved_bin_err(&roots, "binary", "missing.sh");
+        assert!(format!("{}", e).starts_with("failed to resolve binary"));
+        assert_eq!(
+            script_interpreter(&script),
+            Some("/nonexistent/bash".to_string())
+        );
+        assert_eq!(script_interpreter(&dir.root), None);
+    }
+
+    #[test]
+    fn test_cleanup_queue() {
+        let dir = TestDir::new("cleanup");
+        let path = dir.path("scr");
+        fs::create_dir_all(path.clone() + "/a").unwrap();
+        fs::write(path.clone() + "/a/b", b"b").unwrap();
+
+        let queue = CleanupQueue::new();
+        let timeout = Duration::from_secs(10);
+        queue.push(&path, ScrUmount::Off, timeout, Arc::new(MockClock::new()));
+        // The path is free for reuse right away.
+        assert!(!std::path::Path::new(&path).exists());
+        queue.flush();
+        assert_eq!(fs::read_dir(&dir.root).unwrap().count(), 0);
+
+        // Missing paths are ignored.
+        queue.push(&path, ScrUmount::Off, timeout, Arc::new(MockClock::new()));
+        queue.flush();
+    }
+
+    #[test]
+    fn test_cleanup_queue_sweep() {
+        assert!(is_trash_name("foo.rm-123-4"));
+        assert!(is_trash_name("foo.rm-bar.rm-1-0"));
+        assert!(!is_trash_name(".rm-123-4"));
+        assert!(!is_trash_name("foo.rm-123"));
+        assert!(!is_trash_name("foo.rm-x-4"));
+        assert!(!is_trash_name("foo"));
+
+        let dir = TestDir::new("sweep");
+        for sub in ["a/b.rm-123-4/x", "c.rm-1-2", "a/keep", "d.rm-x-1"].iter() {
+            fs::create_dir_all(dir.path(sub)).unwrap();
         }
-        Self {
-            cfg,
-            svc: None,
-            size: 0,
+        fs::write(dir.path("e.rm-5-6"), b"").unwrap();
+
+        let queue = CleanupQueue::new();
+        let timeout = Duration::from_secs(10);
+        let clock = Arc::new(MockClock::new());
+        assert_eq!(
+            queue.sweep(&dir.root, 1, ScrUmount::Off, timeout, clock.clone()),
+            1
+        );
+        queue.flush();
+        assert!(!std::path::Path::new(&dir.path("c.rm-1-2")).exists());
+        assert!(std::path::Path::new(&dir.path("a/b.rm-123-4")).exists());
+
+        assert_eq!(queue.sweep(&dir.root, 2, ScrUmount::Off, timeout, clock), 1);
+        queue.flush();
+        assert!(!std::path::Path::new(&dir.path("a/b.rm-123-4")).exists());
+        for sub in ["a/keep", "d.rm-x-1", "e.rm-5-6"].iter() {
+            assert!(std::path::Path::new(&dir.path(sub)).exists());
         }
     }
 
-    pub fn set_size(&mut self, size: usize) -> Result<()> {
-        if self.size == size {
-            if let Some(svc) = self.svc.as_mut() {
-                if let Ok(()) = svc.unit.refresh() {
-                    if svc.unit.state == systemd::UnitState::Running {
-                        return Ok(());
+    #[test]
+    fn test_expand_scr_template() {
+        let exp = |tmpl| expand_scr_template(tmpl, "foo", ScratchKind::Side, "bar", "2020-10-01");
+
+        assert_eq!(exp("{name}").unwrap(), "foo");
+        assert_eq!(
+            exp("{kind}/{tag}/{date}/{name}").unwrap(),
+            "side/bar/2020-10-01/foo"
+        );
+        assert!(exp("../{name}").is_err());
+        assert!(exp("/tmp/{name}").is_err());
+        assert!(exp("{tag}/./{name}").is_ok());
+        assert!(expand_scr_template("{tag}", "foo", ScratchKind::Sys, "..", "").is_err());
+    }
+
+    #[test]
+    fn test_check_tar_header() {
+        let mut hdr = [0u8; TAR_BLOCK_SIZE];
+        hdr[..15].copy_from_slice(b"linux-5.8.11/\0\0");
+        hdr[156] = b'5';
+        hdr[257..263].copy_from_slice(b"ustar\0");
+        let sum: u32 = hdr.iter().map(|c| *c as u32).sum::<u32>() + 8 * b' ' as u32;
+        hdr[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+        assert!(check_tar_header(&hdr).is_ok());
+
+        hdr[0] = b'L';
+        assert!(check_tar_header(&hdr).is_err());
+
+        let mut html = [0u8; TAR_BLOCK_SIZE];
+        let page = b"<html><body>404 Not Found</body></html>";
+        html[..page.len()].copy_from_slice(page);
+        assert!(check_tar_header(&html).is_err());
+        assert!(check_tar_header(&html[..100]).is_err());
+    }
+
+    // Serve `body` with `status` to `nr_conns` HTTP connections on a local
+    // port. "Range: bytes=N-" is honored if `ranges`. Returns the URL.
+    fn serve_http(body: Vec<u8>, status: u16, ranges: bool, nr_conns: usize) -> String {
+        use std::io::{BufRead, BufReader, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/linux.tar", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming().take(nr_conns) {
+                let mut stream = stream.unwrap();
+                let mut start = 0;
+                for line in BufReader::new(&stream).lines() {
+                    let line = line.unwrap();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(range) = line.strip_prefix("Range: bytes=") {
+                        start = range.trim_end_matches('-').parse::<usize>().unwrap();
                     }
                 }
+                let (status, body) = match (status, ranges && start > 0) {
+                    (200, true) if start >= body.len() => (416, &body[0..0]),
+                    (200, true) => (206, &body[start..]),
+                    (status, _) => (status, &body[..]),
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                );
+                let _ = stream.write_all(body);
             }
+        });
+        url
+    }
+
+    #[test]
+    fn test_download_file() {
+        let dir = TestDir::new("download");
+        let dest = dir.path("linux.tar.xz");
+        let body: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+
+        let url = serve_http(body.clone(), 200, true, 1);
+        let mut progress = vec![];
+        download_file(&url, &dest, &mut |p| progress.push(p)).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), body);
+        let len = body.len() as u64;
+        assert_eq!(
+            progress.last(),
+            Some(&PrepareProgress::Downloaded {
+                done: len,
+                total: Some(len)
+            })
+        );
+
+        // Resume from a partial and don't touch a complete one.
+        let url = serve_http(body.clone(), 200, true, 2);
+        fs::write(&dest, &body[..1000]).unwrap();
+        download_file(&url, &dest, &mut |_| ()).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), body);
+        download_file(&url, &dest, &mut |_| ()).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), body);
+
+        // Start over if the server ignores the range.
+        let url = serve_http(body.clone(), 200, false, 1);
+        fs::write(&dest, b"stale").unwrap();
+        download_file(&url, &dest, &mut |_| ()).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), body);
+
+        let url = serve_http(b"not found".to_vec(), 404, false, 1);
+        match download_file(&url, &dir.path("404"), &mut |_| ()).unwrap_err() {
+            DownloadError::Http(404, _) => (),
+            e => panic!("unexpected error {:?}", &e),
         }
 
-        self.svc.take();
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let agent = ureq::AgentBuilder::new().build();
+        let url = format!("http://127.0.0.1:{}/", port);
+        let e = download_once(&agent, &url, &dest, &mut |_| ());
+        assert!(matches!(e, Err(DownloadError::Connect(_))));
+    }
 
-        if size == 0 {
-            return Ok(());
+    #[test]
+    fn test_dl_progress_step() {
+        assert_eq!(dl_progress_step(0, 5, 100), None);
+        assert_eq!(dl_progress_step(5, 12, 100), Some(12));
+        assert_eq!(dl_progress_step(12, 19, 100), None);
+        assert_eq!(dl_progress_step(19, 100, 100), Some(100));
+        assert_eq!(dl_progress_step(0, 10, 0), None);
+    }
+
+    #[test]
+    fn test_check_output_size() {
+        let dir = TestDir::new("output-size");
+        let path = dir.path("out");
+
+        assert!(check_output_size(&path, 1).is_err());
+
+        fs::write(&path, b"").unwrap();
+        let e = check_output_size(&path, 1).unwrap_err();
+        assert!(format!("{}", e).contains("empty"));
+
+        fs::write(&path, b"404").unwrap();
+        let e = check_output_size(&path, 1024).unwrap_err();
+        assert!(format!("{}", e).contains("implausibly small"));
+        assert_eq!(check_output_size(&path, 3).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_verify_linux_tar() {
+        let dir = TestDir::new("verify-tar");
+        let path = dir.path("linux.tar");
+
+        let mut hdr = [0u8; TAR_BLOCK_SIZE];
+        hdr[..13].copy_from_slice(b"linux-5.8.11/");
+        hdr[257..263].copy_from_slice(b"ustar\0");
+        let sum: u32 = hdr.iter().map(|c| *c as u32).sum::<u32>() + 8 * b' ' as u32;
+        hdr[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+        fs::write(&path, &hdr[..]).unwrap();
+
+        let sha256 = super::LinuxTarInfo::sha256(&path).unwrap();
+        assert!(verify_linux_tar(&path, None).is_ok());
+        assert!(verify_linux_tar(&path, Some(&sha256)).is_ok());
+        assert!(verify_linux_tar(&path, Some(&sha256.to_uppercase())).is_ok());
+        let e = verify_linux_tar(&path, Some(&"0".repeat(64))).unwrap_err();
+        assert!(format!("{}", e).contains(&sha256));
+        assert!(matches!(e, TarPrepError::Checksum { .. }));
+
+        fs::write(&path, b"<html>404</html>").unwrap();
+        let e = verify_linux_tar(&path, None).unwrap_err();
+        assert!(matches!(e, TarPrepError::InvalidArchive(_)));
+    }
+
+    #[test]
+    fn test_discard_foreign_partial() {
+        let dir = TestDir::new("partial");
+        let tmp_path = dir.path("linux.tar.tmp");
+        let xz_path = tmp_path.clone() + ".xz";
+        let (url_a, url_b) = ("https://a/linux.tar.xz", "https://b/linux.tar.xz");
+
+        discard_foreign_partial(&tmp_path, url_a);
+        fs::write(&xz_path, b"partial").unwrap();
+        discard_foreign_partial(&tmp_path, url_a);
+        assert!(std::path::Path::new(&xz_path).exists());
+
+        discard_foreign_partial(&tmp_path, url_b);
+        assert!(!std::path::Path::new(&xz_path).exists());
+        assert_eq!(
+            fs::read_to_string(partial_url_path(&tmp_path)).unwrap(),
+            url_b.to_string() + "\n"
+        );
+    }
+
+    #[test]
+    fn test_verify_fetched_tar() {
+        let dir = TestDir::new("fetched-tar");
+        let path = dir.path("linux.tar");
+
+        let mut hdr = [0u8; TAR_BLOCK_SIZE];
+        hdr[..13].copy_from_slice(b"linux-5.8.11/");
+        hdr[257..263].copy_from_slice(b"ustar\0");
+        let sum: u32 = hdr.iter().map(|c| *c as u32).sum::<u32>() + 8 * b' ' as u32;
+        hdr[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+        fs::write(&path, &hdr[..]).unwrap();
+        let sha256 = super::LinuxTarInfo::sha256(&path).unwrap();
+
+        assert!(verify_fetched_tar(&path, Some(&sha256)).is_ok());
+        assert!(std::path::Path::new(&path).exists());
+
+        let expected = "0".repeat(64);
+        match verify_fetched_tar(&path, Some(&expected)).unwrap_err() {
+            TarPrepError::Checksum {
+                expected: exp,
+                actual,
+            } => {
+                assert_eq!(exp, expected);
+                assert_eq!(actual, sha256);
+            }
+            e => panic!("unexpected error {:?}", &e),
         }
+        assert!(!std::path::Path::new(&path).exists());
+    }
 
-        let mut svc = TransientService::new_sys(
-            Self::UNIT_NAME.into(),
-            vec![self.cfg.balloon_bin.clone(), format!("{}", size)],
-            vec![],
-            Some(0o002),
-        )?;
+    #[test]
+    fn test_expected_linux_tar_sha256() {
+        assert_eq!(linux_tar_name(LINUX_TAR_XZ_MIRRORS[0]), "linux-5.8.11.tar");
+        assert_eq!(
+            linux_tar_name("https://example.com/linux-6.1.tar"),
+            "linux-6.1.tar"
+        );
 
-        svc.set_slice(Slice::Sys.name())
-            .add_prop("MemorySwapMax".into(), systemd::Prop::U64(0));
-        svc.start()?;
+        for (name, sha256) in LINUX_TAR_SHA256S.iter() {
+            let url = format!("https://example.com/pub/{}.xz", name);
+            assert_eq!(expected_linux_tar_sha256(&url, None), Some(*sha256));
+        }
+        let url = "https://example.com/linux-0.01.tar.xz";
+        let sha256 = "0".repeat(64);
+        assert_eq!(expected_linux_tar_sha256(url, None), None);
+        assert_eq!(
+            expected_linux_tar_sha256(url, Some(&sha256)),
+            Some(sha256.as_str())
+        );
+    }
 
-        self.size = size;
-        self.svc = Some(svc);
-        Ok(())
+    #[test]
+    fn test_parse_tool_version() {
+        assert_eq!(
+            parse_tool_version("GNU Wget 1.20.3 built on linux-gnu.\n\n-cares +digest\n").unwrap(),
+            "1.20.3"
+        );
+        assert_eq!(
+            parse_tool_version("xz (XZ Utils) 5.2.4\nliblzma 5.2.4\n").unwrap(),
+            "5.2.4"
+        );
+        assert_eq!(
+            parse_tool_version("curl 7.68.0 (x86_64-pc-linux-gnu) libcurl/7.68.0").unwrap(),
+            "7.68.0"
+        );
+        assert!(parse_tool_version("").is_none());
+        assert!(parse_tool_version("no version here").is_none());
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        let cpus = parse_cpu_list("0-2, 5,7-8").unwrap();
+        assert_eq!(
+            cpus.iter().cloned().collect::<Vec<u32>>(),
+            vec![0, 1, 2, 5, 7, 8]
+        );
+        assert_eq!(cpu_list_str(&cpus), "0-2,5,7-8");
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("a").is_err());
+    }
+
+    #[test]
+    fn test_parse_mem_high() {
+        let gib = 1 << 30;
+        assert_eq!(parse_mem_high("1073741824", 4 * gib).unwrap(), gib);
+        assert_eq!(parse_mem_high("25%", 4 * gib).unwrap(), gib);
+        assert_eq!(parse_mem_high(" 100% ", 4 * gib).unwrap(), 4 * gib);
+        assert!(parse_mem_high("0", 4 * gib).is_err());
+        assert!(parse_mem_high("0%", 4 * gib).is_err());
+        assert!(parse_mem_high("150%", 4 * gib).is_err());
+        assert!(parse_mem_high("8589934592", 4 * gib).is_err());
+        assert!(parse_mem_high("1G", 4 * gib).is_err());
+    }
+
+    #[test]
+    fn test_format_unit_file() {
+        assert_eq!(
+            unit_prop_str(&util::systemd::Prop::Bytes(vec![0x0c, 0x01])),
+            "2-3,8"
+        );
+        assert_eq!(
+            unit_prop_str(&util::systemd::Prop::String("system.slice".into())),
+            "system.slice"
+        );
+
+        let mut props = BTreeMap::new();
+        props.insert("Slice".to_string(), "system.slice".to_string());
+        props.insert("MemoryHigh".to_string(), "1024".to_string());
+        let unit = format_unit_file(
+            "test",
+            &["/bin/sh".into(), "-c".into(), "echo \"$HOME\"".into()],
+            &["A=b c".into()],
+            &props,
+            &[("io.max", "8:0 rbps=1".into())],
+        );
+        assert_eq!(
+            unit,
+            "# Generated by rd-agent for inspection, not meant to be loaded\n\
+             # cgroup io.max: 8:0 rbps=1\n\
+             \n[Unit]\nDescription=test\n\n[Service]\n\
+             ExecStart=/bin/sh -c \"echo \\\"$$HOME\\\"\"\n\
+             Environment=\"A=b c\"\n\
+             MemoryHigh=1024\nSlice=system.slice\n"
+        );
+    }
+
+    #[test]
+    fn test_jobs_path_err() {
+        let erofs = std::io::Error::from_raw_os_error(libc::EROFS);
+        assert!(format!("{}", jobs_path_err("/jobs", &erofs)).contains("read-only"));
+        let enoent = std::io::Error::from_raw_os_error(libc::ENOENT);
+        assert!(format!("{}", jobs_path_err("/jobs", &enoent)).contains("doesn't exist"));
+
+        let dir = TestDir::new("jobs");
+        let path = dir.path("jobs");
+        assert!(format!("{}", check_jobs_path(&path).unwrap_err()).contains("doesn't exist"));
+        fs::create_dir_all(&path).unwrap();
+        assert!(check_jobs_path(&path).is_ok());
+    }
+
+    #[test]
+    fn test_clean_env_args() {
+        assert_eq!(
+            clean_env_args(
+                vec!["/bin/true".into(), "x".into()],
+                &["A=1".into(), "B=2".into()]
+            ),
+            vec![
+                "/usr/bin/env",
+                "-i",
+                "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+                "A=1",
+                "B=2",
+                "/bin/true",
+                "x"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tail_file() {
+        let dir = TestDir::new("tail");
+        let path = dir.path("log");
+        fs::write(&path, "a\nb\nc\nd\n").unwrap();
+        assert_eq!(tail_file(&path, 2).unwrap(), vec!["c", "d"]);
+        assert_eq!(tail_file(&path, 10).unwrap(), vec!["a", "b", "c", "d"]);
+        fs::remove_file(&path).unwrap();
+        assert!(tail_file(&path, 2).is_err());
+    }
+
+    #[test]
+    fn test_next_ramp_size() {
+        assert_eq!(next_ramp_size(0, 10, 4), 4);
+        assert_eq!(next_ramp_size(8, 10, 4), 10);
+        assert_eq!(next_ramp_size(10, 0, 4), 6);
+        assert_eq!(next_ramp_size(3, 0, 4), 0);
+        assert_eq!(next_ramp_size(5, 5, 4), 5);
+        assert_eq!(next_ramp_size(usize::MAX - 1, usize::MAX, 4), usize::MAX);
+    }
+
+    #[test]
+    fn test_balloon_should_deflate() {
+        assert!(!balloon_should_deflate(false, 9.0, 10.0));
+        assert!(balloon_should_deflate(false, 10.0, 10.0));
+        assert!(balloon_should_deflate(true, 6.0, 10.0));
+        assert!(!balloon_should_deflate(true, 4.0, 10.0));
+    }
+
+    #[test]
+    fn test_tmpfs_mount_opts() {
+        assert_eq!(tmpfs_mount_opts(None), "mode=0755");
+        assert_eq!(tmpfs_mount_opts(Some(1 << 30)), "mode=0755,size=1073741824");
+    }
+
+    #[test]
+    fn test_scratch_quota_exceeded() {
+        let spec = SideloadSpec {
+            scratch_quota: Some(1 << 30),
+            ..Default::default()
+        };
+        assert_eq!(scratch_quota_exceeded(&spec, 1 << 30), None);
+        assert!(scratch_quota_exceeded(&spec, (1 << 30) + 1).is_some());
+        assert_eq!(scratch_quota_exceeded(&Default::default(), u64::MAX), None);
+    }
+
+    #[test]
+    fn test_parse_major_minor() {
+        for (out, ver) in [
+            ("gcc (GCC) 10.2.1 20201125 (Red Hat 10.2.1-9)\n", (10, 2)),
+            ("gcc (Ubuntu 9.3.0-17ubuntu1~20.04) 9.3.0\n", (9, 3)),
+            (
+                "GNU Make 3.82\nBuilt for x86_64-redhat-linux-gnu\n",
+                (3, 82),
+            ),
+            ("GNU ld version 2.23.52.0.1-55.el7 20130226\n", (2, 23)),
+            ("GNU ld (GNU Binutils for Ubuntu) 2.34\n", (2, 34)),
+        ]
+        .iter()
+        {
+            let parsed = parse_tool_version(out).and_then(|x| parse_major_minor(&x));
+            assert_eq!(parsed, Some(*ver), "{:?}", out);
+        }
+        assert_eq!(parse_major_minor("4"), None);
+        assert_eq!(parse_major_minor("UNKNOWN"), None);
+    }
+
+    #[test]
+    fn test_install_hint() {
+        let ubuntu = "NAME=\"Ubuntu\"\nID=ubuntu\nID_LIKE=debian\n";
+        let centos = "ID=\"centos\"\nID_LIKE=\"rhel fedora\"\n";
+        assert_eq!(os_release_pkg_mgr(ubuntu), Some(PkgMgr::Apt));
+        assert_eq!(os_release_pkg_mgr(centos), Some(PkgMgr::Dnf));
+        assert_eq!(os_release_pkg_mgr("ID=arch\n"), None);
+
+        let missing = ["ld", "libssl", "pkg-config"];
+        assert_eq!(
+            install_hint(&missing, PkgMgr::Apt),
+            "apt install binutils libssl-dev pkg-config"
+        );
+        assert_eq!(
+            install_hint(&missing, PkgMgr::Dnf),
+            "dnf install binutils openssl-devel pkgconf-pkg-config"
+        );
+    }
+
+    #[test]
+    fn test_verify_extra_envs() {
+        let spec = |envs: &[&str]| SideloadSpec {
+            extra_envs: envs.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        };
+        assert!(verify_extra_envs(&spec(&[])).is_ok());
+        assert!(verify_extra_envs(&spec(&["MAKE_JOBS=8", "_FOO=a=b", "BAR="])).is_ok());
+        assert!(verify_extra_envs(&spec(&["MAKE_JOBS"])).is_err());
+        assert!(verify_extra_envs(&spec(&["=8"])).is_err());
+        assert!(verify_extra_envs(&spec(&["8JOBS=8"])).is_err());
+        assert!(verify_extra_envs(&spec(&["MAKE-JOBS=8"])).is_err());
+        assert!(verify_extra_envs(&spec(&["IO_DEV=sda"])).is_err());
+        assert!(verify_extra_envs(&spec(&["A=1", "A=2"])).is_err());
+    }
+
+    #[test]
+    fn test_needs_fio() {
+        assert!(!needs_fio(&Default::default()));
+        assert!(needs_fio(&SideloadSpec {
+            io_load: Some(Default::default()),
+            ..Default::default()
+        }));
+        for args in [vec!["io-load.sh"], vec!["/opt/side/io-load.sh", "x"]].iter() {
+            assert!(needs_fio(&SideloadSpec {
+                args: args.iter().map(|x| x.to_string()).collect(),
+                ..Default::default()
+            }));
+        }
+        assert!(!needs_fio(&SideloadSpec {
+            args: vec!["read-bomb.py".into()],
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn test_verify_spec() {
+        assert!(verify_spec(&Default::default()).is_ok());
+        assert!(verify_spec(&SideloadSpec {
+            io_load: Some(Default::default()),
+            ..Default::default()
+        })
+        .is_ok());
+        for spec in [
+            SideloadSpec {
+                max_runtime: Some(0),
+                ..Default::default()
+            },
+            SideloadSpec {
+                min_runtime: Some(10),
+                max_runtime: Some(5),
+                ..Default::default()
+            },
+            SideloadSpec {
+                cpu_weight: Some(0),
+                ..Default::default()
+            },
+            SideloadSpec {
+                make_jobs: Some(0),
+                ..Default::default()
+            },
+            SideloadSpec {
+                kill_signal: Some("SIGFOO".into()),
+                ..Default::default()
+            },
+            SideloadSpec {
+                exclusive_cpus: Some("3-1".into()),
+                ..Default::default()
+            },
+            SideloadSpec {
+                io_load: Some(IoLoad {
+                    read_pct: 101,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            SideloadSpec {
+                io_load: Some(IoLoad {
+                    block_size: 1000,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            SideloadSpec {
+                net_load: Some(NetLoad {
+                    host: Some("10.0.0.1".into()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        ]
+        .iter()
+        {
+            assert!(verify_spec(spec).is_err(), "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn test_verify_unit_name_len() {
+        // "rd-sideload-" and ".service" leave 235 characters for the name.
+        assert!(verify_unit_name_len(&"a".repeat(235)).is_ok());
+        let e = verify_unit_name_len(&"a".repeat(236)).unwrap_err();
+        assert_eq!(
+            format!("{}", &e),
+            format!(
+                "unit name \"rd-sideload-{}.service\" is 256 characters long, max 255",
+                "a".repeat(236)
+            )
+        );
+    }
+
+    #[test]
+    fn test_sideload_start_order() {
+        let mut defs = SideloadDefs {
+            defs: BTreeMap::new(),
+        };
+        let spec = |after: &[&str]| SideloadSpec {
+            args: vec!["true".into()],
+            after: after.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        };
+        defs.defs.insert("warmup".into(), spec(&[]));
+        defs.defs.insert("main".into(), spec(&["b-warm", "gone"]));
+        defs.defs.insert("loop".into(), spec(&["z-loop"]));
+
+        let mut target = BTreeMap::new();
+        target.insert("a-main".to_string(), "main".to_string());
+        target.insert("b-warm".to_string(), "warmup".to_string());
+        target.insert("c-other".to_string(), "warmup".to_string());
+        assert_eq!(
+            sideload_start_order(&target, &defs).unwrap(),
+            vec!["b-warm", "c-other", "a-main"]
+        );
+
+        target.insert("z-loop".to_string(), "loop".to_string());
+        let e = sideload_start_order(&target, &defs).unwrap_err();
+        assert_eq!(
+            format!("{}", &e),
+            "sideloads [\"z-loop\"] have a dependency cycle in after"
+        );
+    }
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("SIGINT").unwrap(), libc::SIGINT);
+        assert_eq!(parse_signal("QUIT").unwrap(), libc::SIGQUIT);
+        assert!(parse_signal("SIGFOO").is_err());
+        assert!(parse_signal("sigterm").is_err());
+    }
+
+    #[test]
+    fn test_expand_clones() {
+        let map = |kvs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            kvs.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let mut sys = map(&[("build", "build-linux-2x")]);
+        let mut side = map(&[("bomb", "read-bomb")]);
+        let clones = map(&[
+            ("build-1", "build"),
+            ("bomb-1", "bomb"),
+            ("bomb", "build"),
+            ("bad/name", "build"),
+            ("orphan", "nope"),
+        ]);
+
+        let errors = expand_clones(&clones, &mut sys, &mut side);
+        assert_eq!(errors.len(), 3);
+        assert_eq!(
+            sys.get("build-1").map(|x| x.as_str()),
+            Some("build-linux-2x")
+        );
+        assert_eq!(side.get("bomb-1").map(|x| x.as_str()), Some("read-bomb"));
+        assert_eq!(side.get("bomb").map(|x| x.as_str()), Some("read-bomb"));
+        assert!(!sys.contains_key("orphan") && !side.contains_key("orphan"));
+    }
+
+    #[test]
+    fn test_parse_apply_target() {
+        let json = r#"{
+            "sysloads": { "build": "my-build" },
+            "defs": { "my-build": { "args": ["build-linux.sh", "allmodconfig", "2"], "heavy": true } }
+        }"#;
+        let toml = r#"
+            [sysloads]
+            build = "my-build"
+
+            [defs.my-build]
+            args = ["build-linux.sh", "allmodconfig", "2"]
+            heavy = true
+        "#;
+        for body in [json, toml].iter() {
+            let target = parse_apply_target(body).unwrap();
+            assert_eq!(
+                target.sysloads.get("build").map(|x| x.as_str()),
+                Some("my-build")
+            );
+            assert!(target.sideloads.is_empty());
+            let spec = &target.defs["my-build"];
+            assert_eq!(spec.args, vec!["build-linux.sh", "allmodconfig", "2"]);
+            assert!(spec.heavy);
+
+            let mut defs = SideloadDefs::default();
+            defs.defs.clear();
+            assert_eq!(verify_target(&target, &defs).len(), 1);
+            assert!(verify_target(&target, &defs.overlay(&target.defs)).is_empty());
+            assert!(defs.defs.is_empty());
+        }
+        assert!(parse_apply_target("{ not json").is_err());
+        assert!(parse_apply_target("sysloads = 1").is_err());
+    }
+
+    #[test]
+    fn test_parse_oom_kills() {
+        let log = "\
+python3 invoked oom-killer: gfp_mask=0xcc0(GFP_KERNEL), order=0, oom_score_adj=0
+oom-kill:constraint=CONSTRAINT_MEMCG,nodemask=(null),cpuset=/,mems_allowed=0,oom_memcg=/system.slice/rd-sysload-other.service,task_memcg=/system.slice/rd-sysload-other.service,task=stress,pid=100,uid=0
+Memory cgroup out of memory: Killed process 100 (stress) total-vm:1024kB, anon-rss:512kB, file-rss:0kB, shmem-rss:0kB, UID:0 pgtables:64kB oom_score_adj:0
+oom-kill:constraint=CONSTRAINT_MEMCG,nodemask=(null),cpuset=/,mems_allowed=0,oom_memcg=/system.slice/rd-sysload-grow.service,task_memcg=/system.slice/rd-sysload-grow.service,task=python3,pid=1234,uid=0
+Memory cgroup out of memory: Killed process 1234 (python3) total-vm:2048kB, anon-rss:1024kB, file-rss:8kB, shmem-rss:4kB, UID:0 pgtables:64kB oom_score_adj:0
+";
+        let kills = parse_oom_kills(log, "/system.slice/rd-sysload-grow.service");
+        assert_eq!(kills.len(), 1);
+        assert_eq!(kills[0].pid, 1234);
+        assert_eq!(kills[0].task, "python3");
+        assert_eq!(kills[0].total_vm, 2048 << 10);
+        assert_eq!(kills[0].anon_rss, 1024 << 10);
+        assert_eq!(kills[0].file_rss, 8 << 10);
+        assert_eq!(kills[0].shmem_rss, 4 << 10);
+
+        assert!(parse_oom_kills(log, "/system.slice/rd-sysload-none.service").is_empty());
+        assert!(parse_oom_kills("", "/system.slice/rd-sysload-grow.service").is_empty());
     }
 }
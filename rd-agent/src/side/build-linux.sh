@@ -2,25 +2,69 @@
 # Copyright (c) Facebook, Inc. and its affiliates
 
 set -e
+set -o pipefail
 
-NR_JOBS=
+# $MAKE_JOBS comes from the sideload's make_jobs or extra_envs. The
+# optional job ratio $2[/$3] scales it, or $NR_CPUS if unset, with 20%
+# headroom. Without a ratio, $MAKE_JOBS is the -j count and make -j runs
+# without a count if it's unset.
+NR_JOBS=$MAKE_JOBS
 if [ -n "$2" ]; then
-    NR_JOBS=$((NR_CPUS * $2))
+    NR_JOBS=$((${MAKE_JOBS:-$NR_CPUS} * $2))
     if [ -n "$3" ]; then
         NR_JOBS=$((NR_JOBS / $3))
     fi
     NR_JOBS=$(((NR_JOBS * 12 + 9) / 10))
 fi
 
-echo "Building $1 kernel with $NR_JOBS jobs..."
+function status {
+    if [ -n "$STATUS_FILE" ]; then
+        echo "$1" > "$STATUS_FILE.tmp" && mv -f "$STATUS_FILE.tmp" "$STATUS_FILE"
+    fi
+}
+
+LINUX_TAR=${LINUX_TAR:-../../linux.tar}
+KCONFIG_DESC=${KCONFIG:-$1}
+
+echo "Building $KCONFIG_DESC kernel with $NR_JOBS jobs..."
 
-rm -rf linux-*
-tar --checkpoint=2500 --checkpoint-action=echo="Untarred %u files: %T" -xf ../../linux.tar
-cd linux-*
-make "$1"
+# With $LINUX_SRC, build out-of-tree against the shared read-only source
+# tree instead of untarring a private copy.
+MAKE_ARGS=()
+if [ -n "$LINUX_SRC" ]; then
+    SRC=$(echo "$LINUX_SRC"/linux-*)
+    rm -rf linux-* build
+    mkdir build
+    cd build
+    MAKE_ARGS=(-C "$SRC" O="$PWD")
+else
+    status "untarring"
+    rm -rf linux-*
+    tar --checkpoint=2500 --checkpoint-action=echo="Untarred %u files: %T" -xf "$LINUX_TAR"
+    cd linux-*
+fi
+status "configuring $KCONFIG_DESC"
+if [ -n "$KCONFIG" ]; then
+    cp "$KCONFIG" .config
+    make "${MAKE_ARGS[@]}" olddefconfig
+else
+    make "${MAKE_ARGS[@]}" "$1"
+fi
 
 STARTED_AT=$(date +%s)
-make -j$NR_JOBS
+NR_BUILT=0
+make "${MAKE_ARGS[@]}" -j$NR_JOBS | while read -r line; do
+    echo "$line"
+    case "$line" in
+        CC*|LD*|AR*)
+            NR_BUILT=$((NR_BUILT + 1))
+            if ((NR_BUILT % 100 == 0)); then
+                status "building: $NR_BUILT targets, last ${line##* }"
+            fi
+            ;;
+    esac
+done
 ENDED_AT=$(date +%s)
 
+status "done in $((ENDED_AT-STARTED_AT))s"
 echo "Compilation took $((ENDED_AT-STARTED_AT)) seconds"
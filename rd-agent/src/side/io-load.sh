@@ -0,0 +1,18 @@
+#!/bin/bash
+# Copyright (c) Facebook, Inc. and its affiliates
+
+set -e
+
+# Knobs come from the def's io_load through the environment.
+RW_MIX=${IO_RW_MIX:-70}
+BS=${IO_BS:-4096}
+DEPTH=${IO_DEPTH:-32}
+SIZE=${IO_SIZE:-1073741824}
+
+# The file is in the scratch dir which is on $IO_DEV.
+echo "Running ${RW_MIX}% random reads with ${BS}B blocks and $DEPTH depth on $IO_DEV..."
+
+fio --direct=1 --ioengine=libaio --name=io-load --filename=io-load.dat \
+    --size=$SIZE --readwrite=randrw --rwmixread=$RW_MIX --blocksize=$BS \
+    --iodepth=$DEPTH --time_based --runtime=$((365 * 24 * 3600)) \
+    --eta=always --eta-interval=1 | stdbuf -oL tr '\r' '\n'
@@ -0,0 +1,68 @@
+#!/bin/python3
+# Copyright (c) Facebook, Inc. and its affiliates
+
+import os
+import socket
+import sys
+import threading
+import time
+
+CHUNK_SZ = 64 << 10
+
+# Knobs come from the def's net_load through the environment. Without
+# NET_HOST, traffic goes over loopback to a sink in this process so that
+# both sending and receiving are exercised. Otherwise, something must be
+# accepting and discarding connections at NET_HOST:NET_PORT, e.g.
+# "nc -lk PORT > /dev/null".
+host = os.environ.get('NET_HOST', '')
+port = int(os.environ.get('NET_PORT', '0'))
+rate = int(os.environ.get('NET_RATE', '0'))
+conns = int(os.environ.get('NET_CONNS', '4'))
+
+def sink(conn):
+    buf = bytearray(CHUNK_SZ)
+    with conn:
+        while conn.recv_into(buf):
+            pass
+
+def serve(server):
+    while True:
+        conn, _ = server.accept()
+        threading.Thread(target=sink, args=(conn,), daemon=True).start()
+
+def send(addr, rate):
+    try:
+        buf = bytes(CHUNK_SZ)
+        with socket.create_connection(addr) as conn:
+            started_at = time.time()
+            sent = 0
+            while True:
+                sent += conn.send(buf)
+                if rate:
+                    ahead = sent / rate - (time.time() - started_at)
+                    if ahead > 0:
+                        time.sleep(ahead)
+    except Exception as e:
+        print(f'Sending to {addr} failed ({e})', file=sys.stderr, flush=True)
+        os._exit(1)
+
+if host:
+    if not port:
+        print('NET_PORT must be set with NET_HOST', file=sys.stderr);
+        sys.exit(1)
+    addr = (host, port)
+else:
+    server = socket.socket()
+    server.bind(('127.0.0.1', port))
+    server.listen()
+    threading.Thread(target=serve, args=(server,), daemon=True).start()
+    addr = server.getsockname()
+
+rate_str = f'{rate / (1 << 20):.2f}M/s' if rate else 'unlimited'
+print(f'Sending to {addr[0]}:{addr[1]} over {conns} connections, {rate_str}', flush=True)
+
+threads = [threading.Thread(target=send, args=(addr, rate / conns)) for i in range(conns)]
+for t in threads:
+    t.start()
+for t in threads:
+    t.join()
@@ -5,6 +5,7 @@ import os
 import sys
 import math
 import subprocess
+import time
 
 JOBS=8
 MIN_DEPTH_PER_JOB=8
@@ -20,6 +21,11 @@ if len(sys.argv) >= 3:
 else:
    size = 4096
 
+# READ_BOMB_BURST=BURST_SECS:IDLE_SECS alternates between read bursts and idling
+burst = None
+if os.environ.get('READ_BOMB_BURST'):
+   burst = [int(x) for x in os.environ.get('READ_BOMB_BURST').split(':')]
+
 jobs = min(JOBS, math.ceil(depth / MIN_DEPTH_PER_JOB))
 depth = math.ceil(depth / jobs)
 
@@ -27,7 +33,16 @@ print(f'Reading {size/1024}k with {depth} depth and {jobs} jobs from {dev}', flu
 
 cmd = (f"fio --direct=1 --ioengine=libaio --name=read-bomb "
        f"--filename={dev} --readwrite=randread --iodepth={depth} --blocksize={size} "
-       f"--numjobs={jobs} --eta=always --eta-interval=1 | stdbuf -oL tr '\r' '\n'")
+       f"--numjobs={jobs} --eta=always --eta-interval=1")
+if burst is not None:
+   cmd += f" --time_based --runtime={burst[0]}"
+cmd += " | stdbuf -oL tr '\r' '\n'"
 print(f'Running \"{cmd}\"', flush=True)
 
-subprocess.check_call(cmd, shell=True)
+if burst is None:
+   subprocess.check_call(cmd, shell=True)
+else:
+   print(f'Burst mode: {burst[0]}s reads followed by {burst[1]}s idle', flush=True)
+   while True:
+      subprocess.check_call(cmd, shell=True)
+      time.sleep(burst[1])
@@ -14,9 +14,15 @@ use util::systemd::UnitState as US;
 use util::*;
 
 use super::Config;
-use rd_agent_intf::{DisableSeqKnobs, MemoryKnob, Slice, SliceKnobs, SysReq};
+use rd_agent_intf::{DisableSeqKnobs, IoLatProtReport, MemoryKnob, Slice, SliceKnobs, SysReq};
 
-pub fn check_other_io_controllers(sr_failed: &mut HashSet<SysReq>) {
+fn io_latency_path() -> String {
+    Slice::Work.cgrp().to_string() + "/io.latency"
+}
+
+/// `io_latency` is the --io-latency config, whose own io.latency setting
+/// on workload.slice doesn't count.
+pub fn check_other_io_controllers(sr_failed: &mut HashSet<SysReq>, io_latency: Option<u64>) {
     let mut failed = None;
     let mut nr_fails = 0;
 
@@ -26,6 +32,9 @@ pub fn check_other_io_controllers(sr_failed: &mut HashSet<SysReq>) {
         .chain(glob("/sys/fs/cgroup/**/io.low").unwrap())
         .filter_map(Result::ok)
     {
+        if io_latency.is_some() && path == Path::new(&io_latency_path()) {
+            continue;
+        }
         match read_one_line(&path) {
             Ok(line) if line.trim().len() == 0 => continue,
             Err(_) => continue,
@@ -532,6 +541,72 @@ pub fn verify_and_fix_slices(
         verify_and_fix_one_slice(knobs, slice, verify_mem_high, cfg.memcg_recursive_prot())?;
     }
 
-    check_other_io_controllers(&mut HashSet::new());
+    if let Err(e) = verify_and_fix_io_latency(cfg) {
+        warn!("resctl: Failed to apply --io-latency ({:?})", &e);
+    }
+    check_other_io_controllers(&mut HashSet::new(), cfg.io_latency);
+    Ok(())
+}
+
+/// Make workload.slice's io.latency target on the scratch device match
+/// --io-latency so that the kernel throttles the other slices when the
+/// protected workloads' IO latency rises above it.
+pub fn verify_and_fix_io_latency(cfg: &Config) -> Result<()> {
+    let path = io_latency_path();
+    let (maj, min) = cfg.scr_devnr;
+    let devnr = format!("{}:{}", maj, min);
+    let target = cfg.io_latency.unwrap_or(0);
+
+    let cur = match super::report::read_cgroup_nested_keyed_file(&path) {
+        Ok(v) => v
+            .get(&devnr)
+            .and_then(|kv| kv.get("target"))
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0),
+        Err(_) if target == 0 => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if cur != target {
+        info!(
+            "resctl: Setting io.latency target of {:?} to {}usecs",
+            Slice::Work.name(),
+            target
+        );
+        write_one_line(&path, &format!("{} target={}", &devnr, target))?;
+    }
     Ok(())
 }
+
+/// Whether --io-latency is throttling the other slices. The kernel shows
+/// the io.latency queue depth in io.stat only with blkcg debug stats on.
+pub fn io_lat_prot_report(cfg: &Config) -> IoLatProtReport {
+    let mut rep = IoLatProtReport {
+        target: cfg.io_latency,
+        ..Default::default()
+    };
+    if cfg.io_latency.is_none() {
+        return rep;
+    }
+
+    let devnr = format!("{}:{}", cfg.scr_devnr.0, cfg.scr_devnr.1);
+    let mut has_stats = false;
+    for slice in Slice::into_enum_iter().filter(|s| *s != Slice::Work) {
+        let path = slice.cgrp().to_string() + "/io.stat";
+        let stat = match super::report::read_cgroup_nested_keyed_file(&path) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        let depth = match stat.get(&devnr).and_then(|kv| kv.get("depth")) {
+            Some(v) => v,
+            None => continue,
+        };
+        has_stats = true;
+        if let Ok(depth) = depth.parse::<u32>() {
+            rep.throttled.insert(slice.name().into(), depth);
+        }
+    }
+    if has_stats {
+        rep.engaged = Some(!rep.throttled.is_empty());
+    }
+    rep
+}
@@ -150,7 +150,7 @@ pub fn format_pct(ratio: f64) -> String {
     }
 }
 
-fn is_executable<P: AsRef<Path>>(path_in: P) -> bool {
+pub fn is_executable<P: AsRef<Path>>(path_in: P) -> bool {
     let path = path_in.as_ref();
     match path.metadata() {
         Ok(md) => md.is_file() && md.mode() & 0o111 != 0,
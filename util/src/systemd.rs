@@ -27,10 +27,12 @@ thread_local!(pub static SYS_SD_BUS: SystemdDbus = SystemdDbus::new(false).unwra
 thread_local!(pub static USR_SD_BUS: SystemdDbus = SystemdDbus::new(true).unwrap());
 
 pub enum Prop {
+    I32(i32),
     U32(u32),
     U64(u64),
     Bool(bool),
     String(String),
+    Bytes(Vec<u8>),
 }
 
 fn escape_name(name: &str) -> String {
@@ -167,6 +169,12 @@ impl SystemdDbus {
         Ok(())
     }
 
+    pub fn kill_unit(&self, name: &str, signal: i32) -> Result<()> {
+        let m = new_sd1_msg("KillUnit")?.append3(name, "all", signal);
+        self.conn.send_with_reply_and_block(m, *DBUS_TIMEOUT)?;
+        Ok(())
+    }
+
     pub fn restart_unit(&self, name: &str) -> Result<()> {
         let m = new_sd1_msg("RestartUnit")?.append2(&name, "fail");
         self.conn.send_with_reply_and_block(m, *DBUS_TIMEOUT)?;
@@ -227,6 +235,13 @@ impl UnitProps {
             .and_then(|x| Some(x.to_string()))
     }
 
+    pub fn i32(&self, key: &str) -> Option<i32> {
+        self.props
+            .get(key)
+            .and_then(|x| x.as_i64())
+            .map(|x| x as i32)
+    }
+
     pub fn u64_dfl_max(&self, key: &str) -> Option<u64> {
         match self.props.get(key) {
             Some(v) => match v.as_u64() {
@@ -434,10 +449,12 @@ impl Unit {
         let props: PropVec = vec![(
             key.to_string(),
             match prop {
+                Prop::I32(v) => Variant(Box::new(v)),
                 Prop::U32(v) => Variant(Box::new(v)),
                 Prop::U64(v) => Variant(Box::new(v)),
                 Prop::Bool(v) => Variant(Box::new(v)),
                 Prop::String(v) => Variant(Box::new(v)),
+                Prop::Bytes(v) => Variant(Box::new(v)),
             },
         )];
         self.sd_bus()
@@ -535,6 +552,11 @@ impl Unit {
         info!("svc: {:?} restarting ({:?})", &self.name, &self.state);
         self.sd_bus().with(|s| s.restart_unit(&self.name))
     }
+
+    pub fn kill(&mut self, signal: i32) -> Result<()> {
+        info!("svc: {:?} killing with signal {}", &self.name, signal);
+        self.sd_bus().with(|s| s.kill_unit(&self.name, signal))
+    }
 }
 
 pub struct TransientService {
@@ -617,10 +639,12 @@ impl TransientService {
         let mut pv: PropVec = self.unit.resctl_props();
         for (k, v) in self.extra_props.iter() {
             match v {
+                Prop::I32(v) => pv.push((k.clone(), Variant(Box::new(*v)))),
                 Prop::U32(v) => pv.push((k.clone(), Variant(Box::new(*v)))),
                 Prop::U64(v) => pv.push((k.clone(), Variant(Box::new(*v)))),
                 Prop::Bool(v) => pv.push((k.clone(), Variant(Box::new(*v)))),
                 Prop::String(v) => pv.push((k.clone(), Variant(Box::new(v.clone())))),
+                Prop::Bytes(v) => pv.push((k.clone(), Variant(Box::new(v.clone())))),
             }
         }
 